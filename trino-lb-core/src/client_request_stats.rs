@@ -0,0 +1,139 @@
+//! Building blocks for the (opt-in) per-user request statistics, which help to identify clients sending too many
+//! requests or abusively big headers.
+//!
+//! The statistics are kept in buckets of [`CLIENT_REQUEST_STATS_BUCKET_SIZE`], so that persistences only need to
+//! increment counters and can drop whole buckets once they are older than the configured window.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::HeaderMap;
+use serde::Serialize;
+
+pub const CLIENT_REQUEST_STATS_BUCKET_SIZE: Duration = Duration::from_secs(60);
+
+/// Users are stored in the persistence, so they are truncated to this number of characters.
+pub const MAX_USER_LENGTH: usize = 64;
+
+/// Requests without (or with an empty) `X-Trino-User` header are accounted to this user.
+pub const UNKNOWN_USER: &str = "<unknown>";
+
+/// Requests of users exceeding the maximum number of users per bucket are accounted to this user.
+pub const OTHER_USERS: &str = "<other>";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRequestCounts {
+    pub requests: u64,
+
+    /// Sum of the header sizes of all requests.
+    pub header_bytes: u64,
+}
+
+impl std::ops::AddAssign for ClientRequestCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.requests = self.requests.saturating_add(other.requests);
+        self.header_bytes = self.header_bytes.saturating_add(other.header_bytes);
+    }
+}
+
+/// Returns the bucket the given point in time falls into.
+pub fn bucket_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / CLIENT_REQUEST_STATS_BUCKET_SIZE.as_secs()
+}
+
+/// Returns the number of buckets needed to cover the given duration, which is at least one.
+pub fn bucket_count(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .div_ceil(CLIENT_REQUEST_STATS_BUCKET_SIZE.as_secs())
+        .max(1)
+}
+
+/// As the user is controlled by the client, only a safe subset of characters is kept (all others are replaced by
+/// `_`) and it is truncated to [`MAX_USER_LENGTH`] characters.
+pub fn sanitize_user(user: Option<&str>) -> String {
+    let sanitized = user
+        .unwrap_or_default()
+        .chars()
+        .take(MAX_USER_LENGTH)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if sanitized.is_empty() {
+        UNKNOWN_USER.to_owned()
+    } else {
+        sanitized
+    }
+}
+
+/// Size of all headers (names and values) of a request.
+pub fn header_bytes(headers: &HeaderMap) -> u64 {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().len() + value.len()) as u64)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(Some("alice"), "alice")]
+    #[case(Some("alice.smith@example.com"), "alice.smith@example.com")]
+    #[case(Some("alice smith"), "alice_smith")]
+    #[case(Some("<other>"), "_other_")]
+    #[case(Some("a/b:c\n"), "a_b_c_")]
+    #[case(Some("ä"), "_")]
+    #[case(Some(""), UNKNOWN_USER)]
+    #[case(None, UNKNOWN_USER)]
+    fn test_sanitize_user(#[case] user: Option<&str>, #[case] expected: &str) {
+        assert_eq!(sanitize_user(user), expected);
+    }
+
+    #[test]
+    fn test_sanitize_user_truncates() {
+        let user = "a".repeat(10 * MAX_USER_LENGTH);
+        assert_eq!(sanitize_user(Some(&user)).len(), MAX_USER_LENGTH);
+    }
+
+    #[rstest]
+    #[case(Duration::ZERO, 1)]
+    #[case(Duration::from_secs(1), 1)]
+    #[case(Duration::from_secs(60), 1)]
+    #[case(Duration::from_secs(61), 2)]
+    #[case(Duration::from_secs(10 * 60), 10)]
+    fn test_bucket_count(#[case] duration: Duration, #[case] expected: u64) {
+        assert_eq!(bucket_count(duration), expected);
+    }
+
+    #[test]
+    fn test_bucket_of() {
+        assert_eq!(bucket_of(UNIX_EPOCH), 0);
+        assert_eq!(bucket_of(UNIX_EPOCH + Duration::from_secs(59)), 0);
+        assert_eq!(bucket_of(UNIX_EPOCH + Duration::from_secs(60)), 1);
+    }
+
+    #[test]
+    fn test_header_bytes() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(header_bytes(&headers), 0);
+
+        headers.insert("x-trino-user", HeaderValue::from_static("alice"));
+        headers.append("x-trino-client-tags", HeaderValue::from_static("a"));
+        headers.append("x-trino-client-tags", HeaderValue::from_static("bc"));
+        assert_eq!(header_bytes(&headers), 12 + 5 + 19 + 1 + 19 + 2);
+    }
+}
@@ -0,0 +1,208 @@
+//! Parsing of the `X-Trino-Client-Tags` header, which is shared by all routers looking at client tags.
+//!
+//! As the header is fully controlled by the client, the parsing never fails. Instead, it tolerates bare tags (`foo`),
+//! empty values (`foo=`), duplicate tags and surrounding whitespace and ignores headers exceeding
+//! [`MAX_CLIENT_TAGS_HEADER_LENGTH`].
+
+use std::collections::HashSet;
+
+use http::HeaderMap;
+use tracing::warn;
+
+pub const TRINO_CLIENT_TAGS_HEADER: &str = "x-trino-client-tags";
+
+/// Longer headers are ignored as a whole, so that hostile clients can not slow down routing.
+pub const MAX_CLIENT_TAGS_HEADER_LENGTH: usize = 4096;
+
+/// The client tags in the order the client sent them, without duplicates.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientTags {
+    tags: Vec<String>,
+}
+
+impl ClientTags {
+    /// Parses all `X-Trino-Client-Tags` headers of the request. Headers that are not valid UTF-8 are skipped.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let values = headers
+            .get_all(TRINO_CLIENT_TAGS_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+
+        let length = values.iter().map(|value| value.len() + 1).sum::<usize>();
+        if length > MAX_CLIENT_TAGS_HEADER_LENGTH {
+            warn!(
+                length,
+                max_length = MAX_CLIENT_TAGS_HEADER_LENGTH,
+                "Ignoring the client tags, as the header is too long"
+            );
+            return Self::default();
+        }
+
+        Self::parse(&values.join(","))
+    }
+
+    /// Parses a comma separated list of client tags. Whitespace around tags as well as empty and duplicate tags are
+    /// dropped.
+    pub fn parse(value: &str) -> Self {
+        let mut seen = HashSet::new();
+        let tags = value
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .filter(|tag| seen.insert(*tag))
+            .map(ToOwned::to_owned)
+            .collect();
+
+        Self { tags }
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Returns the value of the first `key=value` tag with the given key. Everything after the first `=` is part of
+    /// the value, so `key=` results in an empty value. Bare tags don't have a value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .filter_map(|tag| tag.split_once('='))
+            .find(|(k, _)| k.trim_end() == key)
+            .map(|(_, value)| value.trim_start())
+    }
+
+    /// Formats the tags the same way Trino clients send them.
+    pub fn to_header_value(&self) -> String {
+        self.tags.join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use rand::{seq::SliceRandom, Rng};
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("", &[])]
+    #[case(",,, ,", &[])]
+    #[case("foo", &["foo"])]
+    #[case(" foo , bar ", &["foo", "bar"])]
+    #[case("foo,foo,bar,foo", &["foo", "bar"])]
+    #[case("system=airflow,foo", &["system=airflow", "foo"])]
+    #[case("key=,=value,=", &["key=", "=value", "="])]
+    #[case("a=b=c", &["a=b=c"])]
+    fn test_parse(#[case] value: &str, #[case] expected: &[&str]) {
+        assert_eq!(
+            ClientTags::parse(value).iter().collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case("system=airflow", "system", Some("airflow"))]
+    #[case("system = airflow", "system", Some("airflow"))]
+    #[case("system", "system", None)]
+    #[case("system=", "system", Some(""))]
+    #[case("system=a=b", "system", Some("a=b"))]
+    // The first occurrence wins
+    #[case("system=airflow,system=superset", "system", Some("airflow"))]
+    #[case("system,system=superset", "system", Some("superset"))]
+    #[case("=airflow", "", Some("airflow"))]
+    #[case("label=foo", "system", None)]
+    fn test_get(#[case] value: &str, #[case] key: &str, #[case] expected: Option<&str>) {
+        assert_eq!(ClientTags::parse(value).get(key), expected);
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(ClientTags::from_headers(&headers).is_empty());
+
+        headers.append(
+            TRINO_CLIENT_TAGS_HEADER,
+            HeaderValue::from_static("foo,bar"),
+        );
+        headers.append(
+            TRINO_CLIENT_TAGS_HEADER,
+            HeaderValue::from_static("bar,bak"),
+        );
+        assert_eq!(
+            ClientTags::from_headers(&headers).to_header_value(),
+            "foo,bar,bak"
+        );
+
+        // Not valid UTF-8
+        headers.append(
+            TRINO_CLIENT_TAGS_HEADER,
+            HeaderValue::from_bytes(b"\xff\xfe").unwrap(),
+        );
+        assert_eq!(
+            ClientTags::from_headers(&headers).to_header_value(),
+            "foo,bar,bak"
+        );
+    }
+
+    #[test]
+    fn test_from_headers_too_long() {
+        let mut headers = HeaderMap::new();
+        let value = "a,".repeat(MAX_CLIENT_TAGS_HEADER_LENGTH);
+        headers.insert(TRINO_CLIENT_TAGS_HEADER, value.parse().unwrap());
+        assert!(ClientTags::from_headers(&headers).is_empty());
+
+        // Also many headers, each of them being short
+        let mut headers = HeaderMap::new();
+        for _ in 0..MAX_CLIENT_TAGS_HEADER_LENGTH {
+            headers.append(TRINO_CLIENT_TAGS_HEADER, HeaderValue::from_static("a"));
+        }
+        assert!(ClientTags::from_headers(&headers).is_empty());
+    }
+
+    /// Throws random garbage made up of the characters relevant for parsing at the parser and checks that it does not
+    /// panic and always produces well-formed tags.
+    #[test]
+    fn test_parse_adversarial_inputs() {
+        let alphabet = [",", "=", " ", "\t", "a", "b", "key", "==", ",,", "ä", "🦀"];
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10_000 {
+            let length = rng.gen_range(0..32);
+            let value = (0..length)
+                .map(|_| *alphabet.choose(&mut rng).unwrap())
+                .collect::<String>();
+
+            let tags = ClientTags::parse(&value);
+            for tag in tags.iter() {
+                assert!(!tag.is_empty(), "Empty tag parsed from {value:?}");
+                assert!(!tag.contains(','), "Tag {tag:?} parsed from {value:?}");
+                assert_eq!(tag, tag.trim(), "Untrimmed tag parsed from {value:?}");
+                assert!(tags.contains(tag));
+            }
+            assert_eq!(
+                tags.iter().collect::<HashSet<_>>().len(),
+                tags.iter().count(),
+                "Duplicate tags parsed from {value:?}"
+            );
+
+            // Parsing is deterministic and the formatted tags parse to the same tags again
+            assert_eq!(ClientTags::parse(&value), tags);
+            assert_eq!(ClientTags::parse(&tags.to_header_value()), tags);
+
+            for key in ["", "a", "key"] {
+                if let Some(v) = tags.get(key) {
+                    assert!(!v.contains(','));
+                }
+            }
+        }
+    }
+}
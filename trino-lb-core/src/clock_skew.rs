@@ -0,0 +1,42 @@
+//! Points in time (such as the creation time of a queued query) are often recorded by a different trino-lb replica
+//! than the one using them. As the clocks of the replicas are never perfectly in sync, such a point in time can lie
+//! slightly in the future.
+
+use std::time::{Duration, SystemTime, SystemTimeError};
+
+/// Returns the time elapsed since the given point in time. In case it lies in the future by at most `max_clock_skew`,
+/// zero is returned instead of an error.
+pub fn elapsed_allowing_clock_skew(
+    time: SystemTime,
+    max_clock_skew: Duration,
+) -> Result<Duration, SystemTimeError> {
+    match time.elapsed() {
+        Err(error) if error.duration() <= max_clock_skew => Ok(Duration::ZERO),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elapsed_allowing_clock_skew() {
+        let max_clock_skew = Duration::from_secs(5);
+
+        let past = SystemTime::now() - Duration::from_secs(60);
+        assert!(
+            elapsed_allowing_clock_skew(past, max_clock_skew).unwrap() >= Duration::from_secs(60)
+        );
+
+        let slightly_in_future = SystemTime::now() + Duration::from_secs(1);
+        assert_eq!(
+            elapsed_allowing_clock_skew(slightly_in_future, max_clock_skew).unwrap(),
+            Duration::ZERO
+        );
+        assert!(elapsed_allowing_clock_skew(slightly_in_future, Duration::ZERO).is_err());
+
+        let far_in_future = SystemTime::now() + Duration::from_secs(60);
+        assert!(elapsed_allowing_clock_skew(far_in_future, max_clock_skew).is_err());
+    }
+}
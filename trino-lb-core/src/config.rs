@@ -2,6 +2,7 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
     fs::File,
+    num::NonZeroUsize,
     path::PathBuf,
     time::Duration,
 };
@@ -10,7 +11,9 @@ use serde::Deserialize;
 use snafu::{ResultExt, Snafu};
 use url::Url;
 
-use crate::{trino_query_plan::QueryPlanEstimation, TrinoClusterName};
+use crate::{
+    trino_api::QUEUED_IN_TRINO_LB_STATE, trino_query_plan::QueryPlanEstimation, TrinoClusterName,
+};
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -43,6 +46,11 @@ pub struct Config {
 
     pub routing_fallback: String,
 
+    /// Cluster group to fall back to based on the `X-Trino-Source` header of the query, which is used in case no
+    /// router made a decision. Queries with a source that is not listed here fall back to the `routing_fallback`.
+    #[serde(default)]
+    pub routing_fallback_by_source: HashMap<String, String>,
+
     pub cluster_autoscaler: Option<ScalerConfig>,
 }
 
@@ -62,17 +70,141 @@ pub struct TrinoLbConfig {
     )]
     pub refresh_query_counter_interval: Duration,
 
+    /// How many Trino clusters the query counters are fetched from at the same time, so that a big fleet of clusters is
+    /// not asked all at once. Unbounded by default.
+    #[serde(default)]
+    pub refresh_query_counter_concurrency: Option<NonZeroUsize>,
+
     pub tracing: Option<TrinoLbTracingConfig>,
 
     #[serde(default)]
     pub ports: TrinoLbPortsConfig,
+
+    /// How the root path `/` of the main and the metrics server responds, e.g. for health checks.
+    #[serde(default)]
+    pub root_path: TrinoLbRootPathConfig,
+
+    pub idempotency: Option<TrinoLbIdempotencyConfig>,
+
+    /// Allows trusted clients to bypass the routers and force the cluster group of a query. Disabled in case this is
+    /// not configured.
+    pub cluster_group_override: Option<TrinoLbClusterGroupOverrideConfig>,
+
+    /// The admin API is only enabled in case this is configured.
+    pub admin: Option<TrinoLbAdminConfig>,
+
+    /// Experimental: Record the actual runtime of queries, so that routers can make use of it.
+    pub query_runtime_feedback: Option<TrinoLbQueryRuntimeFeedbackConfig>,
+
+    /// State reported to clients for queries queued in trino-lb. Some clients and dashboards only know the Trino
+    /// states, which can be worked around by setting this to `QUEUED`.
+    #[serde(default = "default_queued_query_state")]
+    pub queued_query_state: String,
+
+    /// `User-Agent` header trino-lb sends on the requests it issues to the Trino clusters.
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+
+    /// Only forward polls and cancellations of queries running on Trino in case the slug and token of the requested
+    /// path match the nextUri Trino issued. This costs an additional write to the persistence per poll.
+    #[serde(default)]
+    pub validate_statement_uris: bool,
+
+    /// Queries are only handed over to a Trino cluster once the client polled the queued query this many times, even if
+    /// a cluster has capacity left. This forces new queries through the queue, which spreads bursts of queries. The
+    /// default of `0` hands over queries directly on submission.
+    #[serde(default)]
+    pub min_admission_sequence: u64,
+
+    /// Queued queries are failed once the client polled them more than this many times, so that a misbehaving client
+    /// can not keep a query queued forever. Unbounded by default.
+    #[serde(default)]
+    pub max_poll_sequence: Option<u64>,
+
+    /// In case this is configured, queued queries only store the essential headers Trino needs to run them (see
+    /// [`crate::trino_query::ESSENTIAL_QUEUED_QUERY_HEADERS`]) plus the listed headers, all other headers are dropped
+    /// and not sent to Trino once the query is handed over. All headers are stored by default.
+    #[serde(default)]
+    pub queued_query_header_allowlist: Option<Vec<String>>,
+
+    /// Points in time recorded by other trino-lb replicas (e.g. the creation time of a queued query) may lie in the
+    /// future by this much, as the clocks of the replicas are never perfectly in sync. They are treated as being now
+    /// instead of failing the request.
+    #[serde(default = "default_max_clock_skew", with = "humantime_serde")]
+    pub max_clock_skew: Duration,
+
+    /// How often handing over a query is retried right away in case the chosen cluster got full in the meantime (e.g.
+    /// because of other queries submitted at the same time), before the query is queued.
+    #[serde(default = "default_hand_over_retries")]
+    pub hand_over_retries: u64,
+
+    /// How often the persistence reads needed to choose a cluster for a query (cluster states, routing exclusions and
+    /// query counters) are retried after a short delay in case they fail, e.g. because of a transient network problem.
+    /// Writes are never retried, as they might have been applied despite the failure.
+    #[serde(default = "default_routing_persistence_read_retries")]
+    pub routing_persistence_read_retries: u64,
+
+    /// HTTP status codes (e.g. `503`) Trino responds with in case of transient problems. Requests polling the state of
+    /// a query are retried a few times with a backoff in case Trino responds with one of them. Empty by default, so no
+    /// request is retried.
+    #[serde(default)]
+    pub retryable_trino_status_codes: HashSet<u16>,
+
+    /// Remove the stored state and query count of Trino clusters that are not configured any more during startup.
+    #[serde(default)]
+    pub cleanup_removed_clusters: bool,
+
+    /// Access log in the Combined Log Format, which is only written in case this is configured.
+    pub access_log: Option<TrinoLbAccessLogConfig>,
+
+    /// Record the request rate and header sizes per user, which can be inspected using the admin API.
+    pub client_request_stats: Option<TrinoLbClientRequestStatsConfig>,
+
+    /// Count the queries handed over to Trino per user and cluster group (e.g. for internal billing), which can be
+    /// inspected using the admin API.
+    pub query_chargeback: Option<TrinoLbQueryChargebackConfig>,
+
+    /// Webhook that is called whenever the scaler changes the state of a Trino cluster.
+    pub cluster_state_webhook: Option<TrinoLbClusterStateWebhookConfig>,
+
+    /// Trino clusters pointing to the same endpoint (e.g. because the same coordinator serves multiple cluster groups)
+    /// share a single query counter, so that the coordinator does not get more queries than any of its cluster groups
+    /// allows.
+    #[serde(default)]
+    pub share_query_counters_of_same_endpoint: bool,
+
+    /// What to do in case a query handed over to a Trino cluster has the same id as a query already stored for a
+    /// different Trino cluster. Query ids are expected to be unique, but two Trino clusters could theoretically
+    /// generate the same one.
+    #[serde(default)]
+    pub query_id_collision_behavior: QueryIdCollisionBehavior,
 }
 
 fn default_refresh_query_counter_interval() -> Duration {
     Duration::from_secs(60)
 }
 
-#[derive(Clone, Debug, Default, Deserialize)]
+fn default_max_clock_skew() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_hand_over_retries() -> u64 {
+    2
+}
+
+fn default_routing_persistence_read_retries() -> u64 {
+    1
+}
+
+fn default_queued_query_state() -> String {
+    QUEUED_IN_TRINO_LB_STATE.to_owned()
+}
+
+fn default_user_agent() -> String {
+    format!("trino-lb/{}", env!("CARGO_PKG_VERSION"))
+}
+
+#[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TrinoLbTlsConfig {
     #[serde(default)]
@@ -80,6 +212,50 @@ pub struct TrinoLbTlsConfig {
 
     pub cert_pem_file: Option<PathBuf>,
     pub key_pem_file: Option<PathBuf>,
+
+    /// Minimum TLS version clients need to use. Defaults to TLS 1.2.
+    #[serde(default)]
+    pub min_tls_version: TlsVersion,
+
+    /// Restricts the cipher suites offered to the given ones, e.g. `TLS13_AES_256_GCM_SHA384`.
+    /// Defaults to all cipher suites rustls considers safe.
+    pub cipher_suites: Option<Vec<String>>,
+
+    /// Interval in which the PEM files are checked for changes, so that rotated certificates are picked up without a
+    /// restart.
+    #[serde(
+        default = "TrinoLbTlsConfig::default_reload_interval",
+        with = "humantime_serde"
+    )]
+    pub reload_interval: Duration,
+}
+
+impl TrinoLbTlsConfig {
+    fn default_reload_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+impl Default for TrinoLbTlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_pem_file: None,
+            key_pem_file: None,
+            min_tls_version: TlsVersion::default(),
+            cipher_suites: None,
+            reload_interval: Self::default_reload_interval(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -134,14 +310,343 @@ impl Default for TrinoLbPortsConfig {
     }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbRootPathConfig {
+    /// The server handling the Trino client requests (listening on the http or https port).
+    #[serde(default = "TrinoLbRootPathConfig::default_main")]
+    pub main: RootPathResponse,
+
+    /// The server exposing the Prometheus metrics (listening on the metrics port).
+    #[serde(default = "TrinoLbRootPathConfig::default_metrics")]
+    pub metrics: RootPathResponse,
+}
+
+impl TrinoLbRootPathConfig {
+    fn default_main() -> RootPathResponse {
+        RootPathResponse::NotFound
+    }
+
+    fn default_metrics() -> RootPathResponse {
+        RootPathResponse::Redirect("/metrics".to_owned())
+    }
+}
+
+impl Default for TrinoLbRootPathConfig {
+    fn default() -> Self {
+        Self {
+            main: Self::default_main(),
+            metrics: Self::default_metrics(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RootPathResponse {
+    /// Permanently redirect to the given path.
+    Redirect(String),
+    /// Respond with `200 OK`.
+    Ok,
+    /// Respond with `404 Not Found`.
+    NotFound,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbIdempotencyConfig {
+    /// Name of the HTTP header clients can use to send an idempotency key with the initial `POST /v1/statement`.
+    #[serde(default = "TrinoLbIdempotencyConfig::default_header_name")]
+    pub header_name: String,
+
+    /// How long retries with the same idempotency key will get the already submitted query.
+    #[serde(
+        default = "TrinoLbIdempotencyConfig::default_ttl",
+        with = "humantime_serde"
+    )]
+    pub ttl: Duration,
+}
+
+impl TrinoLbIdempotencyConfig {
+    fn default_header_name() -> String {
+        "X-Trino-Lb-Idempotency-Key".to_string()
+    }
+
+    fn default_ttl() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbClusterGroupOverrideConfig {
+    /// Name of the HTTP header containing the cluster group the query should run on.
+    #[serde(default = "TrinoLbClusterGroupOverrideConfig::default_header_name")]
+    pub header_name: String,
+
+    /// Name of the HTTP header containing the token authenticating the override.
+    #[serde(default = "TrinoLbClusterGroupOverrideConfig::default_token_header_name")]
+    pub token_header_name: String,
+
+    /// Shared secret only trusted clients know. Overrides with a missing or wrong token are rejected.
+    pub token: String,
+}
+
+impl TrinoLbClusterGroupOverrideConfig {
+    fn default_header_name() -> String {
+        "X-Trino-Lb-Force-Group".to_string()
+    }
+
+    fn default_token_header_name() -> String {
+        "X-Trino-Lb-Force-Group-Token".to_string()
+    }
+}
+
+impl Debug for TrinoLbClusterGroupOverrideConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrinoLbClusterGroupOverrideConfig")
+            .field("header_name", &self.header_name)
+            .field("token_header_name", &self.token_header_name)
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum QueryIdCollisionBehavior {
+    /// Fail the request submitting the second query, so that the first query keeps working.
+    #[default]
+    Error,
+    /// Replace the stored query, so that the second query works, but polls of the first query are sent to the wrong
+    /// Trino cluster.
+    Overwrite,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbAccessLogConfig {
+    #[serde(default)]
+    pub output: AccessLogOutput,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AccessLogOutput {
+    #[default]
+    Stdout,
+    /// Append to the given file, which is created in case it does not exist.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbClientRequestStatsConfig {
+    /// Period the statistics are kept for. It is rounded up to full minutes.
+    #[serde(
+        default = "TrinoLbClientRequestStatsConfig::default_window",
+        with = "humantime_serde"
+    )]
+    pub window: Duration,
+
+    /// Maximum number of users tracked per minute, requests of further users are accounted to `<other>`.
+    #[serde(default = "TrinoLbClientRequestStatsConfig::default_max_users")]
+    pub max_users: u64,
+}
+
+impl TrinoLbClientRequestStatsConfig {
+    fn default_window() -> Duration {
+        Duration::from_secs(10 * 60)
+    }
+
+    fn default_max_users() -> u64 {
+        1000
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbQueryChargebackConfig {
+    /// Period the query counts are kept for. It is rounded up to full days.
+    #[serde(
+        default = "TrinoLbQueryChargebackConfig::default_retention",
+        with = "humantime_serde"
+    )]
+    pub retention: Duration,
+
+    /// Maximum number of users tracked per day, queries of further users are accounted to `<other>`.
+    #[serde(default = "TrinoLbQueryChargebackConfig::default_max_users")]
+    pub max_users: u64,
+}
+
+impl TrinoLbQueryChargebackConfig {
+    fn default_retention() -> Duration {
+        Duration::from_secs(31 * 24 * 60 * 60)
+    }
+
+    fn default_max_users() -> u64 {
+        1000
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbClusterStateWebhookConfig {
+    /// The URL the state changes are POSTed to as JSON.
+    pub url: Url,
+
+    /// Failed or timed out calls are only logged and not retried.
+    #[serde(
+        default = "TrinoLbClusterStateWebhookConfig::default_timeout",
+        with = "humantime_serde"
+    )]
+    pub timeout: Duration,
+}
+
+impl TrinoLbClusterStateWebhookConfig {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbQueryRuntimeFeedbackConfig {
+    /// Weight (between `0.0` and `1.0`) a newly observed runtime gets when blended with the runtime observed so far for
+    /// the same query fingerprint. A value of `1.0` only remembers the latest runtime.
+    #[serde(default = "TrinoLbQueryRuntimeFeedbackConfig::default_decay")]
+    pub decay: f64,
+
+    /// How long the observed runtime of a query fingerprint is remembered after the last time it was observed.
+    #[serde(
+        default = "TrinoLbQueryRuntimeFeedbackConfig::default_ttl",
+        with = "humantime_serde"
+    )]
+    pub ttl: Duration,
+}
+
+impl TrinoLbQueryRuntimeFeedbackConfig {
+    fn default_decay() -> f64 {
+        0.3
+    }
+
+    fn default_ttl() -> Duration {
+        Duration::from_secs(7 * 24 * 60 * 60)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbAdminConfig {
+    pub authentication: TrinoLbAdminAuthenticationConfig,
+
+    /// The other trino-lb replicas, which `GET /admin/status` asks for their local status.
+    pub peers: Option<TrinoLbAdminPeersConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoLbAdminPeersConfig {
+    pub discovery: TrinoLbAdminPeerDiscoveryConfig,
+
+    /// Bearer token the replicas use to ask each other for their local status. In case it is not set, the credentials
+    /// of the client are passed on, which is only done for static peers. Required for DNS discovery, as we must not
+    /// send the credentials of the client to whatever the hostname resolves to.
+    pub token: Option<String>,
+
+    /// Peers not answering within this time are reported as failed.
+    #[serde(
+        default = "TrinoLbAdminPeersConfig::default_timeout",
+        with = "humantime_serde"
+    )]
+    pub timeout: Duration,
+
+    #[serde(default)]
+    pub ignore_cert: bool,
+}
+
+impl TrinoLbAdminPeersConfig {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(2)
+    }
+}
+
+impl Debug for TrinoLbAdminPeersConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrinoLbAdminPeersConfig")
+            .field("discovery", &self.discovery)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("timeout", &self.timeout)
+            .field("ignore_cert", &self.ignore_cert)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub enum TrinoLbAdminPeerDiscoveryConfig {
+    /// A fixed list of peers, e.g. `https://trino-lb-1.trino-lb:8443`.
+    Static { urls: Vec<Url> },
+
+    /// Every address the hostname resolves to is a peer, e.g. the headless service of a Kubernetes StatefulSet.
+    Dns {
+        hostname: String,
+        port: u16,
+        #[serde(default = "TrinoLbAdminPeerDiscoveryConfig::default_https")]
+        https: bool,
+    },
+}
+
+impl TrinoLbAdminPeerDiscoveryConfig {
+    fn default_https() -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub enum TrinoLbAdminAuthenticationConfig {
+    BasicAuth { username: String, password: String },
+    BearerToken { token: String },
+}
+
+impl Debug for TrinoLbAdminAuthenticationConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BasicAuth { username, .. } => f
+                .debug_struct("BasicAuth")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Self::BearerToken { .. } => f
+                .debug_struct("BearerToken")
+                .field("token", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub enum PersistenceConfig {
-    InMemory {},
+    InMemory(InMemoryConfig),
     Redis(RedisConfig),
     Postgres(PostgresConfig),
 }
 
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InMemoryConfig {
+    /// Maximum number of queued queries stored at the same time. Further queries are rejected with
+    /// `429 Too Many Requests` until some of the queued queries are handed over to Trino. Unlimited by default.
+    #[serde(default)]
+    pub max_queued_queries: Option<u64>,
+    /// In case this is set, the stored data is written to this file on graceful shutdown and read again on startup,
+    /// so that queued and running queries survive planned restarts.
+    #[serde(default)]
+    pub snapshot_path: Option<PathBuf>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct RedisConfig {
@@ -149,6 +654,11 @@ pub struct RedisConfig {
 
     #[serde(default)]
     pub cluster_mode: bool,
+
+    /// Use a separate connection for the latency-sensitive reads done while routing queries (cluster states, query
+    /// counts and routing exclusions), so that they don't queue up behind other commands.
+    #[serde(default)]
+    pub dedicated_routing_connection: bool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -158,6 +668,11 @@ pub struct PostgresConfig {
 
     #[serde(default = "default_max_connections")]
     pub max_connections: u32,
+
+    /// In case this is set, a separate pool with the given number of connections is used for the latency-sensitive
+    /// reads done while routing queries (cluster states, query counts and routing exclusions), so that they don't
+    /// need to wait for a free connection of the main pool.
+    pub routing_pool_max_connections: Option<u32>,
 }
 
 fn default_max_connections() -> u32 {
@@ -168,8 +683,50 @@ fn default_max_connections() -> u32 {
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TrinoClusterGroupConfig {
     pub max_running_queries: u64,
+    /// Overwrites `maxRunningQueries` during the given time ranges, e.g. to allow more queries per cluster during
+    /// off-peak ETL windows. In case multiple entries match, the last one wins.
+    #[serde(default)]
+    pub max_running_queries_schedule: Vec<MaxRunningQueriesScheduleConfig>,
     pub autoscaling: Option<TrinoClusterGroupAutoscalingConfig>,
     pub trino_clusters: Vec<TrinoClusterConfig>,
+
+    /// Turns this cluster group into a parking cluster group. It must not contain any clusters, queries routed to it
+    /// are only queued and promoted to the configured target cluster group once it has free capacity.
+    pub parking: Option<TrinoClusterGroupParkingConfig>,
+
+    /// Fail queries right away instead of queuing them in case none of the clusters is ready or can become ready (e.g.
+    /// because all clusters are deactivated). Clusters the autoscaler can start are considered to become ready.
+    #[serde(default)]
+    pub reject_when_no_ready_cluster: bool,
+
+    /// Fail new queries right away instead of queuing them in case the queries handed over during the last 5 minutes
+    /// were queued longer than this on average, as the new query would most likely wait longer as well. This sheds
+    /// load to protect latency SLOs. Disabled by default.
+    ///
+    /// The average is tracked by every trino-lb replica on its own (it is not persisted), so replicas can make
+    /// different decisions for the same cluster group.
+    #[serde(default, with = "humantime_serde")]
+    pub max_estimated_wait: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TrinoClusterGroupParkingConfig {
+    /// The cluster group parked queries are promoted to.
+    pub target_cluster_group: String,
+
+    /// How often to check the target cluster group for free capacity.
+    #[serde(
+        default = "TrinoClusterGroupParkingConfig::default_promotion_interval",
+        with = "humantime_serde"
+    )]
+    pub promotion_interval: Duration,
+}
+
+impl TrinoClusterGroupParkingConfig {
+    fn default_promotion_interval() -> Duration {
+        Duration::from_secs(10)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -178,6 +735,35 @@ pub struct TrinoClusterConfig {
     pub name: String,
     pub endpoint: Url,
     pub credentials: TrinoClusterCredentialsConfig,
+
+    /// Overflow clusters only get queries in case all other clusters of the cluster group are full (or not ready).
+    #[serde(default)]
+    pub overflow: bool,
+
+    /// In case multiple clusters have the same number of queries, the one with the highest weight gets the query.
+    #[serde(default)]
+    pub weight: u64,
+
+    /// The endpoint trino-lb uses to reach the cluster, in case it differs from `endpoint` (e.g. in split networks).
+    #[serde(default)]
+    pub internal_endpoint: Option<Url>,
+
+    /// The endpoint operators and users can reach the cluster at (e.g. the Trino web UI), in case it differs from
+    /// `endpoint`.
+    #[serde(default)]
+    pub external_endpoint: Option<Url>,
+}
+
+impl TrinoClusterConfig {
+    /// The endpoint trino-lb uses to communicate with the cluster.
+    pub fn internal_endpoint(&self) -> &Url {
+        self.internal_endpoint.as_ref().unwrap_or(&self.endpoint)
+    }
+
+    /// The endpoint used in links pointing to the cluster, e.g. the `infoUri` of queries.
+    pub fn external_endpoint(&self) -> &Url {
+        self.external_endpoint.as_ref().unwrap_or(&self.endpoint)
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -195,6 +781,30 @@ pub struct TrinoClusterGroupAutoscalingConfig {
     #[serde(with = "humantime_serde")]
     pub drain_idle_duration_before_shutdown: Duration,
     pub min_clusters: Vec<MinClustersConfig>,
+    /// By default the last ready cluster of a group is only shut down in case no queries are running on it.
+    /// Setting this to `true` allows draining the last cluster even if there are some queries running, new queries
+    /// will be queued and trigger an upscale again.
+    #[serde(default)]
+    pub allow_scale_to_zero: bool,
+    /// Once a cluster reports to be ready, wait for this period before sending queries to it.
+    #[serde(default, with = "humantime_serde")]
+    pub ready_grace_period: Duration,
+    /// Start another cluster once at least this many queries are blocked (e.g. waiting for memory) on the clusters of
+    /// the group, even if no queries are queued in trino-lb. Disabled by default.
+    #[serde(default)]
+    pub upscale_blocked_queries_threshold: Option<u64>,
+    /// How much a blocked query counts towards the utilization used for downscaling, in percent of a running query.
+    /// The default of `100` counts blocked queries just like running ones.
+    #[serde(
+        default = "TrinoClusterGroupAutoscalingConfig::default_blocked_queries_weight_percentage"
+    )]
+    pub blocked_queries_weight_percentage: u64,
+}
+
+impl TrinoClusterGroupAutoscalingConfig {
+    fn default_blocked_queries_weight_percentage() -> u64 {
+        100
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -205,6 +815,14 @@ pub struct MinClustersConfig {
     pub min: u64,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct MaxRunningQueriesScheduleConfig {
+    pub time_utc: String,
+    pub weekdays: String,
+    pub max: u64,
+}
+
 impl Debug for TrinoClusterCredentialsConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TrinoClusterCredentialsConfig")
@@ -221,6 +839,8 @@ pub enum RoutingConfig {
     TrinoRoutingGroupHeader(TrinoRoutingGroupHeaderRouterConfig),
     PythonScript(PythonScriptRouterConfig),
     ClientTags(ClientTagsRouterConfig),
+    LoadAware(LoadAwareRouterConfig),
+    StatementType(StatementTypeRouterConfig),
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -229,6 +849,24 @@ pub struct ExplainCostsRouterConfig {
     pub trino_cluster_to_run_explain_query: TrinoClientConfig,
 
     pub targets: Vec<ExplainCostTargetConfig>,
+
+    /// In case the `explain` query takes longer, the router does not make a decision.
+    #[serde(
+        default = "ExplainCostsRouterConfig::default_explain_timeout",
+        with = "humantime_serde"
+    )]
+    pub explain_timeout: Duration,
+
+    /// Maximum number of `explain` queries running at the same time. In case all are in use, the router does not make
+    /// a decision instead of waiting. Unlimited by default.
+    #[serde(default)]
+    pub max_concurrent_explains: Option<usize>,
+}
+
+impl ExplainCostsRouterConfig {
+    fn default_explain_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -260,6 +898,11 @@ pub struct ExplainCostTargetConfig {
     #[serde(flatten)]
     pub cluster_max_query_plan_estimation: QueryPlanEstimation,
     pub trino_cluster_group: String,
+
+    /// Experimental: In case `trinoLb.queryRuntimeFeedback` is enabled and a runtime was observed for the query, it
+    /// is compared against this value instead of looking at the query plan estimation.
+    #[serde(default, with = "humantime_serde")]
+    pub max_observed_runtime: Option<Duration>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -296,6 +939,32 @@ pub enum TagMatchingStrategy {
     OneOf(HashSet<String>),
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct LoadAwareRouterConfig {
+    /// The cluster groups to pick the least loaded one from. In case multiple groups have the same load, the first one
+    /// in this list wins.
+    pub trino_cluster_groups: Vec<String>,
+}
+
+/// Maps the category of the query statement to the cluster group it should run on. Categories without a cluster group
+/// are not routed by this router.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct StatementTypeRouterConfig {
+    /// E.g. `CREATE`, `ALTER` (including `ALTER TABLE ... EXECUTE OPTIMIZE`) or `DROP`.
+    pub ddl: Option<String>,
+
+    /// E.g. `INSERT`, `UPDATE`, `DELETE` or `MERGE`.
+    pub dml: Option<String>,
+
+    /// E.g. `SELECT`, `WITH`, `SHOW` or `EXPLAIN`.
+    pub query: Option<String>,
+
+    /// E.g. `GRANT`, `CALL`, `ANALYZE` or `SET SESSION`.
+    pub admin: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub enum ScalerConfig {
@@ -306,6 +975,27 @@ pub enum ScalerConfig {
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct StackableScalerConfig {
     pub clusters: HashMap<TrinoClusterName, StackableCluster>,
+
+    /// Ask the Trino coordinator to shut down gracefully before stopping a cluster, which is only done in case this is
+    /// configured.
+    pub graceful_shutdown: Option<StackableGracefulShutdownConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct StackableGracefulShutdownConfig {
+    /// Maximum time to wait for Trino to finish the running queries, after which the cluster is stopped anyway.
+    #[serde(
+        default = "StackableGracefulShutdownConfig::default_timeout",
+        with = "humantime_serde"
+    )]
+    pub timeout: Duration,
+}
+
+impl StackableGracefulShutdownConfig {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -1,4 +1,9 @@
+pub mod client_request_stats;
+pub mod client_tags;
+pub mod clock_skew;
 pub mod config;
+pub mod query_chargeback;
+pub mod query_runtime;
 pub mod sanitization;
 pub mod trino_api;
 pub mod trino_cluster;
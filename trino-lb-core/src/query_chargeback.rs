@@ -0,0 +1,59 @@
+//! Building blocks for the (opt-in) query chargeback, which counts the queries handed over to Trino per user and
+//! cluster group, e.g. for internal billing.
+//!
+//! The counters are kept in daily buckets, so that persistences only need to increment counters and can drop whole
+//! buckets once they are older than the configured retention.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const QUERY_CHARGEBACK_BUCKET_SIZE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the (UTC) day the given point in time falls into.
+pub fn bucket_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / QUERY_CHARGEBACK_BUCKET_SIZE.as_secs()
+}
+
+/// Returns the number of days needed to cover the given duration, which is at least one.
+pub fn bucket_count(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .div_ceil(QUERY_CHARGEBACK_BUCKET_SIZE.as_secs())
+        .max(1)
+}
+
+/// Returns the point in time the given bucket starts at.
+pub fn bucket_start(bucket: u64) -> SystemTime {
+    UNIX_EPOCH + QUERY_CHARGEBACK_BUCKET_SIZE * u32::try_from(bucket).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(Duration::ZERO, 1)]
+    #[case(Duration::from_secs(60 * 60), 1)]
+    #[case(Duration::from_secs(24 * 60 * 60), 1)]
+    #[case(Duration::from_secs(24 * 60 * 60 + 1), 2)]
+    #[case(Duration::from_secs(31 * 24 * 60 * 60), 31)]
+    fn test_bucket_count(#[case] duration: Duration, #[case] expected: u64) {
+        assert_eq!(bucket_count(duration), expected);
+    }
+
+    #[test]
+    fn test_bucket_of() {
+        let day = QUERY_CHARGEBACK_BUCKET_SIZE;
+        assert_eq!(bucket_of(UNIX_EPOCH), 0);
+        assert_eq!(bucket_of(UNIX_EPOCH + day - Duration::from_secs(1)), 0);
+        assert_eq!(bucket_of(UNIX_EPOCH + day), 1);
+        assert_eq!(
+            bucket_start(bucket_of(UNIX_EPOCH + 3 * day + day / 2)),
+            UNIX_EPOCH + 3 * day
+        );
+    }
+}
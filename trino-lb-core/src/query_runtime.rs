@@ -0,0 +1,165 @@
+//! Building blocks for the (experimental) feedback loop, which records the actual runtime of queries so that routers
+//! can use it for subsequent routing decisions of the same query.
+
+use std::{iter::Peekable, str::Chars, time::Duration};
+
+use sha2::{Digest, Sha256};
+
+/// Calculates a fingerprint of the given query, which is identical for queries only differing in literals, casing,
+/// comments or whitespace. E.g. `SELECT * FROM t WHERE id = 42` and `select *  from t where id = 43` share the same
+/// fingerprint.
+///
+/// The fingerprint is stored in the persistence, so it uses SHA-256, which is (other than the hasher of the standard
+/// library) stable across Rust and trino-lb versions.
+pub fn query_fingerprint(query: &str) -> String {
+    let hash = Sha256::digest(normalize_query(query));
+
+    // The first 64 bits are plenty to tell the queries apart
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash[..8]);
+    format!("{:016x}", u64::from_be_bytes(prefix))
+}
+
+/// Blends the runtime observed so far for a query fingerprint with a newly observed runtime. The `decay` determines
+/// the weight of the newly observed runtime, so that older observations fade out over time.
+pub fn blend_query_runtime(previous: Option<Duration>, observed: Duration, decay: f64) -> Duration {
+    let Some(previous) = previous else {
+        return observed;
+    };
+    let decay = decay.clamp(0.0, 1.0);
+
+    previous.mul_f64(1.0 - decay) + observed.mul_f64(decay)
+}
+
+/// Normalizes the given query, so that queries only differing in literals, casing, comments or whitespace are
+/// identical. As all literals are replaced by `?`, the normalized query can also be used to share a query without
+/// leaking the values it contains.
+pub fn normalize_query(query: &str) -> String {
+    let mut normalized = String::with_capacity(query.len());
+    let mut chars = query.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // String literals
+            '\'' => {
+                skip_string_literal(&mut chars);
+                normalized.push('?');
+            }
+            // Line comments
+            '-' if chars.peek() == Some(&'-') => {
+                chars.find(|c| *c == '\n');
+                push_whitespace(&mut normalized);
+            }
+            // Block comments
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                push_whitespace(&mut normalized);
+            }
+            // Numeric literals, as long as they are not part of an identifier such as "table_1"
+            c if c.is_ascii_digit() && !normalized.ends_with(is_identifier_char) => {
+                while chars
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+                {
+                    chars.next();
+                }
+                normalized.push('?');
+            }
+            c if c.is_whitespace() => push_whitespace(&mut normalized),
+            c => normalized.extend(c.to_lowercase()),
+        }
+    }
+
+    normalized.trim_end().to_owned()
+}
+
+/// Skips the remainder of a string literal, the opening quote was already consumed. Quotes within the literal are
+/// escaped by doubling them.
+fn skip_string_literal(chars: &mut Peekable<Chars>) {
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            if chars.peek() == Some(&'\'') {
+                chars.next();
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+fn push_whitespace(normalized: &mut String) {
+    if !normalized.is_empty() && !normalized.ends_with(' ') {
+        normalized.push(' ');
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("SELECT * FROM t WHERE id = 42", "select * from t where id = ?")]
+    #[case("select *\n  from t\twhere id=42.5 ", "select * from t where id=?")]
+    #[case("select 'it''s', 'foo' from t", "select ?, ? from t")]
+    #[case("select * from table_1 limit 10", "select * from table_1 limit ?")]
+    #[case("-- my comment\nselect 1", "select ?")]
+    #[case("select /* hint */ 1 from t", "select ? from t")]
+    fn test_normalize_query(#[case] query: &str, #[case] expected: &str) {
+        assert_eq!(normalize_query(query), expected);
+    }
+
+    #[test]
+    fn test_query_fingerprint() {
+        assert_eq!(
+            query_fingerprint("SELECT * FROM t WHERE id = 42"),
+            query_fingerprint("select *  from t where id = 43")
+        );
+        assert_ne!(
+            query_fingerprint("select * from t1"),
+            query_fingerprint("select * from t2")
+        );
+
+        // The fingerprint is persisted, so it must not change between versions
+        assert_eq!(
+            query_fingerprint("SELECT * FROM t WHERE id = 42"),
+            "88e41652b573c403"
+        );
+    }
+
+    #[rstest]
+    #[case(None, 10, 0.3, 10_000)]
+    #[case(Some(10), 20, 0.5, 15_000)]
+    #[case(Some(10), 20, 0.1, 11_000)]
+    #[case(Some(10), 20, 1.0, 20_000)]
+    #[case(Some(10), 20, 0.0, 10_000)]
+    // Out of range decays are clamped
+    #[case(Some(10), 20, 2.0, 20_000)]
+    #[case(Some(10), 20, -1.0, 10_000)]
+    fn test_blend_query_runtime(
+        #[case] previous_secs: Option<u64>,
+        #[case] observed_secs: u64,
+        #[case] decay: f64,
+        #[case] expected_millis: u128,
+    ) {
+        let blended = blend_query_runtime(
+            previous_secs.map(Duration::from_secs),
+            Duration::from_secs(observed_secs),
+            decay,
+        );
+
+        assert_eq!(blended.as_millis(), expected_millis);
+    }
+}
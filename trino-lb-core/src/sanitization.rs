@@ -2,11 +2,23 @@ pub trait Sanitize {
     fn sanitize(&self) -> Self;
 }
 
+/// Headers carrying credentials, all of their values are redacted.
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-trino-extra-credential",
+];
+
 impl Sanitize for http::HeaderMap {
     fn sanitize(&self) -> Self {
         let mut sanitized = self.clone();
-        if let Some(authorization) = sanitized.get_mut("Authorization") {
-            *authorization = http::HeaderValue::from_static("<redacted>");
+        for name in SENSITIVE_HEADERS {
+            if let http::header::Entry::Occupied(mut entry) = sanitized.entry(name) {
+                for value in entry.iter_mut() {
+                    *value = http::HeaderValue::from_static("<redacted>");
+                }
+            }
         }
         sanitized
     }
@@ -38,5 +50,28 @@ mod tests {
         headers.insert("authorization", HeaderValue::from_static("secure"));
         let sanitized = headers.sanitize();
         assert_eq!(sanitized.get("authorization").unwrap(), "<redacted>");
+
+        // All values of repeated headers and other credentials
+        headers.append("Authorization", HeaderValue::from_static("secure"));
+        headers.insert("Proxy-Authorization", HeaderValue::from_static("secure"));
+        headers.insert("Cookie", HeaderValue::from_static("session=secure"));
+        headers.insert(
+            "X-Trino-Extra-Credential",
+            HeaderValue::from_static("token=secure"),
+        );
+        let sanitized = headers.sanitize();
+        assert_eq!(sanitized.get_all("Authorization").iter().count(), 2);
+        for name in [
+            "Authorization",
+            "Proxy-Authorization",
+            "Cookie",
+            "X-Trino-Extra-Credential",
+        ] {
+            assert!(sanitized
+                .get_all(name)
+                .iter()
+                .all(|value| value == "<redacted>"));
+        }
+        assert_eq!(sanitized.get(HOST).unwrap(), "example.com");
     }
 }
@@ -5,11 +5,12 @@ use std::{
 
 use prusto::{QueryError, Warning};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use snafu::{ResultExt, Snafu};
 use tracing::instrument;
 use url::Url;
 
-use crate::{trino_query::QueuedQuery, TrinoQueryId};
+use crate::{clock_skew::elapsed_allowing_clock_skew, trino_query::QueuedQuery, TrinoQueryId};
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -22,6 +23,9 @@ pub enum Error {
     #[snafu(display("Failed to parse nextUri Trino send us"))]
     ParseNextUriFromTrino { source: url::ParseError },
 
+    #[snafu(display("Failed to parse infoUri Trino send us"))]
+    ParseInfoUriFromTrino { source: url::ParseError },
+
     #[snafu(display("Failed to determine the elapsed time of a queued query. Are all system clocks of trino-lb instances in sync?"))]
     DetermineElapsedTime { source: SystemTimeError },
 
@@ -30,8 +34,22 @@ pub enum Error {
         source: TryFromIntError,
         queued_time: Duration,
     },
+
+    #[snafu(display("Failed to construct the error of a failed query"))]
+    ConstructQueryError { source: serde_json::Error },
 }
 
+/// State reported to clients for queries queued in trino-lb, which can be changed using `trinoLb.queuedQueryState`.
+pub const QUEUED_IN_TRINO_LB_STATE: &str = "QUEUED_IN_TRINO_LB";
+
+/// Error Trino uses in case no nodes are available to run a query.
+const NO_NODES_AVAILABLE_ERROR_NAME: &str = "NO_NODES_AVAILABLE";
+const NO_NODES_AVAILABLE_ERROR_CODE: i32 = 0x0001_0005;
+
+/// Error Trino uses in case the client stopped taking care of a query.
+const ABANDONED_QUERY_ERROR_NAME: &str = "ABANDONED_QUERY";
+const ABANDONED_QUERY_ERROR_CODE: i32 = 0x0000_0002;
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TrinoQueryApiResponse {
@@ -42,8 +60,12 @@ pub struct TrinoQueryApiResponse {
     pub info_uri: String,
     pub partial_cancel_uri: Option<String>,
 
-    pub columns: Option<serde_json::Value>,
-    pub data: Option<serde_json::Value>,
+    /// Passed through as is, so that e.g. big numbers don't lose their precision by being parsed (and serialized
+    /// again) as [`f64`].
+    pub columns: Option<Box<RawValue>>,
+    /// Passed through as is, so that e.g. big numbers don't lose their precision by being parsed (and serialized
+    /// again) as [`f64`].
+    pub data: Option<Box<RawValue>>,
 
     pub error: Option<QueryError>,
     pub warnings: Vec<Warning>,
@@ -87,13 +109,13 @@ impl TrinoQueryApiResponse {
     pub fn new_from_queued_query(
         query: &QueuedQuery,
         current_sequence_number: u64,
+        queued_state: &str,
         trino_lb_addr: &Url,
+        max_clock_skew: Duration,
     ) -> Result<Self, Error> {
         let next_sequence_number = current_sequence_number + 1;
         let query_id = &query.id;
-        let queued_time = query
-            .creation_time
-            .elapsed()
+        let queued_time = elapsed_allowing_clock_skew(query.creation_time, max_clock_skew)
             .context(DetermineElapsedTimeSnafu)?;
         let queued_time_ms: u64 = queued_time
             .as_millis()
@@ -140,7 +162,7 @@ impl TrinoQueryApiResponse {
                 running_splits: 0,
                 scheduled: false,
                 spilled_bytes: 0,
-                state: "QUEUED_IN_TRINO_LB".to_string(),
+                state: queued_state.to_string(),
                 total_splits: 0,
                 wall_time_millis: 0,
             },
@@ -150,6 +172,90 @@ impl TrinoQueryApiResponse {
         })
     }
 
+    /// Fails the queued query without ever sending it to Trino, using the `NO_NODES_AVAILABLE` error Trino itself uses
+    /// in case it has no nodes to run a query. Clients get the response the same way they get failures from Trino.
+    #[instrument(
+        fields(trino_lb_addr = %trino_lb_addr),
+    )]
+    pub fn new_no_nodes_available_from_queued_query(
+        query: &QueuedQuery,
+        message: &str,
+        trino_lb_addr: &Url,
+        max_clock_skew: Duration,
+    ) -> Result<Self, Error> {
+        Self::new_failed_from_queued_query(
+            query,
+            message,
+            NO_NODES_AVAILABLE_ERROR_NAME,
+            NO_NODES_AVAILABLE_ERROR_CODE,
+            "INTERNAL_ERROR",
+            trino_lb_addr,
+            max_clock_skew,
+        )
+    }
+
+    /// Fails the queued query without ever sending it to Trino, using the `ABANDONED_QUERY` error Trino itself uses
+    /// for queries the client stopped taking care of.
+    #[instrument(
+        fields(trino_lb_addr = %trino_lb_addr),
+    )]
+    pub fn new_abandoned_from_queued_query(
+        query: &QueuedQuery,
+        message: &str,
+        trino_lb_addr: &Url,
+        max_clock_skew: Duration,
+    ) -> Result<Self, Error> {
+        Self::new_failed_from_queued_query(
+            query,
+            message,
+            ABANDONED_QUERY_ERROR_NAME,
+            ABANDONED_QUERY_ERROR_CODE,
+            "USER_ERROR",
+            trino_lb_addr,
+            max_clock_skew,
+        )
+    }
+
+    fn new_failed_from_queued_query(
+        query: &QueuedQuery,
+        message: &str,
+        error_name: &str,
+        error_code: i32,
+        error_type: &str,
+        trino_lb_addr: &Url,
+        max_clock_skew: Duration,
+    ) -> Result<Self, Error> {
+        let mut response = Self::new_from_queued_query(
+            query,
+            0,
+            QUEUED_IN_TRINO_LB_STATE,
+            trino_lb_addr,
+            max_clock_skew,
+        )?;
+
+        // Constructed from JSON, so that we produce exactly what Trino sends
+        let error = serde_json::from_value(serde_json::json!({
+            "message": message,
+            "errorCode": error_code,
+            "errorName": error_name,
+            "errorType": error_type,
+            "failureInfo": {
+                "type": "io.trino.spi.TrinoException",
+                "message": message,
+                "suppressed": [],
+                "stack": [],
+            },
+        }))
+        .context(ConstructQuerySnafu)?;
+
+        response.next_uri = None;
+        response.error = Some(error);
+        response.stats.queued = false;
+        response.stats.state = "FAILED".to_string();
+
+        Ok(response)
+    }
+
     #[instrument(
         fields(trino_lb_addr = %trino_lb_addr),
     )]
@@ -161,6 +267,36 @@ impl TrinoQueryApiResponse {
 
         Ok(())
     }
+
+    /// Points the infoUri (a link to the Trino web UI) to the given endpoint of the Trino cluster, keeping the path and
+    /// query.
+    #[instrument(
+        fields(external_endpoint = %external_endpoint),
+    )]
+    pub fn change_info_uri_to_external_endpoint(
+        &mut self,
+        external_endpoint: &Url,
+    ) -> Result<(), Error> {
+        let info_uri = Url::parse(&self.info_uri).context(ParseInfoUriFromTrinoSnafu)?;
+        let mut result = external_endpoint.clone();
+        result.set_path(info_uri.path());
+        result.set_query(info_uri.query());
+        self.info_uri = result.to_string();
+
+        Ok(())
+    }
+
+    /// Returns the path of the nextUri, which contains the slug and token Trino issued for the next request.
+    pub fn next_uri_path(&self) -> Result<Option<String>, Error> {
+        self.next_uri
+            .as_deref()
+            .map(|next_uri| {
+                Url::parse(next_uri)
+                    .map(|next_uri| next_uri.path().to_owned())
+                    .context(ParseNextUriFromTrinoSnafu)
+            })
+            .transpose()
+    }
 }
 
 fn change_next_uri_to_trino_lb(next_uri: &Url, trino_lb_addr: &Url) -> Url {
@@ -171,6 +307,8 @@ fn change_next_uri_to_trino_lb(next_uri: &Url, trino_lb_addr: &Url) -> Url {
 
 #[cfg(test)]
 mod tests {
+    use std::time::SystemTime;
+
     use rstest::rstest;
 
     use super::*;
@@ -203,4 +341,172 @@ mod tests {
         let result = change_next_uri_to_trino_lb(&next_uri, &trino_lb_addr);
         assert_eq!(result.to_string(), expected);
     }
+
+    #[test]
+    fn test_change_info_uri_to_external_endpoint() {
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            http::HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let mut response = TrinoQueryApiResponse::new_from_queued_query(
+            &queued_query,
+            0,
+            QUEUED_IN_TRINO_LB_STATE,
+            &"https://trino-coordinator.trino.svc.cluster.local:8443"
+                .parse()
+                .unwrap(),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        response
+            .change_info_uri_to_external_endpoint(&"https://trino.example.com".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            response.info_uri,
+            format!(
+                "https://trino.example.com/ui/query.html?{}",
+                queued_query.id
+            )
+        );
+    }
+
+    #[rstest]
+    #[case(QUEUED_IN_TRINO_LB_STATE)]
+    #[case("QUEUED")]
+    fn test_new_from_queued_query(#[case] queued_state: &str) {
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            http::HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let response = TrinoQueryApiResponse::new_from_queued_query(
+            &queued_query,
+            3,
+            queued_state,
+            &"https://trino-lb:8443".parse().unwrap(),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        assert_eq!(response.stats.state, queued_state);
+        assert!(response.stats.queued);
+        assert_eq!(
+            response.next_uri.unwrap(),
+            format!(
+                "https://trino-lb:8443/v1/statement/queued_in_trino_lb/{}/4",
+                queued_query.id
+            )
+        );
+    }
+
+    #[test]
+    fn test_new_from_queued_query_created_in_the_future() {
+        // Created by a trino-lb replica whose clock is ahead of ours
+        let queued_query = QueuedQuery {
+            creation_time: SystemTime::now() + Duration::from_secs(2),
+            ..QueuedQuery::new_from(
+                "SELECT 1".to_owned(),
+                http::HeaderMap::new(),
+                "s".to_owned(),
+                None,
+            )
+        };
+        let new_response = |max_clock_skew| {
+            TrinoQueryApiResponse::new_from_queued_query(
+                &queued_query,
+                0,
+                QUEUED_IN_TRINO_LB_STATE,
+                &"https://trino-lb:8443".parse().unwrap(),
+                max_clock_skew,
+            )
+        };
+
+        let response = new_response(Duration::from_secs(5)).unwrap();
+        assert_eq!(response.stats.queued_time_millis, 0);
+        assert_eq!(response.stats.elapsed_time_millis, 0);
+
+        assert!(matches!(
+            new_response(Duration::from_secs(1)),
+            Err(Error::DetermineElapsedTime { .. })
+        ));
+    }
+
+    #[test]
+    fn test_new_no_nodes_available_from_queued_query() {
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            http::HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let response = TrinoQueryApiResponse::new_no_nodes_available_from_queued_query(
+            &queued_query,
+            "No Trino cluster of the cluster group s can become ready",
+            &"https://trino-lb:8443".parse().unwrap(),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        assert_eq!(response.id, queued_query.id);
+        assert_eq!(response.next_uri, None);
+        assert_eq!(response.stats.state, "FAILED");
+        let error = response.error.unwrap();
+        assert_eq!(error.error_name, "NO_NODES_AVAILABLE");
+        assert_eq!(error.error_code, 65541);
+    }
+
+    #[test]
+    fn test_new_abandoned_from_queued_query() {
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            http::HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let response = TrinoQueryApiResponse::new_abandoned_from_queued_query(
+            &queued_query,
+            "Query abandoned after too many polls",
+            &"https://trino-lb:8443".parse().unwrap(),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        assert_eq!(response.next_uri, None);
+        assert_eq!(response.stats.state, "FAILED");
+        let error = response.error.unwrap();
+        assert_eq!(error.error_name, "ABANDONED_QUERY");
+        assert_eq!(error.error_code, 2);
+        assert_eq!(error.error_type, "USER_ERROR");
+    }
+
+    #[test]
+    fn test_numbers_are_passed_through_unchanged() {
+        // A decimal(38,0) (which Trino usually sends as string), the biggest bigint and a double in scientific notation
+        let data = r#"[[12345678901234567890123456789012345678,"12345678901234567890123456789012345678",9223372036854775807,-9223372036854775808,1.0E10]]"#;
+        let columns = r#"[{"name":"d","type":"decimal(38,0)","typeSignature":{"rawType":"decimal","arguments":[{"kind":"LONG","value":38},{"kind":"LONG","value":0}]}}]"#;
+        let response = format!(
+            r#"{{"id":"20240101_120000_00001_abcde","infoUri":"https://trino:8443/ui/query.html?20240101_120000_00001_abcde","columns":{columns},"data":{data},"warnings":[],"stats":{{"completedSplits":0,"cpuTimeMillis":0,"elapsedTimeMillis":0,"nodes":1,"peakMemoryBytes":0,"physicalInputBytes":0,"processedBytes":0,"processedRows":0,"queuedSplits":0,"queuedTimeMillis":0,"queued":false,"runningSplits":0,"scheduled":true,"spilledBytes":0,"state":"FINISHED","totalSplits":0,"wallTimeMillis":0}}}}"#
+        );
+
+        let response: TrinoQueryApiResponse = serde_json::from_str(&response).unwrap();
+        let proxied = serde_json::to_string(&response).unwrap();
+
+        assert!(proxied.contains(&format!(r#""data":{data}"#)), "{proxied}");
+        assert!(
+            proxied.contains(&format!(r#""columns":{columns}"#)),
+            "{proxied}"
+        );
+    }
+
+    #[test]
+    fn test_missing_data_is_passed_through() {
+        let response: TrinoQueryApiResponse = serde_json::from_str(r#"{"id":"20240101_120000_00001_abcde","infoUri":"https://trino:8443/ui/query.html?20240101_120000_00001_abcde","data":null,"warnings":[],"stats":{"completedSplits":0,"cpuTimeMillis":0,"elapsedTimeMillis":0,"nodes":1,"peakMemoryBytes":0,"physicalInputBytes":0,"processedBytes":0,"processedRows":0,"queuedSplits":0,"queuedTimeMillis":0,"queued":false,"runningSplits":0,"scheduled":true,"spilledBytes":0,"state":"RUNNING","totalSplits":0,"wallTimeMillis":0}}"#).unwrap();
+
+        assert!(response.data.is_none());
+        assert!(response.columns.is_none());
+    }
 }
@@ -1,8 +1,15 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use strum::IntoStaticStr;
 
+/// The cluster state is persisted using bincode (Redis) and JSON (Postgres), so its serialized format is part of the
+/// persistence format and must not change:
+///
+/// * Variants are only ever added at the end, as bincode identifies variants by their index.
+/// * Timestamps are serialized as `{"secs_since_epoch": u64, "nanos_since_epoch": u32}` (see [`system_time`]).
+///
+/// The format is pinned by tests, please extend them when adding variants.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize, IntoStaticStr)]
 pub enum ClusterState {
     Unknown,
@@ -15,12 +22,20 @@ pub enum ClusterState {
     /// No new queries should be submitted. Once all running queries are finished and a certain time period has passed
     /// go to `Terminating`
     Draining {
+        #[serde(with = "system_time")]
         last_time_seen_with_queries: SystemTime,
     },
     /// In the process of shutting down, don't send new queries
     Terminating,
     /// Deactivated by a system administrator (manually or programmatically), probably for maintenance
     Deactivated,
+    /// The cluster reported to be ready, but we wait for the configured ready grace period before sending queries to
+    /// it, e.g. to give DNS records some time to propagate.
+    // This is added at the end to not break the (binary) format of already persisted cluster states.
+    WarmingUp {
+        #[serde(with = "system_time")]
+        ready_since: SystemTime,
+    },
 }
 
 impl ClusterState {
@@ -30,6 +45,9 @@ impl ClusterState {
             | ClusterState::Stopped
             | ClusterState::Starting
             | ClusterState::Terminating => ClusterState::Starting,
+            ClusterState::WarmingUp { ready_since } => ClusterState::WarmingUp {
+                ready_since: *ready_since,
+            },
             ClusterState::Ready | ClusterState::Draining { .. } => ClusterState::Ready,
             ClusterState::Deactivated => ClusterState::Deactivated,
         }
@@ -41,6 +59,7 @@ impl ClusterState {
             ClusterState::Unknown
             // No, because it is already started
             | ClusterState::Starting
+            | ClusterState::WarmingUp { .. }
             | ClusterState::Ready
             | ClusterState::Terminating
             | ClusterState::Deactivated => false,
@@ -54,8 +73,169 @@ impl ClusterState {
             | ClusterState::Draining { .. }
             | ClusterState::Terminating
             | ClusterState::Starting
+            | ClusterState::WarmingUp { .. }
             | ClusterState::Deactivated => false,
             ClusterState::Ready => true,
         }
     }
 }
+
+/// Explicit (de)serialization of [`SystemTime`], so that the persisted format does not depend on the implementation
+/// serde ships for it. It is the same format, so cluster states persisted by older versions can still be read.
+mod system_time {
+    use super::*;
+
+    #[derive(Deserialize, Serialize)]
+    struct SinceEpoch {
+        secs_since_epoch: u64,
+        nanos_since_epoch: u32,
+    }
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| serde::ser::Error::custom("timestamp is before the UNIX epoch"))?;
+
+        SinceEpoch {
+            secs_since_epoch: since_epoch.as_secs(),
+            nanos_since_epoch: since_epoch.subsec_nanos(),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let SinceEpoch {
+            secs_since_epoch,
+            nanos_since_epoch,
+        } = SinceEpoch::deserialize(deserializer)?;
+
+        SystemTime::UNIX_EPOCH
+            .checked_add(Duration::new(secs_since_epoch, nanos_since_epoch))
+            .ok_or_else(|| serde::de::Error::custom("timestamp is out of range"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// 2024-01-01T12:00:00.123456789Z
+    fn timestamp() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(1_704_110_400, 123_456_789)
+    }
+
+    /// Returns the given variant with the timestamps replaced by [`timestamp`]. This intentionally has no wildcard, so
+    /// that adding a variant fails to compile until it is added to the pinned formats below.
+    fn example(state: &ClusterState) -> ClusterState {
+        match state {
+            ClusterState::Unknown => ClusterState::Unknown,
+            ClusterState::Stopped => ClusterState::Stopped,
+            ClusterState::Starting => ClusterState::Starting,
+            ClusterState::Ready => ClusterState::Ready,
+            ClusterState::Draining { .. } => ClusterState::Draining {
+                last_time_seen_with_queries: timestamp(),
+            },
+            ClusterState::Terminating => ClusterState::Terminating,
+            ClusterState::Deactivated => ClusterState::Deactivated,
+            ClusterState::WarmingUp { .. } => ClusterState::WarmingUp {
+                ready_since: timestamp(),
+            },
+        }
+    }
+
+    #[rstest]
+    #[case(ClusterState::Unknown, &[0x00, 0x00, 0x00, 0x00], r#""Unknown""#)]
+    #[case(ClusterState::Stopped, &[0x01, 0x00, 0x00, 0x00], r#""Stopped""#)]
+    #[case(ClusterState::Starting, &[0x02, 0x00, 0x00, 0x00], r#""Starting""#)]
+    #[case(ClusterState::Ready, &[0x03, 0x00, 0x00, 0x00], r#""Ready""#)]
+    #[case(
+        ClusterState::Draining { last_time_seen_with_queries: SystemTime::UNIX_EPOCH },
+        &[0x04, 0x00, 0x00, 0x00, 0x40, 0xa9, 0x92, 0x65, 0x00, 0x00, 0x00, 0x00, 0x15, 0xcd, 0x5b, 0x07],
+        r#"{"Draining":{"last_time_seen_with_queries":{"secs_since_epoch":1704110400,"nanos_since_epoch":123456789}}}"#
+    )]
+    #[case(ClusterState::Terminating, &[0x05, 0x00, 0x00, 0x00], r#""Terminating""#)]
+    #[case(ClusterState::Deactivated, &[0x06, 0x00, 0x00, 0x00], r#""Deactivated""#)]
+    #[case(
+        ClusterState::WarmingUp { ready_since: SystemTime::UNIX_EPOCH },
+        &[0x07, 0x00, 0x00, 0x00, 0x40, 0xa9, 0x92, 0x65, 0x00, 0x00, 0x00, 0x00, 0x15, 0xcd, 0x5b, 0x07],
+        r#"{"WarmingUp":{"ready_since":{"secs_since_epoch":1704110400,"nanos_since_epoch":123456789}}}"#
+    )]
+    fn test_pinned_format(
+        #[case] state: ClusterState,
+        #[case] expected_bincode: &[u8],
+        #[case] expected_json: &str,
+    ) {
+        let state = example(&state);
+
+        let bincode = bincode::serialize(&state).unwrap();
+        assert_eq!(bincode, expected_bincode);
+        assert_eq!(
+            bincode::deserialize::<ClusterState>(&bincode).unwrap(),
+            state
+        );
+
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(json, expected_json);
+        assert_eq!(serde_json::from_str::<ClusterState>(&json).unwrap(), state);
+    }
+
+    /// A state read by one persistence and written by another one (e.g. during `trino-lb migrate`) must not change.
+    #[rstest]
+    #[case(ClusterState::Unknown)]
+    #[case(ClusterState::Ready)]
+    #[case(ClusterState::Draining { last_time_seen_with_queries: SystemTime::UNIX_EPOCH })]
+    #[case(ClusterState::WarmingUp { ready_since: SystemTime::UNIX_EPOCH })]
+    fn test_round_trip_across_formats(#[case] state: ClusterState) {
+        let state = example(&state);
+
+        let from_bincode: ClusterState =
+            bincode::deserialize(&bincode::serialize(&state).unwrap()).unwrap();
+        let from_json: ClusterState =
+            serde_json::from_str(&serde_json::to_string(&from_bincode).unwrap()).unwrap();
+        let back_to_bincode: ClusterState =
+            bincode::deserialize(&bincode::serialize(&from_json).unwrap()).unwrap();
+
+        assert_eq!(back_to_bincode, state);
+    }
+
+    /// Cluster states written by older trino-lb versions used the serde implementation of [`SystemTime`].
+    #[test]
+    fn test_compatible_with_serde_system_time() {
+        #[derive(Serialize)]
+        enum LegacyClusterState {
+            _Unknown,
+            _Stopped,
+            _Starting,
+            _Ready,
+            Draining {
+                last_time_seen_with_queries: SystemTime,
+            },
+        }
+        let legacy = LegacyClusterState::Draining {
+            last_time_seen_with_queries: timestamp(),
+        };
+        let expected = ClusterState::Draining {
+            last_time_seen_with_queries: timestamp(),
+        };
+
+        assert_eq!(
+            bincode::deserialize::<ClusterState>(&bincode::serialize(&legacy).unwrap()).unwrap(),
+            expected
+        );
+        let legacy_json = serde_json::to_value(&legacy).unwrap();
+        assert_eq!(
+            serde_json::from_value::<ClusterState>(legacy_json).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_timestamp_before_epoch_is_rejected() {
+        let state = ClusterState::Draining {
+            last_time_seen_with_queries: SystemTime::UNIX_EPOCH - Duration::from_secs(1),
+        };
+        assert!(serde_json::to_string(&state).is_err());
+    }
+}
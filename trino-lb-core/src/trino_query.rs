@@ -10,6 +10,32 @@ use crate::{sanitization::Sanitize, TrinoClusterName, TrinoLbQueryId, TrinoQuery
 
 pub const QUEUED_QUERY_ID_PREFIX: &str = "trino_lb_";
 
+/// Headers that are always stored with a queued query in case the headers are limited using
+/// `trinoLb.queuedQueryHeaderAllowlist`, as Trino needs them to run the query the same way as if it was submitted
+/// directly (e.g. authentication, user, catalog, schema and session properties).
+pub const ESSENTIAL_QUEUED_QUERY_HEADERS: &[&str] = &[
+    "authorization",
+    "x-trino-user",
+    "x-trino-original-user",
+    "x-trino-original-roles",
+    "x-trino-source",
+    "x-trino-catalog",
+    "x-trino-schema",
+    "x-trino-path",
+    "x-trino-time-zone",
+    "x-trino-language",
+    "x-trino-session",
+    "x-trino-role",
+    "x-trino-prepared-statement",
+    "x-trino-transaction-id",
+    "x-trino-client-tags",
+    "x-trino-client-info",
+    "x-trino-client-capabilities",
+    "x-trino-extra-credential",
+    "x-trino-resource-estimate",
+    "x-trino-trace-token",
+];
+
 /// A query that is queued in trino-lb.
 /// It does *not* track on which cluster it is queued, as the assignment to an actual.
 /// Trino cluster happens as late as possible. Instead, it contains the needed info to
@@ -37,6 +63,10 @@ pub struct QueuedQuery {
 
     /// The target group the `trino_lb::routing::Router` has determined for this query.
     pub cluster_group: String,
+
+    /// Why the query was routed to its cluster group, e.g. which router matched. Only used to inspect queued queries,
+    /// it is [`None`] for queries queued by older trino-lb versions.
+    pub routing_reason: Option<String>,
 }
 
 /// A query that was already submitted to a Trino cluster.
@@ -57,10 +87,26 @@ pub struct TrinoQuery {
 
     /// The time the query was send to Trino
     pub delivered_time: SystemTime,
+
+    /// Fingerprint of the query, which is only set in case the runtime of the query should be recorded (see
+    /// [`crate::query_runtime`]).
+    pub query_fingerprint: Option<String>,
+
+    /// Path of the nextUri Trino issued last. As it contains the slug and token of the query, clients can only poll or
+    /// cancel the query in case they know it. Only set in case `trinoLb.validateStatementUris` is enabled.
+    pub next_uri_path: Option<String>,
+
+    /// Path of the nextUri Trino issued before [`Self::next_uri_path`], so that clients can retry their last request.
+    pub previous_next_uri_path: Option<String>,
 }
 
 impl QueuedQuery {
-    pub fn new_from(query: String, headers: http::HeaderMap, cluster_group: String) -> Self {
+    pub fn new_from(
+        query: String,
+        headers: http::HeaderMap,
+        cluster_group: String,
+        routing_reason: Option<String>,
+    ) -> Self {
         let query_id = new_query_id();
         let now = SystemTime::now();
 
@@ -71,6 +117,22 @@ impl QueuedQuery {
             creation_time: now,
             last_accessed: now,
             cluster_group,
+            routing_reason,
+        }
+    }
+
+    /// Drops all headers except the [`ESSENTIAL_QUEUED_QUERY_HEADERS`] and the headers in the given allowlist (which
+    /// is matched case-insensitive), so that e.g. big cookies are not stored with the queued query.
+    pub fn retain_headers(&mut self, allowlist: &[String]) {
+        let headers = std::mem::take(&mut self.headers);
+        for (name, value) in &headers {
+            if ESSENTIAL_QUEUED_QUERY_HEADERS.contains(&name.as_str())
+                || allowlist
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(name.as_str()))
+            {
+                self.headers.append(name, value.clone());
+            }
         }
     }
 }
@@ -82,6 +144,8 @@ impl TrinoQuery {
         trino_endpoint: Url,
         creation_time: SystemTime,
         delivered_time: SystemTime,
+        query_fingerprint: Option<String>,
+        next_uri_path: Option<String>,
     ) -> Self {
         TrinoQuery {
             id: trino_query_id,
@@ -89,6 +153,21 @@ impl TrinoQuery {
             trino_endpoint,
             creation_time,
             delivered_time,
+            query_fingerprint,
+            next_uri_path,
+            previous_next_uri_path: None,
+        }
+    }
+
+    /// Checks if the requested path (containing the slug and token) was issued by Trino for this query. Queries not
+    /// tracking their nextUri accept any path.
+    pub fn is_valid_statement_path(&self, requested_path: &str) -> bool {
+        match &self.next_uri_path {
+            Some(next_uri_path) => {
+                next_uri_path == requested_path
+                    || self.previous_next_uri_path.as_deref() == Some(requested_path)
+            }
+            None => true,
         }
     }
 }
@@ -101,6 +180,7 @@ impl Debug for QueuedQuery {
             .field("headers", &self.headers.sanitize())
             .field("creation_time", &self.creation_time)
             .field("cluster_group", &self.cluster_group)
+            .field("routing_reason", &self.routing_reason)
             .finish()
     }
 }
@@ -115,3 +195,108 @@ fn new_query_id() -> TrinoLbQueryId {
 
     format!("{QUEUED_QUERY_ID_PREFIX}{time_part}_{rand_part}",)
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const QUERY_ID: &str = "20240112_082858_00000_kggk9";
+
+    fn query(next_uri_path: Option<&str>, previous_next_uri_path: Option<&str>) -> TrinoQuery {
+        let mut query = TrinoQuery::new_from(
+            "trino-m-1".to_owned(),
+            QUERY_ID.to_owned(),
+            "https://trino-m-1:8443".parse().unwrap(),
+            SystemTime::now(),
+            SystemTime::now(),
+            None,
+            next_uri_path.map(ToOwned::to_owned),
+        );
+        query.previous_next_uri_path = previous_next_uri_path.map(ToOwned::to_owned);
+        query
+    }
+
+    #[test]
+    fn test_retain_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("authorization", "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        headers.insert("x-trino-user", "alice".parse().unwrap());
+        headers.append("x-trino-session", "a=1".parse().unwrap());
+        headers.append("x-trino-session", "b=2".parse().unwrap());
+        headers.insert("x-my-header", "keep".parse().unwrap());
+        headers.insert("cookie", "big-cookie".parse().unwrap());
+        let mut queued_query =
+            QueuedQuery::new_from("SELECT 1".to_owned(), headers, "s".to_owned(), None);
+
+        queued_query.retain_headers(&["X-My-Header".to_owned()]);
+
+        let mut names = queued_query
+            .headers
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "authorization",
+                "x-my-header",
+                "x-trino-session",
+                "x-trino-user"
+            ]
+        );
+        assert_eq!(
+            queued_query
+                .headers
+                .get_all("x-trino-session")
+                .iter()
+                .collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+    }
+
+    #[rstest]
+    #[case(
+        None,
+        None,
+        "/v1/statement/executing/20240112_082858_00000_kggk9/yxyz/3",
+        true
+    )]
+    #[case(
+        Some("/v1/statement/executing/20240112_082858_00000_kggk9/yabc/2"),
+        None,
+        "/v1/statement/executing/20240112_082858_00000_kggk9/yabc/2",
+        true
+    )]
+    #[case(
+        Some("/v1/statement/executing/20240112_082858_00000_kggk9/yabc/2"),
+        Some("/v1/statement/executing/20240112_082858_00000_kggk9/ydef/1"),
+        "/v1/statement/executing/20240112_082858_00000_kggk9/ydef/1",
+        true
+    )]
+    #[case(
+        Some("/v1/statement/executing/20240112_082858_00000_kggk9/yabc/2"),
+        Some("/v1/statement/executing/20240112_082858_00000_kggk9/ydef/1"),
+        "/v1/statement/executing/20240112_082858_00000_kggk9/yxyz/2",
+        false
+    )]
+    #[case(
+        Some("/v1/statement/queued/20240112_082858_00000_kggk9/yabc/1"),
+        None,
+        "/v1/statement/queued/20240112_082858_00000_kggk9/yabc/2",
+        false
+    )]
+    fn test_is_valid_statement_path(
+        #[case] next_uri_path: Option<&str>,
+        #[case] previous_next_uri_path: Option<&str>,
+        #[case] requested_path: &str,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(
+            query(next_uri_path, previous_next_uri_path).is_valid_statement_path(requested_path),
+            expected
+        );
+    }
+}
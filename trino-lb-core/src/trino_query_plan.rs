@@ -1,15 +1,8 @@
 use std::{collections::BTreeMap, fmt::Display, iter::Sum, ops::Add};
 
 use number_prefix::NumberPrefix;
-use prusto::Presto;
 use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Clone, Debug, Presto)]
-pub struct ExplainQueryResult {
-    #[presto(rename = "Query Plan")]
-    pub query_plan: String,
-}
-
 #[derive(Debug, Deserialize)]
 pub struct QueryPlan {
     #[serde(flatten)]
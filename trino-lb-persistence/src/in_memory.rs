@@ -1,62 +1,254 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
     num::TryFromIntError,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicU64, Ordering},
     time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH},
 };
 
-use snafu::{OptionExt, ResultExt, Snafu};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use tokio::sync::RwLock;
-use tracing::{error, info, instrument};
+use tracing::{debug, info, instrument, warn};
 use trino_lb_core::{
+    client_request_stats::{bucket_count, ClientRequestCounts, OTHER_USERS},
+    config::InMemoryConfig,
+    trino_api::TrinoQueryApiResponse,
     trino_cluster::ClusterState,
     trino_query::{QueuedQuery, TrinoQuery},
     TrinoClusterName, TrinoLbQueryId, TrinoQueryId,
 };
 
-use crate::Persistence;
+use crate::{query_count_allows_increment, Persistence, QueryCountDecrement};
+
+/// Number of handed over queries per user and cluster group.
+type HandedOverQueries = HashMap<String, HashMap<String, u64>>;
 
 pub struct InMemoryPersistence {
+    max_queued_queries: Option<u64>,
+    snapshot_path: Option<PathBuf>,
     queued_queries: RwLock<HashMap<TrinoLbQueryId, QueuedQuery>>,
     queries: RwLock<HashMap<TrinoQueryId, TrinoQuery>>,
     cluster_query_counts: RwLock<HashMap<TrinoClusterName, AtomicU64>>,
+    cluster_blocked_query_counts: RwLock<HashMap<TrinoClusterName, u64>>,
     cluster_states: RwLock<HashMap<TrinoClusterName, ClusterState>>,
+    disabled_routers: RwLock<BTreeSet<usize>>,
+    routing_excluded_clusters: RwLock<BTreeSet<TrinoClusterName>>,
+    manually_managed_clusters: RwLock<BTreeSet<TrinoClusterName>>,
     last_query_count_fetcher_update: AtomicU64,
+    /// Stores the serialized response together with the expiration time.
+    idempotent_responses: RwLock<HashMap<String, (String, SystemTime)>>,
+    query_runtimes: RwLock<HashMap<String, (Duration, SystemTime)>>,
+    client_request_stats: RwLock<BTreeMap<u64, HashMap<String, ClientRequestCounts>>>,
+    handed_over_queries: RwLock<BTreeMap<u64, HandedOverQueries>>,
 }
 
 #[derive(Snafu, Debug)]
 pub enum Error {
-    #[snafu(display("Queued query with id {queued_query_id:?} not found"))]
-    QueuedQueryNotFound { queued_query_id: TrinoLbQueryId },
-
-    #[snafu(display("Query with id {query_id:?} not found"))]
-    QueryNotFound { query_id: TrinoQueryId },
-
     #[snafu(display("Failed to determined elapsed time since last queryCountFetcher update"))]
     DetermineElapsedTimeSinceLastUpdate { source: SystemTimeError },
 
     #[snafu(display("Failed to store determined elapsed time since last queryCountFetcher update as millis in a u64"))]
     ConvertElapsedTimeSinceLastUpdateToMillis { source: TryFromIntError },
+
+    #[snafu(display("The stored last queryCountFetcher update of {millis} ms since the epoch can not be represented as timestamp"))]
+    LastQueryCountFetcherUpdateOutOfRange { millis: u64 },
+
+    #[snafu(display("Failed to serialize idempotent response"))]
+    SerializeIdempotentResponse { source: serde_json::Error },
+
+    #[snafu(display("Failed to deserialize idempotent response"))]
+    DeserializeIdempotentResponse { source: serde_json::Error },
+
+    #[snafu(display(
+        "Refusing to queue the query, as the maximum number of {max_queued_queries} queued queries is reached"
+    ))]
+    TooManyQueuedQueries { max_queued_queries: u64 },
+
+    #[snafu(display(
+        "Refusing to store query {query_id:?} of Trino cluster {trino_cluster:?}, as a query with the same id is already stored for a different Trino cluster"
+    ))]
+    QueryIdCollision {
+        query_id: TrinoQueryId,
+        trino_cluster: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to serialize the snapshot"))]
+    SerializeSnapshot { source: bincode::Error },
+
+    #[snafu(display("Failed to write the snapshot to {path:?}"))]
+    WriteSnapshot {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
+
+/// The data written to disk on shutdown. Data that is re-fetched from Trino (e.g. the number of blocked queries) or is
+/// only kept for a short time (the client request stats) is not included.
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    queued_queries: HashMap<TrinoLbQueryId, QueuedQuery>,
+    queries: HashMap<TrinoQueryId, TrinoQuery>,
+    cluster_query_counts: HashMap<TrinoClusterName, u64>,
+    cluster_states: HashMap<TrinoClusterName, ClusterState>,
+    disabled_routers: BTreeSet<usize>,
+    routing_excluded_clusters: BTreeSet<TrinoClusterName>,
+    manually_managed_clusters: BTreeSet<TrinoClusterName>,
+    idempotent_responses: HashMap<String, (String, SystemTime)>,
+    query_runtimes: HashMap<String, (Duration, SystemTime)>,
+}
+
+impl Snapshot {
+    /// Missing or corrupt snapshots are not an error, we start with empty state instead, which is what happened
+    /// without snapshots as well.
+    fn read_or_default(path: &Path) -> Self {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                info!(?path, "No snapshot found, starting with empty state");
+                return Self::default();
+            }
+            Err(error) => {
+                warn!(
+                    ?path,
+                    ?error,
+                    "Failed to read snapshot, starting with empty state"
+                );
+                return Self::default();
+            }
+        };
+
+        match bincode::deserialize(&bytes) {
+            Ok(snapshot) => {
+                info!(?path, "Restored state from snapshot");
+                snapshot
+            }
+            Err(error) => {
+                warn!(
+                    ?path,
+                    ?error,
+                    "Failed to parse snapshot, starting with empty state"
+                );
+                Self::default()
+            }
+        }
+    }
 }
 
 impl Default for InMemoryPersistence {
     fn default() -> Self {
+        Self::new(&InMemoryConfig::default())
+    }
+}
+
+impl InMemoryPersistence {
+    pub fn new(config: &InMemoryConfig) -> Self {
         info!("Using in-memory persistence");
 
+        let snapshot = config
+            .snapshot_path
+            .as_deref()
+            .map(Snapshot::read_or_default)
+            .unwrap_or_default();
+
         Self {
-            queued_queries: RwLock::new(HashMap::new()),
-            queries: RwLock::new(HashMap::new()),
-            cluster_query_counts: RwLock::new(HashMap::new()),
-            cluster_states: RwLock::new(HashMap::new()),
+            max_queued_queries: config.max_queued_queries,
+            snapshot_path: config.snapshot_path.clone(),
+            queued_queries: RwLock::new(snapshot.queued_queries),
+            queries: RwLock::new(snapshot.queries),
+            cluster_query_counts: RwLock::new(
+                snapshot
+                    .cluster_query_counts
+                    .into_iter()
+                    .map(|(cluster, count)| (cluster, AtomicU64::new(count)))
+                    .collect(),
+            ),
+            cluster_blocked_query_counts: RwLock::new(HashMap::new()),
+            cluster_states: RwLock::new(snapshot.cluster_states),
+            disabled_routers: RwLock::new(snapshot.disabled_routers),
+            routing_excluded_clusters: RwLock::new(snapshot.routing_excluded_clusters),
+            manually_managed_clusters: RwLock::new(snapshot.manually_managed_clusters),
             last_query_count_fetcher_update: AtomicU64::from(0),
+            idempotent_responses: RwLock::new(snapshot.idempotent_responses),
+            query_runtimes: RwLock::new(snapshot.query_runtimes),
+            client_request_stats: RwLock::new(BTreeMap::new()),
+            handed_over_queries: RwLock::new(BTreeMap::new()),
         }
     }
+
+    /// Writes the stored data to the configured `snapshotPath`, so that it can be restored on the next start. Does
+    /// nothing in case no snapshot path is configured.
+    #[instrument(skip(self))]
+    pub async fn write_snapshot(&self) -> Result<(), Error> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+
+        let snapshot = Snapshot {
+            queued_queries: self.queued_queries.read().await.clone(),
+            queries: self.queries.read().await.clone(),
+            cluster_query_counts: self
+                .cluster_query_counts
+                .read()
+                .await
+                .iter()
+                .map(|(cluster, count)| (cluster.clone(), count.load(Ordering::SeqCst)))
+                .collect(),
+            cluster_states: self.cluster_states.read().await.clone(),
+            disabled_routers: self.disabled_routers.read().await.clone(),
+            routing_excluded_clusters: self.routing_excluded_clusters.read().await.clone(),
+            manually_managed_clusters: self.manually_managed_clusters.read().await.clone(),
+            idempotent_responses: self.idempotent_responses.read().await.clone(),
+            query_runtimes: self.query_runtimes.read().await.clone(),
+        };
+        let bytes = bincode::serialize(&snapshot).context(SerializeSnapshotSnafu)?;
+
+        // Write to a temporary file first, so that we don't end up with a corrupt snapshot in case we get killed
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        write_private_file(Path::new(&tmp_path), &bytes)
+            .context(WriteSnapshotSnafu { path: &tmp_path })?;
+        fs::rename(&tmp_path, path).context(WriteSnapshotSnafu { path })?;
+
+        info!(?path, "Wrote snapshot");
+        Ok(())
+    }
+
+    /// Returns the number of entries stored in the maps that can grow with the number of queries. Maps that are
+    /// currently locked are skipped, as this is called from (synchronous) metrics callbacks, which must not block.
+    pub fn stored_entries(&self) -> Vec<(&'static str, u64)> {
+        let mut stored_entries = Vec::new();
+        if let Ok(queued_queries) = self.queued_queries.try_read() {
+            stored_entries.push(("queued_queries", queued_queries.len() as u64));
+        }
+        if let Ok(queries) = self.queries.try_read() {
+            stored_entries.push(("queries", queries.len() as u64));
+        }
+        if let Ok(idempotent_responses) = self.idempotent_responses.try_read() {
+            stored_entries.push(("idempotent_responses", idempotent_responses.len() as u64));
+        }
+        if let Ok(query_runtimes) = self.query_runtimes.try_read() {
+            stored_entries.push(("query_runtimes", query_runtimes.len() as u64));
+        }
+
+        stored_entries
+    }
 }
 
 impl Persistence for InMemoryPersistence {
     #[instrument(skip(self))]
     async fn store_queued_query(&self, queued_query: QueuedQuery) -> Result<(), super::Error> {
         let mut queued_queries = self.queued_queries.write().await;
+        // Updates of already queued queries are always fine, as they don't increase the memory usage
+        if let Some(max_queued_queries) = self.max_queued_queries {
+            if !queued_queries.contains_key(&queued_query.id)
+                && queued_queries.len() as u64 >= max_queued_queries
+            {
+                TooManyQueuedQueriesSnafu { max_queued_queries }.fail()?;
+            }
+        }
         queued_queries.insert(queued_query.id.clone(), queued_query);
 
         Ok(())
@@ -66,12 +258,9 @@ impl Persistence for InMemoryPersistence {
     async fn load_queued_query(
         &self,
         queued_query_id: &TrinoLbQueryId,
-    ) -> Result<QueuedQuery, super::Error> {
+    ) -> Result<Option<QueuedQuery>, super::Error> {
         let queued_queries = self.queued_queries.read().await;
-        Ok(queued_queries
-            .get(queued_query_id)
-            .context(QueuedQueryNotFoundSnafu { queued_query_id })?
-            .clone())
+        Ok(queued_queries.get(queued_query_id).cloned())
     }
 
     #[instrument(skip(self))]
@@ -82,21 +271,74 @@ impl Persistence for InMemoryPersistence {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn move_queued_query(
+        &self,
+        queued_query: &QueuedQuery,
+        cluster_group: &str,
+    ) -> Result<(), super::Error> {
+        let mut queued_queries = self.queued_queries.write().await;
+        if let Some(queued_query) = queued_queries.get_mut(&queued_query.id) {
+            cluster_group.clone_into(&mut queued_query.cluster_group);
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn update_queued_query_last_accessed(
+        &self,
+        queued_query: &QueuedQuery,
+        last_accessed: SystemTime,
+    ) -> Result<(), super::Error> {
+        let mut queued_queries = self.queued_queries.write().await;
+        if let Some(stored) = queued_queries.get_mut(&queued_query.id) {
+            if stored.cluster_group == queued_query.cluster_group {
+                stored.last_accessed = last_accessed;
+            }
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn store_query(&self, query: TrinoQuery) -> Result<(), super::Error> {
         let mut queries = self.queries.write().await;
+        if let Some(stored) = queries.get(&query.id) {
+            ensure!(
+                stored.trino_cluster == query.trino_cluster,
+                QueryIdCollisionSnafu {
+                    query_id: query.id,
+                    trino_cluster: query.trino_cluster,
+                }
+            );
+        }
         queries.insert(query.id.clone(), query);
 
         Ok(())
     }
 
     #[instrument(skip(self))]
-    async fn load_query(&self, query_id: &TrinoQueryId) -> Result<TrinoQuery, super::Error> {
+    async fn load_query(
+        &self,
+        query_id: &TrinoQueryId,
+    ) -> Result<Option<TrinoQuery>, super::Error> {
         let queries = self.queries.read().await;
-        Ok(queries
-            .get(query_id)
-            .context(QueryNotFoundSnafu { query_id })?
-            .clone())
+        Ok(queries.get(query_id).cloned())
+    }
+
+    #[instrument(skip(self))]
+    async fn update_query_next_uri_path(
+        &self,
+        query_id: &TrinoQueryId,
+        next_uri_path: String,
+    ) -> Result<(), super::Error> {
+        let mut queries = self.queries.write().await;
+        if let Some(query) = queries.get_mut(query_id) {
+            query.previous_next_uri_path = query.next_uri_path.replace(next_uri_path);
+        }
+
+        Ok(())
     }
 
     #[instrument(skip(self))]
@@ -107,6 +349,11 @@ impl Persistence for InMemoryPersistence {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn list_query_ids(&self) -> Result<Vec<TrinoQueryId>, super::Error> {
+        Ok(self.queries.read().await.keys().cloned().collect())
+    }
+
     #[instrument(skip(self))]
     async fn inc_cluster_query_count(
         &self,
@@ -118,7 +365,7 @@ impl Persistence for InMemoryPersistence {
         if let Some(count) = current_counts.get(cluster_name) {
             let mut current = count.load(Ordering::SeqCst);
             loop {
-                if current + 1 > max_allowed_count {
+                if !query_count_allows_increment(current, max_allowed_count) {
                     return Ok(false);
                 }
 
@@ -140,6 +387,10 @@ impl Persistence for InMemoryPersistence {
             // Otherwise the [`RwLock::write`] call will block forever.
             drop(current_counts);
 
+            if !query_count_allows_increment(0, max_allowed_count) {
+                return Ok(false);
+            }
+
             self.cluster_query_counts
                 .write()
                 .await
@@ -153,20 +404,21 @@ impl Persistence for InMemoryPersistence {
     async fn dec_cluster_query_count(
         &self,
         cluster_name: &TrinoClusterName,
-    ) -> Result<(), super::Error> {
-        if let Some(count) = self.cluster_query_counts.read().await.get(cluster_name) {
-            if count.fetch_sub(1, Ordering::SeqCst) == 0 {
-                error!(
-                    cluster_name,
-                    "Persistence was asked to decrement the number of queries for the given cluster, but it would result in a negative amount of queries. Setting it to 0 instead."
-                );
-                count.store(0, Ordering::SeqCst);
-            }
-        } else {
-            error!("Persistence was asked to decrement the number of queries, but no query count for this cluster was not known. This should not happen.")
+    ) -> Result<QueryCountDecrement, super::Error> {
+        let cluster_query_counts = self.cluster_query_counts.read().await;
+        let Some(count) = cluster_query_counts.get(cluster_name) else {
+            debug!("No query count known for this cluster, nothing to do here");
+            return Ok(QueryCountDecrement::AlreadyZero);
+        };
+
+        let previous = count.fetch_sub(1, Ordering::SeqCst);
+        if previous == 0 {
+            debug!("Current value was already 0, resetting it to 0");
+            count.store(0, Ordering::SeqCst);
+            return Ok(QueryCountDecrement::AlreadyZero);
         }
 
-        Ok(())
+        Ok(QueryCountDecrement::Decremented(previous - 1))
     }
 
     #[instrument(skip(self))]
@@ -205,6 +457,35 @@ impl Persistence for InMemoryPersistence {
             .unwrap_or_default())
     }
 
+    #[instrument(skip(self))]
+    async fn set_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        count: u64,
+    ) -> Result<(), super::Error> {
+        self.cluster_blocked_query_counts
+            .write()
+            .await
+            .insert(cluster_name.clone(), count);
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn get_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<u64, super::Error> {
+        Ok(self
+            .cluster_blocked_query_counts
+            .read()
+            .await
+            .get(cluster_name)
+            .copied()
+            // The count might not have been set yet
+            .unwrap_or_default())
+    }
+
     #[instrument(skip(self))]
     async fn get_queued_query_count(&self, cluster_group: &str) -> Result<u64, super::Error> {
         Ok(self
@@ -217,6 +498,21 @@ impl Persistence for InMemoryPersistence {
             .count() as u64)
     }
 
+    #[instrument(skip(self))]
+    async fn list_queued_query_ids(
+        &self,
+        cluster_group: &str,
+    ) -> Result<Vec<TrinoLbQueryId>, super::Error> {
+        Ok(self
+            .queued_queries
+            .read()
+            .await
+            .values()
+            .filter(|q| q.cluster_group == cluster_group)
+            .map(|q| q.id.clone())
+            .collect())
+    }
+
     #[instrument(skip(self))]
     async fn delete_queued_queries_not_accessed_after(
         &self,
@@ -239,8 +535,8 @@ impl Persistence for InMemoryPersistence {
     async fn get_last_query_count_fetcher_update(&self) -> Result<SystemTime, super::Error> {
         let ms = self.last_query_count_fetcher_update.load(Ordering::SeqCst);
 
-        // TODO: Check for overflows
-        Ok(UNIX_EPOCH + Duration::from_millis(ms))
+        Ok(crate::timestamp_from_millis(ms)
+            .context(LastQueryCountFetcherUpdateOutOfRangeSnafu { millis: ms })?)
     }
 
     #[instrument(skip(self))]
@@ -285,4 +581,885 @@ impl Persistence for InMemoryPersistence {
             .cloned()
             .unwrap_or(ClusterState::Unknown))
     }
+
+    #[instrument(skip(self))]
+    async fn list_cluster_states(
+        &self,
+    ) -> Result<Vec<(TrinoClusterName, ClusterState)>, super::Error> {
+        let cluster_states = self.cluster_states.read().await;
+        let mut cluster_states = cluster_states
+            .iter()
+            .map(|(cluster, state)| (cluster.clone(), state.clone()))
+            .collect::<Vec<_>>();
+        cluster_states.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(cluster_states)
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_cluster(&self, cluster_name: &TrinoClusterName) -> Result<(), super::Error> {
+        self.cluster_states.write().await.remove(cluster_name);
+        self.cluster_query_counts.write().await.remove(cluster_name);
+        self.cluster_blocked_query_counts
+            .write()
+            .await
+            .remove(cluster_name);
+        self.routing_excluded_clusters
+            .write()
+            .await
+            .remove(cluster_name);
+        self.manually_managed_clusters
+            .write()
+            .await
+            .remove(cluster_name);
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_router_disabled(
+        &self,
+        router_index: usize,
+        disabled: bool,
+    ) -> Result<(), super::Error> {
+        let mut disabled_routers = self.disabled_routers.write().await;
+        if disabled {
+            disabled_routers.insert(router_index);
+        } else {
+            disabled_routers.remove(&router_index);
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_disabled_routers(&self) -> Result<Vec<usize>, super::Error> {
+        Ok(self.disabled_routers.read().await.iter().copied().collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_routing_excluded(
+        &self,
+        cluster_name: &TrinoClusterName,
+        excluded: bool,
+    ) -> Result<(), super::Error> {
+        let mut routing_excluded_clusters = self.routing_excluded_clusters.write().await;
+        if excluded {
+            routing_excluded_clusters.insert(cluster_name.clone());
+        } else {
+            routing_excluded_clusters.remove(cluster_name);
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_routing_excluded_clusters(&self) -> Result<Vec<TrinoClusterName>, super::Error> {
+        Ok(self
+            .routing_excluded_clusters
+            .read()
+            .await
+            .iter()
+            .cloned()
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_manually_managed(
+        &self,
+        cluster_name: &TrinoClusterName,
+        manually_managed: bool,
+    ) -> Result<(), super::Error> {
+        let mut manually_managed_clusters = self.manually_managed_clusters.write().await;
+        if manually_managed {
+            manually_managed_clusters.insert(cluster_name.clone());
+        } else {
+            manually_managed_clusters.remove(cluster_name);
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_manually_managed_clusters(&self) -> Result<Vec<TrinoClusterName>, super::Error> {
+        Ok(self
+            .manually_managed_clusters
+            .read()
+            .await
+            .iter()
+            .cloned()
+            .collect())
+    }
+
+    #[instrument(skip(self, response))]
+    async fn store_idempotent_response(
+        &self,
+        idempotency_key: &str,
+        response: &TrinoQueryApiResponse,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let response = serde_json::to_string(response).context(SerializeIdempotentResponseSnafu)?;
+        let now = SystemTime::now();
+
+        let mut idempotent_responses = self.idempotent_responses.write().await;
+        // Clean up expired entries, so that we don't leak memory
+        idempotent_responses.retain(|_, (_, expires_at)| *expires_at > now);
+        idempotent_responses.insert(idempotency_key.to_owned(), (response, now + ttl));
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_idempotent_response(
+        &self,
+        idempotency_key: &str,
+    ) -> Result<Option<TrinoQueryApiResponse>, super::Error> {
+        let idempotent_responses = self.idempotent_responses.read().await;
+
+        match idempotent_responses.get(idempotency_key) {
+            Some((response, expires_at)) if *expires_at > SystemTime::now() => Ok(Some(
+                serde_json::from_str(response).context(DeserializeIdempotentResponseSnafu)?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn store_query_runtime(
+        &self,
+        query_fingerprint: &str,
+        runtime: Duration,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let now = SystemTime::now();
+
+        let mut query_runtimes = self.query_runtimes.write().await;
+        // Clean up expired entries, so that we don't leak memory
+        query_runtimes.retain(|_, (_, expires_at)| *expires_at > now);
+        query_runtimes.insert(query_fingerprint.to_owned(), (runtime, now + ttl));
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_query_runtime(
+        &self,
+        query_fingerprint: &str,
+    ) -> Result<Option<Duration>, super::Error> {
+        let query_runtimes = self.query_runtimes.read().await;
+
+        Ok(match query_runtimes.get(query_fingerprint) {
+            Some((runtime, expires_at)) if *expires_at > SystemTime::now() => Some(*runtime),
+            _ => None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn record_client_requests(
+        &self,
+        bucket: u64,
+        user: &str,
+        counts: ClientRequestCounts,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let mut client_request_stats = self.client_request_stats.write().await;
+        // Clean up expired buckets, so that we don't leak memory
+        *client_request_stats =
+            client_request_stats.split_off(&bucket.saturating_sub(bucket_count(ttl)));
+
+        let users = client_request_stats.entry(bucket).or_default();
+        let user = if users.contains_key(user) || (users.len() as u64) < max_users {
+            user
+        } else {
+            OTHER_USERS
+        };
+        *users.entry(user.to_owned()).or_default() += counts;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_client_request_stats(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<String, ClientRequestCounts>, super::Error> {
+        let client_request_stats = self.client_request_stats.read().await;
+
+        let mut result = HashMap::<String, ClientRequestCounts>::new();
+        for (user, counts) in client_request_stats
+            .range(buckets)
+            .flat_map(|(_, users)| users)
+        {
+            *result.entry(user.clone()).or_default() += *counts;
+        }
+
+        Ok(result)
+    }
+
+    #[instrument(skip(self))]
+    async fn record_handed_over_query(
+        &self,
+        bucket: u64,
+        user: &str,
+        cluster_group: &str,
+        max_users: u64,
+        _ttl: Duration,
+    ) -> Result<(), super::Error> {
+        // Expired buckets are deleted by `delete_handed_over_queries_before`
+        let mut handed_over_queries = self.handed_over_queries.write().await;
+        let users = handed_over_queries.entry(bucket).or_default();
+        let user = if users.contains_key(user) || (users.len() as u64) < max_users {
+            user
+        } else {
+            OTHER_USERS
+        };
+        *users
+            .entry(user.to_owned())
+            .or_default()
+            .entry(cluster_group.to_owned())
+            .or_default() += 1;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_handed_over_queries_before(&self, bucket: u64) -> Result<(), super::Error> {
+        let mut handed_over_queries = self.handed_over_queries.write().await;
+        *handed_over_queries = handed_over_queries.split_off(&bucket);
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_handed_over_query_counts(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<(String, String), u64>, super::Error> {
+        let handed_over_queries = self.handed_over_queries.read().await;
+
+        let mut result = HashMap::<(String, String), u64>::new();
+        for (user, cluster_groups) in handed_over_queries
+            .range(buckets)
+            .flat_map(|(_, users)| users)
+        {
+            for (cluster_group, queries) in cluster_groups {
+                *result
+                    .entry((user.clone(), cluster_group.clone()))
+                    .or_default() += queries;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Writes the file readable for the owner only, as the snapshot contains the headers (including credentials) of the
+/// queued queries.
+fn write_private_file(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    // The mode only applies to newly created files, a leftover temporary file might have other permissions
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    std::io::Write::write_all(&mut file, bytes)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "trino-lb-snapshot-{name}-{}.bin",
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let path = snapshot_path("round-trip");
+        let config = InMemoryConfig {
+            snapshot_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let cluster = "trino-s-1".to_owned();
+
+        let persistence = InMemoryPersistence::new(&config);
+        persistence
+            .store_queued_query(QueuedQuery {
+                id: "trino_lb_1".to_owned(),
+                query: "SELECT 1".to_owned(),
+                headers: Default::default(),
+                creation_time: SystemTime::now(),
+                last_accessed: SystemTime::now(),
+                cluster_group: "s".to_owned(),
+                routing_reason: None,
+            })
+            .await
+            .unwrap();
+        persistence
+            .inc_cluster_query_count(&cluster, 10)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        persistence.set_router_disabled(1, true).await.unwrap();
+        persistence.write_snapshot().await.unwrap();
+
+        let restored = InMemoryPersistence::new(&config);
+        assert_eq!(
+            restored.list_queued_query_ids("s").await.unwrap(),
+            ["trino_lb_1"]
+        );
+        assert_eq!(restored.get_cluster_query_count(&cluster).await.unwrap(), 1);
+        assert_eq!(
+            restored.get_cluster_state(&cluster).await.unwrap(),
+            ClusterState::Ready
+        );
+        assert_eq!(restored.list_disabled_routers().await.unwrap(), [1]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missing_or_corrupt_snapshot() {
+        let path = snapshot_path("corrupt");
+        let config = InMemoryConfig {
+            snapshot_path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let persistence = InMemoryPersistence::new(&config);
+        assert!(persistence
+            .list_queued_query_ids("s")
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        let persistence = InMemoryPersistence::new(&config);
+        assert!(persistence
+            .list_queued_query_ids("s")
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inc_cluster_query_count_up_to_max() {
+        let persistence = InMemoryPersistence::default();
+        let cluster = "trino-s-1".to_owned();
+        let max_running_queries = 3;
+
+        for _ in 0..max_running_queries {
+            assert!(persistence
+                .inc_cluster_query_count(&cluster, max_running_queries)
+                .await
+                .unwrap());
+        }
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            max_running_queries
+        );
+
+        // The cluster is full now, which is in line with the check done when selecting a cluster
+        assert!(!query_count_allows_increment(
+            max_running_queries,
+            max_running_queries
+        ));
+        assert!(!persistence
+            .inc_cluster_query_count(&cluster, max_running_queries)
+            .await
+            .unwrap());
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            max_running_queries
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_cluster_states() {
+        let persistence = InMemoryPersistence::default();
+        assert!(persistence.list_cluster_states().await.unwrap().is_empty());
+
+        persistence
+            .set_cluster_state(&"trino-s-2".to_owned(), ClusterState::Stopped)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            persistence.list_cluster_states().await.unwrap(),
+            [
+                ("trino-s-1".to_owned(), ClusterState::Ready),
+                ("trino-s-2".to_owned(), ClusterState::Stopped),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_routers() {
+        let persistence = InMemoryPersistence::default();
+        assert!(persistence
+            .list_disabled_routers()
+            .await
+            .unwrap()
+            .is_empty());
+
+        persistence.set_router_disabled(2, true).await.unwrap();
+        persistence.set_router_disabled(0, true).await.unwrap();
+        // Disabling twice is fine
+        persistence.set_router_disabled(2, true).await.unwrap();
+        assert_eq!(persistence.list_disabled_routers().await.unwrap(), [0, 2]);
+
+        persistence.set_router_disabled(2, false).await.unwrap();
+        // Enabling a router that is not disabled is fine as well
+        persistence.set_router_disabled(1, false).await.unwrap();
+        assert_eq!(persistence.list_disabled_routers().await.unwrap(), [0]);
+    }
+
+    #[tokio::test]
+    async fn test_routing_excluded_clusters() {
+        let persistence = InMemoryPersistence::default();
+        let cluster_1 = "trino-s-1".to_owned();
+        let cluster_2 = "trino-s-2".to_owned();
+
+        persistence
+            .set_cluster_routing_excluded(&cluster_2, true)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_routing_excluded(&cluster_1, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence.list_routing_excluded_clusters().await.unwrap(),
+            [cluster_1.clone(), cluster_2.clone()]
+        );
+
+        persistence
+            .set_cluster_routing_excluded(&cluster_1, false)
+            .await
+            .unwrap();
+        // Removed clusters are not excluded any more
+        persistence.remove_cluster(&cluster_2).await.unwrap();
+        assert!(persistence
+            .list_routing_excluded_clusters()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_manually_managed_clusters() {
+        let persistence = InMemoryPersistence::default();
+        let cluster_1 = "trino-s-1".to_owned();
+        let cluster_2 = "trino-s-2".to_owned();
+
+        persistence
+            .set_cluster_manually_managed(&cluster_2, true)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_manually_managed(&cluster_1, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence.list_manually_managed_clusters().await.unwrap(),
+            [cluster_1.clone(), cluster_2.clone()]
+        );
+
+        persistence
+            .set_cluster_manually_managed(&cluster_1, false)
+            .await
+            .unwrap();
+        // Removed clusters are not manually managed any more
+        persistence.remove_cluster(&cluster_2).await.unwrap();
+        assert!(persistence
+            .list_manually_managed_clusters()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_queued_query_last_accessed() {
+        let persistence = InMemoryPersistence::default();
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            http::HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        persistence
+            .store_queued_query(queued_query.clone())
+            .await
+            .unwrap();
+
+        let later = queued_query.last_accessed + Duration::from_secs(60);
+        persistence
+            .update_queued_query_last_accessed(&queued_query, later)
+            .await
+            .unwrap();
+        let stored = persistence
+            .load_queued_query(&queued_query.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.last_accessed, later);
+
+        // The copy of the query is outdated once the query was moved
+        persistence
+            .move_queued_query(&queued_query, "m")
+            .await
+            .unwrap();
+        persistence
+            .update_queued_query_last_accessed(&queued_query, later + Duration::from_secs(60))
+            .await
+            .unwrap();
+        let stored = persistence
+            .load_queued_query(&queued_query.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.cluster_group, "m");
+        assert_eq!(stored.last_accessed, later);
+    }
+
+    #[tokio::test]
+    async fn test_store_query_with_colliding_id() {
+        let persistence = InMemoryPersistence::default();
+        let query_id = "20240101_120000_00001_abcde".to_owned();
+        let query_of_cluster = |cluster: &str, next_uri_path: &str| {
+            TrinoQuery::new_from(
+                cluster.to_owned(),
+                query_id.clone(),
+                "http://trino.example.com:8080".parse().unwrap(),
+                SystemTime::now(),
+                SystemTime::now(),
+                None,
+                Some(next_uri_path.to_owned()),
+            )
+        };
+
+        persistence
+            .store_query(query_of_cluster("trino-1", "/a"))
+            .await
+            .unwrap();
+
+        // Storing the query of the same cluster again replaces it
+        persistence
+            .store_query(query_of_cluster("trino-1", "/b"))
+            .await
+            .unwrap();
+        let stored = persistence.load_query(&query_id).await.unwrap().unwrap();
+        assert_eq!(stored.next_uri_path.as_deref(), Some("/b"));
+
+        // The query of a different cluster is refused and the stored query is kept
+        let error = persistence
+            .store_query(query_of_cluster("trino-2", "/c"))
+            .await
+            .unwrap_err();
+        assert!(error.is_query_id_collision());
+        let stored = persistence.load_query(&query_id).await.unwrap().unwrap();
+        assert_eq!(stored.trino_cluster, "trino-1");
+        assert_eq!(stored.next_uri_path.as_deref(), Some("/b"));
+    }
+
+    #[tokio::test]
+    async fn test_dec_cluster_query_count() {
+        let persistence = InMemoryPersistence::default();
+        let cluster = "trino-s-1".to_owned();
+
+        // Unknown clusters have no queries
+        assert_eq!(
+            persistence.dec_cluster_query_count(&cluster).await.unwrap(),
+            QueryCountDecrement::AlreadyZero
+        );
+
+        persistence
+            .set_cluster_query_count(&cluster, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence.dec_cluster_query_count(&cluster).await.unwrap(),
+            QueryCountDecrement::Decremented(1)
+        );
+        assert_eq!(
+            persistence.dec_cluster_query_count(&cluster).await.unwrap(),
+            QueryCountDecrement::Decremented(0)
+        );
+
+        // Never goes below zero
+        assert_eq!(
+            persistence.dec_cluster_query_count(&cluster).await.unwrap(),
+            QueryCountDecrement::AlreadyZero
+        );
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inc_cluster_query_count_with_zero_max() {
+        let persistence = InMemoryPersistence::default();
+        let cluster = "trino-s-1".to_owned();
+
+        assert!(!persistence
+            .inc_cluster_query_count(&cluster, 0)
+            .await
+            .unwrap());
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_last_query_count_fetcher_update() {
+        let persistence = InMemoryPersistence::default();
+        assert_eq!(
+            persistence
+                .get_last_query_count_fetcher_update()
+                .await
+                .unwrap(),
+            UNIX_EPOCH
+        );
+
+        let update = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        persistence
+            .set_last_query_count_fetcher_update(update)
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence
+                .get_last_query_count_fetcher_update()
+                .await
+                .unwrap(),
+            update
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_last_query_count_fetcher_update_does_not_overflow() {
+        let persistence = InMemoryPersistence::default();
+        persistence
+            .last_query_count_fetcher_update
+            .store(u64::MAX, Ordering::SeqCst);
+
+        let error = persistence
+            .get_last_query_count_fetcher_update()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::Error::InMemoryError {
+                    source: Error::LastQueryCountFetcherUpdateOutOfRange { millis: u64::MAX }
+                }
+            ),
+            "Unexpected error {error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_request_stats() {
+        let persistence = InMemoryPersistence::default();
+        let ttl = Duration::from_secs(2 * 60);
+
+        for (bucket, user, requests, header_bytes) in [
+            (10, "alice", 1, 100),
+            (10, "alice", 2, 300),
+            (10, "bob", 1, 50),
+            // Exceeds the maximum of two users per bucket
+            (10, "eve", 1, 1000),
+            (11, "eve", 1, 2000),
+        ] {
+            let counts = ClientRequestCounts {
+                requests,
+                header_bytes,
+            };
+            persistence
+                .record_client_requests(bucket, user, counts, 2, ttl)
+                .await
+                .unwrap();
+        }
+
+        let stats = persistence
+            .load_client_request_stats(10..=11)
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            HashMap::from([
+                (
+                    "alice".to_owned(),
+                    ClientRequestCounts {
+                        requests: 3,
+                        header_bytes: 400
+                    }
+                ),
+                (
+                    "bob".to_owned(),
+                    ClientRequestCounts {
+                        requests: 1,
+                        header_bytes: 50
+                    }
+                ),
+                (
+                    OTHER_USERS.to_owned(),
+                    ClientRequestCounts {
+                        requests: 1,
+                        header_bytes: 1000
+                    }
+                ),
+                (
+                    "eve".to_owned(),
+                    ClientRequestCounts {
+                        requests: 1,
+                        header_bytes: 2000
+                    }
+                ),
+            ])
+        );
+
+        // Bucket 10 expires once bucket 13 is recorded
+        persistence
+            .record_client_requests(
+                13,
+                "alice",
+                ClientRequestCounts {
+                    requests: 1,
+                    header_bytes: 1,
+                },
+                2,
+                ttl,
+            )
+            .await
+            .unwrap();
+        let stats = persistence.load_client_request_stats(0..=13).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["eve"].requests, 1);
+        assert_eq!(stats["alice"].requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_handed_over_query_counts() {
+        let persistence = InMemoryPersistence::default();
+        let ttl = Duration::from_secs(2 * 24 * 60 * 60);
+
+        for (bucket, user, cluster_group) in [
+            (10, "alice", "s"),
+            (10, "alice", "s"),
+            (10, "alice", "m"),
+            (10, "bob", "s"),
+            // Exceeds the maximum of two users per bucket
+            (10, "eve", "s"),
+            (11, "eve", "m"),
+        ] {
+            persistence
+                .record_handed_over_query(bucket, user, cluster_group, 2, ttl)
+                .await
+                .unwrap();
+        }
+
+        let counts = persistence
+            .load_handed_over_query_counts(10..=11)
+            .await
+            .unwrap();
+        let count = |user: &str, cluster_group: &str| {
+            counts
+                .get(&(user.to_owned(), cluster_group.to_owned()))
+                .copied()
+        };
+        assert_eq!(counts.len(), 5);
+        assert_eq!(count("alice", "s"), Some(2));
+        assert_eq!(count("alice", "m"), Some(1));
+        assert_eq!(count("bob", "s"), Some(1));
+        assert_eq!(count(OTHER_USERS, "s"), Some(1));
+        assert_eq!(count("eve", "m"), Some(1));
+
+        // Bucket 10 expired
+        persistence
+            .delete_handed_over_queries_before(11)
+            .await
+            .unwrap();
+        persistence
+            .record_handed_over_query(13, "alice", "s", 2, ttl)
+            .await
+            .unwrap();
+        let counts = persistence
+            .load_handed_over_query_counts(0..=13)
+            .await
+            .unwrap();
+        assert_eq!(
+            counts,
+            HashMap::from([
+                (("alice".to_owned(), "s".to_owned()), 1),
+                (("eve".to_owned(), "m".to_owned()), 1),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_queued_queries() {
+        let persistence = InMemoryPersistence::new(&InMemoryConfig {
+            max_queued_queries: Some(2),
+            ..Default::default()
+        });
+        let queued_query = || {
+            QueuedQuery::new_from(
+                "select 42".to_owned(),
+                http::HeaderMap::new(),
+                "s".to_owned(),
+                None,
+            )
+        };
+
+        let first = queued_query();
+        persistence.store_queued_query(first.clone()).await.unwrap();
+        persistence
+            .store_queued_query(queued_query())
+            .await
+            .unwrap();
+
+        let err = persistence
+            .store_queued_query(queued_query())
+            .await
+            .unwrap_err();
+        assert!(err.is_queue_full());
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 2);
+        assert_eq!(persistence.stored_entries()[0], ("queued_queries", 2));
+
+        // Already queued queries can still be updated
+        persistence.store_queued_query(first.clone()).await.unwrap();
+
+        // Once a queued query is removed, there is space for a new one again
+        persistence.remove_queued_query(&first).await.unwrap();
+        persistence
+            .store_queued_query(queued_query())
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,540 @@
+//! Records the duration of every persistence operation, independent of the backend in use.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::RangeInclusive,
+    time::{Duration, Instant, SystemTime},
+};
+
+use opentelemetry::{metrics::Histogram, KeyValue};
+use trino_lb_core::{
+    client_request_stats::ClientRequestCounts,
+    trino_api::TrinoQueryApiResponse,
+    trino_cluster::ClusterState,
+    trino_query::{QueuedQuery, TrinoQuery},
+    TrinoClusterName, TrinoLbQueryId, TrinoQueryId,
+};
+
+use crate::{Error, Persistence, PersistenceImplementation, QueryCountDecrement};
+
+/// Wraps another persistence and records the duration of every operation in the `persistence_operation_duration`
+/// histogram, labeled by the operation and the backend. This way the backends don't need to care about metrics.
+pub struct InstrumentedPersistence {
+    /// Boxed, as the wrapped persistence is a [`PersistenceImplementation`] itself.
+    inner: Box<PersistenceImplementation>,
+    backend: &'static str,
+    operation_duration: Histogram<f64>,
+}
+
+impl InstrumentedPersistence {
+    /// Needs to be called after the global meter provider is set, as the histogram would not record anything
+    /// otherwise.
+    pub fn new(inner: PersistenceImplementation) -> Self {
+        let operation_duration = opentelemetry::global::meter("trino-lb")
+            .f64_histogram("persistence_operation_duration")
+            .with_unit("ms")
+            .with_description("The time it took the persistence to execute an operation")
+            .init();
+
+        Self {
+            backend: inner.backend(),
+            inner: Box::new(inner),
+            operation_duration,
+        }
+    }
+
+    pub fn inner(&self) -> &PersistenceImplementation {
+        &self.inner
+    }
+
+    async fn record<T>(
+        &self,
+        operation: &'static str,
+        future: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let start = Instant::now();
+        let result = future.await;
+        self.operation_duration.record(
+            start.elapsed().as_secs_f64() * 1000.0,
+            &[
+                KeyValue::new("operation", operation),
+                KeyValue::new("backend", self.backend),
+            ],
+        );
+
+        result
+    }
+}
+
+// The calls to the wrapped persistence are boxed, as the futures would have an infinite size otherwise.
+impl Persistence for InstrumentedPersistence {
+    async fn store_queued_query(&self, query: QueuedQuery) -> Result<(), Error> {
+        self.record(
+            "store_queued_query",
+            Box::pin(self.inner.store_queued_query(query)),
+        )
+        .await
+    }
+
+    async fn load_queued_query(
+        &self,
+        query_id: &TrinoLbQueryId,
+    ) -> Result<Option<QueuedQuery>, Error> {
+        self.record(
+            "load_queued_query",
+            Box::pin(self.inner.load_queued_query(query_id)),
+        )
+        .await
+    }
+
+    async fn remove_queued_query(&self, query: &QueuedQuery) -> Result<(), Error> {
+        self.record(
+            "remove_queued_query",
+            Box::pin(self.inner.remove_queued_query(query)),
+        )
+        .await
+    }
+
+    async fn move_queued_query(
+        &self,
+        query: &QueuedQuery,
+        cluster_group: &str,
+    ) -> Result<(), Error> {
+        self.record(
+            "move_queued_query",
+            Box::pin(self.inner.move_queued_query(query, cluster_group)),
+        )
+        .await
+    }
+
+    async fn update_queued_query_last_accessed(
+        &self,
+        query: &QueuedQuery,
+        last_accessed: SystemTime,
+    ) -> Result<(), Error> {
+        self.record(
+            "update_queued_query_last_accessed",
+            Box::pin(
+                self.inner
+                    .update_queued_query_last_accessed(query, last_accessed),
+            ),
+        )
+        .await
+    }
+
+    async fn store_query(&self, query: TrinoQuery) -> Result<(), Error> {
+        self.record("store_query", Box::pin(self.inner.store_query(query)))
+            .await
+    }
+
+    async fn load_query(&self, query_id: &TrinoQueryId) -> Result<Option<TrinoQuery>, Error> {
+        self.record("load_query", Box::pin(self.inner.load_query(query_id)))
+            .await
+    }
+
+    async fn remove_query(&self, query_id: &TrinoQueryId) -> Result<(), Error> {
+        self.record("remove_query", Box::pin(self.inner.remove_query(query_id)))
+            .await
+    }
+
+    async fn list_query_ids(&self) -> Result<Vec<TrinoQueryId>, Error> {
+        self.record("list_query_ids", Box::pin(self.inner.list_query_ids()))
+            .await
+    }
+
+    async fn update_query_next_uri_path(
+        &self,
+        query_id: &TrinoQueryId,
+        next_uri_path: String,
+    ) -> Result<(), Error> {
+        self.record(
+            "update_query_next_uri_path",
+            Box::pin(
+                self.inner
+                    .update_query_next_uri_path(query_id, next_uri_path),
+            ),
+        )
+        .await
+    }
+
+    async fn inc_cluster_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        max_allowed_count: u64,
+    ) -> Result<bool, Error> {
+        self.record(
+            "inc_cluster_query_count",
+            Box::pin(
+                self.inner
+                    .inc_cluster_query_count(cluster_name, max_allowed_count),
+            ),
+        )
+        .await
+    }
+
+    async fn dec_cluster_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<QueryCountDecrement, Error> {
+        self.record(
+            "dec_cluster_query_count",
+            Box::pin(self.inner.dec_cluster_query_count(cluster_name)),
+        )
+        .await
+    }
+
+    async fn set_cluster_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        count: u64,
+    ) -> Result<(), Error> {
+        self.record(
+            "set_cluster_query_count",
+            Box::pin(self.inner.set_cluster_query_count(cluster_name, count)),
+        )
+        .await
+    }
+
+    async fn get_cluster_query_count(&self, cluster_name: &TrinoClusterName) -> Result<u64, Error> {
+        self.record(
+            "get_cluster_query_count",
+            Box::pin(self.inner.get_cluster_query_count(cluster_name)),
+        )
+        .await
+    }
+
+    async fn set_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        count: u64,
+    ) -> Result<(), Error> {
+        self.record(
+            "set_cluster_blocked_query_count",
+            Box::pin(
+                self.inner
+                    .set_cluster_blocked_query_count(cluster_name, count),
+            ),
+        )
+        .await
+    }
+
+    async fn get_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<u64, Error> {
+        self.record(
+            "get_cluster_blocked_query_count",
+            Box::pin(self.inner.get_cluster_blocked_query_count(cluster_name)),
+        )
+        .await
+    }
+
+    async fn get_queued_query_count(&self, cluster_group: &str) -> Result<u64, Error> {
+        self.record(
+            "get_queued_query_count",
+            Box::pin(self.inner.get_queued_query_count(cluster_group)),
+        )
+        .await
+    }
+
+    async fn list_queued_query_ids(
+        &self,
+        cluster_group: &str,
+    ) -> Result<Vec<TrinoLbQueryId>, Error> {
+        self.record(
+            "list_queued_query_ids",
+            Box::pin(self.inner.list_queued_query_ids(cluster_group)),
+        )
+        .await
+    }
+
+    async fn delete_queued_queries_not_accessed_after(
+        &self,
+        not_accessed_after: SystemTime,
+    ) -> Result<u64, Error> {
+        self.record(
+            "delete_queued_queries_not_accessed_after",
+            Box::pin(
+                self.inner
+                    .delete_queued_queries_not_accessed_after(not_accessed_after),
+            ),
+        )
+        .await
+    }
+
+    async fn get_last_query_count_fetcher_update(&self) -> Result<SystemTime, Error> {
+        self.record(
+            "get_last_query_count_fetcher_update",
+            Box::pin(self.inner.get_last_query_count_fetcher_update()),
+        )
+        .await
+    }
+
+    async fn set_last_query_count_fetcher_update(&self, update: SystemTime) -> Result<(), Error> {
+        self.record(
+            "set_last_query_count_fetcher_update",
+            Box::pin(self.inner.set_last_query_count_fetcher_update(update)),
+        )
+        .await
+    }
+
+    async fn set_cluster_state(
+        &self,
+        cluster_name: &TrinoClusterName,
+        state: ClusterState,
+    ) -> Result<(), Error> {
+        self.record(
+            "set_cluster_state",
+            Box::pin(self.inner.set_cluster_state(cluster_name, state)),
+        )
+        .await
+    }
+
+    async fn get_cluster_state(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<ClusterState, Error> {
+        self.record(
+            "get_cluster_state",
+            Box::pin(self.inner.get_cluster_state(cluster_name)),
+        )
+        .await
+    }
+
+    async fn list_cluster_states(&self) -> Result<Vec<(TrinoClusterName, ClusterState)>, Error> {
+        self.record(
+            "list_cluster_states",
+            Box::pin(self.inner.list_cluster_states()),
+        )
+        .await
+    }
+
+    async fn remove_cluster(&self, cluster_name: &TrinoClusterName) -> Result<(), Error> {
+        self.record(
+            "remove_cluster",
+            Box::pin(self.inner.remove_cluster(cluster_name)),
+        )
+        .await
+    }
+
+    async fn set_router_disabled(&self, router_index: usize, disabled: bool) -> Result<(), Error> {
+        self.record(
+            "set_router_disabled",
+            Box::pin(self.inner.set_router_disabled(router_index, disabled)),
+        )
+        .await
+    }
+
+    async fn list_disabled_routers(&self) -> Result<Vec<usize>, Error> {
+        self.record(
+            "list_disabled_routers",
+            Box::pin(self.inner.list_disabled_routers()),
+        )
+        .await
+    }
+
+    async fn set_cluster_routing_excluded(
+        &self,
+        cluster_name: &TrinoClusterName,
+        excluded: bool,
+    ) -> Result<(), Error> {
+        self.record(
+            "set_cluster_routing_excluded",
+            Box::pin(
+                self.inner
+                    .set_cluster_routing_excluded(cluster_name, excluded),
+            ),
+        )
+        .await
+    }
+
+    async fn list_routing_excluded_clusters(&self) -> Result<Vec<TrinoClusterName>, Error> {
+        self.record(
+            "list_routing_excluded_clusters",
+            Box::pin(self.inner.list_routing_excluded_clusters()),
+        )
+        .await
+    }
+
+    async fn set_cluster_manually_managed(
+        &self,
+        cluster_name: &TrinoClusterName,
+        manually_managed: bool,
+    ) -> Result<(), Error> {
+        self.record(
+            "set_cluster_manually_managed",
+            Box::pin(
+                self.inner
+                    .set_cluster_manually_managed(cluster_name, manually_managed),
+            ),
+        )
+        .await
+    }
+
+    async fn list_manually_managed_clusters(&self) -> Result<Vec<TrinoClusterName>, Error> {
+        self.record(
+            "list_manually_managed_clusters",
+            Box::pin(self.inner.list_manually_managed_clusters()),
+        )
+        .await
+    }
+
+    async fn store_idempotent_response(
+        &self,
+        idempotency_key: &str,
+        response: &TrinoQueryApiResponse,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.record(
+            "store_idempotent_response",
+            Box::pin(
+                self.inner
+                    .store_idempotent_response(idempotency_key, response, ttl),
+            ),
+        )
+        .await
+    }
+
+    async fn load_idempotent_response(
+        &self,
+        idempotency_key: &str,
+    ) -> Result<Option<TrinoQueryApiResponse>, Error> {
+        self.record(
+            "load_idempotent_response",
+            Box::pin(self.inner.load_idempotent_response(idempotency_key)),
+        )
+        .await
+    }
+
+    async fn store_query_runtime(
+        &self,
+        query_fingerprint: &str,
+        runtime: Duration,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.record(
+            "store_query_runtime",
+            Box::pin(
+                self.inner
+                    .store_query_runtime(query_fingerprint, runtime, ttl),
+            ),
+        )
+        .await
+    }
+
+    async fn load_query_runtime(&self, query_fingerprint: &str) -> Result<Option<Duration>, Error> {
+        self.record(
+            "load_query_runtime",
+            Box::pin(self.inner.load_query_runtime(query_fingerprint)),
+        )
+        .await
+    }
+
+    async fn record_client_requests(
+        &self,
+        bucket: u64,
+        user: &str,
+        counts: ClientRequestCounts,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.record(
+            "record_client_requests",
+            Box::pin(
+                self.inner
+                    .record_client_requests(bucket, user, counts, max_users, ttl),
+            ),
+        )
+        .await
+    }
+
+    async fn load_client_request_stats(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<String, ClientRequestCounts>, Error> {
+        self.record(
+            "load_client_request_stats",
+            Box::pin(self.inner.load_client_request_stats(buckets)),
+        )
+        .await
+    }
+
+    async fn record_handed_over_query(
+        &self,
+        bucket: u64,
+        user: &str,
+        cluster_group: &str,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.record(
+            "record_handed_over_query",
+            Box::pin(self.inner.record_handed_over_query(
+                bucket,
+                user,
+                cluster_group,
+                max_users,
+                ttl,
+            )),
+        )
+        .await
+    }
+
+    async fn delete_handed_over_queries_before(&self, bucket: u64) -> Result<(), Error> {
+        self.record(
+            "delete_handed_over_queries_before",
+            Box::pin(self.inner.delete_handed_over_queries_before(bucket)),
+        )
+        .await
+    }
+
+    async fn load_handed_over_query_counts(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<(String, String), u64>, Error> {
+        self.record(
+            "load_handed_over_query_counts",
+            Box::pin(self.inner.load_handed_over_query_counts(buckets)),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::in_memory::InMemoryPersistence;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_delegates_to_inner_persistence() {
+        let persistence: PersistenceImplementation =
+            InstrumentedPersistence::new(InMemoryPersistence::default().into()).into();
+        assert_eq!(persistence.backend(), "in_memory");
+        assert!(persistence.as_in_memory().is_some());
+
+        let cluster = "trino-s-1".to_owned();
+        assert!(persistence
+            .inc_cluster_query_count(&cluster, 1)
+            .await
+            .unwrap());
+        assert!(!persistence
+            .inc_cluster_query_count(&cluster, 1)
+            .await
+            .unwrap());
+        assert_eq!(
+            persistence
+                .as_in_memory()
+                .unwrap()
+                .get_cluster_query_count(&cluster)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            persistence.dec_cluster_query_count(&cluster).await.unwrap(),
+            QueryCountDecrement::Decremented(0)
+        );
+    }
+}
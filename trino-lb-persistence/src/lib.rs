@@ -1,14 +1,25 @@
-use std::{fmt::Debug, time::SystemTime};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    ops::RangeInclusive,
+    time::{Duration, SystemTime},
+};
 
 use enum_dispatch::enum_dispatch;
 use snafu::Snafu;
+use sqlx::types::chrono::{DateTime, Utc};
+#[cfg(doc)]
+use trino_lb_core::client_request_stats::OTHER_USERS;
 use trino_lb_core::{
+    client_request_stats::ClientRequestCounts,
+    trino_api::TrinoQueryApiResponse,
     trino_cluster::ClusterState,
     trino_query::{QueuedQuery, TrinoQuery},
     TrinoClusterName, TrinoLbQueryId, TrinoQueryId,
 };
 
 pub mod in_memory;
+pub mod instrumented;
 pub mod postgres;
 pub mod redis;
 
@@ -24,6 +35,55 @@ pub enum Error {
     PostgresError { source: postgres::Error },
 }
 
+impl Error {
+    /// Whether the persistence refused to store a queued query, as it reached the configured maximum number of queued
+    /// queries.
+    pub fn is_queue_full(&self) -> bool {
+        matches!(
+            self,
+            Error::InMemoryError {
+                source: in_memory::Error::TooManyQueuedQueries { .. }
+            }
+        )
+    }
+
+    /// Whether the persistence refused to store a query, as a query with the same id is already stored for a
+    /// different Trino cluster.
+    pub fn is_query_id_collision(&self) -> bool {
+        matches!(
+            self,
+            Error::InMemoryError {
+                source: in_memory::Error::QueryIdCollision { .. }
+            } | Error::RedisError {
+                source: redis::Error::QueryIdCollision { .. }
+            } | Error::PostgresError {
+                source: postgres::Error::QueryIdCollision { .. }
+            }
+        )
+    }
+}
+
+/// The outcome of [`Persistence::dec_cluster_query_count`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryCountDecrement {
+    /// The query count was decremented, contains the query count after the decrement.
+    Decremented(u64),
+
+    /// The query count was already zero (or not known at all), so it was left untouched. This should never happen
+    /// and points to a bug, such as a query being decremented twice or an increment getting lost.
+    AlreadyZero,
+}
+
+impl QueryCountDecrement {
+    /// The query count after the decrement.
+    pub fn remaining(&self) -> u64 {
+        match self {
+            QueryCountDecrement::Decremented(remaining) => *remaining,
+            QueryCountDecrement::AlreadyZero => 0,
+        }
+    }
+}
+
 /// Please note that the following functions *must* be atomic! trino-lb is build on the concept that you can deploy (and scale)
 /// multiple replicas of trino-lb and every instance can answer requests for every query correctly. This is especially important
 /// for increment and decrement operations to not end up with a wrong query count after multiple trino-lb instances modifying the
@@ -33,14 +93,54 @@ pub enum Error {
 #[trait_variant::make(SendPersistence: Send)]
 pub trait Persistence {
     async fn store_queued_query(&self, query: QueuedQuery) -> Result<(), Error>;
-    async fn load_queued_query(&self, query_id: &TrinoLbQueryId) -> Result<QueuedQuery, Error>;
+    /// Returns [`None`] in case no queued query with the given id is stored.
+    async fn load_queued_query(
+        &self,
+        query_id: &TrinoLbQueryId,
+    ) -> Result<Option<QueuedQuery>, Error>;
     async fn remove_queued_query(&self, query: &QueuedQuery) -> Result<(), Error>;
+    /// Moves the queued query into the given cluster group, e.g. to promote it out of a parking cluster group. The id
+    /// of the queued query stays the same, so that clients can continue polling it. Queued queries that are not
+    /// stored (any more) are ignored. Implementations retrying in case the queued query is changed concurrently must
+    /// give up with an error after a bounded number of attempts.
+    async fn move_queued_query(
+        &self,
+        query: &QueuedQuery,
+        cluster_group: &str,
+    ) -> Result<(), Error>;
+    /// Sets the [`QueuedQuery::last_accessed`] of the stored queued query. Nothing is changed in case the queued query
+    /// is not stored (any more) or was moved to a different cluster group than the one of the given queued query in
+    /// the meantime, so that a client polling an outdated copy never undoes a move.
+    async fn update_queued_query_last_accessed(
+        &self,
+        query: &QueuedQuery,
+        last_accessed: SystemTime,
+    ) -> Result<(), Error>;
 
+    /// Stores the query, replacing a query stored with the same id for the same Trino cluster. Query ids are expected
+    /// to be unique, but two Trino clusters could theoretically generate the same one. In this case the stored query is
+    /// kept and an error is returned (see [`Error::is_query_id_collision`]).
     async fn store_query(&self, query: TrinoQuery) -> Result<(), Error>;
-    async fn load_query(&self, query_id: &TrinoQueryId) -> Result<TrinoQuery, Error>;
+    /// Returns [`None`] in case no query with the given id is stored.
+    async fn load_query(&self, query_id: &TrinoQueryId) -> Result<Option<TrinoQuery>, Error>;
     async fn remove_query(&self, query_id: &TrinoQueryId) -> Result<(), Error>;
+    /// Returns the ids of all queries running on Trino clusters. Only the ids are returned, so that callers can load the
+    /// queries in batches.
+    async fn list_query_ids(&self) -> Result<Vec<TrinoQueryId>, Error>;
 
-    /// `max_allowed_count` is the (inclusive) maximum count that is allowed *after* the increment.
+    /// Sets the [`TrinoQuery::next_uri_path`] of the stored query, moving the current one to
+    /// [`TrinoQuery::previous_next_uri_path`]. As only a single client polls a query and does so sequentially, this
+    /// does not need to guard against concurrent updates of the same query. Queries that are not stored (any more)
+    /// are ignored.
+    async fn update_query_next_uri_path(
+        &self,
+        query_id: &TrinoQueryId,
+        next_uri_path: String,
+    ) -> Result<(), Error>;
+
+    /// `max_allowed_count` is the (inclusive) maximum count that is allowed *after* the increment, so a cluster with
+    /// a `max_allowed_count` of `n` can have up to `n` queries. Implementations must use
+    /// [`query_count_allows_increment`] to make this decision.
     /// The returned boolean represents wether the increment has happened or was denied because
     /// the count would get to high.
     async fn inc_cluster_query_count(
@@ -50,7 +150,12 @@ pub trait Persistence {
     ) -> Result<bool, Error>;
 
     /// It is in the responsibility of the implementation to make sure the resulting counter is not less than zero.
-    async fn dec_cluster_query_count(&self, cluster_name: &TrinoClusterName) -> Result<(), Error>;
+    /// Decrements the query count of the given cluster, but never below zero. Returns the query count *after* the
+    /// decrement, so that callers can e.g. react on a cluster running out of queries.
+    async fn dec_cluster_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<QueryCountDecrement, Error>;
 
     /// This function does not need to check for any transactional guarantees. Just set the passed value as fast as
     /// possible.
@@ -61,9 +166,29 @@ pub trait Persistence {
     ) -> Result<(), Error>;
     async fn get_cluster_query_count(&self, cluster_name: &TrinoClusterName) -> Result<u64, Error>;
 
+    /// Sets the number of queries that are blocked on the given cluster (e.g. waiting for memory). They are already
+    /// part of the cluster query count, but are stored separately so that the scaler can weight them differently.
+    /// Just like [`Persistence::set_cluster_query_count`] this does not need any transactional guarantees.
+    async fn set_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        count: u64,
+    ) -> Result<(), Error>;
+    async fn get_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<u64, Error>;
+
     /// Returns the number of queued queries in trino-lb for every cluster group.
     async fn get_queued_query_count(&self, cluster_group: &str) -> Result<u64, Error>;
 
+    /// Returns the ids of all queries queued in trino-lb for the given cluster group. Only the ids are returned, so
+    /// that callers can load the (potentially big) queued queries in batches.
+    async fn list_queued_query_ids(
+        &self,
+        cluster_group: &str,
+    ) -> Result<Vec<TrinoLbQueryId>, Error>;
+
     /// Deletes all queued queries that have not been accessed after the given timestamp using
     /// [`QueuedQuery::last_accessed`]. Returns the number of removed queued queries.
     async fn delete_queued_queries_not_accessed_after(
@@ -84,6 +209,128 @@ pub trait Persistence {
         &self,
         cluster_name: &TrinoClusterName,
     ) -> Result<ClusterState, Error>;
+
+    /// Returns every cluster the persistence has a state stored for, regardless of whether the cluster is still
+    /// configured. The clusters are sorted by name.
+    async fn list_cluster_states(&self) -> Result<Vec<(TrinoClusterName, ClusterState)>, Error>;
+
+    /// Removes the stored state and query count of the given cluster, e.g. because it is not configured any more.
+    async fn remove_cluster(&self, cluster_name: &TrinoClusterName) -> Result<(), Error>;
+
+    /// Disables (or enables again) the router at the given index of the configured `routers`. Disabled routers are
+    /// skipped by all trino-lb replicas.
+    async fn set_router_disabled(&self, router_index: usize, disabled: bool) -> Result<(), Error>;
+
+    /// Returns the indices of all disabled routers in ascending order.
+    async fn list_disabled_routers(&self) -> Result<Vec<usize>, Error>;
+
+    /// Excludes (or includes again) the given cluster from getting new queries, e.g. because it misbehaves although it
+    /// is ready. Excluded clusters are skipped by all trino-lb replicas, independent of their cluster state.
+    async fn set_cluster_routing_excluded(
+        &self,
+        cluster_name: &TrinoClusterName,
+        excluded: bool,
+    ) -> Result<(), Error>;
+
+    /// Returns the names of all clusters excluded from routing in ascending order.
+    async fn list_routing_excluded_clusters(&self) -> Result<Vec<TrinoClusterName>, Error>;
+
+    /// Marks the given cluster as manually managed (or hands it back to the scaler), e.g. because an operator stopped
+    /// it for debugging. The scaler does not touch manually managed clusters at all.
+    async fn set_cluster_manually_managed(
+        &self,
+        cluster_name: &TrinoClusterName,
+        manually_managed: bool,
+    ) -> Result<(), Error>;
+
+    /// Returns the names of all manually managed clusters in ascending order.
+    async fn list_manually_managed_clusters(&self) -> Result<Vec<TrinoClusterName>, Error>;
+
+    /// Remembers the response the client got for the request with the given idempotency key, so that retries of the
+    /// same request can get the same response. The entry must expire after the given `ttl`.
+    async fn store_idempotent_response(
+        &self,
+        idempotency_key: &str,
+        response: &TrinoQueryApiResponse,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    /// Returns [`None`] in case no response is stored for the given idempotency key or the entry already expired.
+    async fn load_idempotent_response(
+        &self,
+        idempotency_key: &str,
+    ) -> Result<Option<TrinoQueryApiResponse>, Error>;
+
+    /// Stores the runtime observed for queries with the given fingerprint, overwriting the previously stored runtime.
+    /// The entry must expire after the given `ttl`.
+    async fn store_query_runtime(
+        &self,
+        query_fingerprint: &str,
+        runtime: Duration,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    /// Returns [`None`] in case no runtime is stored for the given query fingerprint or the entry already expired.
+    async fn load_query_runtime(&self, query_fingerprint: &str) -> Result<Option<Duration>, Error>;
+
+    /// Adds the given request counts of the given (already sanitized) user to the given bucket. In case the bucket
+    /// already tracks `max_users` users and the user is not one of them, the requests are accounted to
+    /// [`OTHER_USERS`] instead, so that the storage needed is bounded. Buckets must expire after the given `ttl`.
+    async fn record_client_requests(
+        &self,
+        bucket: u64,
+        user: &str,
+        counts: ClientRequestCounts,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    /// Returns the request counts per user, summed up over the given buckets.
+    async fn load_client_request_stats(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<String, ClientRequestCounts>, Error>;
+
+    /// Accounts a query of the given (already sanitized) user handed over to the given cluster group to the given
+    /// bucket. In case the bucket already tracks `max_users` users and the user is not one of them, the query is
+    /// accounted to [`OTHER_USERS`] instead, so that the storage needed is bounded. Persistences that can expire
+    /// entries on their own let buckets expire after the given `ttl`, all others rely on
+    /// [`Persistence::delete_handed_over_queries_before`] being called periodically.
+    async fn record_handed_over_query(
+        &self,
+        bucket: u64,
+        user: &str,
+        cluster_group: &str,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    /// Deletes the handed over query counts of all buckets before the given one.
+    async fn delete_handed_over_queries_before(&self, bucket: u64) -> Result<(), Error>;
+
+    /// Returns the number of handed over queries per user and cluster group, summed up over the given buckets.
+    async fn load_handed_over_query_counts(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<(String, String), u64>, Error>;
+}
+
+/// Determines if a cluster with the `current` query count can get one more query without exceeding the
+/// `max_allowed_count`. The `max_allowed_count` is inclusive, so a cluster can run exactly `max_allowed_count` queries.
+///
+/// This is used by the persistence implementations in [`Persistence::inc_cluster_query_count`] as well as when
+/// selecting a cluster for a query, so that both agree on the semantics.
+pub fn query_count_allows_increment(current: u64, max_allowed_count: u64) -> bool {
+    current < max_allowed_count
+}
+
+/// Converts the milliseconds since the epoch, as stored by the Redis and in-memory persistence, back into a timestamp.
+///
+/// Only timestamps the Postgres persistence can store as well are accepted, so that all persistence implementations
+/// agree on the valid range. Returns [`None`] for bigger values instead of overflowing.
+pub(crate) fn timestamp_from_millis(millis: u64) -> Option<SystemTime> {
+    let millis = i64::try_from(millis).ok()?;
+    DateTime::<Utc>::from_timestamp_millis(millis).map(SystemTime::from)
 }
 
 #[enum_dispatch]
@@ -96,4 +343,69 @@ pub enum PersistenceImplementation {
     ),
     Postgres(postgres::PostgresPersistence),
     InMemory(in_memory::InMemoryPersistence),
+    Instrumented(instrumented::InstrumentedPersistence),
+}
+
+impl PersistenceImplementation {
+    /// Name of the backend, e.g. used as label of metrics.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            PersistenceImplementation::Redis(_) => "redis",
+            PersistenceImplementation::RedisCluster(_) => "redis_cluster",
+            PersistenceImplementation::Postgres(_) => "postgres",
+            PersistenceImplementation::InMemory(_) => "in_memory",
+            PersistenceImplementation::Instrumented(instrumented) => instrumented.inner().backend(),
+        }
+    }
+
+    /// Returns the in-memory persistence in case it is used, looking through the [`Instrumented`] wrapper.
+    ///
+    /// [`Instrumented`]: PersistenceImplementation::Instrumented
+    pub fn as_in_memory(&self) -> Option<&in_memory::InMemoryPersistence> {
+        match self {
+            PersistenceImplementation::InMemory(in_memory) => Some(in_memory),
+            PersistenceImplementation::Instrumented(instrumented) => {
+                instrumented.inner().as_in_memory()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the persistence is still experimental, currently only the Postgres persistence is.
+    pub fn is_experimental(&self) -> bool {
+        match self {
+            PersistenceImplementation::Postgres(_) => true,
+            PersistenceImplementation::Instrumented(instrumented) => {
+                instrumented.inner().is_experimental()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(0, 0, false)]
+    #[case(0, 1, true)]
+    #[case(1, 1, false)]
+    #[case(9, 10, true)]
+    #[case(10, 10, false)]
+    #[case(11, 10, false)]
+    #[case(u64::MAX, u64::MAX, false)]
+    #[case(u64::MAX - 1, u64::MAX, true)]
+    fn test_query_count_allows_increment(
+        #[case] current: u64,
+        #[case] max_allowed_count: u64,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(
+            query_count_allows_increment(current, max_allowed_count),
+            expected
+        );
+    }
 }
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS idempotent_responses
+(
+    idempotency_key  VARCHAR PRIMARY KEY NOT NULL,
+    response         JSONB NOT NULL,
+    expires_at       TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,8 @@
+ALTER TABLE queries ADD COLUMN IF NOT EXISTS query_fingerprint VARCHAR;
+
+CREATE TABLE IF NOT EXISTS query_runtimes
+(
+    query_fingerprint  VARCHAR PRIMARY KEY NOT NULL,
+    runtime_millis     BIGINT NOT NULL,
+    expires_at         TIMESTAMP WITH TIME ZONE NOT NULL
+);
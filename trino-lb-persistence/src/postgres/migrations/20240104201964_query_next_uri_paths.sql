@@ -0,0 +1,2 @@
+ALTER TABLE queries ADD COLUMN IF NOT EXISTS next_uri_path VARCHAR;
+ALTER TABLE queries ADD COLUMN IF NOT EXISTS previous_next_uri_path VARCHAR;
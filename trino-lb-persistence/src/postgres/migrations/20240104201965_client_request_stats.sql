@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS client_request_stats
+(
+    bucket        BIGINT NOT NULL,
+    user_name     VARCHAR NOT NULL,
+    requests      BIGINT NOT NULL,
+    header_bytes  BIGINT NOT NULL,
+    PRIMARY KEY (bucket, user_name)
+);
@@ -0,0 +1 @@
+ALTER TABLE queued_queries ADD COLUMN IF NOT EXISTS routing_reason VARCHAR;
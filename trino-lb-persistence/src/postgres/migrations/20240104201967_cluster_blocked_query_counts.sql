@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS cluster_blocked_query_counts
+(
+    cluster  VARCHAR PRIMARY KEY NOT NULL,
+    count    BIGINT NOT NULL
+);
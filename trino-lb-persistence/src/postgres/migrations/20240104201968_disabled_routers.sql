@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS disabled_routers
+(
+    router_index BIGINT PRIMARY KEY NOT NULL
+);
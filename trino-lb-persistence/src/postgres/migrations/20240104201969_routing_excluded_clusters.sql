@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS routing_excluded_clusters
+(
+    cluster VARCHAR PRIMARY KEY NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS handed_over_queries
+(
+    bucket         BIGINT NOT NULL,
+    user_name      VARCHAR NOT NULL,
+    cluster_group  VARCHAR NOT NULL,
+    queries        BIGINT NOT NULL,
+    PRIMARY KEY (bucket, user_name, cluster_group)
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS manually_managed_clusters
+(
+    cluster VARCHAR PRIMARY KEY NOT NULL
+);
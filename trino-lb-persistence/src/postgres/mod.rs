@@ -1,11 +1,13 @@
 use std::{
+    collections::HashMap,
     num::TryFromIntError,
-    time::{SystemTime, UNIX_EPOCH},
+    ops::RangeInclusive,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use http::HeaderMap;
 use serde::{Deserialize, Serialize};
-use snafu::{ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use sqlx::{
     migrate::MigrateError,
     postgres::PgPoolOptions,
@@ -15,14 +17,16 @@ use sqlx::{
 };
 use tracing::{debug, info, instrument, warn};
 use trino_lb_core::{
+    client_request_stats::{bucket_count, ClientRequestCounts, OTHER_USERS},
     config::PostgresConfig,
+    trino_api::TrinoQueryApiResponse,
     trino_cluster::ClusterState,
     trino_query::{QueuedQuery, TrinoQuery},
     TrinoClusterName, TrinoLbQueryId, TrinoQueryId,
 };
 use url::Url;
 
-use crate::Persistence;
+use crate::{query_count_allows_increment, Persistence, QueryCountDecrement};
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -53,12 +57,29 @@ pub enum Error {
     #[snafu(display("Failed to store query"))]
     StoreQuery { source: sqlx::Error },
 
+    #[snafu(display(
+        "Refusing to store query {query_id:?} of Trino cluster {trino_cluster:?}, as a query with the same id is already stored for a different Trino cluster"
+    ))]
+    QueryIdCollision {
+        query_id: TrinoQueryId,
+        trino_cluster: TrinoClusterName,
+    },
+
     #[snafu(display("Failed to load query"))]
     LoadQuery { source: sqlx::Error },
 
     #[snafu(display("Failed to delete query"))]
     DeleteQuery { source: sqlx::Error },
 
+    #[snafu(display("Failed to move the queued query to another cluster group"))]
+    MoveQueuedQuery { source: sqlx::Error },
+
+    #[snafu(display("Failed to update the last access of the queued query"))]
+    UpdateQueuedQueryLastAccessed { source: sqlx::Error },
+
+    #[snafu(display("Failed to update the nextUri path of the query"))]
+    UpdateQueryNextUriPath { source: sqlx::Error },
+
     #[snafu(display("Failed to get current queued query counter"))]
     GetCurrentQueuedQueryCounter { source: sqlx::Error },
 
@@ -74,6 +95,59 @@ pub enum Error {
     #[snafu(display("Failed to get current cluster state"))]
     GetCurrentClusterState { source: sqlx::Error },
 
+    #[snafu(display("Failed to list cluster states"))]
+    ListClusterStates { source: sqlx::Error },
+
+    #[snafu(display("Failed to remove cluster {cluster_name:?}"))]
+    RemoveCluster {
+        source: sqlx::Error,
+        cluster_name: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to set router {router_index} to disabled={disabled}"))]
+    SetRouterDisabled {
+        source: sqlx::Error,
+        router_index: usize,
+        disabled: bool,
+    },
+
+    #[snafu(display("Failed to list disabled routers"))]
+    ListDisabledRouters { source: sqlx::Error },
+
+    #[snafu(display("Failed to convert router index {router_index} to a stored i64"))]
+    ConvertRouterIndexToI64 {
+        source: TryFromIntError,
+        router_index: usize,
+    },
+
+    #[snafu(display("Failed to convert stored router index {router_index} to an usize"))]
+    ConvertStoredRouterIndexToUsize {
+        source: TryFromIntError,
+        router_index: i64,
+    },
+
+    #[snafu(display("Failed to set cluster {cluster_name:?} to excluded={excluded}"))]
+    SetClusterRoutingExcluded {
+        source: sqlx::Error,
+        cluster_name: TrinoClusterName,
+        excluded: bool,
+    },
+
+    #[snafu(display("Failed to list clusters excluded from routing"))]
+    ListRoutingExcludedClusters { source: sqlx::Error },
+
+    #[snafu(display(
+        "Failed to set cluster {cluster_name:?} to manually_managed={manually_managed}"
+    ))]
+    SetClusterManuallyManaged {
+        source: sqlx::Error,
+        cluster_name: TrinoClusterName,
+        manually_managed: bool,
+    },
+
+    #[snafu(display("Failed to list manually managed clusters"))]
+    ListManuallyManagedClusters { source: sqlx::Error },
+
     #[snafu(display("Failed to set current cluster state"))]
     SetCurrentClusterState { source: sqlx::Error },
 
@@ -83,18 +157,68 @@ pub enum Error {
     #[snafu(display("Failed to set last query count fetcher update"))]
     SetLastQueryCountFetcherUpdate { source: sqlx::Error },
 
+    #[snafu(display(
+        "The last queryCountFetcher update {update:?} can not be represented as Postgres timestamp"
+    ))]
+    LastQueryCountFetcherUpdateOutOfRange { update: SystemTime },
+
     #[snafu(display("Failed to parse headers of stored queued query"))]
     ParseHeadersOfStoredQueuedQuery { source: serde_json::Error },
 
+    #[snafu(display("Failed to store idempotent response"))]
+    StoreIdempotentResponse { source: sqlx::Error },
+
+    #[snafu(display("Failed to load idempotent response"))]
+    LoadIdempotentResponse { source: sqlx::Error },
+
+    #[snafu(display("Failed to parse stored idempotent response"))]
+    ParseStoredIdempotentResponse { source: serde_json::Error },
+
+    #[snafu(display("Failed to list queued query ids"))]
+    ListQueuedQueryIds { source: sqlx::Error },
+
+    #[snafu(display("Failed to list query ids"))]
+    ListQueryIds { source: sqlx::Error },
+
+    #[snafu(display("Failed to store query runtime"))]
+    StoreQueryRuntime { source: sqlx::Error },
+
+    #[snafu(display("Failed to load query runtime"))]
+    LoadQueryRuntime { source: sqlx::Error },
+
+    #[snafu(display("Failed to record client request"))]
+    RecordClientRequest { source: sqlx::Error },
+
+    #[snafu(display("Failed to load client request stats"))]
+    LoadClientRequestStats { source: sqlx::Error },
+
+    #[snafu(display("Failed to record handed over query"))]
+    RecordHandedOverQuery { source: sqlx::Error },
+
+    #[snafu(display("Failed to delete expired handed over query counts"))]
+    DeleteHandedOverQueries { source: sqlx::Error },
+
+    #[snafu(display("Failed to load handed over query counts"))]
+    LoadHandedOverQueryCounts { source: sqlx::Error },
+
+    #[snafu(display("Failed to convert query runtime {runtime:?} to millis stored in an i64"))]
+    ConvertQueryRuntimeToMillis {
+        source: TryFromIntError,
+        runtime: Duration,
+    },
+
+    #[snafu(display("Failed to convert stored query runtime of {millis} ms to an u64"))]
+    ConvertStoredQueryRuntimeToU64 {
+        source: TryFromIntError,
+        millis: i64,
+    },
+
     #[snafu(display("Failed to parse state of stored cluster state"))]
     ParseStateOfStoredClusterState { source: serde_json::Error },
 
     #[snafu(display("Failed to parse endpoint url of cluster from stored query"))]
     ParseClusterEndpointFromStoredQuery { source: url::ParseError },
 
-    #[snafu(display("Failed to convert max query counter to u64, as it is too high"))]
-    ConvertMaxAllowedQueryCounterToU64 { source: TryFromIntError },
-
     #[snafu(display("Failed to convert current query counter to u64, as it is too high"))]
     ConvertCurrentQueryCounterToU64 { source: TryFromIntError },
 
@@ -107,6 +231,8 @@ pub enum Error {
 
 pub struct PostgresPersistence {
     pool: Pool<Postgres>,
+    /// Optional separate pool used for the latency-sensitive reads done while routing queries.
+    routing_pool: Option<Pool<Postgres>>,
 }
 
 impl PostgresPersistence {
@@ -124,7 +250,30 @@ impl PostgresPersistence {
             .await
             .context(RunMigrationsSnafu)?;
 
-        Ok(Self { pool })
+        let routing_pool = match config.routing_pool_max_connections {
+            Some(max_connections) => {
+                info!(
+                    max_connections,
+                    "Using a dedicated Postgres pool for routing"
+                );
+                Some(
+                    PgPoolOptions::new()
+                        .max_connections(max_connections)
+                        .connect(config.url.as_str())
+                        .await
+                        .context(CreatePoolSnafu)?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self { pool, routing_pool })
+    }
+
+    /// Pool for the reads done while routing queries, which falls back to the shared pool in case no dedicated one is
+    /// configured.
+    fn routing_pool(&self) -> &Pool<Postgres> {
+        self.routing_pool.as_ref().unwrap_or(&self.pool)
     }
 }
 
@@ -138,8 +287,8 @@ impl Persistence for PostgresPersistence {
     #[instrument(skip(self))]
     async fn store_queued_query(&self, queued_query: QueuedQuery) -> Result<(), super::Error> {
         query!(
-            r#"INSERT INTO queued_queries (id, query, headers, creation_time, last_accessed, cluster_group)
-            VALUES ($1, $2, $3, $4, $5, $6)"#,
+            r#"INSERT INTO queued_queries (id, query, headers, creation_time, last_accessed, cluster_group, routing_reason)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
             queued_query.id,
             queued_query.query,
             sqlx::types::Json(HeaderMapWrapper {
@@ -148,6 +297,7 @@ impl Persistence for PostgresPersistence {
             Into::<DateTime<Utc>>::into(queued_query.creation_time),
             Into::<DateTime<Utc>>::into(queued_query.last_accessed),
             queued_query.cluster_group,
+            queued_query.routing_reason,
         )
         .execute(&self.pool)
         .await
@@ -160,16 +310,19 @@ impl Persistence for PostgresPersistence {
     async fn load_queued_query(
         &self,
         queued_query_id: &TrinoLbQueryId,
-    ) -> Result<QueuedQuery, super::Error> {
+    ) -> Result<Option<QueuedQuery>, super::Error> {
         let result = query!(
-            r#"SELECT id, query, headers, creation_time, last_accessed, cluster_group
+            r#"SELECT id, query, headers, creation_time, last_accessed, cluster_group, routing_reason
             FROM queued_queries
             WHERE id = $1"#,
             queued_query_id,
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await
         .context(LoadQueuedQuerySnafu)?;
+        let Some(result) = result else {
+            return Ok(None);
+        };
 
         let headers: HeaderMapWrapper =
             serde_json::from_value(result.headers).context(ParseHeadersOfStoredQueuedQuerySnafu)?;
@@ -180,9 +333,10 @@ impl Persistence for PostgresPersistence {
             creation_time: result.creation_time.into(),
             last_accessed: result.last_accessed.into(),
             cluster_group: result.cluster_group,
+            routing_reason: result.routing_reason,
         };
 
-        Ok(queued_query)
+        Ok(Some(queued_query))
     }
 
     #[instrument(skip(self))]
@@ -200,34 +354,100 @@ impl Persistence for PostgresPersistence {
     }
 
     #[instrument(skip(self))]
-    async fn store_query(&self, query: TrinoQuery) -> Result<(), super::Error> {
+    async fn move_queued_query(
+        &self,
+        queued_query: &QueuedQuery,
+        cluster_group: &str,
+    ) -> Result<(), super::Error> {
+        query!(
+            r#"UPDATE queued_queries
+            SET cluster_group = $2
+            WHERE id = $1"#,
+            queued_query.id,
+            cluster_group,
+        )
+        .execute(&self.pool)
+        .await
+        .context(MoveQueuedQuerySnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn update_queued_query_last_accessed(
+        &self,
+        queued_query: &QueuedQuery,
+        last_accessed: SystemTime,
+    ) -> Result<(), super::Error> {
         query!(
-            r#"INSERT INTO queries (id, trino_cluster, trino_endpoint, creation_time, delivered_time)
-            VALUES ($1, $2, $3, $4, $5)"#,
+            r#"UPDATE queued_queries
+            SET last_accessed = $2
+            WHERE id = $1 AND cluster_group = $3"#,
+            queued_query.id,
+            Into::<DateTime<Utc>>::into(last_accessed),
+            queued_query.cluster_group,
+        )
+        .execute(&self.pool)
+        .await
+        .context(UpdateQueuedQueryLastAccessedSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn store_query(&self, query: TrinoQuery) -> Result<(), super::Error> {
+        // In case a query with the same id is already stored for a different cluster, no row is affected
+        let result = query!(
+            r#"INSERT INTO queries (id, trino_cluster, trino_endpoint, creation_time, delivered_time, query_fingerprint, next_uri_path, previous_next_uri_path)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (id) DO UPDATE SET
+                trino_endpoint = EXCLUDED.trino_endpoint,
+                creation_time = EXCLUDED.creation_time,
+                delivered_time = EXCLUDED.delivered_time,
+                query_fingerprint = EXCLUDED.query_fingerprint,
+                next_uri_path = EXCLUDED.next_uri_path,
+                previous_next_uri_path = EXCLUDED.previous_next_uri_path
+            WHERE queries.trino_cluster = EXCLUDED.trino_cluster"#,
             query.id,
             query.trino_cluster,
             query.trino_endpoint.as_str(),
             Into::<DateTime<Utc>>::into(query.creation_time),
             Into::<DateTime<Utc>>::into(query.delivered_time),
+            query.query_fingerprint,
+            query.next_uri_path,
+            query.previous_next_uri_path,
         )
         .execute(&self.pool)
         .await
         .context(StoreQuerySnafu)?;
+        ensure!(
+            result.rows_affected() > 0,
+            QueryIdCollisionSnafu {
+                query_id: query.id,
+                trino_cluster: query.trino_cluster,
+            }
+        );
 
         Ok(())
     }
 
     #[instrument(skip(self))]
-    async fn load_query(&self, query_id: &TrinoQueryId) -> Result<TrinoQuery, super::Error> {
+    async fn load_query(
+        &self,
+        query_id: &TrinoQueryId,
+    ) -> Result<Option<TrinoQuery>, super::Error> {
         let result = query!(
-            r#"SELECT id, trino_cluster, trino_endpoint, creation_time, delivered_time
+            r#"SELECT id, trino_cluster, trino_endpoint, creation_time, delivered_time, query_fingerprint, next_uri_path, previous_next_uri_path
             FROM queries
             WHERE id = $1"#,
             query_id,
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await
         .context(LoadQuerySnafu)?;
+        let Some(result) = result else {
+            return Ok(None);
+        };
 
         let query = TrinoQuery {
             id: result.id,
@@ -236,9 +456,32 @@ impl Persistence for PostgresPersistence {
                 .context(ParseClusterEndpointFromStoredQuerySnafu)?,
             creation_time: result.creation_time.into(),
             delivered_time: result.delivered_time.into(),
+            query_fingerprint: result.query_fingerprint,
+            next_uri_path: result.next_uri_path,
+            previous_next_uri_path: result.previous_next_uri_path,
         };
 
-        Ok(query)
+        Ok(Some(query))
+    }
+
+    #[instrument(skip(self))]
+    async fn update_query_next_uri_path(
+        &self,
+        query_id: &TrinoQueryId,
+        next_uri_path: String,
+    ) -> Result<(), super::Error> {
+        query!(
+            r#"UPDATE queries
+            SET previous_next_uri_path = next_uri_path, next_uri_path = $2
+            WHERE id = $1"#,
+            query_id,
+            next_uri_path,
+        )
+        .execute(&self.pool)
+        .await
+        .context(UpdateQueryNextUriPathSnafu)?;
+
+        Ok(())
     }
 
     #[instrument(skip(self))]
@@ -255,6 +498,20 @@ impl Persistence for PostgresPersistence {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn list_query_ids(&self) -> Result<Vec<TrinoQueryId>, super::Error> {
+        Ok(query!(
+            r#"SELECT id
+            FROM queries"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(ListQueryIdsSnafu)?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+    }
+
     #[instrument(skip(self))]
     async fn inc_cluster_query_count(
         &self,
@@ -263,9 +520,6 @@ impl Persistence for PostgresPersistence {
     ) -> Result<bool, super::Error> {
         let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
 
-        let max_allowed_count: i64 = max_allowed_count
-            .try_into()
-            .context(ConvertMaxAllowedQueryCounterToU64Snafu)?;
         let current = query!(
             r#"SELECT count
             FROM cluster_query_counts
@@ -280,7 +534,10 @@ impl Persistence for PostgresPersistence {
 
         debug!(?current, "Current counter is");
 
-        if current + 1 > max_allowed_count {
+        let current_u64: u64 = current
+            .try_into()
+            .context(ConvertStoredQueryCounterToU64Snafu)?;
+        if !query_count_allows_increment(current_u64, max_allowed_count) {
             debug!(current, max_allowed_count,
                 "Rejected increasing the cluster query count, as the current count + 1 is bigger than the max allowed count");
             transaction
@@ -310,7 +567,7 @@ impl Persistence for PostgresPersistence {
     async fn dec_cluster_query_count(
         &self,
         cluster_name: &TrinoClusterName,
-    ) -> Result<(), super::Error> {
+    ) -> Result<QueryCountDecrement, super::Error> {
         let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
 
         let current = query!(
@@ -333,23 +590,28 @@ impl Persistence for PostgresPersistence {
                 .rollback()
                 .await
                 .context(RollbackTransactionSnafu)?;
-            return Ok(());
+            return Ok(QueryCountDecrement::AlreadyZero);
         }
 
+        let new = current - 1;
         query!(
             r#"INSERT INTO cluster_query_counts (cluster, count)
             VALUES ($1, $2)
             ON CONFLICT (cluster) DO UPDATE SET count = $2
             "#,
             cluster_name,
-            current - 1,
+            new,
         )
         .execute(&mut *transaction)
         .await
         .context(SetCurrentQueryCounterSnafu)?;
 
         transaction.commit().await.context(CommitTransactionSnafu)?;
-        Ok(())
+
+        Ok(QueryCountDecrement::Decremented(
+            new.try_into()
+                .context(ConvertStoredQueryCounterToU64Snafu)?,
+        ))
     }
 
     #[instrument(skip(self))]
@@ -388,6 +650,50 @@ impl Persistence for PostgresPersistence {
             WHERE cluster = $1"#,
             cluster_name,
         )
+        .fetch_optional(self.routing_pool())
+        .await
+        .context(GetCurrentQueryCounterSnafu)?;
+
+        Ok(result
+            .map(|r| r.count)
+            // The count might not have been set yet
+            .unwrap_or_default()
+            .try_into()
+            .context(ConvertStoredQueryCounterToU64Snafu)?)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        count: u64,
+    ) -> Result<(), super::Error> {
+        query!(
+            r#"INSERT INTO cluster_blocked_query_counts (cluster, count)
+            VALUES ($1, $2)
+            ON CONFLICT (cluster) DO UPDATE SET count = $2
+            "#,
+            cluster_name,
+            TryInto::<i64>::try_into(count).context(ConvertCurrentQueryCounterToU64Snafu)?,
+        )
+        .execute(&self.pool)
+        .await
+        .context(SetCurrentQueryCounterSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn get_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<u64, super::Error> {
+        let result = query!(
+            r#"SELECT count
+            FROM cluster_blocked_query_counts
+            WHERE cluster = $1"#,
+            cluster_name,
+        )
         .fetch_optional(&self.pool)
         .await
         .context(GetCurrentQueryCounterSnafu)?;
@@ -417,6 +723,25 @@ impl Persistence for PostgresPersistence {
         .context(ConvertCurrentQueuedQueryCounterToU64Snafu)?)
     }
 
+    #[instrument(skip(self))]
+    async fn list_queued_query_ids(
+        &self,
+        cluster_group: &str,
+    ) -> Result<Vec<TrinoLbQueryId>, super::Error> {
+        Ok(query!(
+            r#"SELECT id
+            FROM queued_queries
+            WHERE cluster_group = $1"#,
+            cluster_group,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(ListQueuedQueryIdsSnafu)?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+    }
+
     #[instrument(skip(self))]
     async fn delete_queued_queries_not_accessed_after(
         &self,
@@ -472,7 +797,7 @@ impl Persistence for PostgresPersistence {
             ON CONFLICT (dummy) DO UPDATE SET last_query_count_fetcher_update = $2
             "#,
             19971208,
-            Into::<DateTime<Utc>>::into(update),
+            last_query_count_fetcher_update_to_date_time(update)?,
         )
         .execute(&mut *transaction)
         .await
@@ -519,7 +844,7 @@ impl Persistence for PostgresPersistence {
             WHERE id = $1"#,
             cluster_name,
         )
-        .fetch_optional(&self.pool)
+        .fetch_optional(self.routing_pool())
         .await
         .context(GetCurrentClusterStateSnafu)?;
 
@@ -532,4 +857,585 @@ impl Persistence for PostgresPersistence {
 
         Ok(cluster_state)
     }
+
+    #[instrument(skip(self))]
+    async fn list_cluster_states(
+        &self,
+    ) -> Result<Vec<(TrinoClusterName, ClusterState)>, super::Error> {
+        let result = query!(
+            r#"SELECT id, state
+            FROM cluster_states
+            ORDER BY id"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(ListClusterStatesSnafu)?;
+
+        let cluster_states = result
+            .into_iter()
+            .map(|row| {
+                let state = serde_json::from_value(row.state)
+                    .context(ParseStateOfStoredClusterStateSnafu)?;
+                Ok((row.id, state))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(cluster_states)
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_cluster(&self, cluster_name: &TrinoClusterName) -> Result<(), super::Error> {
+        let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
+
+        query!(
+            r#"DELETE FROM cluster_states
+            WHERE id = $1"#,
+            cluster_name,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RemoveClusterSnafu { cluster_name })?;
+
+        query!(
+            r#"DELETE FROM cluster_query_counts
+            WHERE cluster = $1"#,
+            cluster_name,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RemoveClusterSnafu { cluster_name })?;
+
+        query!(
+            r#"DELETE FROM cluster_blocked_query_counts
+            WHERE cluster = $1"#,
+            cluster_name,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RemoveClusterSnafu { cluster_name })?;
+
+        query!(
+            r#"DELETE FROM routing_excluded_clusters
+            WHERE cluster = $1"#,
+            cluster_name,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RemoveClusterSnafu { cluster_name })?;
+
+        query!(
+            r#"DELETE FROM manually_managed_clusters
+            WHERE cluster = $1"#,
+            cluster_name,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RemoveClusterSnafu { cluster_name })?;
+
+        transaction.commit().await.context(CommitTransactionSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_router_disabled(
+        &self,
+        router_index: usize,
+        disabled: bool,
+    ) -> Result<(), super::Error> {
+        let stored_router_index: i64 = router_index
+            .try_into()
+            .context(ConvertRouterIndexToI64Snafu { router_index })?;
+
+        if disabled {
+            query!(
+                r#"INSERT INTO disabled_routers (router_index)
+                VALUES ($1)
+                ON CONFLICT (router_index) DO NOTHING"#,
+                stored_router_index,
+            )
+            .execute(&self.pool)
+            .await
+        } else {
+            query!(
+                r#"DELETE FROM disabled_routers
+                WHERE router_index = $1"#,
+                stored_router_index,
+            )
+            .execute(&self.pool)
+            .await
+        }
+        .context(SetRouterDisabledSnafu {
+            router_index,
+            disabled,
+        })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_disabled_routers(&self) -> Result<Vec<usize>, super::Error> {
+        let result = query!(
+            r#"SELECT router_index
+            FROM disabled_routers
+            ORDER BY router_index"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(ListDisabledRoutersSnafu)?;
+
+        let disabled_routers = result
+            .into_iter()
+            .map(|row| {
+                row.router_index
+                    .try_into()
+                    .context(ConvertStoredRouterIndexToUsizeSnafu {
+                        router_index: row.router_index,
+                    })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(disabled_routers)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_routing_excluded(
+        &self,
+        cluster_name: &TrinoClusterName,
+        excluded: bool,
+    ) -> Result<(), super::Error> {
+        if excluded {
+            query!(
+                r#"INSERT INTO routing_excluded_clusters (cluster)
+                VALUES ($1)
+                ON CONFLICT (cluster) DO NOTHING"#,
+                cluster_name,
+            )
+            .execute(&self.pool)
+            .await
+        } else {
+            query!(
+                r#"DELETE FROM routing_excluded_clusters
+                WHERE cluster = $1"#,
+                cluster_name,
+            )
+            .execute(&self.pool)
+            .await
+        }
+        .context(SetClusterRoutingExcludedSnafu {
+            cluster_name,
+            excluded,
+        })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_routing_excluded_clusters(&self) -> Result<Vec<TrinoClusterName>, super::Error> {
+        let result = query!(
+            r#"SELECT cluster
+            FROM routing_excluded_clusters
+            ORDER BY cluster"#,
+        )
+        .fetch_all(self.routing_pool())
+        .await
+        .context(ListRoutingExcludedClustersSnafu)?;
+
+        Ok(result.into_iter().map(|row| row.cluster).collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_manually_managed(
+        &self,
+        cluster_name: &TrinoClusterName,
+        manually_managed: bool,
+    ) -> Result<(), super::Error> {
+        if manually_managed {
+            query!(
+                r#"INSERT INTO manually_managed_clusters (cluster)
+                VALUES ($1)
+                ON CONFLICT (cluster) DO NOTHING"#,
+                cluster_name,
+            )
+            .execute(&self.pool)
+            .await
+        } else {
+            query!(
+                r#"DELETE FROM manually_managed_clusters
+                WHERE cluster = $1"#,
+                cluster_name,
+            )
+            .execute(&self.pool)
+            .await
+        }
+        .context(SetClusterManuallyManagedSnafu {
+            cluster_name,
+            manually_managed,
+        })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_manually_managed_clusters(&self) -> Result<Vec<TrinoClusterName>, super::Error> {
+        let result = query!(
+            r#"SELECT cluster
+            FROM manually_managed_clusters
+            ORDER BY cluster"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(ListManuallyManagedClustersSnafu)?;
+
+        Ok(result.into_iter().map(|row| row.cluster).collect())
+    }
+
+    #[instrument(skip(self, response))]
+    async fn store_idempotent_response(
+        &self,
+        idempotency_key: &str,
+        response: &TrinoQueryApiResponse,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
+
+        // Clean up expired entries, so that the table does not grow forever
+        query!(
+            r#"DELETE FROM idempotent_responses
+            WHERE expires_at < now()"#
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(StoreIdempotentResponseSnafu)?;
+
+        query!(
+            r#"INSERT INTO idempotent_responses (idempotency_key, response, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (idempotency_key) DO UPDATE SET response = $2, expires_at = $3
+            "#,
+            idempotency_key,
+            sqlx::types::Json(response) as _,
+            Into::<DateTime<Utc>>::into(SystemTime::now() + ttl),
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(StoreIdempotentResponseSnafu)?;
+
+        transaction.commit().await.context(CommitTransactionSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_idempotent_response(
+        &self,
+        idempotency_key: &str,
+    ) -> Result<Option<TrinoQueryApiResponse>, super::Error> {
+        let result = query!(
+            r#"SELECT response
+            FROM idempotent_responses
+            WHERE idempotency_key = $1 AND expires_at > now()"#,
+            idempotency_key,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context(LoadIdempotentResponseSnafu)?;
+
+        Ok(match result {
+            Some(result) => Some(
+                serde_json::from_value(result.response)
+                    .context(ParseStoredIdempotentResponseSnafu)?,
+            ),
+            None => None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn store_query_runtime(
+        &self,
+        query_fingerprint: &str,
+        runtime: Duration,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let runtime_millis: i64 = runtime
+            .as_millis()
+            .try_into()
+            .context(ConvertQueryRuntimeToMillisSnafu { runtime })?;
+
+        let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
+
+        // Clean up expired entries, so that the table does not grow forever
+        query!(
+            r#"DELETE FROM query_runtimes
+            WHERE expires_at < now()"#
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(StoreQueryRuntimeSnafu)?;
+
+        query!(
+            r#"INSERT INTO query_runtimes (query_fingerprint, runtime_millis, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (query_fingerprint) DO UPDATE SET runtime_millis = $2, expires_at = $3
+            "#,
+            query_fingerprint,
+            runtime_millis,
+            Into::<DateTime<Utc>>::into(SystemTime::now() + ttl),
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(StoreQueryRuntimeSnafu)?;
+
+        transaction.commit().await.context(CommitTransactionSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_query_runtime(
+        &self,
+        query_fingerprint: &str,
+    ) -> Result<Option<Duration>, super::Error> {
+        let result = query!(
+            r#"SELECT runtime_millis
+            FROM query_runtimes
+            WHERE query_fingerprint = $1 AND expires_at > now()"#,
+            query_fingerprint,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context(LoadQueryRuntimeSnafu)?;
+
+        Ok(match result {
+            Some(result) => {
+                let millis = result.runtime_millis;
+                Some(Duration::from_millis(
+                    millis
+                        .try_into()
+                        .context(ConvertStoredQueryRuntimeToU64Snafu { millis })?,
+                ))
+            }
+            None => None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn record_client_requests(
+        &self,
+        bucket: u64,
+        user: &str,
+        counts: ClientRequestCounts,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        // The counters are far away from exceeding an i64, so we simply saturate
+        let to_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+
+        let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
+
+        // Clean up expired buckets, so that the table does not grow forever
+        query!(
+            r#"DELETE FROM client_request_stats
+            WHERE bucket < $1"#,
+            to_i64(bucket.saturating_sub(bucket_count(ttl))),
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RecordClientRequestSnafu)?;
+
+        query!(
+            r#"INSERT INTO client_request_stats (bucket, user_name, requests, header_bytes)
+            SELECT $1::BIGINT,
+                CASE WHEN EXISTS (SELECT 1 FROM client_request_stats WHERE bucket = $1 AND user_name = $2)
+                        OR (SELECT COUNT(*) FROM client_request_stats WHERE bucket = $1) < $4::BIGINT
+                    THEN $2::VARCHAR
+                    ELSE $5::VARCHAR
+                END,
+                $6::BIGINT,
+                $3::BIGINT
+            ON CONFLICT (bucket, user_name) DO UPDATE
+            SET requests = client_request_stats.requests + EXCLUDED.requests,
+                header_bytes = client_request_stats.header_bytes + EXCLUDED.header_bytes
+            "#,
+            to_i64(bucket),
+            user,
+            to_i64(counts.header_bytes),
+            to_i64(max_users),
+            OTHER_USERS,
+            to_i64(counts.requests),
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RecordClientRequestSnafu)?;
+
+        transaction.commit().await.context(CommitTransactionSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_client_request_stats(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<String, ClientRequestCounts>, super::Error> {
+        let to_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+        let result = query!(
+            r#"SELECT user_name, SUM(requests)::BIGINT AS "requests!", SUM(header_bytes)::BIGINT AS "header_bytes!"
+            FROM client_request_stats
+            WHERE bucket BETWEEN $1 AND $2
+            GROUP BY user_name"#,
+            to_i64(*buckets.start()),
+            to_i64(*buckets.end()),
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(LoadClientRequestStatsSnafu)?;
+
+        Ok(result
+            .into_iter()
+            .map(|row| {
+                (
+                    row.user_name,
+                    ClientRequestCounts {
+                        requests: row.requests.try_into().unwrap_or_default(),
+                        header_bytes: row.header_bytes.try_into().unwrap_or_default(),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn record_handed_over_query(
+        &self,
+        bucket: u64,
+        user: &str,
+        cluster_group: &str,
+        max_users: u64,
+        _ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let to_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+
+        let mut transaction = self.pool.begin().await.context(StartTransactionSnafu)?;
+
+        // Serialize the recording, as otherwise concurrent transactions could all count the users before any of them
+        // inserted its user, so that more than `max_users` users are tracked. Readers are not blocked by this lock.
+        query!(r#"LOCK TABLE handed_over_queries IN SHARE ROW EXCLUSIVE MODE"#)
+            .execute(&mut *transaction)
+            .await
+            .context(RecordHandedOverQuerySnafu)?;
+
+        query!(
+            r#"INSERT INTO handed_over_queries (bucket, user_name, cluster_group, queries)
+            SELECT $1::BIGINT,
+                CASE WHEN EXISTS (SELECT 1 FROM handed_over_queries WHERE bucket = $1 AND user_name = $2)
+                        OR (SELECT COUNT(DISTINCT user_name) FROM handed_over_queries WHERE bucket = $1) < $4::BIGINT
+                    THEN $2::VARCHAR
+                    ELSE $5::VARCHAR
+                END,
+                $3::VARCHAR,
+                1
+            ON CONFLICT (bucket, user_name, cluster_group) DO UPDATE
+            SET queries = handed_over_queries.queries + 1
+            "#,
+            to_i64(bucket),
+            user,
+            cluster_group,
+            to_i64(max_users),
+            OTHER_USERS,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(RecordHandedOverQuerySnafu)?;
+
+        transaction.commit().await.context(CommitTransactionSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_handed_over_queries_before(&self, bucket: u64) -> Result<(), super::Error> {
+        query!(
+            r#"DELETE FROM handed_over_queries
+            WHERE bucket < $1"#,
+            i64::try_from(bucket).unwrap_or(i64::MAX),
+        )
+        .execute(&self.pool)
+        .await
+        .context(DeleteHandedOverQueriesSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_handed_over_query_counts(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<(String, String), u64>, super::Error> {
+        let to_i64 = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+        let result = query!(
+            r#"SELECT user_name, cluster_group, SUM(queries)::BIGINT AS "queries!"
+            FROM handed_over_queries
+            WHERE bucket BETWEEN $1 AND $2
+            GROUP BY user_name, cluster_group"#,
+            to_i64(*buckets.start()),
+            to_i64(*buckets.end()),
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context(LoadHandedOverQueryCountsSnafu)?;
+
+        Ok(result
+            .into_iter()
+            .map(|row| {
+                (
+                    (row.user_name, row.cluster_group),
+                    row.queries.try_into().unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+}
+
+/// Converts the last queryCountFetcher update with microsecond precision (the precision Postgres stores). Unlike the
+/// [`From`] implementation of [`DateTime`], this does not panic for timestamps chrono can not represent.
+fn last_query_count_fetcher_update_to_date_time(
+    update: SystemTime,
+) -> Result<DateTime<Utc>, Error> {
+    update
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|since_epoch| i64::try_from(since_epoch.as_micros()).ok())
+        .and_then(DateTime::from_timestamp_micros)
+        .context(LastQueryCountFetcherUpdateOutOfRangeSnafu { update })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn test_last_query_count_fetcher_update_to_date_time() {
+        let update = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        assert_eq!(
+            SystemTime::from(last_query_count_fetcher_update_to_date_time(update).unwrap()),
+            update
+        );
+    }
+
+    #[rstest]
+    #[case::before_epoch(UNIX_EPOCH - Duration::from_secs(1))]
+    #[case::overflow(UNIX_EPOCH + Duration::from_secs(u64::MAX / 2))]
+    fn test_last_query_count_fetcher_update_out_of_range(#[case] update: SystemTime) {
+        let error = last_query_count_fetcher_update_to_date_time(update).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                Error::LastQueryCountFetcherUpdateOutOfRange { update: actual } if actual == update
+            ),
+            "Unexpected error {error:?}"
+        );
+    }
 }
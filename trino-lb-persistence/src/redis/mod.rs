@@ -1,6 +1,8 @@
 use std::{
+    collections::HashMap,
     fmt::Debug,
     num::TryFromIntError,
+    ops::RangeInclusive,
     time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH},
 };
 
@@ -11,19 +13,31 @@ use redis::{
     cluster_async::ClusterConnection,
     AsyncCommands, Client, RedisError, Script,
 };
+use serde::Deserialize;
 use snafu::{OptionExt, ResultExt, Snafu};
 use tracing::{debug, debug_span, info, instrument, Instrument};
 use trino_lb_core::{
+    client_request_stats::{
+        bucket_count, ClientRequestCounts, CLIENT_REQUEST_STATS_BUCKET_SIZE, OTHER_USERS,
+    },
     config::RedisConfig,
+    query_chargeback::{self, QUERY_CHARGEBACK_BUCKET_SIZE},
+    trino_api::TrinoQueryApiResponse,
     trino_cluster::ClusterState,
     trino_query::{QueuedQuery, TrinoQuery},
     TrinoClusterName, TrinoLbQueryId, TrinoQueryId,
 };
 use url::Url;
 
-use crate::Persistence;
+use crate::{query_count_allows_increment, Persistence, QueryCountDecrement};
 
 const LAST_QUERY_COUNT_FETCHER_UPDATE_KEY: &str = "lastQueryCountFetcherUpdate";
+const DISABLED_ROUTERS_KEY: &str = "disabledRouters";
+const ROUTING_EXCLUDED_CLUSTERS_KEY: &str = "routingExcludedClusters";
+const MANUALLY_MANAGED_CLUSTERS_KEY: &str = "manuallyManagedClusters";
+
+/// How often moving a queued query is attempted, in case it is changed concurrently (e.g. by the client polling it).
+const MOVE_QUEUED_QUERY_MAX_ATTEMPTS: usize = 10;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -39,9 +53,32 @@ pub enum Error {
     #[snafu(display("Failed to deserialize from binary representation"))]
     DeserializeFromBinary { source: bincode::Error },
 
+    #[snafu(display(
+        "Refusing to store query {query_id:?} of Trino cluster {trino_cluster:?}, as a query with the same id is already stored for a different Trino cluster"
+    ))]
+    QueryIdCollision {
+        query_id: TrinoQueryId,
+        trino_cluster: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to serialize to JSON"))]
+    SerializeToJson { source: serde_json::Error },
+
+    #[snafu(display("Failed to deserialize from JSON"))]
+    DeserializeFromJson { source: serde_json::Error },
+
     #[snafu(display("Failed to write to redis"))]
     WriteToRedis { source: RedisError },
 
+    #[snafu(display(
+        "Failed to move queued query {queued_query_id:?} to cluster group {cluster_group:?}, as it was changed concurrently {attempts} times in a row"
+    ))]
+    MoveQueuedQueryContended {
+        queued_query_id: TrinoLbQueryId,
+        cluster_group: String,
+        attempts: usize,
+    },
+
     #[snafu(display("Failed to read from redis"))]
     ReadFromRedis { source: RedisError },
 
@@ -83,6 +120,12 @@ pub enum Error {
         retrieved: Option<i64>,
     },
 
+    #[snafu(display("Failed to convert query runtime {runtime:?} to millis stored in an u64"))]
+    ConvertQueryRuntimeToMillis {
+        source: TryFromIntError,
+        runtime: Duration,
+    },
+
     #[snafu(display("Failed to get last cluster query count fetcher update timestamp"))]
     GetLastQueryCountFetcherUpdate { source: RedisError },
 
@@ -95,17 +138,76 @@ pub enum Error {
     #[snafu(display("Failed to store determined elapsed time since last queryCountFetcher update as millis in a u64"))]
     ConvertElapsedTimeSinceLastUpdateToMillis { source: TryFromIntError },
 
+    #[snafu(display("The stored last queryCountFetcher update of {millis} ms since the epoch can not be represented as timestamp"))]
+    LastQueryCountFetcherUpdateOutOfRange { millis: u64 },
+
     #[snafu(display("Failed to set cluster state"))]
     SetClusterState { source: RedisError },
 
     #[snafu(display("Failed to get cluster state"))]
     GetClusterState { source: RedisError },
 
+    #[snafu(display("Failed to list cluster states"))]
+    ListClusterStates { source: RedisError },
+
+    #[snafu(display("Failed to list query ids"))]
+    ListQueryIds { source: RedisError },
+
+    #[snafu(display("Failed to remove cluster {cluster_name:?}"))]
+    RemoveCluster {
+        source: RedisError,
+        cluster_name: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to set router {router_index} to disabled={disabled}"))]
+    SetRouterDisabled {
+        source: RedisError,
+        router_index: usize,
+        disabled: bool,
+    },
+
+    #[snafu(display("Failed to list disabled routers"))]
+    ListDisabledRouters { source: RedisError },
+
+    #[snafu(display("Failed to set cluster {cluster_name:?} to excluded={excluded}"))]
+    SetClusterRoutingExcluded {
+        source: RedisError,
+        cluster_name: TrinoClusterName,
+        excluded: bool,
+    },
+
+    #[snafu(display("Failed to list clusters excluded from routing"))]
+    ListRoutingExcludedClusters { source: RedisError },
+
+    #[snafu(display(
+        "Failed to set cluster {cluster_name:?} to manually_managed={manually_managed}"
+    ))]
+    SetClusterManuallyManaged {
+        source: RedisError,
+        cluster_name: TrinoClusterName,
+        manually_managed: bool,
+    },
+
+    #[snafu(display("Failed to list manually managed clusters"))]
+    ListManuallyManagedClusters { source: RedisError },
+
     #[snafu(display("Failed to execute compare and set lua script."))]
     ExecuteCASScript { source: RedisError },
 
     #[snafu(display("Invalid response from compare and set lua script. Expected either 0 or 1"))]
     InvalidCASScriptResponse { response: u64 },
+
+    #[snafu(display("Failed to execute store query lua script"))]
+    ExecuteStoreQueryScript { source: RedisError },
+
+    #[snafu(display("Invalid response from store query lua script. Expected either 0 or 1"))]
+    InvalidStoreQueryScriptResponse { response: u64 },
+
+    #[snafu(display("Failed to execute record client request lua script"))]
+    ExecuteRecordClientRequestScript { source: RedisError },
+
+    #[snafu(display("Failed to execute record handed over query lua script"))]
+    ExecuteRecordHandedOverQueryScript { source: RedisError },
 }
 
 /// This Redis implementation works against Redis clusters. It uses a single connection that is shared between all
@@ -120,7 +222,13 @@ where
     R: AsyncCommands + Clone,
 {
     connection: R,
+    /// Optional separate connection used for the latency-sensitive reads done while routing queries.
+    routing_connection: Option<R>,
     compare_and_set_script: Script,
+    replace_unchanged_script: Script,
+    store_query_script: Script,
+    record_client_request_script: Script,
+    record_handed_over_query_script: Script,
 
     /// Sometimes we need to do stuff for all cluster groups, so we need to store them to iterate over them
     cluster_groups: Vec<String>,
@@ -138,10 +246,26 @@ impl RedisPersistence<ConnectionManager> {
             .get_connection_manager()
             .await
             .context(CreateClientSnafu)?;
+        let routing_connection = if config.dedicated_routing_connection {
+            info!("Using a dedicated redis connection for routing");
+            Some(
+                client
+                    .get_connection_manager()
+                    .await
+                    .context(CreateClientSnafu)?,
+            )
+        } else {
+            None
+        };
 
         Ok(Self {
             connection,
+            routing_connection,
             compare_and_set_script: compare_and_set_script(),
+            replace_unchanged_script: replace_unchanged_script(),
+            store_query_script: store_query_script(),
+            record_client_request_script: record_client_request_script(),
+            record_handed_over_query_script: record_handed_over_query_script(),
             cluster_groups,
         })
     }
@@ -161,10 +285,26 @@ impl RedisPersistence<ClusterConnection<MultiplexedConnection>> {
             .get_async_connection()
             .await
             .context(CreateClientSnafu)?;
+        let routing_connection = if config.dedicated_routing_connection {
+            info!("Using a dedicated redis cluster connection for routing");
+            Some(
+                client
+                    .get_async_connection()
+                    .await
+                    .context(CreateClientSnafu)?,
+            )
+        } else {
+            None
+        };
 
         Ok(Self {
             connection,
+            routing_connection,
             compare_and_set_script: compare_and_set_script(),
+            replace_unchanged_script: replace_unchanged_script(),
+            store_query_script: store_query_script(),
+            record_client_request_script: record_client_request_script(),
+            record_handed_over_query_script: record_handed_over_query_script(),
             cluster_groups,
         })
     }
@@ -199,15 +339,18 @@ where
     async fn load_queued_query(
         &self,
         queued_query_id: &TrinoLbQueryId,
-    ) -> Result<QueuedQuery, super::Error> {
+    ) -> Result<Option<QueuedQuery>, super::Error> {
         let key = queued_query_key(queued_query_id);
-        let value: Vec<u8> = self
+        let value: Option<Vec<u8>> = self
             .connection()
             .get(key)
             .await
             .context(ReadFromRedisSnafu)?;
 
-        Ok(bincode::deserialize(&value).context(DeserializeFromBinarySnafu)?)
+        Ok(value
+            .map(|value| deserialize_queued_query(&value))
+            .transpose()
+            .context(DeserializeFromBinarySnafu)?)
     }
 
     #[instrument(skip(self))]
@@ -225,30 +368,134 @@ where
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn move_queued_query(
+        &self,
+        queued_query: &QueuedQuery,
+        cluster_group: &str,
+    ) -> Result<(), super::Error> {
+        let key = queued_query_key(&queued_query.id);
+
+        for _ in 0..MOVE_QUEUED_QUERY_MAX_ATTEMPTS {
+            let Some((current, mut stored)) = self.load_queued_query_with_value(key).await? else {
+                return Ok(());
+            };
+            if stored.cluster_group == cluster_group {
+                return Ok(());
+            }
+            let old_cluster_group =
+                std::mem::replace(&mut stored.cluster_group, cluster_group.to_owned());
+
+            if self
+                .replace_unchanged_queued_query(key, &current, &stored)
+                .await?
+            {
+                // The sets only index the queued queries of the cluster groups. They can not be changed in the same
+                // script, as they are stored in different slots of a Redis cluster. Add it to the new group first, so
+                // that the query is never missing in both groups.
+                let mut connection = self.connection();
+                let _: () = connection
+                    .sadd(queued_query_set_name(cluster_group), key)
+                    .await
+                    .context(WriteToRedisSnafu)?;
+                let _: () = connection
+                    .srem(queued_query_set_name(&old_cluster_group), key)
+                    .await
+                    .context(WriteToRedisSnafu)?;
+
+                return Ok(());
+            }
+            // The queued query was changed in the meantime (e.g. its last access was updated), so try again
+        }
+
+        MoveQueuedQueryContendedSnafu {
+            queued_query_id: &queued_query.id,
+            cluster_group,
+            attempts: MOVE_QUEUED_QUERY_MAX_ATTEMPTS,
+        }
+        .fail()?
+    }
+
+    #[instrument(skip(self))]
+    async fn update_queued_query_last_accessed(
+        &self,
+        queued_query: &QueuedQuery,
+        last_accessed: SystemTime,
+    ) -> Result<(), super::Error> {
+        let key = queued_query_key(&queued_query.id);
+        let Some((current, mut stored)) = self.load_queued_query_with_value(key).await? else {
+            return Ok(());
+        };
+        if stored.cluster_group != queued_query.cluster_group {
+            return Ok(());
+        }
+        stored.last_accessed = last_accessed;
+
+        // In case the queued query was changed in the meantime, it was either moved or its last access was updated by
+        // another request, so there is nothing left to do
+        self.replace_unchanged_queued_query(key, &current, &stored)
+            .await?;
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn store_query(&self, query: TrinoQuery) -> Result<(), super::Error> {
         let key = query_key(&query.id);
         let value = bincode::serialize(&query).context(SerializeToBinarySnafu)?;
-
-        let _: () = self
-            .connection()
-            .set(key, value)
+        let owner = query_owner(&query)?;
+
+        let response: u8 = self
+            .store_query_script
+            .key(key)
+            .arg(value)
+            .arg(owner)
+            .invoke_async(&mut self.connection())
+            .instrument(debug_span!("invoking store query lua script"))
             .await
-            .context(WriteToRedisSnafu)?;
+            .context(ExecuteStoreQueryScriptSnafu)?;
 
-        Ok(())
+        match response {
+            0 => QueryIdCollisionSnafu {
+                query_id: query.id,
+                trino_cluster: query.trino_cluster,
+            }
+            .fail()?,
+            1 => Ok(()),
+            _ => InvalidStoreQueryScriptResponseSnafu { response }.fail()?,
+        }
     }
 
     #[instrument(skip(self))]
-    async fn load_query(&self, query_id: &TrinoQueryId) -> Result<TrinoQuery, super::Error> {
+    async fn load_query(
+        &self,
+        query_id: &TrinoQueryId,
+    ) -> Result<Option<TrinoQuery>, super::Error> {
         let key = query_key(query_id);
-        let value: Vec<u8> = self
+        let value: Option<Vec<u8>> = self
             .connection()
             .get(key)
             .await
             .context(ReadFromRedisSnafu)?;
 
-        Ok(bincode::deserialize(&value).context(DeserializeFromBinarySnafu)?)
+        Ok(value
+            .map(|value| deserialize_query(&value))
+            .transpose()
+            .context(DeserializeFromBinarySnafu)?)
+    }
+
+    #[instrument(skip(self))]
+    async fn update_query_next_uri_path(
+        &self,
+        query_id: &TrinoQueryId,
+        next_uri_path: String,
+    ) -> Result<(), super::Error> {
+        let Some(mut query) = self.load_query(query_id).await? else {
+            return Ok(());
+        };
+        query.previous_next_uri_path = query.next_uri_path.replace(next_uri_path);
+
+        self.set_query(&query).await
     }
 
     #[instrument(skip(self))]
@@ -263,6 +510,22 @@ where
         Ok(())
     }
 
+    /// Uses `SCAN`, so the keys are not locked. Please note that a Redis cluster connection only scans a single node.
+    #[instrument(skip(self))]
+    async fn list_query_ids(&self) -> Result<Vec<TrinoQueryId>, super::Error> {
+        let mut connection = self.connection();
+        let mut iter = connection
+            .scan_match::<_, String>(QUERY_KEY_PATTERN)
+            .await
+            .context(ListQueryIdsSnafu)?;
+        let mut query_ids = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            query_ids.push(key);
+        }
+
+        Ok(query_ids)
+    }
+
     #[instrument(skip(self))]
     async fn inc_cluster_query_count(
         &self,
@@ -282,7 +545,7 @@ where
 
             debug!(current, "Current counter is");
 
-            if current + 1 > max_allowed_count {
+            if !query_count_allows_increment(current, max_allowed_count) {
                 debug!(current, max_allowed_count,
                     "Rejected increasing the cluster query count, as the current count + 1 is bigger than the max allowed count");
                 return Ok(false);
@@ -318,7 +581,7 @@ where
     async fn dec_cluster_query_count(
         &self,
         cluster_name: &TrinoClusterName,
-    ) -> Result<(), super::Error> {
+    ) -> Result<QueryCountDecrement, super::Error> {
         let key = cluster_query_counter_key(cluster_name);
         let mut connection = self.connection();
 
@@ -332,7 +595,7 @@ where
 
             if current == 0 {
                 debug!("Current value was already 0, nothing to do here");
-                return Ok(());
+                return Ok(QueryCountDecrement::AlreadyZero);
             }
 
             let response: u8 = self
@@ -350,7 +613,7 @@ where
                     continue;
                 }
                 1 => {
-                    return Ok(());
+                    return Ok(QueryCountDecrement::Decremented(current - 1));
                 }
                 _ => InvalidCASScriptResponseSnafu { response }.fail()?,
             }
@@ -381,7 +644,7 @@ where
     ) -> Result<u64, super::Error> {
         let key = cluster_query_counter_key(cluster_name);
         Ok(self
-            .connection()
+            .routing_connection()
             .get::<_, Option<u64>>(key)
             .await
             .context(ReadClusterQueryCountSnafu { cluster_name })?
@@ -389,17 +652,61 @@ where
             .unwrap_or_default())
     }
 
+    #[instrument(skip(self))]
+    async fn set_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+        count: u64,
+    ) -> Result<(), super::Error> {
+        let key = cluster_blocked_query_counter_key(cluster_name);
+
+        let _: () = self
+            .connection()
+            .set(key, count)
+            .await
+            .context(SetClusterQueryCountSnafu { cluster_name })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn get_cluster_blocked_query_count(
+        &self,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<u64, super::Error> {
+        let key = cluster_blocked_query_counter_key(cluster_name);
+        Ok(self
+            .connection()
+            .get::<_, Option<u64>>(key)
+            .await
+            .context(ReadClusterQueryCountSnafu { cluster_name })?
+            // The count might not have been set yet
+            .unwrap_or_default())
+    }
+
     #[instrument(skip(self))]
     async fn get_queued_query_count(&self, cluster_group: &str) -> Result<u64, super::Error> {
         Ok(self
             .connection()
             .scard::<_, Option<u64>>(queued_query_set_name(cluster_group))
             .await
-            .unwrap()
+            .context(ReadFromRedisSnafu)?
             // The set might not be there yet, as no queries have been queued for this cluster group so far.
             .unwrap_or_default())
     }
 
+    #[instrument(skip(self))]
+    async fn list_queued_query_ids(
+        &self,
+        cluster_group: &str,
+    ) -> Result<Vec<TrinoLbQueryId>, super::Error> {
+        Ok(self
+            .connection()
+            .smembers(queued_query_set_name(cluster_group))
+            .await
+            .context(ReadFromRedisSnafu)?)
+    }
+
     #[instrument(skip(self))]
     async fn delete_queued_queries_not_accessed_after(
         &self,
@@ -424,8 +731,8 @@ where
             // safely return 1970-01-01 here,
             .unwrap_or_default();
 
-        // TODO: Check for overflows
-        Ok(UNIX_EPOCH + Duration::from_millis(ms))
+        Ok(crate::timestamp_from_millis(ms)
+            .context(LastQueryCountFetcherUpdateOutOfRangeSnafu { millis: ms })?)
     }
 
     #[instrument(skip(self))]
@@ -475,7 +782,7 @@ where
         let key = cluster_state_key(cluster_name);
 
         let cluster_state: Option<Vec<u8>> = self
-            .connection()
+            .routing_connection()
             .get(key)
             .await
             .context(GetClusterStateSnafu)?;
@@ -487,6 +794,392 @@ where
             None => ClusterState::Unknown,
         })
     }
+
+    /// Uses `SCAN`, so the keys are not locked. Please note that a Redis cluster connection only scans a single node.
+    #[instrument(skip(self))]
+    async fn list_cluster_states(
+        &self,
+    ) -> Result<Vec<(TrinoClusterName, ClusterState)>, super::Error> {
+        let mut connection = self.connection();
+        let mut keys: Vec<String> = Vec::new();
+        {
+            let mut iter = connection
+                .scan_match::<_, String>(cluster_state_key("*"))
+                .await
+                .context(ListClusterStatesSnafu)?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+
+        let mut cluster_states = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some(cluster) = key.strip_suffix(CLUSTER_STATE_KEY_SUFFIX) else {
+                continue;
+            };
+            let cluster_state: Option<Vec<u8>> =
+                connection.get(&key).await.context(ListClusterStatesSnafu)?;
+            // The cluster state might have been removed in the meantime
+            if let Some(cluster_state) = cluster_state {
+                cluster_states.push((
+                    cluster.to_owned(),
+                    bincode::deserialize(&cluster_state).context(DeserializeFromBinarySnafu)?,
+                ));
+            }
+        }
+        cluster_states.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(cluster_states)
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_cluster(&self, cluster_name: &TrinoClusterName) -> Result<(), super::Error> {
+        let mut connection = self.connection();
+
+        // We can't delete both keys at once, as we otherwise get "CrossSlot Keys in request don't hash to the same slot"
+        let _: () = connection
+            .del(cluster_state_key(cluster_name))
+            .await
+            .context(RemoveClusterSnafu { cluster_name })?;
+        let _: () = connection
+            .del(cluster_query_counter_key(cluster_name))
+            .await
+            .context(RemoveClusterSnafu { cluster_name })?;
+        let _: () = connection
+            .del(cluster_blocked_query_counter_key(cluster_name))
+            .await
+            .context(RemoveClusterSnafu { cluster_name })?;
+        let _: () = connection
+            .srem(ROUTING_EXCLUDED_CLUSTERS_KEY, cluster_name)
+            .await
+            .context(RemoveClusterSnafu { cluster_name })?;
+        let _: () = connection
+            .srem(MANUALLY_MANAGED_CLUSTERS_KEY, cluster_name)
+            .await
+            .context(RemoveClusterSnafu { cluster_name })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_router_disabled(
+        &self,
+        router_index: usize,
+        disabled: bool,
+    ) -> Result<(), super::Error> {
+        let mut connection = self.connection();
+        let result: Result<(), _> = if disabled {
+            connection.sadd(DISABLED_ROUTERS_KEY, router_index).await
+        } else {
+            connection.srem(DISABLED_ROUTERS_KEY, router_index).await
+        };
+        result.context(SetRouterDisabledSnafu {
+            router_index,
+            disabled,
+        })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_disabled_routers(&self) -> Result<Vec<usize>, super::Error> {
+        let mut disabled_routers: Vec<usize> = self
+            .connection()
+            .smembers(DISABLED_ROUTERS_KEY)
+            .await
+            .context(ListDisabledRoutersSnafu)?;
+        disabled_routers.sort_unstable();
+
+        Ok(disabled_routers)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_routing_excluded(
+        &self,
+        cluster_name: &TrinoClusterName,
+        excluded: bool,
+    ) -> Result<(), super::Error> {
+        let mut connection = self.connection();
+        let result: Result<(), _> = if excluded {
+            connection
+                .sadd(ROUTING_EXCLUDED_CLUSTERS_KEY, cluster_name)
+                .await
+        } else {
+            connection
+                .srem(ROUTING_EXCLUDED_CLUSTERS_KEY, cluster_name)
+                .await
+        };
+        result.context(SetClusterRoutingExcludedSnafu {
+            cluster_name,
+            excluded,
+        })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_routing_excluded_clusters(&self) -> Result<Vec<TrinoClusterName>, super::Error> {
+        let mut routing_excluded_clusters: Vec<TrinoClusterName> = self
+            .routing_connection()
+            .smembers(ROUTING_EXCLUDED_CLUSTERS_KEY)
+            .await
+            .context(ListRoutingExcludedClustersSnafu)?;
+        routing_excluded_clusters.sort_unstable();
+
+        Ok(routing_excluded_clusters)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_cluster_manually_managed(
+        &self,
+        cluster_name: &TrinoClusterName,
+        manually_managed: bool,
+    ) -> Result<(), super::Error> {
+        let mut connection = self.connection();
+        let result: Result<(), _> = if manually_managed {
+            connection
+                .sadd(MANUALLY_MANAGED_CLUSTERS_KEY, cluster_name)
+                .await
+        } else {
+            connection
+                .srem(MANUALLY_MANAGED_CLUSTERS_KEY, cluster_name)
+                .await
+        };
+        result.context(SetClusterManuallyManagedSnafu {
+            cluster_name,
+            manually_managed,
+        })?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_manually_managed_clusters(&self) -> Result<Vec<TrinoClusterName>, super::Error> {
+        let mut manually_managed_clusters: Vec<TrinoClusterName> = self
+            .connection()
+            .smembers(MANUALLY_MANAGED_CLUSTERS_KEY)
+            .await
+            .context(ListManuallyManagedClustersSnafu)?;
+        manually_managed_clusters.sort_unstable();
+
+        Ok(manually_managed_clusters)
+    }
+
+    /// [`TrinoQueryApiResponse`] contains [`serde_json::Value`]s, which can not be deserialized by bincode, so we
+    /// store it as JSON.
+    #[instrument(skip(self, response))]
+    async fn store_idempotent_response(
+        &self,
+        idempotency_key: &str,
+        response: &TrinoQueryApiResponse,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let key = idempotency_key_key(idempotency_key);
+        let value = serde_json::to_vec(response).context(SerializeToJsonSnafu)?;
+
+        let _: () = self
+            .connection()
+            // Round up, so that we never expire too early
+            .set_ex(key, value, ttl.as_secs().saturating_add(1))
+            .await
+            .context(WriteToRedisSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_idempotent_response(
+        &self,
+        idempotency_key: &str,
+    ) -> Result<Option<TrinoQueryApiResponse>, super::Error> {
+        let key = idempotency_key_key(idempotency_key);
+
+        let response: Option<Vec<u8>> = self
+            .connection()
+            .get(key)
+            .await
+            .context(ReadFromRedisSnafu)?;
+
+        Ok(match response {
+            Some(response) => {
+                Some(serde_json::from_slice(&response).context(DeserializeFromJsonSnafu)?)
+            }
+            None => None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn store_query_runtime(
+        &self,
+        query_fingerprint: &str,
+        runtime: Duration,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let key = query_runtime_key(query_fingerprint);
+        let runtime_millis: u64 = runtime
+            .as_millis()
+            .try_into()
+            .context(ConvertQueryRuntimeToMillisSnafu { runtime })?;
+
+        let _: () = self
+            .connection()
+            // Round up, so that we never expire too early
+            .set_ex(key, runtime_millis, ttl.as_secs().saturating_add(1))
+            .await
+            .context(WriteToRedisSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_query_runtime(
+        &self,
+        query_fingerprint: &str,
+    ) -> Result<Option<Duration>, super::Error> {
+        let key = query_runtime_key(query_fingerprint);
+
+        let runtime_millis: Option<u64> = self
+            .connection()
+            .get(key)
+            .await
+            .context(ReadFromRedisSnafu)?;
+
+        Ok(runtime_millis.map(Duration::from_millis))
+    }
+
+    #[instrument(skip(self))]
+    async fn record_client_requests(
+        &self,
+        bucket: u64,
+        user: &str,
+        counts: ClientRequestCounts,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let _: () = self
+            .record_client_request_script
+            .key(client_request_stats_key(bucket))
+            .arg(user)
+            .arg(counts.requests)
+            .arg(counts.header_bytes)
+            .arg(max_users)
+            .arg(OTHER_USERS)
+            // The bucket is only complete at its end, so keep it one bucket longer
+            .arg((bucket_count(ttl) + 1) * CLIENT_REQUEST_STATS_BUCKET_SIZE.as_secs())
+            .invoke_async(&mut self.connection())
+            .await
+            .context(ExecuteRecordClientRequestScriptSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_client_request_stats(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<String, ClientRequestCounts>, super::Error> {
+        let buckets = try_join_all(buckets.map(|bucket| {
+            let mut connection = self.connection();
+            async move {
+                connection
+                    .hgetall::<_, HashMap<String, u64>>(client_request_stats_key(bucket))
+                    .await
+                    .context(ReadFromRedisSnafu)
+            }
+        }))
+        .await?;
+
+        let mut result = HashMap::<String, ClientRequestCounts>::new();
+        for (field, value) in buckets.into_iter().flatten() {
+            let (user, counts) =
+                if let Some(user) = field.strip_prefix(CLIENT_REQUESTS_FIELD_PREFIX) {
+                    (
+                        user,
+                        ClientRequestCounts {
+                            requests: value,
+                            header_bytes: 0,
+                        },
+                    )
+                } else if let Some(user) = field.strip_prefix(CLIENT_HEADER_BYTES_FIELD_PREFIX) {
+                    (
+                        user,
+                        ClientRequestCounts {
+                            requests: 0,
+                            header_bytes: value,
+                        },
+                    )
+                } else {
+                    continue;
+                };
+            *result.entry(user.to_owned()).or_default() += counts;
+        }
+
+        Ok(result)
+    }
+
+    #[instrument(skip(self))]
+    async fn record_handed_over_query(
+        &self,
+        bucket: u64,
+        user: &str,
+        cluster_group: &str,
+        max_users: u64,
+        ttl: Duration,
+    ) -> Result<(), super::Error> {
+        let _: () = self
+            .record_handed_over_query_script
+            .key(handed_over_queries_key(bucket))
+            .arg(user)
+            .arg(cluster_group)
+            .arg(max_users)
+            .arg(OTHER_USERS)
+            // The bucket is only complete at its end, so keep it one bucket longer
+            .arg((query_chargeback::bucket_count(ttl) + 1) * QUERY_CHARGEBACK_BUCKET_SIZE.as_secs())
+            .invoke_async(&mut self.connection())
+            .await
+            .context(ExecuteRecordHandedOverQueryScriptSnafu)?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_handed_over_queries_before(&self, _bucket: u64) -> Result<(), super::Error> {
+        // Nothing to do, as the buckets expire on their own
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn load_handed_over_query_counts(
+        &self,
+        buckets: RangeInclusive<u64>,
+    ) -> Result<HashMap<(String, String), u64>, super::Error> {
+        let buckets = try_join_all(buckets.map(|bucket| {
+            let mut connection = self.connection();
+            async move {
+                connection
+                    .hgetall::<_, HashMap<String, u64>>(handed_over_queries_key(bucket))
+                    .await
+                    .context(ReadFromRedisSnafu)
+            }
+        }))
+        .await?;
+
+        let mut result = HashMap::<(String, String), u64>::new();
+        for (field, queries) in buckets.into_iter().flatten() {
+            // Users are sanitized and therefore never contain a slash, cluster groups might
+            let Some((user, cluster_group)) = field
+                .strip_prefix(HANDED_OVER_QUERIES_FIELD_PREFIX)
+                .and_then(|field| field.split_once('/'))
+            else {
+                continue;
+            };
+            *result
+                .entry((user.to_owned(), cluster_group.to_owned()))
+                .or_default() += queries;
+        }
+
+        Ok(result)
+    }
 }
 
 impl<R> RedisPersistence<R>
@@ -497,6 +1190,73 @@ where
         self.connection.clone()
     }
 
+    /// Returns the stored queued query together with its serialized value, so that it can be replaced using
+    /// [`Self::replace_unchanged_queued_query`].
+    async fn load_queued_query_with_value(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Vec<u8>, QueuedQuery)>, super::Error> {
+        let value: Option<Vec<u8>> = self
+            .connection()
+            .get(key)
+            .await
+            .context(ReadFromRedisSnafu)?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let queued_query = deserialize_queued_query(&value).context(DeserializeFromBinarySnafu)?;
+
+        Ok(Some((value, queued_query)))
+    }
+
+    /// Atomically replaces the stored queued query, in case its serialized value is still `current`. Returns whether
+    /// the queued query was replaced.
+    async fn replace_unchanged_queued_query(
+        &self,
+        key: &str,
+        current: &[u8],
+        queued_query: &QueuedQuery,
+    ) -> Result<bool, super::Error> {
+        let value = bincode::serialize(queued_query).context(SerializeToBinarySnafu)?;
+        let response: u8 = self
+            .replace_unchanged_script
+            .key(key)
+            .arg(current)
+            .arg(value)
+            .invoke_async(&mut self.connection())
+            .instrument(debug_span!("invoking replace-unchanged lua script"))
+            .await
+            .context(ExecuteCASScriptSnafu)?;
+
+        match response {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => InvalidCASScriptResponseSnafu { response }.fail()?,
+        }
+    }
+
+    /// Stores the query, replacing any query stored with the same id.
+    async fn set_query(&self, query: &TrinoQuery) -> Result<(), super::Error> {
+        let key = query_key(&query.id);
+        let value = bincode::serialize(query).context(SerializeToBinarySnafu)?;
+        let _: () = self
+            .connection()
+            .set(key, value)
+            .await
+            .context(WriteToRedisSnafu)?;
+
+        Ok(())
+    }
+
+    /// Connection for the reads done while routing queries, which falls back to the shared connection in case no
+    /// dedicated one is configured.
+    fn routing_connection(&self) -> R {
+        self.routing_connection
+            .as_ref()
+            .unwrap_or(&self.connection)
+            .clone()
+    }
+
     #[instrument(skip(self))]
     async fn delete_queued_queries_not_accessed_after_for_cluster_group(
         &self,
@@ -509,7 +1269,10 @@ where
         if let Ok(mut queued) = connection.sscan(queued_query_set_name(cluster_group)).await {
             // TODO: Await `load_queued_query` in parallel (if possible) or add them to a Vec to bulk-delete afterwards
             while let Some(key) = queued.next_item().await {
-                let queued_query = self.load_queued_query(&key).await?;
+                let Some(queued_query) = self.load_queued_query(&key).await? else {
+                    // The queued query was removed in the meantime
+                    continue;
+                };
                 if &queued_query.last_accessed < not_accessed_after {
                     self.remove_queued_query(&queued_query).await?;
                     removed += 1;
@@ -533,6 +1296,9 @@ fn query_key(query_id: &TrinoQueryId) -> &str {
     query_id
 }
 
+/// Matches the keys of [`query_key`], which start with the date the query was created at, e.g. `20231208_`.
+const QUERY_KEY_PATTERN: &str = "[0-9][0-9][0-9][0-9][0-9][0-9][0-9][0-9]_*";
+
 /// trino-lb query ids will always start with `trino_lb_20231208` and will therefore be unique.
 fn queued_query_key(query_id: &TrinoLbQueryId) -> &str {
     query_id
@@ -546,8 +1312,217 @@ fn cluster_query_counter_key(cluster: &TrinoClusterName) -> String {
     format!("{cluster}_query_count")
 }
 
-fn cluster_state_key(cluster: &TrinoClusterName) -> String {
-    format!("{cluster}_state")
+fn cluster_blocked_query_counter_key(cluster: &TrinoClusterName) -> String {
+    format!("{cluster}_blocked_query_count")
+}
+
+const CLUSTER_STATE_KEY_SUFFIX: &str = "_state";
+
+fn cluster_state_key(cluster: &str) -> String {
+    format!("{cluster}{CLUSTER_STATE_KEY_SUFFIX}")
+}
+
+fn idempotency_key_key(idempotency_key: &str) -> String {
+    format!("idempotency-{idempotency_key}")
+}
+
+fn query_runtime_key(query_fingerprint: &str) -> String {
+    format!("query-runtime-{query_fingerprint}")
+}
+
+/// [`QueuedQuery`] as stored by trino-lb versions before [`QueuedQuery::routing_reason`] was added.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct QueuedQueryWithoutRoutingReason {
+    id: TrinoLbQueryId,
+    query: String,
+    #[serde(with = "http_serde::header_map")]
+    headers: http::HeaderMap,
+    creation_time: SystemTime,
+    last_accessed: SystemTime,
+    cluster_group: String,
+}
+
+impl From<QueuedQueryWithoutRoutingReason> for QueuedQuery {
+    fn from(queued_query: QueuedQueryWithoutRoutingReason) -> Self {
+        Self {
+            id: queued_query.id,
+            query: queued_query.query,
+            headers: queued_query.headers,
+            creation_time: queued_query.creation_time,
+            last_accessed: queued_query.last_accessed,
+            cluster_group: queued_query.cluster_group,
+            routing_reason: None,
+        }
+    }
+}
+
+/// As bincode does not support adding fields, queued queries stored by older trino-lb versions are read using the
+/// previous format, so that they are not lost during an update.
+fn deserialize_queued_query(value: &[u8]) -> Result<QueuedQuery, bincode::Error> {
+    bincode::deserialize(value).or_else(|error| {
+        bincode::deserialize::<QueuedQueryWithoutRoutingReason>(value)
+            .map(Into::into)
+            .map_err(|_| error)
+    })
+}
+
+/// [`TrinoQuery`] as stored by trino-lb versions before [`TrinoQuery::query_fingerprint`] was added.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct TrinoQueryWithoutFingerprint {
+    id: TrinoQueryId,
+    trino_cluster: TrinoClusterName,
+    trino_endpoint: Url,
+    creation_time: SystemTime,
+    delivered_time: SystemTime,
+}
+
+impl From<TrinoQueryWithoutFingerprint> for TrinoQuery {
+    fn from(query: TrinoQueryWithoutFingerprint) -> Self {
+        Self {
+            id: query.id,
+            trino_cluster: query.trino_cluster,
+            trino_endpoint: query.trino_endpoint,
+            creation_time: query.creation_time,
+            delivered_time: query.delivered_time,
+            query_fingerprint: None,
+            next_uri_path: None,
+            previous_next_uri_path: None,
+        }
+    }
+}
+
+/// [`TrinoQuery`] as stored by trino-lb versions before [`TrinoQuery::next_uri_path`] and
+/// [`TrinoQuery::previous_next_uri_path`] were added.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct TrinoQueryWithoutNextUriPaths {
+    id: TrinoQueryId,
+    trino_cluster: TrinoClusterName,
+    trino_endpoint: Url,
+    creation_time: SystemTime,
+    delivered_time: SystemTime,
+    query_fingerprint: Option<String>,
+}
+
+impl From<TrinoQueryWithoutNextUriPaths> for TrinoQuery {
+    fn from(query: TrinoQueryWithoutNextUriPaths) -> Self {
+        Self {
+            id: query.id,
+            trino_cluster: query.trino_cluster,
+            trino_endpoint: query.trino_endpoint,
+            creation_time: query.creation_time,
+            delivered_time: query.delivered_time,
+            query_fingerprint: query.query_fingerprint,
+            next_uri_path: None,
+            previous_next_uri_path: None,
+        }
+    }
+}
+
+/// Same as [`deserialize_queued_query`], but for the queries running on a Trino cluster, so that clients can keep
+/// polling them during an update. The formats are tried from the newest to the oldest one, as bincode ignores
+/// trailing bytes, but fails in case the value ends before all fields are read.
+fn deserialize_query(value: &[u8]) -> Result<TrinoQuery, bincode::Error> {
+    bincode::deserialize(value).or_else(|error| {
+        bincode::deserialize::<TrinoQueryWithoutNextUriPaths>(value)
+            .map(Into::into)
+            .or_else(|_| {
+                bincode::deserialize::<TrinoQueryWithoutFingerprint>(value).map(Into::into)
+            })
+            .map_err(|_| error)
+    })
+}
+
+fn client_request_stats_key(bucket: u64) -> String {
+    format!("client-request-stats-{bucket}")
+}
+
+/// Prefix of the hash fields storing the request count of a user.
+const CLIENT_REQUESTS_FIELD_PREFIX: &str = "requests/";
+
+/// Prefix of the hash fields storing the summed up header bytes of a user.
+const CLIENT_HEADER_BYTES_FIELD_PREFIX: &str = "header-bytes/";
+
+/// Stores the request counts of all users of a bucket in a single hash, so that this works with Redis clusters as well.
+/// Every user has two fields, so a bucket tracks `max_users` users once it has `2 * max_users` fields.
+fn record_client_request_script() -> Script {
+    Script::new(
+        r"
+    local user = ARGV[1];
+    if redis.call('HEXISTS', KEYS[1], 'requests/' .. user) == 0
+        and redis.call('HLEN', KEYS[1]) >= 2 * tonumber(ARGV[4]) then
+        user = ARGV[5];
+        end;
+    redis.call('HINCRBY', KEYS[1], 'requests/' .. user, ARGV[2]);
+    redis.call('HINCRBY', KEYS[1], 'header-bytes/' .. user, ARGV[3]);
+    redis.call('EXPIRE', KEYS[1], ARGV[6]);
+    ",
+    )
+}
+
+fn handed_over_queries_key(bucket: u64) -> String {
+    format!("handed-over-queries-{bucket}")
+}
+
+/// Prefix of the hash fields storing the number of queries of a user handed over to a cluster group, followed by
+/// `<user>/<cluster group>`.
+const HANDED_OVER_QUERIES_FIELD_PREFIX: &str = "queries/";
+
+/// Stores the query counts of all users of a bucket in a single hash, so that this works with Redis clusters as well.
+/// As users have a field per cluster group, the tracked users are marked with a separate field and counted in the
+/// `user-count` field.
+fn record_handed_over_query_script() -> Script {
+    Script::new(
+        r"
+    local user = ARGV[1];
+    if redis.call('HSETNX', KEYS[1], 'users/' .. user, 1) == 1 then
+        if redis.call('HINCRBY', KEYS[1], 'user-count', 1) > tonumber(ARGV[3]) then
+            redis.call('HDEL', KEYS[1], 'users/' .. user);
+            redis.call('HINCRBY', KEYS[1], 'user-count', -1);
+            user = ARGV[4];
+            end;
+        end;
+    redis.call('HINCRBY', KEYS[1], 'queries/' .. user .. '/' .. ARGV[2], 1);
+    redis.call('EXPIRE', KEYS[1], ARGV[5]);
+    ",
+    )
+}
+
+/// The leading bytes of the serialized [`TrinoQuery`], which consist of its id and cluster. bincode serializes the
+/// fields of a struct one after another, so this is the same as serializing both fields on their own.
+fn query_owner(query: &TrinoQuery) -> Result<Vec<u8>, Error> {
+    bincode::serialize(&(&query.id, &query.trino_cluster)).context(SerializeToBinarySnafu)
+}
+
+/// Stores the query (`ARGV[1]`), unless a query with the same id is stored for a different cluster. Whether the stored
+/// query belongs to the same cluster is checked by comparing its leading bytes with [`query_owner`] (`ARGV[2]`).
+fn store_query_script() -> Script {
+    Script::new(
+        r"
+    local current = redis.call('GET', KEYS[1]);
+    if current and string.sub(current, 1, #ARGV[2]) ~= ARGV[2] then
+        return 0;
+        end;
+    redis.call('SET', KEYS[1], ARGV[1]);
+    return 1;
+    ",
+    )
+}
+
+/// Replaces the value (`ARGV[2]`) in case the current value is `ARGV[1]`. Other than [`compare_and_set_script`], missing
+/// entries are not created.
+fn replace_unchanged_script() -> Script {
+    Script::new(
+        r"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('SET', KEYS[1], ARGV[2]);
+        return 1;
+        end;
+    return 0;
+    ",
+    )
 }
 
 fn compare_and_set_script() -> Script {
@@ -567,3 +1542,298 @@ fn compare_and_set_script() -> Script {
     ",
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use redis::{aio::ConnectionLike, Arg, Cmd, ErrorKind, Pipeline, RedisFuture, Value};
+
+    use super::*;
+
+    /// Connection answering every command with the same response, so that we can test how we handle them. Lua scripts
+    /// can be answered with a different response using `script_response`.
+    #[derive(Clone)]
+    struct MockConnection {
+        response: Result<Value, (ErrorKind, &'static str)>,
+        script_response: Option<Value>,
+    }
+
+    impl ConnectionLike for MockConnection {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            let is_script = matches!(cmd.args_iter().next(), Some(Arg::Simple(b"EVALSHA")));
+            let response = match &self.script_response {
+                Some(script_response) if is_script => Ok(script_response.clone()),
+                _ => self.response.clone().map_err(RedisError::from),
+            };
+            Box::pin(async move { response })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _cmd: &'a Pipeline,
+            _offset: usize,
+            _count: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            let response = self
+                .response
+                .clone()
+                .map(|value| vec![value])
+                .map_err(RedisError::from);
+            Box::pin(async move { response })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn mock_persistence(
+        response: Result<Value, (ErrorKind, &'static str)>,
+    ) -> RedisPersistence<MockConnection> {
+        RedisPersistence {
+            connection: MockConnection {
+                response,
+                script_response: None,
+            },
+            routing_connection: None,
+            compare_and_set_script: compare_and_set_script(),
+            replace_unchanged_script: replace_unchanged_script(),
+            store_query_script: store_query_script(),
+            record_client_request_script: record_client_request_script(),
+            record_handed_over_query_script: record_handed_over_query_script(),
+            cluster_groups: vec!["s".to_owned()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_queued_query_count() {
+        let persistence = mock_persistence(Ok(Value::Int(42)));
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 42);
+
+        let persistence = mock_persistence(Ok(Value::Nil));
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dedicated_routing_connection() {
+        let mut persistence = mock_persistence(Ok(Value::Int(1)));
+        persistence.routing_connection = Some(MockConnection {
+            response: Ok(Value::Int(2)),
+            script_response: None,
+        });
+        let cluster = "trino-s-1".to_owned();
+
+        // Routing reads use the dedicated connection, everything else the shared one
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            2
+        );
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_last_query_count_fetcher_update_does_not_overflow() {
+        let persistence =
+            mock_persistence(Ok(Value::BulkString(u64::MAX.to_string().into_bytes())));
+
+        let error = persistence
+            .get_last_query_count_fetcher_update()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::Error::RedisError {
+                    source: Error::LastQueryCountFetcherUpdateOutOfRange { millis: u64::MAX }
+                }
+            ),
+            "Unexpected error {error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_queued_query_count_redis_error() {
+        let persistence = mock_persistence(Err((ErrorKind::IoError, "connection reset")));
+
+        let error = persistence.get_queued_query_count("s").await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::Error::RedisError {
+                    source: Error::ReadFromRedis { .. }
+                }
+            ),
+            "Expected ReadFromRedis error, got {error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_move_queued_query_gives_up_under_contention() {
+        let queued_query = QueuedQuery::new_from(
+            "select 42".to_owned(),
+            http::HeaderMap::new(),
+            "parking".to_owned(),
+            None,
+        );
+        let mut persistence = mock_persistence(Ok(Value::BulkString(
+            bincode::serialize(&queued_query).unwrap(),
+        )));
+        // The queued query is changed by someone else before every attempt to replace it
+        persistence.connection.script_response = Some(Value::Int(0));
+
+        let error = persistence
+            .move_queued_query(&queued_query, "s")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &error,
+                crate::Error::RedisError {
+                    source: Error::MoveQueuedQueryContended { attempts, .. }
+                } if *attempts == MOVE_QUEUED_QUERY_MAX_ATTEMPTS
+            ),
+            "Expected MoveQueuedQueryContended error, got {error:?}"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_queued_query() {
+        let queued_query = QueuedQuery::new_from(
+            "select 42".to_owned(),
+            http::HeaderMap::new(),
+            "s".to_owned(),
+            Some("routingFallback".to_owned()),
+        );
+        let value = bincode::serialize(&queued_query).unwrap();
+        let deserialized = deserialize_queued_query(&value).unwrap();
+        assert_eq!(deserialized.id, queued_query.id);
+        assert_eq!(
+            deserialized.routing_reason.as_deref(),
+            Some("routingFallback")
+        );
+
+        // Stored by an older trino-lb version
+        let legacy = QueuedQueryWithoutRoutingReason {
+            id: queued_query.id.clone(),
+            query: queued_query.query.clone(),
+            headers: queued_query.headers.clone(),
+            creation_time: queued_query.creation_time,
+            last_accessed: queued_query.last_accessed,
+            cluster_group: queued_query.cluster_group.clone(),
+        };
+        let value = bincode::serialize(&legacy).unwrap();
+        let deserialized = deserialize_queued_query(&value).unwrap();
+        assert_eq!(deserialized.id, queued_query.id);
+        assert_eq!(deserialized.cluster_group, "s");
+        assert_eq!(deserialized.routing_reason, None);
+
+        assert!(deserialize_queued_query(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_query() {
+        let query = TrinoQuery::new_from(
+            "trino-s-1".to_owned(),
+            "20240101_120000_00001_abcde".to_owned(),
+            "http://trino.example.com:8080".parse().unwrap(),
+            SystemTime::now(),
+            SystemTime::now(),
+            Some("fingerprint".to_owned()),
+            Some("/v1/statement/executing/x/y/1".to_owned()),
+        );
+        let value = bincode::serialize(&query).unwrap();
+        let deserialized = deserialize_query(&value).unwrap();
+        assert_eq!(deserialized.id, query.id);
+        assert_eq!(
+            deserialized.query_fingerprint.as_deref(),
+            Some("fingerprint")
+        );
+        assert_eq!(
+            deserialized.next_uri_path.as_deref(),
+            Some("/v1/statement/executing/x/y/1")
+        );
+
+        // Stored by a trino-lb version not tracking the nextUri
+        let legacy = TrinoQueryWithoutNextUriPaths {
+            id: query.id.clone(),
+            trino_cluster: query.trino_cluster.clone(),
+            trino_endpoint: query.trino_endpoint.clone(),
+            creation_time: query.creation_time,
+            delivered_time: query.delivered_time,
+            query_fingerprint: query.query_fingerprint.clone(),
+        };
+        let value = bincode::serialize(&legacy).unwrap();
+        let deserialized = deserialize_query(&value).unwrap();
+        assert_eq!(deserialized.id, query.id);
+        assert_eq!(
+            deserialized.query_fingerprint.as_deref(),
+            Some("fingerprint")
+        );
+        assert_eq!(deserialized.next_uri_path, None);
+        assert_eq!(deserialized.previous_next_uri_path, None);
+        assert!(deserialized.is_valid_statement_path("/v1/statement/executing/x/z/2"));
+
+        // Stored by a trino-lb version not recording query runtimes
+        let legacy = TrinoQueryWithoutFingerprint {
+            id: query.id.clone(),
+            trino_cluster: query.trino_cluster.clone(),
+            trino_endpoint: query.trino_endpoint.clone(),
+            creation_time: query.creation_time,
+            delivered_time: query.delivered_time,
+        };
+        let value = bincode::serialize(&legacy).unwrap();
+        let deserialized = deserialize_query(&value).unwrap();
+        assert_eq!(deserialized.id, query.id);
+        assert_eq!(deserialized.trino_cluster, "trino-s-1");
+        assert_eq!(deserialized.trino_endpoint, query.trino_endpoint);
+        assert_eq!(deserialized.query_fingerprint, None);
+        assert_eq!(deserialized.next_uri_path, None);
+
+        assert!(deserialize_query(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_query_owner_is_prefix_of_serialized_query() {
+        let query = TrinoQuery::new_from(
+            "trino-s-1".to_owned(),
+            "20240101_120000_00001_abcde".to_owned(),
+            "http://trino.example.com:8080".parse().unwrap(),
+            SystemTime::now(),
+            SystemTime::now(),
+            Some("fingerprint".to_owned()),
+            Some("/v1/statement/executing/x/y/1".to_owned()),
+        );
+        let value = bincode::serialize(&query).unwrap();
+        assert!(value.starts_with(&query_owner(&query).unwrap()));
+
+        let other_cluster = TrinoQuery {
+            trino_cluster: "trino-s-2".to_owned(),
+            ..query.clone()
+        };
+        assert!(!value.starts_with(&query_owner(&other_cluster).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_store_query_with_colliding_id() {
+        let query = TrinoQuery::new_from(
+            "trino-s-1".to_owned(),
+            "20240101_120000_00001_abcde".to_owned(),
+            "http://trino.example.com:8080".parse().unwrap(),
+            SystemTime::now(),
+            SystemTime::now(),
+            None,
+            None,
+        );
+
+        // The script refuses to store the query
+        let error = mock_persistence(Ok(Value::Int(0)))
+            .store_query(query.clone())
+            .await
+            .unwrap_err();
+        assert!(error.is_query_id_collision());
+
+        mock_persistence(Ok(Value::Int(1)))
+            .store_query(query)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+//! Provides the git commit and build timestamp for the `build_info` metric.
+
+use std::process::Command;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+fn main() {
+    // Builds without a git checkout (e.g. the Docker build) can pass the commit explicitly.
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+
+    let git_commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(git_commit_from_repo)
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    // Respect https://reproducible-builds.org/specs/source-date-epoch/
+    let build_timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| DateTime::<Utc>::from_timestamp(epoch, 0))
+        .unwrap_or_else(Utc::now);
+
+    println!("cargo:rustc-env=TRINO_LB_GIT_COMMIT={git_commit}");
+    println!(
+        "cargo:rustc-env=TRINO_LB_BUILD_TIMESTAMP={}",
+        build_timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+}
+
+fn git_commit_from_repo() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    (!commit.is_empty()).then_some(commit)
+}
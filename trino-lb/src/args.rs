@@ -1,12 +1,23 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::migrate::MigrateArgs;
 
 /// Loadbalancer in front of Stackable Trino clusters
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
     /// Config file that contains needed information to start trino-lb.
-    #[arg(short, long)]
-    pub config_file: PathBuf,
+    #[arg(short, long, required = true)]
+    pub config_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Migrate the state of trino-lb from one persistence to another.
+    Migrate(MigrateArgs),
 }
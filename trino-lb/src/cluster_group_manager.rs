@@ -1,24 +1,46 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, HashSet},
     fmt::Debug,
+    future::Future,
     sync::Arc,
+    time::Duration,
 };
 
 use axum::{body::Body, response::IntoResponse, Json};
+use chrono::Utc;
 use futures::future::try_join_all;
 use http::{HeaderMap, StatusCode};
 use reqwest::Client;
-use snafu::{OptionExt, ResultExt, Snafu};
-use tracing::{debug, instrument};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::time;
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 use trino_lb_core::{
-    config::Config, sanitization::Sanitize, trino_api::TrinoQueryApiResponse,
+    config::Config,
+    sanitization::Sanitize,
+    trino_api::{self, TrinoQueryApiResponse},
+    trino_cluster::ClusterState,
     trino_query::TrinoQuery,
+    TrinoClusterName,
 };
-use trino_lb_persistence::{Persistence, PersistenceImplementation};
+use trino_lb_persistence::{query_count_allows_increment, Persistence, PersistenceImplementation};
 use url::Url;
 
-use crate::tracing::add_current_context_to_client_request;
+use crate::{
+    max_running_queries::{self, MaxRunningQueries, MAX_RUNNING_QUERIES_REFRESH_INTERVAL},
+    persisted_state_cache::PersistedStateCache,
+    tracing::add_current_context_to_client_request,
+};
+
+/// How often polling the query state is attempted in case Trino responds with one of the
+/// `retryableTrinoStatusCodes`.
+const TRINO_POLL_ATTEMPTS: u32 = 3;
+const TRINO_POLL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Delay before retrying a failed persistence read while choosing a cluster, see
+/// `trinoLb.routingPersistenceReadRetries`. It is short, as the client is waiting for the query to be handed over.
+const ROUTING_PERSISTENCE_READ_RETRY_DELAY: Duration = Duration::from_millis(20);
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -40,6 +62,15 @@ pub enum Error {
     #[snafu(display("Configuration error: A specific Trino cluster can only be part of a single clusterGroup. Please make sure the Trino cluster {cluster_name:?} only is part of a single clusterGroup."))]
     ConfigErrorTrinoClusterInMultipleClusterGroups { cluster_name: String },
 
+    #[snafu(display("Configuration error: The externalAddress {external_address} points to the Trino cluster {cluster_name:?}. Clients would be sent to the Trino cluster instead of trino-lb (or trino-lb would forward the queries to itself), so please set externalAddress to the address trino-lb is reachable at."))]
+    ConfigErrorExternalAddressPointsToTrinoCluster {
+        external_address: Url,
+        cluster_name: String,
+    },
+
+    #[snafu(display("Configuration error: Invalid maxRunningQueriesSchedule"))]
+    ConfigErrorInvalidMaxRunningQueriesSchedule { source: max_running_queries::Error },
+
     #[snafu(display(
         "Failed to get the query counter on the clusters of the group {cluster_group:?}"
     ))]
@@ -66,17 +97,100 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// Whether sending a query to Trino failed for sure before Trino could receive it, e.g. because the connection
+    /// could not be established. Other errors (such as timeouts or undecodable responses) leave it open whether the
+    /// query is running on Trino.
+    pub fn is_query_not_sent(&self) -> bool {
+        match self {
+            Error::ConstructTrinoApiPath { .. } => true,
+            Error::ContactTrinoPostQuery { source } => source.is_connect() || source.is_builder(),
+            _ => false,
+        }
+    }
+}
+
 pub struct ClusterGroupManager {
     groups: HashMap<String, Vec<TrinoCluster>>,
+    max_running_queries: HashMap<String, Arc<MaxRunningQueries>>,
+    /// Cluster groups that have `rejectWhenNoReadyCluster` enabled, mapped to whether the autoscaler can start their
+    /// clusters.
+    reject_when_no_ready_cluster: HashMap<String, bool>,
     persistence: Arc<PersistenceImplementation>,
     http_client: Client,
+    retryable_trino_status_codes: HashSet<u16>,
+    routing_persistence_read_retries: u64,
+    /// Which query counter the clusters use, see [`Self::query_counter_of`].
+    query_counters: QueryCounters,
+    /// Clusters excluded from routing using the admin API.
+    routing_excluded_clusters: Arc<PersistedStateCache<Vec<TrinoClusterName>>>,
+}
+
+/// Knows which query counter every cluster uses. Clusters only use the query counter of another cluster in case
+/// `trinoLb.shareQueryCountersOfSameEndpoint` is enabled and they point to the same endpoint. In this case only the
+/// shared query counter is kept up to date, so every read of a query counter needs to go through
+/// [`Self::query_counter_of`].
+#[derive(Clone, Debug, Default)]
+pub struct QueryCounters {
+    shared: HashMap<TrinoClusterName, TrinoClusterName>,
+}
+
+impl QueryCounters {
+    pub fn new(config: &Config) -> Self {
+        if !config.trino_lb.share_query_counters_of_same_endpoint {
+            return Self::default();
+        }
+
+        Self {
+            shared: shared_query_counters(
+                config
+                    .trino_cluster_groups
+                    .values()
+                    .flat_map(|group| &group.trino_clusters)
+                    .map(|cluster| (&cluster.name, cluster.internal_endpoint())),
+            ),
+        }
+    }
+
+    /// Name of the cluster whose query counter is used for the given cluster.
+    pub fn query_counter_of<'a>(&'a self, cluster: &'a TrinoClusterName) -> &'a TrinoClusterName {
+        self.shared.get(cluster).unwrap_or(cluster)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct TrinoCluster {
     pub name: String,
-    pub max_running_queries: u64,
+    /// The endpoint trino-lb uses to communicate with the cluster.
     pub endpoint: Url,
+    /// The endpoint used in links pointing to the cluster, e.g. the `infoUri` of queries.
+    pub external_endpoint: Url,
+    pub overflow: bool,
+    pub weight: u64,
+}
+
+/// The cluster [`ClusterGroupManager::try_find_best_cluster_for_group`] picked, together with the numbers the decision
+/// was based on.
+#[derive(Clone, Debug)]
+pub struct BestCluster<'a> {
+    pub cluster: &'a TrinoCluster,
+
+    /// The query count of the cluster at the time it was picked.
+    pub query_count: u64,
+
+    /// The `maxRunningQueries` that is currently effective for the cluster.
+    pub max_running_queries: u64,
+}
+
+impl BestCluster<'_> {
+    /// How full the cluster was at the time it was picked, in percent of `maxRunningQueries`.
+    pub fn utilization_percent(&self) -> f64 {
+        if self.max_running_queries == 0 {
+            return 100.0;
+        }
+
+        self.query_count as f64 / self.max_running_queries as f64 * 100.0
+    }
 }
 
 pub enum SendToTrinoResponse {
@@ -112,9 +226,22 @@ impl ClusterGroupManager {
         ignore_certs: bool,
     ) -> Result<Self, Error> {
         let mut clusters_seen = HashSet::new();
+        let mut endpoints_seen = HashMap::new();
+        let external_address = normalized_endpoint(&config.trino_lb.external_address);
 
         let mut groups = HashMap::new();
+        let max_running_queries =
+            max_running_queries::of_cluster_groups(&config.trino_cluster_groups)
+                .context(ConfigErrorInvalidMaxRunningQueriesScheduleSnafu)?;
+        let mut reject_when_no_ready_cluster = HashMap::new();
         for (group_name, group_config) in &config.trino_cluster_groups {
+            if group_config.reject_when_no_ready_cluster {
+                reject_when_no_ready_cluster.insert(
+                    group_name.clone(),
+                    config.cluster_autoscaler.is_some() && group_config.autoscaling.is_some(),
+                );
+            }
+
             let mut group = Vec::with_capacity(group_config.trino_clusters.len());
             for cluster_config in &group_config.trino_clusters {
                 let cluster_name = cluster_config.name.clone();
@@ -125,37 +252,157 @@ impl ClusterGroupManager {
                     .fail()?;
                 }
 
+                // The nextUris we hand out would point to the Trino cluster, so clients would bypass trino-lb or poll
+                // in circles. Clients might reach the cluster using its external endpoint as well.
+                let endpoint = normalized_endpoint(cluster_config.internal_endpoint());
+                ensure!(
+                    endpoint != external_address
+                        && normalized_endpoint(cluster_config.external_endpoint())
+                            != external_address,
+                    ConfigErrorExternalAddressPointsToTrinoClusterSnafu {
+                        external_address: config.trino_lb.external_address.clone(),
+                        cluster_name,
+                    }
+                );
+
+                // Not an error, as e.g. moving a cluster between cluster groups might require both entries for a while
+                if let Some(other_cluster) = endpoints_seen.insert(endpoint, cluster_name.clone()) {
+                    if config.trino_lb.share_query_counters_of_same_endpoint {
+                        info!(
+                            cluster = cluster_name,
+                            other_cluster,
+                            endpoint = %cluster_config.internal_endpoint(),
+                            "The Trino clusters point to the same endpoint, so they share a query counter"
+                        );
+                    } else {
+                        warn!(
+                            cluster = cluster_name,
+                            other_cluster,
+                            endpoint = %cluster_config.internal_endpoint(),
+                            "The Trino clusters point to the same endpoint. This counts the capacity of the coordinator twice, \
+                            so it will get more queries than it should. Consider enabling `trinoLb.shareQueryCountersOfSameEndpoint`"
+                        );
+                    }
+                }
+
                 group.push(TrinoCluster {
                     name: cluster_name,
-                    max_running_queries: group_config.max_running_queries,
-                    endpoint: cluster_config.endpoint.clone(),
+                    endpoint: cluster_config.internal_endpoint().clone(),
+                    external_endpoint: cluster_config.external_endpoint().clone(),
+                    overflow: cluster_config.overflow,
+                    weight: cluster_config.weight,
                 })
             }
             groups.insert(group_name.clone(), group);
         }
 
         let http_client = reqwest::Client::builder()
+            .user_agent(&config.trino_lb.user_agent)
             .danger_accept_invalid_certs(ignore_certs)
             .build()
             .context(CreateHttpClientSnafu)?;
 
+        let routing_excluded_clusters = Arc::new(PersistedStateCache::new(
+            "routing excluded clusters",
+            Arc::clone(&persistence),
+            |persistence| Box::pin(persistence.list_routing_excluded_clusters()),
+        ));
+
         Ok(Self {
             groups,
+            max_running_queries,
+            reject_when_no_ready_cluster,
             persistence,
             http_client,
+            retryable_trino_status_codes: config.trino_lb.retryable_trino_status_codes.clone(),
+            routing_persistence_read_retries: config.trino_lb.routing_persistence_read_retries,
+            query_counters: QueryCounters::new(config),
+            routing_excluded_clusters,
         })
     }
 
+    /// Name of the cluster whose query counter is used for the given cluster. This only differs from the given
+    /// cluster in case `trinoLb.shareQueryCountersOfSameEndpoint` is enabled and other clusters point to the same
+    /// endpoint.
+    pub fn query_counter_of<'a>(&'a self, cluster: &'a TrinoClusterName) -> &'a TrinoClusterName {
+        self.query_counters.query_counter_of(cluster)
+    }
+
+    /// The [`MaxRunningQueries`] of all cluster groups, which are kept up to date by
+    /// [`Self::start_max_running_queries_refresh_loop`].
+    pub fn max_running_queries(&self) -> &HashMap<String, Arc<MaxRunningQueries>> {
+        &self.max_running_queries
+    }
+
+    /// Periodically re-calculates the effective `maxRunningQueries` of all cluster groups that have a
+    /// `maxRunningQueriesSchedule`, so that handing over queries only needs to read the cached value.
+    pub fn start_max_running_queries_refresh_loop(&self) {
+        let scheduled = self
+            .max_running_queries
+            .values()
+            .filter(|m| m.is_scheduled())
+            .cloned()
+            .collect::<Vec<_>>();
+        if scheduled.is_empty() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(MAX_RUNNING_QUERIES_REFRESH_INTERVAL);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+
+                let now = Utc::now();
+                for max_running_queries in &scheduled {
+                    max_running_queries.refresh(&now);
+                }
+            }
+        });
+    }
+
+    /// Returns `true` in case the cluster group has `rejectWhenNoReadyCluster` enabled and none of its clusters is ready
+    /// or can become ready, so that queuing queries would be pointless.
+    #[instrument(skip(self))]
+    pub async fn rejects_queries(&self, cluster_group: &str) -> Result<bool, Error> {
+        let Some(autoscaled) = self.reject_when_no_ready_cluster.get(cluster_group) else {
+            return Ok(false);
+        };
+        let clusters = self
+            .groups
+            .get(cluster_group)
+            .context(ClusterGroupNotFoundSnafu {
+                group: cluster_group.to_string(),
+            })?;
+        if clusters.is_empty() {
+            return Ok(false);
+        }
+
+        let cluster_states = try_join_all(
+            clusters
+                .iter()
+                .map(|c| self.persistence.get_cluster_state(&c.name)),
+        )
+        .await
+        .context(ReadCurrentClusterStateForClusterGroupFromPersistenceSnafu { cluster_group })?;
+
+        Ok(!cluster_states
+            .iter()
+            .any(|state| can_become_ready(state, *autoscaled)))
+    }
+
     #[instrument(skip(self))]
     pub async fn send_query_to_cluster(
         &self,
         query: String,
-        headers: http::HeaderMap,
+        mut headers: http::HeaderMap,
         cluster: &TrinoCluster,
     ) -> Result<SendToTrinoResponse, Error> {
-        // TODO: Enable propagation again. This is disabled, as the POST /v1/statement span runs for the whole
-        // query lifetime and let it look like the initial POST takes multiple minutes.
-        // add_current_context_to_client_request(tracing::Span::current().context(), &mut r_headers);
+        // The POST /v1/statement span runs for the whole query lifetime, as clients keep polling. Propagating it would
+        // let it look like the initial POST takes multiple minutes, so we propagate a span only covering the POST.
+        let post_span = info_span!("Send query to Trino", cluster = cluster.name);
+        add_current_context_to_client_request(post_span.context(), &mut headers);
 
         let response = self
             .http_client
@@ -168,6 +415,7 @@ impl ClusterGroupManager {
             .headers(headers)
             .body(query)
             .send()
+            .instrument(post_span)
             .await
             .context(ContactTrinoPostQuerySnafu)?;
         let headers = response.headers();
@@ -196,6 +444,8 @@ impl ClusterGroupManager {
         })
     }
 
+    /// Polls the state of a query running on Trino. Dropping the returned future aborts the request to Trino (including
+    /// pending retries), so callers should await it directly instead of spawning it.
     #[instrument(
         skip(self),
         fields(next_uri = %next_uri, headers = ?headers.sanitize())
@@ -206,13 +456,37 @@ impl ClusterGroupManager {
         mut headers: HeaderMap,
     ) -> Result<(TrinoQueryApiResponse, HeaderMap), Error> {
         add_current_context_to_client_request(tracing::Span::current().context(), &mut headers);
-        let response = self
-            .http_client
-            .get(next_uri)
-            .headers(headers)
-            .send()
-            .await
-            .context(ContactTrinoPostQuerySnafu)?;
+
+        // Polling the query state does not modify the query, so we can safely retry it
+        let mut backoff = TRINO_POLL_RETRY_BACKOFF;
+        let mut attempt = 1;
+        let response = loop {
+            let response = self
+                .http_client
+                .get(next_uri.clone())
+                .headers(headers.clone())
+                .send()
+                .await
+                .context(ContactTrinoPostQuerySnafu)?;
+
+            let status = response.status();
+            if attempt < TRINO_POLL_ATTEMPTS
+                && self.retryable_trino_status_codes.contains(&status.as_u16())
+            {
+                warn!(
+                    %status,
+                    attempt,
+                    ?backoff,
+                    "Trino responded with a retryable status code while polling the query state, retrying"
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+                continue;
+            }
+
+            break response;
+        };
         let headers = response.headers();
 
         let headers = filter_to_trino_headers(headers);
@@ -251,40 +525,74 @@ impl ClusterGroupManager {
         Ok(())
     }
 
+    /// Points the infoUri of a response of the given cluster to its external endpoint, so that users can open it in
+    /// their browser. Responses of clusters without a separate external endpoint are left untouched.
+    pub fn change_info_uri_to_external_endpoint(
+        &self,
+        cluster_name: &str,
+        trino_query_api_response: &mut TrinoQueryApiResponse,
+    ) -> Result<(), trino_api::Error> {
+        let cluster = self
+            .groups
+            .values()
+            .flatten()
+            .find(|cluster| cluster.name == cluster_name);
+        match cluster {
+            Some(cluster) if cluster.external_endpoint != cluster.endpoint => {
+                trino_query_api_response
+                    .change_info_uri_to_external_endpoint(&cluster.external_endpoint)
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Tries to find the best cluster from the specified `cluster_group`. If all clusters of the requested group have reached their
     /// configured query limit, this function returns [`None`].
+    /// Otherwise it returns the cluster together with its query count and the `maxRunningQueries` that is currently
+    /// effective for it.
     #[instrument(skip(self))]
     pub async fn try_find_best_cluster_for_group(
         &self,
         cluster_group: &str,
-    ) -> Result<Option<&TrinoCluster>, Error> {
+    ) -> Result<Option<BestCluster<'_>>, Error> {
         let clusters = self
             .groups
             .get(cluster_group)
             .context(ClusterGroupNotFoundSnafu {
                 group: cluster_group.to_string(),
             })?;
+        let max_running_queries = self
+            .max_running_queries
+            .get(cluster_group)
+            .context(ClusterGroupNotFoundSnafu {
+                group: cluster_group.to_string(),
+            })?
+            .current();
 
         let cluster_states = try_join_all(
             clusters
                 .iter()
-                .map(|c| self.persistence.get_cluster_state(&c.name)),
+                .map(|c| self.retry_routing_read(|| self.persistence.get_cluster_state(&c.name))),
         )
         .await
         .context(ReadCurrentClusterStateForClusterGroupFromPersistenceSnafu { cluster_group })?;
+        let routing_excluded_clusters = self.routing_excluded_clusters.get();
 
         let clusters = clusters
             .iter()
             .zip(cluster_states)
             .filter(|(_, state)| state.ready_to_accept_queries())
             .map(|(c, _)| c)
+            // Excluded by an operator, e.g. because the cluster is misbehaving although it is ready
+            .filter(|c| !routing_excluded_clusters.contains(&c.name))
             .collect::<Vec<_>>();
 
-        let cluster_query_counters = try_join_all(
-            clusters
-                .iter()
-                .map(|g| async { self.persistence.get_cluster_query_count(&g.name).await }),
-        )
+        let cluster_query_counters = try_join_all(clusters.iter().map(|g| {
+            self.retry_routing_read(|| {
+                self.persistence
+                    .get_cluster_query_count(self.query_counter_of(&g.name))
+            })
+        }))
         .await
         .context(GetQueryCounterForGroupSnafu { cluster_group })?;
 
@@ -295,17 +603,105 @@ impl ClusterGroupManager {
             .collect::<Vec<_>>();
         debug!(query_counters = ?debug_output, "Clusters had the following query counters");
 
-        let cluster_with_min_queries = clusters
-            .into_iter()
-            .zip(cluster_query_counters)
-            .filter(|(cluster, counter)| *counter < cluster.max_running_queries)
-            .min_by_key(|(_, counter)| *counter)
-            .map(|(c, _)| c);
+        Ok(select_cluster_with_min_queries(
+            clusters.into_iter().zip(cluster_query_counters),
+            max_running_queries,
+        )
+        .map(|(cluster, query_count)| BestCluster {
+            cluster,
+            query_count,
+            max_running_queries,
+        }))
+    }
+
+    /// Re-reads the clusters excluded from routing, so that a change made via the admin API applies right away.
+    pub async fn refresh_routing_excluded_clusters(
+        &self,
+    ) -> Result<(), trino_lb_persistence::Error> {
+        self.routing_excluded_clusters.refresh().await
+    }
+
+    /// Periodically re-reads the clusters excluded from routing, so that changes made on other trino-lb replicas are
+    /// picked up.
+    pub fn start_routing_excluded_clusters_refresh_loop(&self) {
+        self.routing_excluded_clusters.start_refresh_loop();
+    }
 
-        Ok(cluster_with_min_queries)
+    /// Returns the name of the cluster group the given cluster is part of.
+    pub fn cluster_group_of_cluster(&self, cluster_name: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(_, clusters)| clusters.iter().any(|c| c.name == cluster_name))
+            .map(|(group, _)| group.as_str())
+    }
+
+    /// Retries the given persistence read up to `trinoLb.routingPersistenceReadRetries` times, so that a single
+    /// transient failure does not fail the query. Must only be used for reads, as writes might have been applied
+    /// despite failing.
+    async fn retry_routing_read<T, F, Fut>(&self, read: F) -> Result<T, trino_lb_persistence::Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, trino_lb_persistence::Error>>,
+    {
+        let mut retry = 0;
+        loop {
+            match read().await {
+                Err(error) if retry < self.routing_persistence_read_retries => {
+                    retry += 1;
+                    warn!(
+                        ?error,
+                        retry,
+                        "Reading from the persistence failed while choosing a cluster, retrying"
+                    );
+                    time::sleep(ROUTING_PERSISTENCE_READ_RETRY_DELAY).await;
+                }
+                result => return result,
+            }
+        }
     }
 }
 
+/// Maps every cluster that points to the same endpoint as other clusters to the cluster whose query counter they share.
+/// The cluster with the lowest name is picked, so that all trino-lb replicas agree on it.
+fn shared_query_counters<'a>(
+    clusters: impl IntoIterator<Item = (&'a TrinoClusterName, &'a Url)>,
+) -> HashMap<TrinoClusterName, TrinoClusterName> {
+    let mut clusters_per_endpoint: HashMap<String, Vec<&TrinoClusterName>> = HashMap::new();
+    for (cluster, endpoint) in clusters {
+        clusters_per_endpoint
+            .entry(normalized_endpoint(endpoint))
+            .or_default()
+            .push(cluster);
+    }
+
+    let mut shared_query_counters = HashMap::new();
+    for clusters in clusters_per_endpoint.into_values() {
+        let Some(&query_counter) = clusters.iter().min() else {
+            continue;
+        };
+        for cluster in clusters {
+            if cluster != query_counter {
+                shared_query_counters.insert(cluster.clone(), query_counter.clone());
+            }
+        }
+    }
+
+    shared_query_counters
+}
+
+/// Picks the cluster with the fewest queries out of the clusters that can take one more query. Overflow clusters are
+/// only picked in case none of the other clusters can take the query. Ties are broken by the highest weight.
+fn select_cluster_with_min_queries<'a>(
+    clusters_with_query_counters: impl IntoIterator<Item = (&'a TrinoCluster, u64)>,
+    max_running_queries: u64,
+) -> Option<(&'a TrinoCluster, u64)> {
+    clusters_with_query_counters
+        .into_iter()
+        .filter(|(_, counter)| query_count_allows_increment(*counter, max_running_queries))
+        // `false` sorts before `true`, so non-overflow clusters are preferred
+        .min_by_key(|(cluster, counter)| (cluster.overflow, *counter, Reverse(cluster.weight)))
+}
+
 fn filter_to_trino_headers(headers: &HeaderMap) -> HeaderMap {
     let mut trino_headers = HeaderMap::new();
     for (name, value) in headers.into_iter() {
@@ -327,3 +723,578 @@ fn filter_to_www_authenticate_headers(headers: &HeaderMap) -> HeaderMap {
 
     www_headers
 }
+
+/// Ready clusters accept queries again once they have capacity left. All other clusters only become ready in case the
+/// autoscaler takes care of them, which it never does for deactivated clusters.
+fn can_become_ready(state: &ClusterState, autoscaled: bool) -> bool {
+    match state {
+        ClusterState::Ready | ClusterState::Starting | ClusterState::WarmingUp { .. } => true,
+        // The state was not determined yet, e.g. right after the startup of trino-lb
+        ClusterState::Unknown => true,
+        ClusterState::Stopped | ClusterState::Draining { .. } | ClusterState::Terminating => {
+            autoscaled
+        }
+        ClusterState::Deactivated => false,
+    }
+}
+
+/// Normalizes the endpoint, so that e.g. `https://Trino:8443/` and `https://trino` are considered the same.
+fn normalized_endpoint(endpoint: &Url) -> String {
+    format!(
+        "{}://{}:{}{}",
+        endpoint.scheme(),
+        endpoint.host_str().unwrap_or_default(),
+        endpoint
+            .port_or_known_default()
+            .map(|port| port.to_string())
+            .unwrap_or_default(),
+        endpoint.path().trim_end_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use axum::routing::{get, post};
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use rstest::rstest;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tracing_subscriber::layer::SubscriberExt;
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    #[rstest]
+    #[case("https://trino:8443", "https://trino:8443/", true)]
+    #[case("https://Trino", "https://trino:443/", true)]
+    #[case("http://trino", "http://trino:80", true)]
+    #[case("https://trino/trino/", "https://trino:443/trino", true)]
+    #[case("https://trino:8443", "http://trino:8443", false)]
+    #[case("https://trino:8443", "https://trino:8444", false)]
+    #[case("https://trino-1:8443", "https://trino-2:8443", false)]
+    #[case("https://proxy/trino-1", "https://proxy/trino-2", false)]
+    fn test_normalized_endpoint(#[case] a: &str, #[case] b: &str, #[case] same: bool) {
+        assert_eq!(
+            normalized_endpoint(&a.parse().unwrap()) == normalized_endpoint(&b.parse().unwrap()),
+            same
+        );
+    }
+
+    fn cluster(name: &str, overflow: bool) -> TrinoCluster {
+        TrinoCluster {
+            name: name.to_owned(),
+            endpoint: format!("https://{name}:8443").parse().unwrap(),
+            external_endpoint: format!("https://{name}:8443").parse().unwrap(),
+            overflow,
+            weight: 0,
+        }
+    }
+
+    #[rstest]
+    // Primary clusters have headroom, so the overflow cluster is never chosen (even though it is idle)
+    #[case(&[3, 5], 0, Some("trino-1"))]
+    #[case(&[9, 5], 0, Some("trino-2"))]
+    #[case(&[9, 10], 0, Some("trino-1"))]
+    // Primary clusters are full, so the overflow cluster gets the query
+    #[case(&[10, 10], 0, Some("trino-overflow"))]
+    #[case(&[10, 10], 9, Some("trino-overflow"))]
+    // Everything is full
+    #[case(&[10, 10], 10, None)]
+    fn test_select_cluster_with_min_queries(
+        #[case] primary_query_counters: &[u64],
+        #[case] overflow_query_counter: u64,
+        #[case] expected: Option<&str>,
+    ) {
+        let primaries = (1..=primary_query_counters.len())
+            .map(|i| cluster(&format!("trino-{i}"), false))
+            .collect::<Vec<_>>();
+        let overflow = cluster("trino-overflow", true);
+
+        // The overflow cluster is listed first to make sure the order does not matter
+        let clusters_with_query_counters = std::iter::once((&overflow, overflow_query_counter))
+            .chain(primaries.iter().zip(primary_query_counters.iter().copied()));
+
+        assert_eq!(
+            select_cluster_with_min_queries(clusters_with_query_counters, 10)
+                .map(|(c, _)| c.name.as_str()),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case(3, 3, Some("trino-heavy"))]
+    #[case(2, 3, Some("trino-light"))]
+    #[case(3, 2, Some("trino-heavy"))]
+    fn test_select_cluster_with_min_queries_prefers_higher_weight(
+        #[case] light_query_counter: u64,
+        #[case] heavy_query_counter: u64,
+        #[case] expected: Option<&str>,
+    ) {
+        let light = TrinoCluster {
+            weight: 1,
+            ..cluster("trino-light", false)
+        };
+        let heavy = TrinoCluster {
+            weight: 10,
+            ..cluster("trino-heavy", false)
+        };
+
+        assert_eq!(
+            select_cluster_with_min_queries(
+                [(&light, light_query_counter), (&heavy, heavy_query_counter)],
+                10
+            )
+            .map(|(c, _)| c.name.as_str()),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case(0, 10, 0.0)]
+    #[case(3, 10, 30.0)]
+    #[case(9, 10, 90.0)]
+    #[case(1, 4, 25.0)]
+    #[case(0, 0, 100.0)]
+    fn test_best_cluster_utilization_percent(
+        #[case] query_count: u64,
+        #[case] max_running_queries: u64,
+        #[case] expected: f64,
+    ) {
+        let cluster = cluster("trino-1", false);
+        let best = BestCluster {
+            cluster: &cluster,
+            query_count,
+            max_running_queries,
+        };
+
+        assert_eq!(best.utilization_percent(), expected);
+    }
+
+    #[test]
+    fn test_filter_to_trino_headers_keeps_prepared_statement_state() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("set-cookie", "foo=bar".parse().unwrap());
+        headers.append(
+            "x-trino-added-prepare",
+            "my_select=SELECT+*+FROM+nation".parse().unwrap(),
+        );
+        headers.append(
+            "x-trino-added-prepare",
+            "my_insert=INSERT+INTO+foo+VALUES+%281%29".parse().unwrap(),
+        );
+        headers.insert(
+            "X-Trino-Deallocated-Prepare",
+            "my_old_select".parse().unwrap(),
+        );
+        headers.insert(
+            "x-trino-set-session",
+            "query_max_run_time=1h".parse().unwrap(),
+        );
+
+        let filtered = filter_to_trino_headers(&headers);
+
+        assert_eq!(filtered.len(), 4);
+        assert_eq!(
+            filtered
+                .get_all("x-trino-added-prepare")
+                .iter()
+                .collect::<Vec<_>>(),
+            [
+                "my_select=SELECT+*+FROM+nation",
+                "my_insert=INSERT+INTO+foo+VALUES+%281%29"
+            ]
+        );
+        assert_eq!(
+            filtered.get("x-trino-deallocated-prepare").unwrap(),
+            "my_old_select"
+        );
+        assert_eq!(
+            filtered.get("x-trino-set-session").unwrap(),
+            "query_max_run_time=1h"
+        );
+        assert!(filtered.get("content-type").is_none());
+        assert!(filtered.get("set-cookie").is_none());
+    }
+
+    #[rstest]
+    #[case::succeeds_after_retries(&[503], StatusCode::SERVICE_UNAVAILABLE, 2, true, 3)]
+    #[case::gives_up_eventually(&[503], StatusCode::SERVICE_UNAVAILABLE, 3, false, 3)]
+    #[case::other_status_codes_are_not_retried(&[503], StatusCode::INTERNAL_SERVER_ERROR, 1, false, 1)]
+    #[case::no_retries_by_default(&[], StatusCode::SERVICE_UNAVAILABLE, 1, false, 1)]
+    #[tokio::test]
+    async fn test_ask_for_query_state_retries(
+        #[case] retryable_trino_status_codes: &[u16],
+        #[case] failure_status: StatusCode,
+        #[case] failures: u64,
+        #[case] succeeds: bool,
+        #[case] expected_requests: u64,
+    ) {
+        // Fake Trino coordinator, which fails the given number of requests before answering
+        let requests = Arc::new(AtomicU64::new(0));
+        let app = axum::Router::new().route(
+            "/v1/statement/executing/20240101_120000_00001_abcde/y1/1",
+            get({
+                let requests = Arc::clone(&requests);
+                move || async move {
+                    if requests.fetch_add(1, Ordering::SeqCst) < failures {
+                        return failure_status.into_response();
+                    }
+                    Json(serde_json::json!({
+                        "id": "20240101_120000_00001_abcde",
+                        "infoUri": "https://trino:8443/ui/query.html?20240101_120000_00001_abcde",
+                        "warnings": [],
+                        "stats": {
+                            "completedSplits": 0,
+                            "cpuTimeMillis": 0,
+                            "elapsedTimeMillis": 0,
+                            "nodes": 1,
+                            "peakMemoryBytes": 0,
+                            "physicalInputBytes": 0,
+                            "processedBytes": 0,
+                            "processedRows": 0,
+                            "queuedSplits": 0,
+                            "queuedTimeMillis": 0,
+                            "queued": false,
+                            "runningSplits": 0,
+                            "scheduled": true,
+                            "spilledBytes": 0,
+                            "state": "FINISHED",
+                            "totalSplits": 0,
+                            "wallTimeMillis": 0,
+                        },
+                    }))
+                    .into_response()
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = TestConfigBuilder::new()
+            .cluster_group("s", 1, &[("trino-s-1", endpoint.as_str())])
+            .build();
+        config.trino_lb.retryable_trino_status_codes =
+            retryable_trino_status_codes.iter().copied().collect();
+        let manager = ClusterGroupManager::new(
+            Arc::new(InMemoryPersistence::default().into()),
+            &config,
+            false,
+        )
+        .unwrap();
+
+        let result = manager
+            .ask_for_query_state(
+                endpoint
+                    .join("/v1/statement/executing/20240101_120000_00001_abcde/y1/1")
+                    .unwrap(),
+                HeaderMap::new(),
+            )
+            .await;
+        assert_eq!(result.is_ok(), succeeds);
+        assert_eq!(requests.load(Ordering::SeqCst), expected_requests);
+    }
+
+    #[tokio::test]
+    async fn test_try_find_best_cluster_for_group_skips_excluded_clusters() {
+        let config = TestConfigBuilder::new()
+            .cluster_group(
+                "s",
+                1,
+                &[
+                    ("trino-s-1", "https://trino-s-1:8443"),
+                    ("trino-s-2", "https://trino-s-2:8443"),
+                ],
+            )
+            .build();
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        for cluster in ["trino-s-1", "trino-s-2"] {
+            persistence
+                .set_cluster_state(&cluster.to_owned(), ClusterState::Ready)
+                .await
+                .unwrap();
+        }
+        let manager = ClusterGroupManager::new(Arc::clone(&persistence), &config, false).unwrap();
+        let best_cluster = || async {
+            manager
+                .try_find_best_cluster_for_group("s")
+                .await
+                .unwrap()
+                .map(|best| best.cluster.name.clone())
+        };
+
+        assert_eq!(best_cluster().await.as_deref(), Some("trino-s-1"));
+
+        persistence
+            .set_cluster_routing_excluded(&"trino-s-1".to_owned(), true)
+            .await
+            .unwrap();
+        // The exclusion only applies once the cached exclusions are refreshed
+        assert_eq!(best_cluster().await.as_deref(), Some("trino-s-1"));
+        manager.refresh_routing_excluded_clusters().await.unwrap();
+        assert_eq!(best_cluster().await.as_deref(), Some("trino-s-2"));
+
+        persistence
+            .set_cluster_routing_excluded(&"trino-s-2".to_owned(), true)
+            .await
+            .unwrap();
+        manager.refresh_routing_excluded_clusters().await.unwrap();
+        assert_eq!(best_cluster().await, None);
+    }
+
+    #[rstest]
+    #[case::no_retries(0, 1, false)]
+    #[case::single_retry(1, 2, true)]
+    #[case::more_retries_than_needed(3, 2, true)]
+    #[tokio::test]
+    async fn test_retry_routing_read(
+        #[case] retries: u64,
+        #[case] expected_attempts: u64,
+        #[case] succeeds: bool,
+    ) {
+        let mut config = TestConfigBuilder::new().build();
+        config.trino_lb.routing_persistence_read_retries = retries;
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let manager = ClusterGroupManager::new(persistence, &config, false).unwrap();
+
+        // Fails the first read, all further reads succeed
+        let attempts = AtomicU64::new(0);
+        let result = manager
+            .retry_routing_read(|| async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(
+                        trino_lb_persistence::in_memory::Error::TooManyQueuedQueries {
+                            max_queued_queries: 0,
+                        }
+                        .into(),
+                    )
+                } else {
+                    Ok(42)
+                }
+            })
+            .await;
+
+        assert_eq!(result.ok(), succeeds.then_some(42));
+        assert_eq!(attempts.load(Ordering::SeqCst), expected_attempts);
+    }
+
+    #[rstest]
+    #[case::not_shared(false, Some("trino-etl-1"))]
+    #[case::shared(true, None)]
+    #[tokio::test]
+    async fn test_try_find_best_cluster_for_group_with_shared_endpoint(
+        #[case] share_query_counters_of_same_endpoint: bool,
+        #[case] expected: Option<&str>,
+    ) {
+        // The same coordinator serves both cluster groups
+        let config = TestConfigBuilder::new()
+            .trino_lb(&format!(
+                "shareQueryCountersOfSameEndpoint: {share_query_counters_of_same_endpoint}"
+            ))
+            .cluster_group(
+                "adhoc",
+                2,
+                &[("trino-adhoc-1", "https://trino-shared:8443")],
+            )
+            .cluster_group("etl", 2, &[("trino-etl-1", "https://trino-shared:8443/")])
+            .routing("routers: []\nroutingFallback: adhoc")
+            .build();
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        for cluster in ["trino-adhoc-1", "trino-etl-1"] {
+            persistence
+                .set_cluster_state(&cluster.to_owned(), ClusterState::Ready)
+                .await
+                .unwrap();
+        }
+        let manager = ClusterGroupManager::new(Arc::clone(&persistence), &config, false).unwrap();
+
+        // The adhoc group filled up the coordinator
+        for _ in 0..2 {
+            let best = manager
+                .try_find_best_cluster_for_group("adhoc")
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(persistence
+                .inc_cluster_query_count(
+                    manager.query_counter_of(&best.cluster.name),
+                    best.max_running_queries
+                )
+                .await
+                .unwrap());
+        }
+
+        assert_eq!(
+            manager
+                .try_find_best_cluster_for_group("etl")
+                .await
+                .unwrap()
+                .map(|best| best.cluster.name.as_str()),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case("https://trino-s-1-internal:8443", false)]
+    #[case("https://Trino-S-1-Internal:8443/", false)]
+    #[case("https://trino-s-1.example.com", false)]
+    #[case("https://trino-s-1.example.com:443/", false)]
+    #[case("https://trino-lb:8443", true)]
+    // Same host, but different ports or paths (e.g. a shared ingress) are fine
+    #[case("https://trino-s-1-internal:8080", true)]
+    #[case("https://trino-s-1-internal:8443/trino-lb", true)]
+    #[case("https://trino-s-1.example.com/trino-lb", true)]
+    fn test_external_address_pointing_to_cluster(
+        #[case] external_address: &str,
+        #[case] valid: bool,
+    ) {
+        let config = TestConfigBuilder::new()
+            .external_address(external_address)
+            .cluster_group_yaml(
+                "s",
+                r#"
+maxRunningQueries: 1
+trinoClusters:
+  - name: trino-s-1
+    endpoint: https://trino-s-1:8443
+    internalEndpoint: https://trino-s-1-internal:8443
+    externalEndpoint: https://trino-s-1.example.com
+    credentials: {username: admin, password: admin}
+"#,
+            )
+            .build();
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+
+        let result = ClusterGroupManager::new(persistence, &config, false);
+        if valid {
+            assert!(result.is_ok());
+        } else {
+            assert!(matches!(
+                result,
+                Err(Error::ConfigErrorExternalAddressPointsToTrinoCluster { cluster_name, .. })
+                    if cluster_name == "trino-s-1"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_shared_query_counters() {
+        let clusters = [
+            cluster("trino-b", false),
+            TrinoCluster {
+                endpoint: "https://trino-b:8443".parse().unwrap(),
+                ..cluster("trino-a", false)
+            },
+            TrinoCluster {
+                endpoint: "https://trino-b:8443/".parse().unwrap(),
+                ..cluster("trino-c", false)
+            },
+            cluster("trino-d", false),
+        ];
+
+        assert_eq!(
+            shared_query_counters(clusters.iter().map(|c| (&c.name, &c.endpoint))),
+            HashMap::from([
+                ("trino-b".to_owned(), "trino-a".to_owned()),
+                ("trino-c".to_owned(), "trino-a".to_owned()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_query_to_cluster_propagates_trace_context() {
+        // Fake Trino coordinator, which records the traceparent header and rejects the query
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/v1/statement",
+            post(move |headers: HeaderMap| async move {
+                let traceparent = headers
+                    .get("traceparent")
+                    .map(|value| value.to_str().unwrap().to_owned());
+                sender.send(traceparent).unwrap();
+                StatusCode::UNAUTHORIZED
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = TestConfigBuilder::new()
+            .cluster_group("s", 1, &[("trino-s-1", endpoint.as_str())])
+            .build();
+        let manager = ClusterGroupManager::new(
+            Arc::new(InMemoryPersistence::default().into()),
+            &config,
+            false,
+        )
+        .unwrap();
+
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = opentelemetry_sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("test");
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer)),
+        );
+
+        let statement_span = info_span!("POST /v1/statement");
+        let response = manager
+            .send_query_to_cluster(
+                "SELECT 1".to_owned(),
+                HeaderMap::new(),
+                &TrinoCluster {
+                    name: "trino-s-1".to_owned(),
+                    external_endpoint: endpoint.clone(),
+                    endpoint,
+                    overflow: false,
+                    weight: 0,
+                },
+            )
+            .instrument(statement_span.clone())
+            .await
+            .unwrap();
+        assert!(matches!(response, SendToTrinoResponse::Unauthorized { .. }));
+
+        // traceparent has the format `00-<trace id>-<parent span id>-<flags>`
+        let traceparent = receiver.recv().await.unwrap().expect("traceparent header");
+        let [version, trace_id, span_id, _flags] = traceparent.split('-').collect::<Vec<_>>()[..]
+        else {
+            panic!("Invalid traceparent header {traceparent:?}");
+        };
+        let statement_span_context = statement_span.context().span().span_context().clone();
+        assert_eq!(version, "00");
+        assert_eq!(trace_id, statement_span_context.trace_id().to_string());
+        // The long running statement span must not be propagated, only the short-lived child span
+        assert_ne!(span_id, statement_span_context.span_id().to_string());
+    }
+
+    #[rstest]
+    #[case(ClusterState::Unknown, false, true)]
+    #[case(ClusterState::Ready, false, true)]
+    #[case(ClusterState::Starting, false, true)]
+    #[case(ClusterState::Stopped, false, false)]
+    #[case(ClusterState::Stopped, true, true)]
+    #[case(ClusterState::Terminating, false, false)]
+    #[case(ClusterState::Terminating, true, true)]
+    #[case(ClusterState::Deactivated, false, false)]
+    #[case(ClusterState::Deactivated, true, false)]
+    fn test_can_become_ready(
+        #[case] state: ClusterState,
+        #[case] autoscaled: bool,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(can_become_ready(&state, autoscaled), expected);
+    }
+}
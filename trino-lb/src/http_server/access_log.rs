@@ -0,0 +1,293 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use http::{header, HeaderMap};
+use snafu::{ResultExt, Snafu};
+use tokio::time::Instant;
+use tracing::warn;
+use trino_lb_core::config::{AccessLogOutput, TrinoLbAccessLogConfig};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to open access log file {path:?}"))]
+    OpenAccessLogFile { source: io::Error, path: PathBuf },
+}
+
+/// Handlers can add this to the extensions of their response, so that the access log contains the cluster group the
+/// query was routed to.
+#[derive(Clone, Debug)]
+pub struct RoutedClusterGroup(pub String);
+
+/// Number of lines that can be waiting to be written, further lines are dropped until the output catches up.
+const BUFFERED_LINES: usize = 10_000;
+
+/// The lines are written by a dedicated thread, so that requests are not blocked by a slow output.
+pub struct AccessLog {
+    lines: SyncSender<String>,
+}
+
+impl AccessLog {
+    pub fn new(config: &TrinoLbAccessLogConfig) -> Result<Self, Error> {
+        let output: Box<dyn Write + Send> = match &config.output {
+            AccessLogOutput::Stdout => Box::new(io::stdout()),
+            AccessLogOutput::File(path) => Box::new(open_file(path)?),
+        };
+
+        Ok(Self::with_output(output))
+    }
+
+    fn with_output(output: Box<dyn Write + Send>) -> Self {
+        let (lines, receiver) = mpsc::sync_channel(BUFFERED_LINES);
+        // The thread stops once the AccessLog is dropped, as the channel is closed then.
+        std::thread::spawn(move || write_lines(output, receiver));
+
+        Self { lines }
+    }
+
+    fn write_line(&self, line: String) {
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Dropping access log line, as the access log can not keep up with the requests"
+                )
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("Dropping access log line, as the access log writer stopped")
+            }
+        }
+    }
+}
+
+fn write_lines(mut output: Box<dyn Write + Send>, lines: Receiver<String>) {
+    for line in lines {
+        if let Err(error) = writeln!(output, "{line}").and_then(|()| output.flush()) {
+            warn!(?error, "Failed to write access log");
+        }
+    }
+}
+
+fn open_file(path: &PathBuf) -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(OpenAccessLogFileSnafu { path })
+}
+
+/// Middleware writing one line in the Combined Log Format per request. The Trino user as well as the duration and
+/// the cluster group the query was routed to are added to the line as well (`-` in case they are unknown), e.g.
+///
+/// `10.0.0.1 - alice [17/Oct/2024:13:55:36 +0000] "POST /v1/statement HTTP/1.1" 200 1234 "-" "trino-cli" 0.042 s`
+pub async fn log_access(
+    State(access_log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let time = Utc::now();
+    let remote_addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let request_line = format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri().path(),
+        request.version()
+    );
+    let request_headers = request.headers().clone();
+
+    let response = next.run(request).await;
+
+    let entry = AccessLogEntry {
+        remote_addr: remote_addr.as_deref(),
+        user: header_value(&request_headers, "x-trino-user"),
+        time,
+        request_line: &request_line,
+        status: response.status().as_u16(),
+        bytes: response_bytes(&response),
+        referer: header_value(&request_headers, header::REFERER.as_str()),
+        user_agent: header_value(&request_headers, header::USER_AGENT.as_str()),
+        duration: start.elapsed(),
+        cluster_group: response
+            .extensions()
+            .get::<RoutedClusterGroup>()
+            .map(|RoutedClusterGroup(cluster_group)| cluster_group.as_str()),
+    };
+    access_log.write_line(entry.to_string());
+
+    response
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn response_bytes(response: &Response) -> Option<u64> {
+    response.body().size_hint().exact().or_else(|| {
+        header_value(response.headers(), header::CONTENT_LENGTH.as_str())
+            .and_then(|length| length.parse().ok())
+    })
+}
+
+struct AccessLogEntry<'a> {
+    remote_addr: Option<&'a str>,
+    user: Option<&'a str>,
+    time: DateTime<Utc>,
+    request_line: &'a str,
+    status: u16,
+    bytes: Option<u64>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    duration: Duration,
+    cluster_group: Option<&'a str>,
+}
+
+impl std::fmt::Display for AccessLogEntry<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{remote_addr} - {user} [{time}] \"{request_line}\" {status} {bytes} \"{referer}\" \"{user_agent}\" {duration:.3} {cluster_group}",
+            remote_addr = self.remote_addr.unwrap_or("-"),
+            user = self
+                .user
+                .map(escape_unquoted)
+                .unwrap_or_else(|| "-".to_owned()),
+            time = self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            request_line = escape(self.request_line),
+            status = self.status,
+            bytes = self
+                .bytes
+                .map(|bytes| bytes.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            referer = self.referer.map(escape).unwrap_or_else(|| "-".to_owned()),
+            user_agent = self.user_agent.map(escape).unwrap_or_else(|| "-".to_owned()),
+            duration = self.duration.as_secs_f64(),
+            cluster_group = self
+                .cluster_group
+                .map(escape_unquoted)
+                .unwrap_or_else(|| "-".to_owned()),
+        )
+    }
+}
+
+/// Escapes values the client controls, so that they can not break the format of the line.
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match c {
+            '"' | '\\' => vec!['\\', c],
+            c if c.is_control() => vec!['?'],
+            c => vec![c],
+        })
+        .collect()
+}
+
+/// Same as [`escape`], but for values that are not surrounded by quotes and thus must not contain spaces.
+fn escape_unquoted(value: &str) -> String {
+    escape(value).replace(' ', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn test_format_entry() {
+        let entry = AccessLogEntry {
+            remote_addr: Some("10.0.0.1"),
+            user: Some("alice"),
+            time: Utc.with_ymd_and_hms(2024, 10, 17, 13, 55, 36).unwrap(),
+            request_line: "POST /v1/statement HTTP/1.1",
+            status: 200,
+            bytes: Some(1234),
+            referer: None,
+            user_agent: Some("trino-cli"),
+            duration: Duration::from_millis(42),
+            cluster_group: Some("s"),
+        };
+
+        assert_eq!(
+            entry.to_string(),
+            "10.0.0.1 - alice [17/Oct/2024:13:55:36 +0000] \"POST /v1/statement HTTP/1.1\" 200 1234 \"-\" \"trino-cli\" 0.042 s"
+        );
+    }
+
+    #[test]
+    fn test_format_entry_unknown_values() {
+        let entry = AccessLogEntry {
+            remote_addr: None,
+            user: None,
+            time: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+            request_line: "GET / HTTP/1.1",
+            status: 404,
+            bytes: None,
+            referer: None,
+            user_agent: None,
+            duration: Duration::ZERO,
+            cluster_group: None,
+        };
+
+        assert_eq!(
+            entry.to_string(),
+            "- - - [02/Jan/2024:03:04:05 +0000] \"GET / HTTP/1.1\" 404 - \"-\" \"-\" 0.000 -"
+        );
+    }
+
+    #[test]
+    fn test_write_lines() {
+        let (output_sender, output_receiver) = mpsc::channel();
+        let access_log = AccessLog::with_output(Box::new(ChannelWriter(output_sender)));
+
+        access_log.write_line("first".to_owned());
+        access_log.write_line("second".to_owned());
+        drop(access_log);
+
+        // The channel is closed once the writer thread finished
+        let written = output_receiver.iter().flatten().collect::<Vec<_>>();
+        assert_eq!(String::from_utf8(written).unwrap(), "first\nsecond\n");
+    }
+
+    struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf.to_vec()).map_err(io::Error::other)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[rstest]
+    #[case("alice", "alice", "alice")]
+    #[case("alice smith", "alice smith", "alice_smith")]
+    #[case("a\"b\\c", "a\\\"b\\\\c", "a\\\"b\\\\c")]
+    #[case("a\nb", "a?b", "a?b")]
+    fn test_escape(#[case] value: &str, #[case] expected: &str, #[case] expected_unquoted: &str) {
+        assert_eq!(escape(value), expected);
+        assert_eq!(escape_unquoted(value), expected_unquoted);
+    }
+}
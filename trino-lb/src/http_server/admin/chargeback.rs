@@ -0,0 +1,172 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::StatusCode;
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt, Snafu};
+use tracing::{instrument, warn};
+use trino_lb_core::query_chargeback::{bucket_count, bucket_of, bucket_start};
+use trino_lb_persistence::Persistence;
+
+use crate::http_server::AppState;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("The query chargeback is not configured"))]
+    QueryChargebackNotConfigured {},
+
+    #[snafu(display("Failed to load the handed over query counts"))]
+    LoadHandedOverQueryCounts { source: trino_lb_persistence::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        let status_code = match self {
+            Error::QueryChargebackNotConfigured { .. } => StatusCode::NOT_FOUND,
+            Error::LoadHandedOverQueryCounts { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status_code, format!("{self:?}")).into_response()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryChargeback {
+    /// Start of the first day the counts cover (UTC), the counts run up to now.
+    pub since: String,
+
+    /// Sorted by the number of queries, descending.
+    pub users: Vec<UserQueryChargeback>,
+
+    /// Sorted by the number of queries, descending.
+    pub cluster_groups: Vec<ClusterGroupQueryChargeback>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserQueryChargeback {
+    pub user: String,
+    pub queries: u64,
+
+    /// Number of queries per cluster group.
+    pub cluster_groups: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterGroupQueryChargeback {
+    pub cluster_group: String,
+    pub queries: u64,
+}
+
+/// Returns the number of queries handed over to Trino per user and cluster group, e.g. for internal billing.
+#[instrument(name = "GET /admin/chargeback", skip(state))]
+pub async fn get_chargeback(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<QueryChargeback>, Error> {
+    let _timer = state.metrics.record_http_request("get_chargeback");
+
+    let config = state
+        .config
+        .trino_lb
+        .query_chargeback
+        .as_ref()
+        .context(QueryChargebackNotConfiguredSnafu)?;
+
+    let current_bucket = bucket_of(SystemTime::now());
+    let first_bucket = current_bucket.saturating_sub(bucket_count(config.retention) - 1);
+    let counts = state
+        .persistence
+        .load_handed_over_query_counts(first_bucket..=current_bucket)
+        .await
+        .context(LoadHandedOverQueryCountsSnafu)?;
+
+    Ok(Json(query_chargeback(bucket_start(first_bucket), counts)))
+}
+
+fn query_chargeback(since: SystemTime, counts: HashMap<(String, String), u64>) -> QueryChargeback {
+    let mut users = HashMap::<String, BTreeMap<String, u64>>::new();
+    let mut cluster_groups = HashMap::<String, u64>::new();
+    for ((user, cluster_group), queries) in counts {
+        *cluster_groups.entry(cluster_group.clone()).or_default() += queries;
+        *users
+            .entry(user)
+            .or_default()
+            .entry(cluster_group)
+            .or_default() += queries;
+    }
+
+    let mut users = users
+        .into_iter()
+        .map(|(user, cluster_groups)| UserQueryChargeback {
+            user,
+            queries: cluster_groups.values().sum(),
+            cluster_groups,
+        })
+        .collect::<Vec<_>>();
+    users.sort_by(|a, b| b.queries.cmp(&a.queries).then_with(|| a.user.cmp(&b.user)));
+
+    let mut cluster_groups = cluster_groups
+        .into_iter()
+        .map(|(cluster_group, queries)| ClusterGroupQueryChargeback {
+            cluster_group,
+            queries,
+        })
+        .collect::<Vec<_>>();
+    cluster_groups.sort_by(|a, b| {
+        b.queries
+            .cmp(&a.queries)
+            .then_with(|| a.cluster_group.cmp(&b.cluster_group))
+    });
+
+    QueryChargeback {
+        since: DateTime::<Utc>::from(since).to_rfc3339_opts(SecondsFormat::Secs, true),
+        users,
+        cluster_groups,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn test_query_chargeback() {
+        let counts = HashMap::from([
+            (("alice".to_owned(), "s".to_owned()), 2),
+            (("alice".to_owned(), "m".to_owned()), 1),
+            (("bob".to_owned(), "s".to_owned()), 3),
+            (("eve".to_owned(), "l".to_owned()), 1),
+        ]);
+
+        let chargeback = query_chargeback(UNIX_EPOCH + Duration::from_secs(1_728_000_000), counts);
+        assert_eq!(
+            serde_json::to_value(chargeback).unwrap(),
+            serde_json::json!({
+                "since": "2024-10-04T00:00:00Z",
+                "users": [
+                    {"user": "alice", "queries": 3, "clusterGroups": {"m": 1, "s": 2}},
+                    {"user": "bob", "queries": 3, "clusterGroups": {"s": 3}},
+                    {"user": "eve", "queries": 1, "clusterGroups": {"l": 1}},
+                ],
+                "clusterGroups": [
+                    {"clusterGroup": "s", "queries": 5},
+                    {"clusterGroup": "l", "queries": 1},
+                    {"clusterGroup": "m", "queries": 1},
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::{sync::Arc, time::SystemTime};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::StatusCode;
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt, Snafu};
+use tracing::{instrument, warn};
+use trino_lb_core::client_request_stats::{
+    bucket_count, bucket_of, CLIENT_REQUEST_STATS_BUCKET_SIZE,
+};
+use trino_lb_persistence::Persistence;
+
+use crate::http_server::AppState;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("The client request stats are not configured"))]
+    ClientRequestStatsNotConfigured {},
+
+    #[snafu(display("Failed to load the client request stats"))]
+    LoadClientRequestStats { source: trino_lb_persistence::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        let status_code = match self {
+            Error::ClientRequestStatsNotConfigured { .. } => StatusCode::NOT_FOUND,
+            Error::LoadClientRequestStats { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status_code, format!("{self:?}")).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRequestStats {
+    /// The period the stats cover, which is the configured window rounded up to full minutes.
+    pub window_seconds: u64,
+
+    /// Sorted by the number of requests, descending.
+    pub users: Vec<UserRequestStats>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRequestStats {
+    pub user: String,
+    pub requests: u64,
+
+    /// Averaged over the whole window.
+    pub requests_per_minute: f64,
+    pub average_header_bytes: u64,
+}
+
+/// Returns the request rate and average header size per user, so that misbehaving clients can be identified.
+#[instrument(name = "GET /admin/clients/stats", skip(state))]
+pub async fn get_stats(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ClientRequestStats>, Error> {
+    let _timer = state.metrics.record_http_request("get_client_stats");
+
+    let config = state
+        .config
+        .trino_lb
+        .client_request_stats
+        .as_ref()
+        .context(ClientRequestStatsNotConfiguredSnafu)?;
+
+    let buckets = bucket_count(config.window);
+    let current_bucket = bucket_of(SystemTime::now());
+    let stats = state
+        .persistence
+        .load_client_request_stats(current_bucket.saturating_sub(buckets - 1)..=current_bucket)
+        .await
+        .context(LoadClientRequestStatsSnafu)?;
+
+    let window_seconds = buckets * CLIENT_REQUEST_STATS_BUCKET_SIZE.as_secs();
+    let mut users = stats
+        .into_iter()
+        .map(|(user, counts)| UserRequestStats {
+            user,
+            requests: counts.requests,
+            requests_per_minute: counts.requests as f64 / buckets as f64,
+            average_header_bytes: counts
+                .header_bytes
+                .checked_div(counts.requests)
+                .unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    users.sort_by(|a, b| {
+        b.requests
+            .cmp(&a.requests)
+            .then_with(|| a.user.cmp(&b.user))
+    });
+
+    Ok(Json(ClientRequestStats {
+        window_seconds,
+        users,
+    }))
+}
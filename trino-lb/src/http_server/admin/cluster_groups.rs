@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::future::try_join_all;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use tracing::{debug, info, instrument, warn};
+use trino_lb_core::{client_request_stats::sanitize_user, trino_query::QueuedQuery};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+use crate::http_server::AppState;
+
+/// Number of queued queries loaded from the persistence at once.
+pub(super) const LOAD_QUEUED_QUERIES_BATCH_SIZE: usize = 100;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Cluster group {cluster_group:?} not found"))]
+    ClusterGroupNotFound { cluster_group: String },
+
+    #[snafu(display(
+        "Failed to remove the queued queries of the cluster group {cluster_group:?}"
+    ))]
+    RemoveQueuedQueries {
+        source: trino_lb_persistence::Error,
+        cluster_group: String,
+    },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        let status_code = match self {
+            Error::ClusterGroupNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::RemoveQueuedQueries { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status_code, format!("{self:?}")).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQueuedQueriesParams {
+    /// Matched exactly against the `X-Trino-User` header of the queued queries.
+    user: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovedQueuedQueries {
+    pub cluster_group: String,
+    pub removed: u64,
+}
+
+/// Removes all queries of the given user that are queued in trino-lb for the cluster group, e.g. because the user is
+/// flooding the queue. Queries already sent to Trino are not affected.
+#[instrument(
+    name = "DELETE /admin/cluster-groups/{cluster_group}/queued",
+    skip(state, params),
+    fields(user = sanitize_user(Some(&params.user)))
+)]
+pub async fn delete_queued_queries(
+    State(state): State<Arc<AppState>>,
+    Path(cluster_group): Path<String>,
+    Query(params): Query<DeleteQueuedQueriesParams>,
+) -> Result<Json<RemovedQueuedQueries>, Error> {
+    let _timer = state
+        .metrics
+        .record_http_request("delete_cluster_group_queued");
+
+    ensure!(
+        state
+            .config
+            .trino_cluster_groups
+            .contains_key(&cluster_group),
+        ClusterGroupNotFoundSnafu { cluster_group }
+    );
+
+    let removed = remove_queued_queries_of_user(&state.persistence, &cluster_group, &params.user)
+        .await
+        .context(RemoveQueuedQueriesSnafu {
+            cluster_group: &cluster_group,
+        })?;
+    info!(cluster_group, removed, "Removed queued queries of user");
+
+    Ok(Json(RemovedQueuedQueries {
+        cluster_group,
+        removed,
+    }))
+}
+
+async fn remove_queued_queries_of_user(
+    persistence: &PersistenceImplementation,
+    cluster_group: &str,
+    user: &str,
+) -> Result<u64, trino_lb_persistence::Error> {
+    let queued_query_ids = persistence.list_queued_query_ids(cluster_group).await?;
+
+    let mut removed = 0;
+    for batch in queued_query_ids.chunks(LOAD_QUEUED_QUERIES_BATCH_SIZE) {
+        let loaded = try_join_all(batch.iter().map(|id| persistence.load_queued_query(id))).await?;
+
+        // Queries that were handed over to Trino in the meantime are not stored any more
+        for queued_query in loaded.into_iter().flatten() {
+            if !is_queued_by_user(&queued_query, user) {
+                continue;
+            }
+
+            persistence.remove_queued_query(&queued_query).await?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Queries without (or with a non-UTF-8) `X-Trino-User` header never match, as we can not attribute them to a user.
+fn is_queued_by_user(queued_query: &QueuedQuery, user: &str) -> bool {
+    match queued_query
+        .headers
+        .get("x-trino-user")
+        .map(|value| value.to_str())
+    {
+        Some(Ok(queued_by)) => queued_by == user,
+        Some(Err(_)) | None => {
+            debug!(
+                queued_query_id = queued_query.id,
+                "Queued query has no usable user header, skipping it"
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use http::{HeaderMap, HeaderValue};
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+
+    fn queued_query(id: &str, cluster_group: &str, user: Option<&str>) -> QueuedQuery {
+        let mut headers = HeaderMap::new();
+        if let Some(user) = user {
+            headers.insert("x-trino-user", HeaderValue::from_str(user).unwrap());
+        }
+
+        QueuedQuery {
+            id: id.to_owned(),
+            query: "SELECT 1".to_owned(),
+            headers,
+            creation_time: SystemTime::now(),
+            last_accessed: SystemTime::now(),
+            cluster_group: cluster_group.to_owned(),
+            routing_reason: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_queued_queries_of_user() {
+        let persistence: PersistenceImplementation = InMemoryPersistence::default().into();
+        for query in [
+            queued_query("q1", "s", Some("flooder")),
+            queued_query("q2", "s", Some("flooder")),
+            queued_query("q3", "s", Some("alice")),
+            queued_query("q4", "s", None),
+            queued_query("q5", "m", Some("flooder")),
+        ] {
+            persistence.store_queued_query(query).await.unwrap();
+        }
+
+        assert_eq!(
+            remove_queued_queries_of_user(&persistence, "s", "flooder")
+                .await
+                .unwrap(),
+            2
+        );
+
+        let mut remaining = persistence.list_queued_query_ids("s").await.unwrap();
+        remaining.sort();
+        assert_eq!(remaining, ["q3", "q4"]);
+        assert_eq!(
+            persistence.list_queued_query_ids("m").await.unwrap(),
+            ["q5"]
+        );
+
+        // Nothing left to remove
+        assert_eq!(
+            remove_queued_queries_of_user(&persistence, "s", "flooder")
+                .await
+                .unwrap(),
+            0
+        );
+    }
+}
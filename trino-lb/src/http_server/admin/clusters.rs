@@ -0,0 +1,320 @@
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::StatusCode;
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tracing::{info, instrument, warn};
+use trino_lb_core::TrinoClusterName;
+use trino_lb_persistence::Persistence;
+
+use crate::{http_server::AppState, trino_client};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Trino cluster {cluster:?} not found"))]
+    ClusterNotFound { cluster: TrinoClusterName },
+
+    #[snafu(display("Failed to get the stored query count of cluster {cluster:?}"))]
+    GetStoredQueryCount {
+        source: trino_lb_persistence::Error,
+        cluster: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to get the cluster info of cluster {cluster:?}"))]
+    GetClusterInfo {
+        source: trino_client::ClusterInfoError,
+        cluster: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to list the stored cluster states"))]
+    ListClusterStates { source: trino_lb_persistence::Error },
+
+    #[snafu(display("Failed to set cluster {cluster:?} to excluded={excluded}"))]
+    SetClusterRoutingExcluded {
+        source: trino_lb_persistence::Error,
+        cluster: TrinoClusterName,
+        excluded: bool,
+    },
+
+    #[snafu(display("Failed to set cluster {cluster:?} to manually_managed={manually_managed}"))]
+    SetClusterManuallyManaged {
+        source: trino_lb_persistence::Error,
+        cluster: TrinoClusterName,
+        manually_managed: bool,
+    },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        let status_code = match self {
+            Error::ClusterNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::GetStoredQueryCount { .. }
+            | Error::ListClusterStates { .. }
+            | Error::SetClusterRoutingExcluded { .. }
+            | Error::SetClusterManuallyManaged { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::GetClusterInfo { .. } => StatusCode::BAD_GATEWAY,
+        };
+        (status_code, format!("{self:?}")).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryCountDrift {
+    pub cluster: TrinoClusterName,
+
+    /// The query count trino-lb has stored in the persistence.
+    pub stored_query_count: u64,
+
+    /// The number of running, blocked and queued queries as reported by the Trino cluster right now.
+    pub actual_query_count: u64,
+
+    /// `stored_query_count - actual_query_count`, so a positive drift means trino-lb over-counts.
+    pub drift: i64,
+}
+
+/// Compares the query count trino-lb has stored for the cluster with the query count the cluster reports right now.
+#[instrument(name = "GET /admin/clusters/{cluster}/drift", skip(state))]
+pub async fn get_drift(
+    State(state): State<Arc<AppState>>,
+    Path(cluster): Path<TrinoClusterName>,
+) -> Result<Json<QueryCountDrift>, Error> {
+    let _timer = state.metrics.record_http_request("get_cluster_drift");
+
+    let cluster_config = state
+        .config
+        .trino_cluster_groups
+        .values()
+        .flat_map(|group| &group.trino_clusters)
+        .find(|c| c.name == cluster)
+        .context(ClusterNotFoundSnafu { cluster: &cluster })?;
+
+    let (stored_query_count, cluster_info) = tokio::try_join!(
+        async {
+            state
+                .persistence
+                .get_cluster_query_count(state.cluster_group_manager.query_counter_of(&cluster))
+                .await
+                .context(GetStoredQueryCountSnafu { cluster: &cluster })
+        },
+        async {
+            trino_client::get_cluster_info(
+                cluster_config.internal_endpoint(),
+                state.config.trino_cluster_groups_ignore_cert,
+                &cluster_config.credentials,
+                &state.config.trino_lb.user_agent,
+            )
+            .await
+            .context(GetClusterInfoSnafu { cluster: &cluster })
+        },
+    )?;
+    let actual_query_count = cluster_info.query_count();
+
+    Ok(Json(QueryCountDrift {
+        drift: i64::try_from(stored_query_count).unwrap_or(i64::MAX)
+            - i64::try_from(actual_query_count).unwrap_or(i64::MAX),
+        cluster,
+        stored_query_count,
+        actual_query_count,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredClusterState {
+    pub cluster: TrinoClusterName,
+    pub state: &'static str,
+
+    /// Whether the cluster is part of the current configuration. Clusters that are not configured any more still have
+    /// their state stored.
+    pub configured: bool,
+}
+
+/// Lists every cluster the persistence has a state stored for.
+#[instrument(name = "GET /admin/cluster-states", skip(state))]
+pub async fn get_cluster_states(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<StoredClusterState>>, Error> {
+    let _timer = state.metrics.record_http_request("get_cluster_states");
+
+    let cluster_states = state
+        .persistence
+        .list_cluster_states()
+        .await
+        .context(ListClusterStatesSnafu)?;
+
+    let configured_clusters = state
+        .config
+        .trino_cluster_groups
+        .values()
+        .flat_map(|group| &group.trino_clusters)
+        .map(|c| &c.name)
+        .collect::<HashSet<_>>();
+
+    Ok(Json(
+        cluster_states
+            .into_iter()
+            .map(|(cluster, cluster_state)| StoredClusterState {
+                configured: configured_clusters.contains(&cluster),
+                state: (&cluster_state).into(),
+                cluster,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterRoutingStatus {
+    pub cluster: TrinoClusterName,
+    pub excluded_from_routing: bool,
+}
+
+/// Stops sending new queries to the cluster on all trino-lb replicas, e.g. because it misbehaves although it is
+/// ready. In contrast to deactivating the cluster, the scaler does not touch this flag.
+#[instrument(name = "POST /admin/clusters/{cluster}/exclude", skip(state))]
+pub async fn post_exclude(
+    State(state): State<Arc<AppState>>,
+    Path(cluster): Path<TrinoClusterName>,
+) -> Result<Json<ClusterRoutingStatus>, Error> {
+    let _timer = state.metrics.record_http_request("post_cluster_exclude");
+
+    set_cluster_routing_excluded(&state, cluster, true)
+        .await
+        .map(Json)
+}
+
+/// Lets the cluster get new queries again.
+#[instrument(name = "POST /admin/clusters/{cluster}/include", skip(state))]
+pub async fn post_include(
+    State(state): State<Arc<AppState>>,
+    Path(cluster): Path<TrinoClusterName>,
+) -> Result<Json<ClusterRoutingStatus>, Error> {
+    let _timer = state.metrics.record_http_request("post_cluster_include");
+
+    set_cluster_routing_excluded(&state, cluster, false)
+        .await
+        .map(Json)
+}
+
+async fn set_cluster_routing_excluded(
+    state: &AppState,
+    cluster: TrinoClusterName,
+    excluded: bool,
+) -> Result<ClusterRoutingStatus, Error> {
+    ensure!(
+        is_cluster_configured(state, &cluster),
+        ClusterNotFoundSnafu { cluster }
+    );
+
+    state
+        .persistence
+        .set_cluster_routing_excluded(&cluster, excluded)
+        .await
+        .context(SetClusterRoutingExcludedSnafu {
+            cluster: &cluster,
+            excluded,
+        })?;
+    info!(cluster, excluded, "Changed routing exclusion of cluster");
+    // Other trino-lb replicas pick up the change once they refresh the clusters excluded from routing
+    if let Err(error) = state
+        .cluster_group_manager
+        .refresh_routing_excluded_clusters()
+        .await
+    {
+        warn!(
+            ?error,
+            "Failed to refresh the clusters excluded from routing, the change applies with the next periodic refresh"
+        );
+    }
+
+    Ok(ClusterRoutingStatus {
+        cluster,
+        excluded_from_routing: excluded,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterManagementStatus {
+    pub cluster: TrinoClusterName,
+    pub manually_managed: bool,
+}
+
+/// Tells the scaler on all trino-lb replicas to not touch the cluster at all (e.g. because an operator stopped it for
+/// debugging), even if it would be needed to satisfy the minimum number of clusters.
+#[instrument(name = "POST /admin/clusters/{cluster}/manage-manually", skip(state))]
+pub async fn post_manage_manually(
+    State(state): State<Arc<AppState>>,
+    Path(cluster): Path<TrinoClusterName>,
+) -> Result<Json<ClusterManagementStatus>, Error> {
+    let _timer = state
+        .metrics
+        .record_http_request("post_cluster_manage_manually");
+
+    set_cluster_manually_managed(&state, cluster, true)
+        .await
+        .map(Json)
+}
+
+/// Hands the cluster back to the scaler.
+#[instrument(
+    name = "POST /admin/clusters/{cluster}/manage-automatically",
+    skip(state)
+)]
+pub async fn post_manage_automatically(
+    State(state): State<Arc<AppState>>,
+    Path(cluster): Path<TrinoClusterName>,
+) -> Result<Json<ClusterManagementStatus>, Error> {
+    let _timer = state
+        .metrics
+        .record_http_request("post_cluster_manage_automatically");
+
+    set_cluster_manually_managed(&state, cluster, false)
+        .await
+        .map(Json)
+}
+
+async fn set_cluster_manually_managed(
+    state: &AppState,
+    cluster: TrinoClusterName,
+    manually_managed: bool,
+) -> Result<ClusterManagementStatus, Error> {
+    ensure!(
+        is_cluster_configured(state, &cluster),
+        ClusterNotFoundSnafu { cluster }
+    );
+
+    state
+        .persistence
+        .set_cluster_manually_managed(&cluster, manually_managed)
+        .await
+        .context(SetClusterManuallyManagedSnafu {
+            cluster: &cluster,
+            manually_managed,
+        })?;
+    info!(
+        cluster,
+        manually_managed, "Changed whether the cluster is managed manually"
+    );
+
+    Ok(ClusterManagementStatus {
+        cluster,
+        manually_managed,
+    })
+}
+
+fn is_cluster_configured(state: &AppState, cluster: &TrinoClusterName) -> bool {
+    state
+        .config
+        .trino_cluster_groups
+        .values()
+        .flat_map(|group| &group.trino_clusters)
+        .any(|c| &c.name == cluster)
+}
@@ -0,0 +1,351 @@
+use std::{collections::BTreeMap, convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::{stream, Stream, StreamExt};
+use http::StatusCode;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex,
+    },
+    time,
+};
+use tracing::{instrument, warn};
+use trino_lb_core::TrinoClusterName;
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+use crate::{config::Config, http_server::AppState};
+
+/// How often the persistence is checked for changes, as long as at least one client is subscribed.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of events a slow client can fall behind before it misses events.
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to load the current state from the persistence"))]
+    LoadCurrentState { source: trino_lb_persistence::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{self:?}")).into_response()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum StateEvent {
+    ClusterState {
+        cluster: TrinoClusterName,
+        state: &'static str,
+    },
+    QueuedQueries {
+        cluster_group: String,
+        count: u64,
+    },
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Snapshot {
+    cluster_states: BTreeMap<TrinoClusterName, &'static str>,
+    queued_queries: BTreeMap<String, u64>,
+}
+
+impl Snapshot {
+    fn events(&self) -> Vec<StateEvent> {
+        self.changes_since(&Snapshot::default())
+    }
+
+    /// Returns the events needed to get from the `previous` snapshot to this one.
+    fn changes_since(&self, previous: &Snapshot) -> Vec<StateEvent> {
+        let cluster_states = self
+            .cluster_states
+            .iter()
+            .filter(|(cluster, state)| previous.cluster_states.get(*cluster) != Some(state))
+            .map(|(cluster, state)| StateEvent::ClusterState {
+                cluster: cluster.clone(),
+                state,
+            });
+        let queued_queries = self
+            .queued_queries
+            .iter()
+            .filter(|(cluster_group, count)| {
+                previous.queued_queries.get(*cluster_group) != Some(count)
+            })
+            .map(|(cluster_group, count)| StateEvent::QueuedQueries {
+                cluster_group: cluster_group.clone(),
+                count: *count,
+            });
+
+        cluster_states.chain(queued_queries).collect()
+    }
+}
+
+/// Publishes changes of the cluster states and queue depths to all subscribed clients. The persistence is polled
+/// centrally (instead of by every client), so that changes made by any trino-lb replica are picked up.
+pub struct StateEvents {
+    persistence: Arc<PersistenceImplementation>,
+    clusters: Vec<TrinoClusterName>,
+    cluster_groups: Vec<String>,
+    sender: broadcast::Sender<StateEvent>,
+    latest: Mutex<Snapshot>,
+}
+
+impl StateEvents {
+    pub fn new(persistence: Arc<PersistenceImplementation>, config: &Config) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        Self {
+            persistence,
+            clusters: config
+                .trino_cluster_groups
+                .values()
+                .flat_map(|group| &group.trino_clusters)
+                .map(|cluster| cluster.name.clone())
+                .collect(),
+            cluster_groups: config.trino_cluster_groups.keys().cloned().collect(),
+            sender,
+            latest: Mutex::new(Snapshot::default()),
+        }
+    }
+
+    pub fn start_loop(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = time::interval(POLL_INTERVAL);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+
+                // Nobody is interested, so we don't need to put load on the persistence
+                if self.sender.receiver_count() == 0 {
+                    continue;
+                }
+                if let Err(error) = self.refresh().await {
+                    warn!(
+                        ?error,
+                        "Failed to refresh the state published to event subscribers"
+                    );
+                }
+            }
+        });
+    }
+
+    /// Returns the current state as events, followed by all future changes.
+    ///
+    /// The state is read from the persistence instead of using the latest published one, as that is not refreshed
+    /// while nobody is subscribed and might be outdated.
+    pub async fn subscribe(
+        &self,
+    ) -> Result<(Vec<StateEvent>, broadcast::Receiver<StateEvent>), trino_lb_persistence::Error>
+    {
+        let mut latest = self.latest.lock().await;
+        let snapshot = self.load_snapshot().await?;
+        self.publish(&mut latest, snapshot);
+
+        // Subscribe while holding the lock, so that no change is published between taking the snapshot and subscribing
+        Ok((latest.events(), self.sender.subscribe()))
+    }
+
+    #[instrument(skip(self))]
+    async fn refresh(&self) -> Result<(), trino_lb_persistence::Error> {
+        // Hold the lock while loading, so that a concurrent subscribe can not replace the snapshot with an older one
+        let mut latest = self.latest.lock().await;
+        let snapshot = self.load_snapshot().await?;
+        self.publish(&mut latest, snapshot);
+
+        Ok(())
+    }
+
+    async fn load_snapshot(&self) -> Result<Snapshot, trino_lb_persistence::Error> {
+        let mut snapshot = Snapshot::default();
+        for cluster in &self.clusters {
+            let state = self.persistence.get_cluster_state(cluster).await?;
+            snapshot
+                .cluster_states
+                .insert(cluster.clone(), (&state).into());
+        }
+        for cluster_group in &self.cluster_groups {
+            let count = self
+                .persistence
+                .get_queued_query_count(cluster_group)
+                .await?;
+            snapshot.queued_queries.insert(cluster_group.clone(), count);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Sends the changes to the already subscribed clients and stores the snapshot as the latest one.
+    fn publish(&self, latest: &mut Snapshot, snapshot: Snapshot) {
+        for event in snapshot.changes_since(latest) {
+            // Fails only in case all clients disconnected in the meantime, which is fine
+            let _ = self.sender.send(event);
+        }
+        *latest = snapshot;
+    }
+}
+
+/// Streams the cluster states and number of queued queries per cluster group as Server-Sent Events. Clients get the
+/// current state on connect, followed by every change.
+#[instrument(name = "GET /admin/events", skip(state))]
+pub async fn get_events(
+    State(state): State<Arc<AppState>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+    let _timer = state.metrics.record_http_request("get_events");
+
+    let (current, receiver) = state
+        .events
+        .subscribe()
+        .await
+        .context(LoadCurrentStateSnafu)?;
+    let changes = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Event subscriber is too slow, skipping events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(Sse::new(
+        stream::iter(current)
+            .chain(changes)
+            .map(|event| Ok(to_sse_event(&event))),
+    )
+    .keep_alive(KeepAlive::default()))
+}
+
+fn to_sse_event(event: &StateEvent) -> Event {
+    let name = match event {
+        StateEvent::ClusterState { .. } => "clusterState",
+        StateEvent::QueuedQueries { .. } => "queuedQueries",
+    };
+
+    Event::default()
+        .event(name)
+        .data(serde_json::to_string(event).expect("StateEvent can always be serialized"))
+}
+
+#[cfg(test)]
+mod tests {
+    use trino_lb_core::trino_cluster::ClusterState;
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    #[test]
+    fn test_changes_since() {
+        let previous = Snapshot {
+            cluster_states: [
+                ("trino-s-1".to_owned(), "Ready"),
+                ("trino-s-2".to_owned(), "Ready"),
+            ]
+            .into(),
+            queued_queries: [("s".to_owned(), 3)].into(),
+        };
+        let current = Snapshot {
+            cluster_states: [
+                ("trino-s-1".to_owned(), "Ready"),
+                ("trino-s-2".to_owned(), "Draining"),
+            ]
+            .into(),
+            queued_queries: [("s".to_owned(), 3), ("m".to_owned(), 0)].into(),
+        };
+
+        assert_eq!(
+            current.changes_since(&previous),
+            [
+                StateEvent::ClusterState {
+                    cluster: "trino-s-2".to_owned(),
+                    state: "Draining",
+                },
+                StateEvent::QueuedQueries {
+                    cluster_group: "m".to_owned(),
+                    count: 0,
+                },
+            ]
+        );
+        assert!(current.changes_since(&current).is_empty());
+    }
+
+    #[test]
+    fn test_event_format() {
+        let event = StateEvent::QueuedQueries {
+            cluster_group: "s".to_owned(),
+            count: 42,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"queuedQueries","clusterGroup":"s","count":42}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let events = StateEvents::new(
+            Arc::clone(&persistence),
+            &TestConfigBuilder::new()
+                .cluster_group("s", 1, &[("trino-s-1", "https://trino-s-1:8443")])
+                .build(),
+        );
+        let cluster = "trino-s-1".to_owned();
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+
+        // New subscribers get the current state, even though it was not refreshed yet
+        let (current, mut receiver) = events.subscribe().await.unwrap();
+        assert_eq!(
+            current,
+            [
+                StateEvent::ClusterState {
+                    cluster: cluster.clone(),
+                    state: "Ready",
+                },
+                StateEvent::QueuedQueries {
+                    cluster_group: "s".to_owned(),
+                    count: 0,
+                },
+            ]
+        );
+
+        // Followed by the changes only
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Deactivated)
+            .await
+            .unwrap();
+        events.refresh().await.unwrap();
+        events.refresh().await.unwrap();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            StateEvent::ClusterState {
+                cluster,
+                state: "Deactivated",
+            }
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
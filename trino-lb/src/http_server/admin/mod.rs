@@ -0,0 +1,243 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use subtle::ConstantTimeEq;
+use tracing::{instrument, warn};
+use trino_lb_core::config::TrinoLbAdminAuthenticationConfig;
+
+use crate::http_server::AppState;
+
+pub mod chargeback;
+pub mod clients;
+pub mod cluster_groups;
+pub mod clusters;
+pub mod events;
+pub mod queued;
+pub mod routers;
+pub mod scaler;
+pub mod status;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("The admin API is not configured"))]
+    AdminApiNotConfigured {},
+
+    #[snafu(display("The request is missing the {scheme} credentials"))]
+    MissingCredentials { scheme: &'static str },
+
+    #[snafu(display("Failed to decode the basic auth credentials"))]
+    DecodeCredentials { source: base64::DecodeError },
+
+    #[snafu(display("The basic auth credentials are malformed"))]
+    MalformedCredentials {},
+
+    #[snafu(display("Invalid credentials for user {username:?}"))]
+    InvalidCredentials { username: String },
+
+    #[snafu(display("Invalid bearer token"))]
+    InvalidBearerToken {},
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Rejected admin API request");
+        (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+    }
+}
+
+/// Middleware protecting all admin API endpoints using the authentication configured in `trinoLb.admin`. This way
+/// the individual admin handlers don't need to care about authentication at all.
+#[instrument(skip_all)]
+pub async fn authenticate(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    let authentication = &state
+        .config
+        .trino_lb
+        .admin
+        .as_ref()
+        .context(AdminApiNotConfiguredSnafu)
+        .map_err(IntoResponse::into_response)?
+        .authentication;
+
+    if let Err(error) = check_authentication(authentication, request.headers()) {
+        let mut response = error.into_response();
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(authentication_challenge(authentication)),
+        );
+        return Err(response);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Middleware protecting `GET /admin/status/local`, which additionally accepts the peer token configured in
+/// `trinoLb.admin.peers`, so that replicas can ask each other without passing on the credentials of the client.
+#[instrument(skip_all)]
+pub async fn authenticate_peer(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    let peer_token = state
+        .config
+        .trino_lb
+        .admin
+        .as_ref()
+        .and_then(|admin| admin.peers.as_ref())
+        .and_then(|peers| peers.token.as_deref());
+    if let Some(peer_token) = peer_token {
+        if authorization_value(request.headers(), "Bearer")
+            .is_ok_and(|token| token.as_bytes().ct_eq(peer_token.as_bytes()).into())
+        {
+            return Ok(next.run(request).await);
+        }
+    }
+
+    authenticate(State(state), request, next).await
+}
+
+fn check_authentication(
+    authentication: &TrinoLbAdminAuthenticationConfig,
+    headers: &HeaderMap,
+) -> Result<(), Error> {
+    match authentication {
+        TrinoLbAdminAuthenticationConfig::BasicAuth {
+            username: expected_username,
+            password: expected_password,
+        } => {
+            let (username, password) = basic_auth_credentials(headers)?;
+            // Constant time comparisons, so that the credentials can not be guessed by measuring response times.
+            // Both are always compared, so that it's not revealed which one is wrong.
+            let valid = username.as_bytes().ct_eq(expected_username.as_bytes())
+                & password.as_bytes().ct_eq(expected_password.as_bytes());
+            ensure!(bool::from(valid), InvalidCredentialsSnafu { username });
+        }
+        TrinoLbAdminAuthenticationConfig::BearerToken {
+            token: expected_token,
+        } => {
+            let token = authorization_value(headers, "Bearer")?;
+            ensure!(
+                bool::from(token.as_bytes().ct_eq(expected_token.as_bytes())),
+                InvalidBearerTokenSnafu
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn authentication_challenge(authentication: &TrinoLbAdminAuthenticationConfig) -> &'static str {
+    match authentication {
+        TrinoLbAdminAuthenticationConfig::BasicAuth { .. } => "Basic realm=\"trino-lb admin\"",
+        TrinoLbAdminAuthenticationConfig::BearerToken { .. } => "Bearer realm=\"trino-lb admin\"",
+    }
+}
+
+/// Returns the value of the `Authorization` header, in case it uses the given scheme.
+fn authorization_value<'a>(headers: &'a HeaderMap, scheme: &'static str) -> Result<&'a str, Error> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(scheme))
+        .and_then(|value| value.strip_prefix(' '))
+        .map(str::trim)
+        .context(MissingCredentialsSnafu { scheme })
+}
+
+/// Extracts username and password from the `Authorization` header.
+fn basic_auth_credentials(headers: &HeaderMap) -> Result<(String, String), Error> {
+    let encoded = authorization_value(headers, "Basic")?;
+
+    let decoded = STANDARD.decode(encoded).context(DecodeCredentialsSnafu)?;
+    let decoded = String::from_utf8(decoded)
+        .ok()
+        .context(MalformedCredentialsSnafu)?;
+    let (username, password) = decoded.split_once(':').context(MalformedCredentialsSnafu)?;
+
+    Ok((username.to_owned(), password.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn headers(authorization: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(authorization).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[rstest]
+    #[case("Basic YWRtaW46c2VjcmV0", Some(("admin", "secret")))]
+    #[case("Basic YWRtaW46c2VjOnJldA==", Some(("admin", "sec:ret")))]
+    #[case("Bearer YWRtaW46c2VjcmV0", None)]
+    #[case("Basic not-base64!", None)]
+    #[case("Basic YWRtaW4=", None)]
+    fn test_basic_auth_credentials(
+        #[case] authorization: &str,
+        #[case] expected: Option<(&str, &str)>,
+    ) {
+        let credentials = basic_auth_credentials(&headers(Some(authorization))).ok();
+        assert_eq!(
+            credentials,
+            expected.map(|(u, p)| (u.to_owned(), p.to_owned()))
+        );
+    }
+
+    #[rstest]
+    // admin:secret
+    #[case(Some("Basic YWRtaW46c2VjcmV0"), true)]
+    // admin:wrong
+    #[case(Some("Basic YWRtaW46d3Jvbmc="), false)]
+    // other:secret
+    #[case(Some("Basic b3RoZXI6c2VjcmV0"), false)]
+    #[case(Some("Bearer secret"), false)]
+    #[case(None, false)]
+    fn test_check_basic_auth(#[case] authorization: Option<&str>, #[case] accepted: bool) {
+        let authentication = TrinoLbAdminAuthenticationConfig::BasicAuth {
+            username: "admin".to_owned(),
+            password: "secret".to_owned(),
+        };
+
+        assert_eq!(
+            check_authentication(&authentication, &headers(authorization)).is_ok(),
+            accepted
+        );
+    }
+
+    #[rstest]
+    #[case(Some("Bearer secret-token"), true)]
+    #[case(Some("Bearer wrong-token"), false)]
+    #[case(Some("Bearer "), false)]
+    #[case(Some("Bearersecret-token"), false)]
+    #[case(Some("Basic c2VjcmV0LXRva2Vu"), false)]
+    #[case(None, false)]
+    fn test_check_bearer_token(#[case] authorization: Option<&str>, #[case] accepted: bool) {
+        let authentication = TrinoLbAdminAuthenticationConfig::BearerToken {
+            token: "secret-token".to_owned(),
+        };
+
+        assert_eq!(
+            check_authentication(&authentication, &headers(authorization)).is_ok(),
+            accepted
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::{collections::BTreeMap, sync::Arc, time::SystemTime};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{future::try_join_all, stream};
+use http::header;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{instrument, warn};
+use trino_lb_core::{
+    query_runtime::normalize_query, sanitization::Sanitize, trino_query::QueuedQuery,
+};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+use super::cluster_groups::LOAD_QUEUED_QUERIES_BATCH_SIZE;
+use crate::http_server::AppState;
+
+/// One line of the dump.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedQueryDumpEntry {
+    pub id: String,
+    pub cluster_group: String,
+    pub user: Option<String>,
+    pub creation_time: String,
+    pub last_accessed: String,
+    pub routing_reason: Option<String>,
+    /// The query with all literals replaced by `?`, so that the dump does not leak the values it contains.
+    pub query: String,
+    /// Headers carrying credentials, such as `Authorization` or `Cookie`, are redacted.
+    pub headers: BTreeMap<String, Vec<String>>,
+}
+
+impl From<&QueuedQuery> for QueuedQueryDumpEntry {
+    fn from(queued_query: &QueuedQuery) -> Self {
+        let mut headers = BTreeMap::<_, Vec<_>>::new();
+        for (name, value) in &queued_query.headers.sanitize() {
+            if let Ok(value) = value.to_str() {
+                headers
+                    .entry(name.as_str().to_owned())
+                    .or_default()
+                    .push(value.to_owned());
+            }
+        }
+
+        Self {
+            id: queued_query.id.clone(),
+            cluster_group: queued_query.cluster_group.clone(),
+            user: queued_query
+                .headers
+                .get("x-trino-user")
+                .and_then(|user| user.to_str().ok())
+                .map(ToOwned::to_owned),
+            creation_time: format_time(queued_query.creation_time),
+            last_accessed: format_time(queued_query.last_accessed),
+            routing_reason: queued_query.routing_reason.clone(),
+            query: normalize_query(&queued_query.query),
+            headers,
+        }
+    }
+}
+
+/// Streams all queries queued in trino-lb as newline-delimited JSON, e.g. for post-incident analysis. The queued
+/// queries are loaded in batches while the response is sent, so that a huge queue is never held in memory at once.
+#[instrument(name = "GET /admin/queued/dump", skip(state))]
+pub async fn get_dump(State(state): State<Arc<AppState>>) -> Response {
+    let _timer = state.metrics.record_http_request("get_queued_dump");
+
+    let mut cluster_groups = state
+        .config
+        .trino_cluster_groups
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    cluster_groups.sort();
+
+    // The channel only holds a single batch, so that we don't load queued queries faster than the client reads them
+    let (sender, receiver) = mpsc::channel(LOAD_QUEUED_QUERIES_BATCH_SIZE);
+    let persistence = Arc::clone(&state.persistence);
+    tokio::spawn(async move {
+        if let Err(error) = dump_queued_queries(&persistence, &cluster_groups, &sender).await {
+            warn!(?error, "Failed to dump queued queries");
+            // Abort the response, so that the client notices that the dump is incomplete
+            let _ = sender.send(Err(error)).await;
+        }
+    });
+
+    let lines = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|line| (line, receiver))
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Sends a line per queued query, stops early in case the client went away.
+async fn dump_queued_queries(
+    persistence: &PersistenceImplementation,
+    cluster_groups: &[String],
+    sender: &mpsc::Sender<Result<Bytes, trino_lb_persistence::Error>>,
+) -> Result<(), trino_lb_persistence::Error> {
+    for cluster_group in cluster_groups {
+        let queued_query_ids = persistence.list_queued_query_ids(cluster_group).await?;
+
+        for batch in queued_query_ids.chunks(LOAD_QUEUED_QUERIES_BATCH_SIZE) {
+            let loaded =
+                try_join_all(batch.iter().map(|id| persistence.load_queued_query(id))).await?;
+
+            // Queries that were handed over to Trino in the meantime are not stored any more
+            for queued_query in loaded.into_iter().flatten() {
+                if sender.send(Ok(to_line(&queued_query))).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn to_line(queued_query: &QueuedQuery) -> Bytes {
+    let mut line = serde_json::to_vec(&QueuedQueryDumpEntry::from(queued_query))
+        .expect("serializing a dump entry can not fail, as it only contains strings");
+    line.push(b'\n');
+
+    line.into()
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::{HeaderMap, HeaderValue};
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+
+    fn queued_query(id: &str, cluster_group: &str) -> QueuedQuery {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-trino-user", HeaderValue::from_static("alice"));
+        headers.insert("authorization", HeaderValue::from_static("Basic c2VjcmV0"));
+        headers.append("authorization", HeaderValue::from_static("Bearer c2VjcmV0"));
+        headers.insert(
+            "x-trino-extra-credential",
+            HeaderValue::from_static("password=secret"),
+        );
+
+        QueuedQuery {
+            id: id.to_owned(),
+            query: "SELECT * FROM customers WHERE ssn = '123-45-6789'".to_owned(),
+            headers,
+            creation_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            last_accessed: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_060),
+            cluster_group: cluster_group.to_owned(),
+            routing_reason: None,
+        }
+    }
+
+    #[test]
+    fn test_to_line() {
+        let line = to_line(&queued_query("q1", "s"));
+        let line = std::str::from_utf8(&line).unwrap();
+
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.lines().count(), 1);
+        let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(
+            entry,
+            serde_json::json!({
+                "id": "q1",
+                "clusterGroup": "s",
+                "user": "alice",
+                "creationTime": "2023-11-14T22:13:20.000Z",
+                "lastAccessed": "2023-11-14T22:14:20.000Z",
+                "routingReason": null,
+                "query": "select * from customers where ssn = ?",
+                "headers": {
+                    "authorization": ["<redacted>", "<redacted>"],
+                    "x-trino-extra-credential": ["<redacted>"],
+                    "x-trino-user": ["alice"],
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dump_queued_queries() {
+        let persistence: PersistenceImplementation = InMemoryPersistence::default().into();
+        let batch_size = LOAD_QUEUED_QUERIES_BATCH_SIZE as u64;
+        for i in 0..batch_size + 1 {
+            persistence
+                .store_queued_query(queued_query(&format!("s-{i}"), "s"))
+                .await
+                .unwrap();
+        }
+        persistence
+            .store_queued_query(queued_query("m-1", "m"))
+            .await
+            .unwrap();
+
+        let (sender, mut receiver) = mpsc::channel(1);
+        let cluster_groups = ["m".to_owned(), "s".to_owned(), "l".to_owned()];
+        let dump = tokio::spawn(async move {
+            dump_queued_queries(&persistence, &cluster_groups, &sender).await
+        });
+
+        let mut lines = Vec::new();
+        while let Some(line) = receiver.recv().await {
+            lines.push(line.unwrap());
+        }
+        dump.await.unwrap().unwrap();
+
+        assert_eq!(lines.len() as u64, batch_size + 2);
+    }
+
+    #[tokio::test]
+    async fn test_dump_queued_queries_stops_when_client_is_gone() {
+        let persistence: PersistenceImplementation = InMemoryPersistence::default().into();
+        persistence
+            .store_queued_query(queued_query("s-1", "s"))
+            .await
+            .unwrap();
+
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+
+        dump_queued_queries(&persistence, &["s".to_owned()], &sender)
+            .await
+            .unwrap();
+    }
+}
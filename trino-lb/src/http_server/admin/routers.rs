@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::StatusCode;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use tracing::{info, instrument, warn};
+use trino_lb_persistence::Persistence;
+
+use crate::{http_server::AppState, routing};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to reload the routers"))]
+    Reload { source: routing::ReloadError },
+
+    #[snafu(display("Router at index {index} not found, only {routers} routers are configured"))]
+    RouterNotFound { index: usize, routers: usize },
+
+    #[snafu(display("Failed to set router at index {index} to disabled={disabled}"))]
+    SetRouterDisabled {
+        source: trino_lb_persistence::Error,
+        index: usize,
+        disabled: bool,
+    },
+
+    #[snafu(display("Failed to list disabled routers"))]
+    ListDisabledRouters { source: trino_lb_persistence::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        let status_code = match &self {
+            // The new configuration is invalid, the old routers are still in place
+            Error::Reload {
+                source: routing::ReloadError::CreateRouter { .. },
+            } => StatusCode::BAD_REQUEST,
+            Error::RouterNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::Reload { .. }
+            | Error::SetRouterDisabled { .. }
+            | Error::ListDisabledRouters { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status_code, format!("{self:?}")).into_response()
+    }
+}
+
+/// Re-reads the routers and routing fallback from the configuration file and swaps them atomically.
+#[instrument(name = "POST /admin/routers/reload", skip(state))]
+pub async fn post_reload(State(state): State<Arc<AppState>>) -> Result<&'static str, Error> {
+    let _timer = state.metrics.record_http_request("post_routers_reload");
+
+    state.router.reload().await.context(ReloadSnafu)?;
+
+    Ok("Reloaded")
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouterStatus {
+    /// Index of the router in the configured `routers`.
+    pub index: usize,
+    pub name: &'static str,
+    pub enabled: bool,
+}
+
+/// Lists the configured routers and whether they are enabled.
+#[instrument(name = "GET /admin/routers", skip(state))]
+pub async fn get_routers(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<RouterStatus>>, Error> {
+    let _timer = state.metrics.record_http_request("get_routers");
+
+    let disabled_routers = state
+        .persistence
+        .list_disabled_routers()
+        .await
+        .context(ListDisabledRoutersSnafu)?;
+
+    let routers = state
+        .router
+        .load()
+        .router_names()
+        .enumerate()
+        .map(|(index, name)| RouterStatus {
+            index,
+            name,
+            enabled: !disabled_routers.contains(&index),
+        })
+        .collect();
+
+    Ok(Json(routers))
+}
+
+/// Disables the router at the given index for all trino-lb replicas, e.g. to temporarily stop sending EXPLAIN queries
+/// during a Trino incident. Disabled routers are skipped when routing queries.
+#[instrument(name = "POST /admin/routers/{index}/disable", skip(state))]
+pub async fn post_disable(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<Json<RouterStatus>, Error> {
+    let _timer = state.metrics.record_http_request("post_router_disable");
+
+    set_router_enabled(&state, index, false).await.map(Json)
+}
+
+/// Enables the router at the given index again.
+#[instrument(name = "POST /admin/routers/{index}/enable", skip(state))]
+pub async fn post_enable(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<usize>,
+) -> Result<Json<RouterStatus>, Error> {
+    let _timer = state.metrics.record_http_request("post_router_enable");
+
+    set_router_enabled(&state, index, true).await.map(Json)
+}
+
+async fn set_router_enabled(
+    state: &AppState,
+    index: usize,
+    enabled: bool,
+) -> Result<RouterStatus, Error> {
+    let router = state.router.load();
+    let name = router.router_names().nth(index);
+    let Some(name) = name else {
+        return RouterNotFoundSnafu {
+            index,
+            routers: router.router_names().count(),
+        }
+        .fail();
+    };
+
+    state
+        .persistence
+        .set_router_disabled(index, !enabled)
+        .await
+        .context(SetRouterDisabledSnafu {
+            index,
+            disabled: !enabled,
+        })?;
+    info!(index, name, enabled, "Changed router state");
+    // Other trino-lb replicas pick up the change once they refresh the disabled routers
+    if let Err(error) = state.router.refresh_disabled_routers().await {
+        warn!(
+            ?error,
+            "Failed to refresh the disabled routers, the change applies with the next periodic refresh"
+        );
+    }
+
+    Ok(RouterStatus {
+        index,
+        name,
+        enabled,
+    })
+}
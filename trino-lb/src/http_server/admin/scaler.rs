@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use snafu::{ResultExt, Snafu};
+use tracing::{info, instrument, warn};
+
+use crate::{http_server::AppState, scaling};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to reconcile the scaler"))]
+    Reconcile { source: scaling::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        warn!(error = ?self, "Error while processing admin request");
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{self:?}")).into_response()
+    }
+}
+
+/// Triggers an immediate reconciliation of the scaler and returns once it completed.
+#[instrument(name = "POST /admin/scaler/reconcile", skip(state))]
+pub async fn post_reconcile(State(state): State<Arc<AppState>>) -> Result<&'static str, Error> {
+    let _timer = state.metrics.record_http_request("post_scaler_reconcile");
+
+    state.scaler.reconcile().await.context(ReconcileSnafu)?;
+    info!("Reconciled scaler as requested via admin API");
+
+    Ok("Reconciled")
+}
@@ -0,0 +1,554 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{extract::State, Json};
+use futures::future::join_all;
+use http::{header, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use tracing::{instrument, warn};
+use trino_lb_core::{
+    config::{TrinoLbAdminPeerDiscoveryConfig, TrinoLbAdminPeersConfig},
+    TrinoClusterName,
+};
+use trino_lb_persistence::Persistence;
+use url::Url;
+
+use crate::{config::Config, http_server::AppState};
+
+/// Label of the replica answering the `GET /admin/status` request.
+const LOCAL_PEER: &str = "local";
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to create HTTP client for the peers"))]
+    CreatePeerHttpClient { source: reqwest::Error },
+
+    #[snafu(display(
+        "Configuration error: DNS discovery of the peers requires a peer token, as the credentials of the client are only passed on to static peers"
+    ))]
+    PeerTokenMissing {},
+
+    #[snafu(display("The peer token can not be used as HTTP header value"))]
+    InvalidPeerToken { source: header::InvalidHeaderValue },
+
+    #[snafu(display("Failed to resolve the peers using the hostname {hostname:?}"))]
+    ResolvePeers {
+        source: std::io::Error,
+        hostname: String,
+    },
+
+    #[snafu(display("Failed to construct the status URL of peer {peer}"))]
+    ConstructPeerStatusUrl { source: url::ParseError, peer: Url },
+
+    #[snafu(display("Failed to get the status of peer {peer}"))]
+    GetPeerStatus { source: reqwest::Error, peer: Url },
+}
+
+/// State only known to this trino-lb replica. In contrast, query counters and cluster states are stored in the
+/// persistence and are therefore the same for all replicas.
+pub struct Replica {
+    /// Randomly generated on startup, so that replicas can be told apart (e.g. when DNS discovery returns our own
+    /// address).
+    instance_id: String,
+    started: Instant,
+    proxy_requests_in_flight: Mutex<BTreeMap<String, i64>>,
+    peers: Option<Peers>,
+}
+
+struct Peers {
+    discovery: TrinoLbAdminPeerDiscoveryConfig,
+    http_client: reqwest::Client,
+
+    /// The `Authorization` header containing the peer token, in case one is configured.
+    token: Option<HeaderValue>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicaStatus {
+    pub instance_id: String,
+    pub version: String,
+    pub uptime_seconds: u64,
+
+    /// Number of client requests currently proxied to Trino per cluster group.
+    pub proxy_requests_in_flight: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerStatus {
+    pub peer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ReplicaStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregatedStatus {
+    /// The first entry always is the replica answering the request.
+    pub replicas: Vec<PeerStatus>,
+    pub responding_replicas: usize,
+    pub failed_peers: usize,
+
+    /// Sum over all responding replicas.
+    pub proxy_requests_in_flight: BTreeMap<String, i64>,
+
+    /// Read from the persistence, so the same for all replicas. Missing in case reading it failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routing_excluded_clusters: Option<Vec<TrinoClusterName>>,
+
+    /// Read from the persistence, so the same for all replicas. Missing in case reading it failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manually_managed_clusters: Option<Vec<TrinoClusterName>>,
+}
+
+impl Replica {
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let peers = config
+            .trino_lb
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.peers.as_ref())
+            .map(|peers| Peers::new(peers, &config.trino_lb.user_agent))
+            .transpose()?;
+
+        Ok(Self {
+            instance_id: format!("{:016x}", rand::random::<u64>()),
+            started: Instant::now(),
+            proxy_requests_in_flight: Mutex::default(),
+            peers,
+        })
+    }
+
+    pub fn proxy_request_started(&self, cluster_group: &str) {
+        if let Ok(mut in_flight) = self.proxy_requests_in_flight.lock() {
+            *in_flight.entry(cluster_group.to_owned()).or_default() += 1;
+        }
+    }
+
+    pub fn proxy_request_finished(&self, cluster_group: &str) {
+        if let Ok(mut in_flight) = self.proxy_requests_in_flight.lock() {
+            if let Some(count) = in_flight.get_mut(cluster_group) {
+                *count -= 1;
+            }
+        }
+    }
+
+    pub fn local_status(&self) -> ReplicaStatus {
+        ReplicaStatus {
+            instance_id: self.instance_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime_seconds: self.started.elapsed().as_secs(),
+            proxy_requests_in_flight: self
+                .proxy_requests_in_flight
+                .lock()
+                .map(|in_flight| in_flight.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Asks all peers for their local status. This is best-effort: Peers that can not be reached in time are reported
+    /// as failed, but don't fail the whole request.
+    #[instrument(skip(self, authorization))]
+    pub async fn gather_status(&self, authorization: Option<&HeaderValue>) -> AggregatedStatus {
+        let mut replicas = vec![PeerStatus {
+            peer: LOCAL_PEER.to_owned(),
+            status: Some(self.local_status()),
+            error: None,
+        }];
+
+        if let Some(peers) = &self.peers {
+            match peers.discover().await {
+                Ok(peer_urls) => {
+                    let results = join_all(
+                        peer_urls
+                            .iter()
+                            .map(|peer| peers.get_status(peer, authorization)),
+                    )
+                    .await;
+                    replicas.extend(peer_urls.into_iter().zip(results).map(|(peer, result)| {
+                        match result {
+                            Ok(status) => PeerStatus {
+                                peer: peer.to_string(),
+                                status: Some(status),
+                                error: None,
+                            },
+                            Err(error) => {
+                                warn!(?error, %peer, "Failed to get the status of peer");
+                                PeerStatus {
+                                    peer: peer.to_string(),
+                                    status: None,
+                                    error: Some(format!("{error:?}")),
+                                }
+                            }
+                        }
+                    }));
+                }
+                Err(error) => {
+                    warn!(?error, "Failed to discover peers");
+                    replicas.push(PeerStatus {
+                        peer: peers.discovery_label(),
+                        status: None,
+                        error: Some(format!("{error:?}")),
+                    });
+                }
+            }
+        }
+
+        aggregate(replicas)
+    }
+}
+
+impl Peers {
+    fn new(config: &TrinoLbAdminPeersConfig, user_agent: &str) -> Result<Self, Error> {
+        let http_client = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(config.timeout)
+            .danger_accept_invalid_certs(config.ignore_cert)
+            .build()
+            .context(CreatePeerHttpClientSnafu)?;
+
+        let token = config
+            .token
+            .as_ref()
+            .map(|token| {
+                let mut token = HeaderValue::try_from(format!("Bearer {token}"))
+                    .context(InvalidPeerTokenSnafu)?;
+                token.set_sensitive(true);
+                Ok(token)
+            })
+            .transpose()?;
+        if token.is_none() {
+            ensure!(
+                matches!(
+                    config.discovery,
+                    TrinoLbAdminPeerDiscoveryConfig::Static { .. }
+                ),
+                PeerTokenMissingSnafu
+            );
+        }
+
+        Ok(Self {
+            discovery: config.discovery.clone(),
+            http_client,
+            token,
+        })
+    }
+
+    /// Used to report failed discoveries, as there is no peer URL in this case.
+    fn discovery_label(&self) -> String {
+        match &self.discovery {
+            TrinoLbAdminPeerDiscoveryConfig::Static { .. } => "static".to_owned(),
+            TrinoLbAdminPeerDiscoveryConfig::Dns { hostname, port, .. } => {
+                format!("{hostname}:{port}")
+            }
+        }
+    }
+
+    async fn discover(&self) -> Result<Vec<Url>, Error> {
+        match &self.discovery {
+            TrinoLbAdminPeerDiscoveryConfig::Static { urls } => Ok(urls.clone()),
+            TrinoLbAdminPeerDiscoveryConfig::Dns {
+                hostname,
+                port,
+                https,
+            } => {
+                let scheme = if *https { "https" } else { "http" };
+                let addresses = tokio::net::lookup_host((hostname.as_str(), *port))
+                    .await
+                    .context(ResolvePeersSnafu { hostname })?;
+
+                // SocketAddr takes care of putting IPv6 addresses in brackets
+                Ok(addresses
+                    .map(|address| format!("{scheme}://{address}"))
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .filter_map(|url| url.parse().ok())
+                    .collect())
+            }
+        }
+    }
+
+    async fn get_status(
+        &self,
+        peer: &Url,
+        authorization: Option<&HeaderValue>,
+    ) -> Result<ReplicaStatus, Error> {
+        let url = peer
+            .join("/admin/status/local")
+            .context(ConstructPeerStatusUrlSnafu { peer: peer.clone() })?;
+
+        // Without a peer token we can only use the credentials of the client, which works as all replicas share the
+        // same configuration. This is limited to static peers (see `Peers::new`), so that the credentials are only
+        // sent to addresses the operator configured explicitly.
+        let mut request = self.http_client.get(url);
+        if let Some(authorization) = self.token.as_ref().or(authorization) {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context(GetPeerStatusSnafu { peer: peer.clone() })?
+            .json()
+            .await
+            .context(GetPeerStatusSnafu { peer: peer.clone() })
+    }
+}
+
+/// Sums up the statuses of all replicas. Replicas reachable via multiple addresses (e.g. ourselves, as the DNS name
+/// resolves to our own address as well) are only accounted once.
+fn aggregate(replicas: Vec<PeerStatus>) -> AggregatedStatus {
+    let mut instance_ids = HashSet::new();
+    let replicas = replicas
+        .into_iter()
+        .filter(|replica| match &replica.status {
+            Some(status) => instance_ids.insert(status.instance_id.clone()),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    let mut proxy_requests_in_flight = BTreeMap::<String, i64>::new();
+    for status in replicas
+        .iter()
+        .filter_map(|replica| replica.status.as_ref())
+    {
+        for (cluster_group, count) in &status.proxy_requests_in_flight {
+            *proxy_requests_in_flight
+                .entry(cluster_group.clone())
+                .or_default() += count;
+        }
+    }
+
+    AggregatedStatus {
+        responding_replicas: instance_ids.len(),
+        failed_peers: replicas.len() - instance_ids.len(),
+        replicas,
+        proxy_requests_in_flight,
+        routing_excluded_clusters: None,
+        manually_managed_clusters: None,
+    }
+}
+
+/// Returns the state only known to this replica, mainly used by other replicas answering `GET /admin/status`.
+#[instrument(name = "GET /admin/status/local", skip(state))]
+pub async fn get_local_status(State(state): State<Arc<AppState>>) -> Json<ReplicaStatus> {
+    let _timer = state.metrics.record_http_request("get_local_status");
+
+    Json(state.replica.local_status())
+}
+
+/// Gathers the local state of this replica and all configured peers.
+#[instrument(name = "GET /admin/status", skip(state, headers))]
+pub async fn get_status(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Json<AggregatedStatus> {
+    let _timer = state.metrics.record_http_request("get_status");
+
+    let (mut status, routing_excluded_clusters, manually_managed_clusters) = tokio::join!(
+        state
+            .replica
+            .gather_status(headers.get(header::AUTHORIZATION)),
+        state.persistence.list_routing_excluded_clusters(),
+        state.persistence.list_manually_managed_clusters(),
+    );
+    match routing_excluded_clusters {
+        Ok(routing_excluded_clusters) => {
+            status.routing_excluded_clusters = Some(routing_excluded_clusters)
+        }
+        Err(error) => warn!(?error, "Failed to list the clusters excluded from routing"),
+    }
+    match manually_managed_clusters {
+        Ok(manually_managed_clusters) => {
+            status.manually_managed_clusters = Some(manually_managed_clusters)
+        }
+        Err(error) => warn!(?error, "Failed to list the manually managed clusters"),
+    }
+
+    Json(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::routing::get;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn peers_config(
+        discovery: TrinoLbAdminPeerDiscoveryConfig,
+        token: Option<&str>,
+    ) -> TrinoLbAdminPeersConfig {
+        TrinoLbAdminPeersConfig {
+            discovery,
+            token: token.map(str::to_owned),
+            timeout: Duration::from_secs(5),
+            ignore_cert: false,
+        }
+    }
+
+    fn replica(peers: Vec<Url>, token: Option<&str>) -> Replica {
+        Replica {
+            instance_id: "local-instance".to_owned(),
+            started: Instant::now(),
+            proxy_requests_in_flight: Mutex::default(),
+            peers: Some(
+                Peers::new(
+                    &peers_config(
+                        TrinoLbAdminPeerDiscoveryConfig::Static { urls: peers },
+                        token,
+                    ),
+                    "trino-lb/test",
+                )
+                .unwrap(),
+            ),
+        }
+    }
+
+    fn status(instance_id: &str, in_flight: &[(&str, i64)]) -> ReplicaStatus {
+        ReplicaStatus {
+            instance_id: instance_id.to_owned(),
+            version: "0.0.0".to_owned(),
+            uptime_seconds: 42,
+            proxy_requests_in_flight: in_flight
+                .iter()
+                .map(|(group, count)| (group.to_string(), *count))
+                .collect(),
+        }
+    }
+
+    /// Starts a fake peer, which only answers requests sending the expected credentials.
+    async fn start_fake_peer(status: ReplicaStatus) -> Url {
+        let app = axum::Router::new().route(
+            "/admin/status/local",
+            get(move |headers: HeaderMap| async move {
+                match headers.get(header::AUTHORIZATION) {
+                    Some(value) if value == "Bearer secret" => Ok(Json(status)),
+                    _ => Err(http::StatusCode::UNAUTHORIZED),
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        url.parse().unwrap()
+    }
+
+    #[test]
+    fn test_proxy_requests_in_flight() {
+        let replica = replica(vec![], None);
+        replica.proxy_request_started("s");
+        replica.proxy_request_started("s");
+        replica.proxy_request_started("m");
+        replica.proxy_request_finished("s");
+
+        assert_eq!(
+            replica.local_status().proxy_requests_in_flight,
+            status("", &[("s", 1), ("m", 1)]).proxy_requests_in_flight
+        );
+    }
+
+    #[test]
+    fn test_aggregate_deduplicates_replicas() {
+        let peer_status = |peer: &str, status: Option<ReplicaStatus>| PeerStatus {
+            peer: peer.to_owned(),
+            error: status.is_none().then(|| "error".to_owned()),
+            status,
+        };
+
+        let aggregated = aggregate(vec![
+            peer_status(LOCAL_PEER, Some(status("a", &[("s", 1), ("m", 2)]))),
+            // Ourselves, e.g. found via DNS discovery
+            peer_status("http://10.0.0.1:8080", Some(status("a", &[("s", 1)]))),
+            peer_status("http://10.0.0.2:8080", Some(status("b", &[("s", 3)]))),
+            peer_status("http://10.0.0.3:8080", None),
+        ]);
+
+        assert_eq!(aggregated.replicas.len(), 3);
+        assert_eq!(aggregated.responding_replicas, 2);
+        assert_eq!(aggregated.failed_peers, 1);
+        assert_eq!(
+            aggregated.proxy_requests_in_flight,
+            status("", &[("s", 4), ("m", 2)]).proxy_requests_in_flight
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gather_status_with_partial_results() {
+        let reachable_peer = start_fake_peer(status("peer", &[("s", 2)])).await;
+
+        // Nobody is listening on the port anymore
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unreachable_peer: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+
+        let replica = replica(vec![reachable_peer.clone(), unreachable_peer.clone()], None);
+        replica.proxy_request_started("s");
+
+        let authorization = HeaderValue::from_static("Bearer secret");
+        let aggregated = replica.gather_status(Some(&authorization)).await;
+
+        assert_eq!(aggregated.responding_replicas, 2);
+        assert_eq!(aggregated.failed_peers, 1);
+        assert_eq!(aggregated.proxy_requests_in_flight.get("s"), Some(&3));
+
+        let peers = aggregated
+            .replicas
+            .iter()
+            .map(|replica| (replica.peer.as_str(), replica.status.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            peers,
+            [
+                (LOCAL_PEER, true),
+                (reachable_peer.as_str(), true),
+                (unreachable_peer.as_str(), false)
+            ]
+        );
+
+        // Without credentials the peer rejects the request
+        let aggregated = replica.gather_status(None).await;
+        assert_eq!(aggregated.responding_replicas, 1);
+        assert_eq!(aggregated.failed_peers, 2);
+    }
+
+    #[tokio::test]
+    async fn test_gather_status_with_peer_token() {
+        let peer = start_fake_peer(status("peer", &[("s", 2)])).await;
+        let replica = replica(vec![peer], Some("secret"));
+
+        // The peer token is sent instead of the credentials of the client
+        let authorization = HeaderValue::from_static("Bearer client-secret");
+        let aggregated = replica.gather_status(Some(&authorization)).await;
+        assert_eq!(aggregated.responding_replicas, 2);
+        assert_eq!(aggregated.failed_peers, 0);
+    }
+
+    #[test]
+    fn test_dns_discovery_requires_peer_token() {
+        let dns = TrinoLbAdminPeerDiscoveryConfig::Dns {
+            hostname: "trino-lb-headless".to_owned(),
+            port: 8443,
+            https: true,
+        };
+
+        assert!(matches!(
+            Peers::new(&peers_config(dns.clone(), None), "trino-lb/test"),
+            Err(Error::PeerTokenMissing {})
+        ));
+        assert!(Peers::new(&peers_config(dns, Some("secret")), "trino-lb/test").is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use futures::future::join_all;
+use tokio::time;
+use tracing::{debug, warn};
+use trino_lb_core::{
+    client_request_stats::{
+        bucket_of, header_bytes, sanitize_user, ClientRequestCounts, OTHER_USERS,
+    },
+    config::TrinoLbClientRequestStatsConfig,
+};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+use crate::http_server::AppState;
+
+/// How often the requests counted by this replica are written to the persistence.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Middleware accounting every request to the user sending it, in case `trinoLb.clientRequestStats` is configured.
+/// The request is only counted in memory, so that it is not slowed down by the persistence.
+pub async fn record_client_request(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(recorder) = &state.client_request_recorder {
+        let user = sanitize_user(
+            request
+                .headers()
+                .get("x-trino-user")
+                .and_then(|value| value.to_str().ok()),
+        );
+        recorder.record(
+            bucket_of(SystemTime::now()),
+            user,
+            header_bytes(request.headers()),
+        );
+    }
+
+    next.run(request).await
+}
+
+/// Sums up the client requests per bucket and user in memory and periodically adds them to the persistence, so that
+/// the persistence is accessed once per user and [`FLUSH_INTERVAL`] instead of once per request.
+pub struct ClientRequestRecorder {
+    persistence: Arc<PersistenceImplementation>,
+    max_users: u64,
+    window: Duration,
+    pending: Mutex<HashMap<u64, HashMap<String, ClientRequestCounts>>>,
+}
+
+impl ClientRequestRecorder {
+    pub fn new(
+        persistence: Arc<PersistenceImplementation>,
+        config: &TrinoLbClientRequestStatsConfig,
+    ) -> Self {
+        Self {
+            persistence,
+            max_users: config.max_users,
+            window: config.window,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Users exceeding `maxUsers` are already accounted to [`OTHER_USERS`] here, so that the memory needed in between
+    /// two flushes is bounded as well.
+    pub fn record(&self, bucket: u64, user: String, header_bytes: u64) {
+        let mut pending = self
+            .pending
+            .lock()
+            .expect("client request stats lock poisoned");
+        let users = pending.entry(bucket).or_default();
+        let user = if users.contains_key(&user) || (users.len() as u64) < self.max_users {
+            user
+        } else {
+            OTHER_USERS.to_owned()
+        };
+        *users.entry(user).or_default() += ClientRequestCounts {
+            requests: 1,
+            header_bytes,
+        };
+    }
+
+    /// Adds all requests counted since the last flush to the persistence. Requests that fail to be written are
+    /// dropped, as the statistics are best effort anyway.
+    pub async fn flush(&self) {
+        let pending = std::mem::take(
+            &mut *self
+                .pending
+                .lock()
+                .expect("client request stats lock poisoned"),
+        );
+
+        // Requests of other users are written last, so that they can not take the place of a user in case other
+        // trino-lb replicas tracked fewer users.
+        let (other_users, users): (Vec<_>, Vec<_>) = pending
+            .iter()
+            .flat_map(|(bucket, users)| {
+                users
+                    .iter()
+                    .map(move |(user, counts)| (*bucket, user, counts))
+            })
+            .partition(|(_, user, _)| *user == OTHER_USERS);
+        let mut flushed = 0;
+        for users in [users, other_users] {
+            flushed += users.len();
+            join_all(users.into_iter().map(|(bucket, user, counts)| async move {
+                if let Err(error) = self
+                    .persistence
+                    .record_client_requests(bucket, user, *counts, self.max_users, self.window)
+                    .await
+                {
+                    warn!(?error, user, "Failed to record client requests");
+                }
+            }))
+            .await;
+        }
+        debug!(users = flushed, "Flushed client requests to persistence");
+    }
+
+    pub fn start_flush_loop(self: &Arc<Self>) {
+        let me = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = time::interval(FLUSH_INTERVAL);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                // First tick does not sleep, so let's put it at the start of the loop.
+                interval.tick().await;
+
+                me.flush().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flush() {
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let recorder = ClientRequestRecorder::new(
+            Arc::clone(&persistence),
+            &TrinoLbClientRequestStatsConfig {
+                window: Duration::from_secs(10 * 60),
+                max_users: 2,
+            },
+        );
+
+        for (user, header_bytes) in [("alice", 100), ("alice", 300), ("bob", 50), ("eve", 1000)] {
+            recorder.record(10, user.to_owned(), header_bytes);
+        }
+        assert!(persistence
+            .load_client_request_stats(0..=10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        recorder.flush().await;
+        let stats = persistence.load_client_request_stats(0..=10).await.unwrap();
+        assert_eq!(
+            stats["alice"],
+            ClientRequestCounts {
+                requests: 2,
+                header_bytes: 400
+            }
+        );
+        assert_eq!(stats["bob"].requests, 1);
+        assert_eq!(stats[OTHER_USERS].header_bytes, 1000);
+
+        // Flushed requests are not written a second time
+        recorder.record(10, "alice".to_owned(), 1);
+        recorder.flush().await;
+        let stats = persistence.load_client_request_stats(0..=10).await.unwrap();
+        assert_eq!(stats["alice"].requests, 3);
+        assert_eq!(stats["bob"].requests, 1);
+    }
+}
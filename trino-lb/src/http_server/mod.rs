@@ -7,32 +7,40 @@ use std::{
 };
 
 use axum::{
-    response::Redirect,
-    routing::{delete, get, post},
+    middleware,
+    response::{IntoResponse, Redirect},
+    routing::{delete, get, post, MethodRouter},
     Router,
 };
 use axum_server::{tls_rustls::RustlsConfig, Handle};
 use futures::FutureExt;
+use http::StatusCode;
 use snafu::{OptionExt, ResultExt, Snafu};
 use tokio::time::sleep;
-use tracing::info;
+use tracing::{error, info};
+use trino_lb_core::config::RootPathResponse;
 use trino_lb_persistence::PersistenceImplementation;
 
 use crate::{
     cluster_group_manager::ClusterGroupManager, config::Config, metrics::Metrics, routing,
+    scaling::ScalerHandle,
 };
 
+mod access_log;
+mod admin;
+mod client_request_stats;
 mod metrics;
+mod tls;
 mod ui;
 mod v1;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
     #[snafu(display(
-        "Failed configure HTTP server PEM cert at {cert_pem_file:?} and PEM key at {key_pem_file:?}"
+        "Failed configure HTTP server TLS using PEM cert at {cert_pem_file:?} and PEM key at {key_pem_file:?}"
     ))]
-    ConfigureServerTrustAndKeystore {
-        source: std::io::Error,
+    ConfigureServerTls {
+        source: tls::Error,
         cert_pem_file: PathBuf,
         key_pem_file: PathBuf,
     },
@@ -44,36 +52,74 @@ pub enum Error {
         "In case https is used the `tls.certPemFile` and `tls.keyPemFile` options must be set"
     ))]
     CertsMissing {},
+
+    #[snafu(display("Failed to set up the access log"))]
+    CreateAccessLog { source: access_log::Error },
+
+    #[snafu(display("Failed to set up the replica status"))]
+    CreateReplica { source: admin::status::Error },
 }
 
 pub struct AppState {
     config: Config,
     persistence: Arc<PersistenceImplementation>,
     cluster_group_manager: ClusterGroupManager,
-    router: routing::Router,
+    router: routing::ReloadableRouter,
+    scaler: ScalerHandle,
     metrics: Arc<Metrics>,
+    replica: admin::status::Replica,
+    events: Arc<admin::events::StateEvents>,
+    client_request_recorder: Option<Arc<client_request_stats::ClientRequestRecorder>>,
 }
 
 pub async fn start_http_server(
     config: Config,
     persistence: Arc<PersistenceImplementation>,
     cluster_group_manager: ClusterGroupManager,
-    router: routing::Router,
+    router: routing::ReloadableRouter,
+    scaler: ScalerHandle,
     metrics: Arc<Metrics>,
 ) -> Result<(), Error> {
+    let access_log = config
+        .trino_lb
+        .access_log
+        .as_ref()
+        .map(access_log::AccessLog::new)
+        .transpose()
+        .context(CreateAccessLogSnafu)?;
     let tls_config = config.trino_lb.tls.clone();
     let ports_config = config.trino_lb.ports.clone();
+    let root_path_config = config.trino_lb.root_path.clone();
+    let replica = admin::status::Replica::new(&config).context(CreateReplicaSnafu)?;
+    let events = Arc::new(admin::events::StateEvents::new(
+        Arc::clone(&persistence),
+        &config,
+    ));
+    Arc::clone(&events).start_loop();
+    let client_request_recorder = config.trino_lb.client_request_stats.as_ref().map(|config| {
+        Arc::new(client_request_stats::ClientRequestRecorder::new(
+            Arc::clone(&persistence),
+            config,
+        ))
+    });
+    if let Some(client_request_recorder) = &client_request_recorder {
+        client_request_recorder.start_flush_loop();
+    }
     let app_state = Arc::new(AppState {
         config,
         persistence,
         cluster_group_manager,
         router,
+        scaler,
         metrics,
+        replica,
+        events,
+        client_request_recorder: client_request_recorder.clone(),
     });
 
     // Start Prometheus metrics exporter
     let app = Router::new()
-        .route("/", get(|| async { Redirect::permanent("/metrics") }))
+        .route("/", root_path(root_path_config.metrics))
         .route("/metrics", get(metrics::get))
         .with_state(Arc::clone(&app_state));
     let listen_addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, ports_config.metrics));
@@ -81,6 +127,8 @@ pub async fn start_http_server(
 
     let handle = Handle::new();
     tokio::spawn(graceful_shutdown(handle.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_routers_on_sighup(Arc::clone(&app_state)));
 
     // TODO: Think about shutting down the whole trino-lb server when the Prometheus metrics exporter fails.
     // This is the reason why we start the metrics exporter first on a new task, so we still fail when the main
@@ -93,7 +141,8 @@ pub async fn start_http_server(
             .await
     });
 
-    let app = Router::new()
+    let mut app = Router::new()
+        .route("/", root_path(root_path_config.main))
         .route("/v1/statement", post(v1::statement::post_statement))
         .route(
             "/v1/statement/queued_in_trino_lb/:query_id/:sequence_number",
@@ -119,26 +168,123 @@ pub async fn start_http_server(
             "/v1/statement/executing/:query_id/:slug/:token",
             delete(v1::statement::delete_trino_executing_statement),
         )
-        .route("/ui/query.html", get(ui::query::get_ui_query))
-        .with_state(app_state);
+        .route("/ui/query.html", get(ui::query::get_ui_query));
+
+    if app_state.client_request_recorder.is_some() {
+        info!("Enabling client request stats");
+        // Only added to the routes above, so that admin requests are not accounted
+        app = app.route_layer(middleware::from_fn_with_state(
+            Arc::clone(&app_state),
+            client_request_stats::record_client_request,
+        ));
+    }
+
+    if app_state.config.trino_lb.admin.is_some() {
+        info!("Enabling admin API");
+
+        let admin_app = Router::new()
+            .route(
+                "/admin/scaler/reconcile",
+                post(admin::scaler::post_reconcile),
+            )
+            .route(
+                "/admin/clusters/:cluster/drift",
+                get(admin::clusters::get_drift),
+            )
+            .route(
+                "/admin/clusters/:cluster/exclude",
+                post(admin::clusters::post_exclude),
+            )
+            .route(
+                "/admin/clusters/:cluster/include",
+                post(admin::clusters::post_include),
+            )
+            .route(
+                "/admin/clusters/:cluster/manage-manually",
+                post(admin::clusters::post_manage_manually),
+            )
+            .route(
+                "/admin/clusters/:cluster/manage-automatically",
+                post(admin::clusters::post_manage_automatically),
+            )
+            .route(
+                "/admin/cluster-states",
+                get(admin::clusters::get_cluster_states),
+            )
+            .route(
+                "/admin/cluster-groups/:cluster_group/queued",
+                delete(admin::cluster_groups::delete_queued_queries),
+            )
+            .route("/admin/queued/dump", get(admin::queued::get_dump))
+            .route("/admin/clients/stats", get(admin::clients::get_stats))
+            .route("/admin/chargeback", get(admin::chargeback::get_chargeback))
+            .route("/admin/routers", get(admin::routers::get_routers))
+            .route("/admin/routers/reload", post(admin::routers::post_reload))
+            .route(
+                "/admin/routers/:index/disable",
+                post(admin::routers::post_disable),
+            )
+            .route(
+                "/admin/routers/:index/enable",
+                post(admin::routers::post_enable),
+            )
+            .route("/admin/events", get(admin::events::get_events))
+            .route("/admin/status", get(admin::status::get_status))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::clone(&app_state),
+                admin::authenticate,
+            ));
+        let peer_app = Router::new()
+            .route("/admin/status/local", get(admin::status::get_local_status))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::clone(&app_state),
+                admin::authenticate_peer,
+            ));
+        app = app.merge(admin_app).merge(peer_app);
+    }
+
+    if let Some(access_log) = access_log {
+        info!("Enabling access log");
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(access_log),
+            access_log::log_access,
+        ));
+    }
+
+    let app = app.with_state(app_state);
 
     if tls_config.enabled {
         // Start https server
         let listen_addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, ports_config.https));
         info!(%listen_addr, "Starting server");
 
-        let cert_pem_file = tls_config.cert_pem_file.context(CertsMissingSnafu)?;
-        let key_pem_file = tls_config.key_pem_file.context(CertsMissingSnafu)?;
-        let tls_config = RustlsConfig::from_pem_file(&cert_pem_file, &key_pem_file)
-            .await
-            .context(ConfigureServerTrustAndKeystoreSnafu {
+        let cert_pem_file = tls_config
+            .cert_pem_file
+            .as_ref()
+            .context(CertsMissingSnafu)?;
+        let key_pem_file = tls_config
+            .key_pem_file
+            .as_ref()
+            .context(CertsMissingSnafu)?;
+        let server_config = tls::server_config(&tls_config, cert_pem_file, key_pem_file).context(
+            ConfigureServerTlsSnafu {
                 cert_pem_file,
                 key_pem_file,
-            })?;
+            },
+        )?;
+        let rustls_config = RustlsConfig::from_config(Arc::new(server_config));
+        tls::CertReloader::new(
+            rustls_config.clone(),
+            tls_config.clone(),
+            cert_pem_file.clone(),
+            key_pem_file.clone(),
+        )
+        .await
+        .start_loop();
 
-        axum_server::bind_rustls(listen_addr, tls_config)
+        axum_server::bind_rustls(listen_addr, rustls_config)
             .handle(handle)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .context(StartHttpServerSnafu)?;
     } else {
@@ -148,16 +294,34 @@ pub async fn start_http_server(
 
         axum_server::bind(listen_addr)
             .handle(handle)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .context(StartHttpServerSnafu)?;
     }
 
+    // Don't lose the requests counted since the last flush
+    if let Some(client_request_recorder) = client_request_recorder {
+        client_request_recorder.flush().await;
+    }
+
     info!("Shut down");
 
     Ok(())
 }
 
+fn root_path<S>(response: RootPathResponse) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    get(move || async move {
+        match response {
+            RootPathResponse::Redirect(to) => Redirect::permanent(&to).into_response(),
+            RootPathResponse::Ok => StatusCode::OK.into_response(),
+            RootPathResponse::NotFound => StatusCode::NOT_FOUND.into_response(),
+        }
+    })
+}
+
 async fn graceful_shutdown(handle: Handle) {
     wait_for_shutdown_signal().await;
 
@@ -174,6 +338,28 @@ async fn graceful_shutdown(handle: Handle) {
     }
 }
 
+/// Reloads the routers every time trino-lb receives a `SIGHUP`.
+#[cfg(unix)]
+async fn reload_routers_on_sighup(app_state: Arc<AppState>) {
+    let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(error) => {
+            error!(
+                ?error,
+                "Failed to listen for SIGHUP, reloading routers on SIGHUP is disabled"
+            );
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading routers");
+        if let Err(error) = app_state.router.reload().await {
+            error!(?error, "Failed to reload routers");
+        }
+    }
+}
+
 async fn wait_for_shutdown_signal() {
     // Copied from kube::runtime::Controller::shutdown_on_signal
     futures::future::select(
@@ -0,0 +1,357 @@
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    version, ServerConfig, SupportedProtocolVersion,
+};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::{fs as async_fs, time};
+use tracing::{error, info};
+use trino_lb_core::config::{TlsVersion, TrinoLbTlsConfig};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to read PEM file {path:?}"))]
+    ReadPemFile { source: io::Error, path: PathBuf },
+
+    #[snafu(display("The PEM file {path:?} does not contain a private key"))]
+    PrivateKeyMissing { path: PathBuf },
+
+    #[snafu(display(
+        "Unknown cipher suite {cipher_suite:?}, supported cipher suites are {supported:?}"
+    ))]
+    UnknownCipherSuite {
+        cipher_suite: String,
+        supported: Vec<&'static str>,
+    },
+
+    #[snafu(display(
+        "The configured cipher suites and minimum TLS version can not be used together"
+    ))]
+    InvalidProtocolSettings { source: rustls::Error },
+
+    #[snafu(display("Failed to use the configured certificate and private key"))]
+    InvalidCertOrKey { source: rustls::Error },
+}
+
+/// Builds the [`ServerConfig`] of the HTTPS server, honoring the configured minimum TLS version and cipher suites.
+pub fn server_config(
+    config: &TrinoLbTlsConfig,
+    cert_pem_file: &Path,
+    key_pem_file: &Path,
+) -> Result<ServerConfig, Error> {
+    let cert_pem = fs::read(cert_pem_file).context(ReadPemFileSnafu {
+        path: cert_pem_file,
+    })?;
+    let key_pem = fs::read(key_pem_file).context(ReadPemFileSnafu { path: key_pem_file })?;
+
+    server_config_from_pem(config, cert_pem_file, &cert_pem, key_pem_file, &key_pem)
+}
+
+/// Same as [`server_config`], but uses the already read contents of the PEM files. The paths are only used in errors.
+fn server_config_from_pem(
+    config: &TrinoLbTlsConfig,
+    cert_pem_file: &Path,
+    cert_pem: &[u8],
+    key_pem_file: &Path,
+    key_pem: &[u8],
+) -> Result<ServerConfig, Error> {
+    let certs = parse_certs(cert_pem_file, cert_pem)?;
+    let key = parse_private_key(key_pem_file, key_pem)?;
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(crypto_provider(config)?))
+        .with_protocol_versions(protocol_versions(config.min_tls_version))
+        .context(InvalidProtocolSettingsSnafu)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context(InvalidCertOrKeySnafu)?;
+    // Same as `axum_server::tls_rustls::RustlsConfig::from_pem_file` does
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+
+    Ok(server_config)
+}
+
+fn crypto_provider(config: &TrinoLbTlsConfig) -> Result<CryptoProvider, Error> {
+    let mut provider = ring::default_provider();
+
+    if let Some(cipher_suites) = &config.cipher_suites {
+        let supported: Vec<&'static str> = provider
+            .cipher_suites
+            .iter()
+            .filter_map(|suite| suite.suite().as_str())
+            .collect();
+        for cipher_suite in cipher_suites {
+            if !supported.contains(&cipher_suite.as_str()) {
+                return UnknownCipherSuiteSnafu {
+                    cipher_suite,
+                    supported,
+                }
+                .fail();
+            }
+        }
+
+        provider.cipher_suites.retain(|suite| {
+            suite
+                .suite()
+                .as_str()
+                .is_some_and(|name| cipher_suites.iter().any(|c| c == name))
+        });
+    }
+
+    Ok(provider)
+}
+
+/// Periodically checks the PEM files for changes and reloads the certificate and key of the HTTPS server, so that
+/// rotated certificates (e.g. by cert-manager) take effect without a restart.
+/// In case the changed files can not be loaded, the previous certificate keeps being served.
+pub struct CertReloader {
+    rustls_config: RustlsConfig,
+    tls_config: TrinoLbTlsConfig,
+    cert_pem_file: PathBuf,
+    key_pem_file: PathBuf,
+
+    /// Contents of the cert and key PEM files the last reload was attempted with.
+    pem_contents: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CertReloader {
+    pub async fn new(
+        rustls_config: RustlsConfig,
+        tls_config: TrinoLbTlsConfig,
+        cert_pem_file: PathBuf,
+        key_pem_file: PathBuf,
+    ) -> Self {
+        let pem_contents = read_pem_contents(&cert_pem_file, &key_pem_file).await.ok();
+
+        Self {
+            rustls_config,
+            tls_config,
+            cert_pem_file,
+            key_pem_file,
+            pem_contents,
+        }
+    }
+
+    pub fn start_loop(mut self) {
+        tokio::spawn(async move {
+            let mut interval = time::interval(self.tls_config.reload_interval);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+
+                match self.reload_if_changed().await {
+                    Ok(true) => info!(
+                        cert_pem_file = ?self.cert_pem_file,
+                        key_pem_file = ?self.key_pem_file,
+                        "Reloaded TLS certificate"
+                    ),
+                    Ok(false) => {}
+                    Err(error) => error!(
+                        ?error,
+                        "Failed to reload TLS certificate, continuing to use the current one"
+                    ),
+                }
+            }
+        });
+    }
+
+    /// Returns `true` in case the certificate was reloaded.
+    async fn reload_if_changed(&mut self) -> Result<bool, Error> {
+        let pem_contents = read_pem_contents(&self.cert_pem_file, &self.key_pem_file).await?;
+        if self.pem_contents.as_ref() == Some(&pem_contents) {
+            return Ok(false);
+        }
+        // Remember the contents even if they are invalid, so that we only complain once per change
+        let (cert_pem, key_pem) = self.pem_contents.insert(pem_contents);
+
+        let server_config = server_config_from_pem(
+            &self.tls_config,
+            &self.cert_pem_file,
+            cert_pem,
+            &self.key_pem_file,
+            key_pem,
+        )?;
+        self.rustls_config
+            .reload_from_config(Arc::new(server_config));
+
+        Ok(true)
+    }
+}
+
+async fn read_pem_contents(
+    cert_pem_file: &Path,
+    key_pem_file: &Path,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let cert = async_fs::read(cert_pem_file)
+        .await
+        .context(ReadPemFileSnafu {
+            path: cert_pem_file,
+        })?;
+    let key = async_fs::read(key_pem_file)
+        .await
+        .context(ReadPemFileSnafu { path: key_pem_file })?;
+
+    Ok((cert, key))
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+fn protocol_versions(min_tls_version: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match min_tls_version {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
+}
+
+fn parse_certs(path: &Path, pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(pem);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<Result<_, _>>()
+        .context(ReadPemFileSnafu { path })
+}
+
+fn parse_private_key(path: &Path, pem: &[u8]) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(pem);
+    rustls_pemfile::private_key(&mut reader)
+        .context(ReadPemFileSnafu { path })?
+        .context(PrivateKeyMissingSnafu { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const CERT_PEM_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../example-configs/self-signed-certs/cert.pem"
+    );
+    const KEY_PEM_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../example-configs/self-signed-certs/key.pem"
+    );
+
+    fn tls_config(min_tls_version: TlsVersion, cipher_suites: Option<&[&str]>) -> TrinoLbTlsConfig {
+        TrinoLbTlsConfig {
+            enabled: true,
+            min_tls_version,
+            cipher_suites: cipher_suites
+                .map(|suites| suites.iter().map(|suite| suite.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn build(config: &TrinoLbTlsConfig) -> Result<ServerConfig, Error> {
+        server_config(config, Path::new(CERT_PEM_FILE), Path::new(KEY_PEM_FILE))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let server_config = build(&TrinoLbTlsConfig::default()).unwrap();
+
+        assert_eq!(
+            server_config.crypto_provider().cipher_suites.len(),
+            ring::default_provider().cipher_suites.len()
+        );
+        assert!(server_config.alpn_protocols.contains(&b"h2".to_vec()));
+    }
+
+    #[rstest]
+    #[case(TlsVersion::Tls12, None, true)]
+    #[case(TlsVersion::Tls13, None, true)]
+    #[case(TlsVersion::Tls13, Some(&["TLS13_AES_256_GCM_SHA384"][..]), true)]
+    #[case(TlsVersion::Tls12, Some(&["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"][..]), true)]
+    // No TLS 1.3 cipher suite left
+    #[case(TlsVersion::Tls13, Some(&["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"][..]), false)]
+    #[case(TlsVersion::Tls12, Some(&[][..]), false)]
+    fn test_protocol_settings(
+        #[case] min_tls_version: TlsVersion,
+        #[case] cipher_suites: Option<&[&str]>,
+        #[case] valid: bool,
+    ) {
+        let result = build(&tls_config(min_tls_version, cipher_suites));
+        assert_eq!(result.is_ok(), valid, "{result:?}");
+    }
+
+    #[test]
+    fn test_restrict_cipher_suites() {
+        let server_config = build(&tls_config(
+            TlsVersion::Tls13,
+            Some(&["TLS13_AES_256_GCM_SHA384", "TLS13_CHACHA20_POLY1305_SHA256"]),
+        ))
+        .unwrap();
+
+        let cipher_suites: Vec<_> = server_config
+            .crypto_provider()
+            .cipher_suites
+            .iter()
+            .filter_map(|suite| suite.suite().as_str())
+            .collect();
+        assert_eq!(
+            cipher_suites,
+            ["TLS13_AES_256_GCM_SHA384", "TLS13_CHACHA20_POLY1305_SHA256"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cert_reloader() {
+        let dir =
+            std::env::temp_dir().join(format!("trino-lb-cert-reloader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_pem_file = dir.join("cert.pem");
+        let key_pem_file = dir.join("key.pem");
+        fs::copy(CERT_PEM_FILE, &cert_pem_file).unwrap();
+        fs::copy(KEY_PEM_FILE, &key_pem_file).unwrap();
+
+        let tls_config = TrinoLbTlsConfig::default();
+        let rustls_config = RustlsConfig::from_config(Arc::new(
+            server_config(&tls_config, &cert_pem_file, &key_pem_file).unwrap(),
+        ));
+        let mut reloader = CertReloader::new(
+            rustls_config.clone(),
+            tls_config,
+            cert_pem_file.clone(),
+            key_pem_file,
+        )
+        .await;
+        let initial = rustls_config.get_inner();
+
+        // Nothing changed
+        assert!(!reloader.reload_if_changed().await.unwrap());
+        assert!(Arc::ptr_eq(&initial, &rustls_config.get_inner()));
+
+        // Broken certificate, the current one keeps being used
+        fs::write(&cert_pem_file, "not a certificate").unwrap();
+        assert!(reloader.reload_if_changed().await.is_err());
+        assert!(!reloader.reload_if_changed().await.unwrap());
+        assert!(Arc::ptr_eq(&initial, &rustls_config.get_inner()));
+
+        // Fixed certificate
+        fs::copy(CERT_PEM_FILE, &cert_pem_file).unwrap();
+        assert!(reloader.reload_if_changed().await.unwrap());
+        assert!(!Arc::ptr_eq(&initial, &rustls_config.get_inner()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_cipher_suite() {
+        let result = build(&tls_config(
+            TlsVersion::Tls12,
+            Some(&["TLS_RSA_WITH_RC4_128_MD5"]),
+        ));
+        assert!(matches!(
+            result,
+            Err(Error::UnknownCipherSuite { cipher_suite, .. }) if cipher_suite == "TLS_RSA_WITH_RC4_128_MD5"
+        ));
+    }
+}
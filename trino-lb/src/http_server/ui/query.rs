@@ -5,7 +5,6 @@ use axum::{
     response::{Html, IntoResponse, Response},
 };
 use http::StatusCode;
-use opentelemetry::KeyValue;
 use snafu::{OptionExt, ResultExt, Snafu};
 use tracing::{instrument, warn};
 use trino_lb_core::TrinoLbQueryId;
@@ -19,7 +18,10 @@ pub enum Error {
     QueryIdMissing {},
 
     #[snafu(display("Query with ID {query_id:?} not found. Maybe the query is not queued any more but was handed over to a Trino cluster."))]
-    QueryIdNotFound {
+    QueryIdNotFound { query_id: TrinoLbQueryId },
+
+    #[snafu(display("Failed to load queued query with ID {query_id:?} from persistence"))]
+    LoadQueuedQuery {
         source: trino_lb_persistence::Error,
         query_id: TrinoLbQueryId,
     },
@@ -31,6 +33,7 @@ impl IntoResponse for Error {
         let status_code = match self {
             Error::QueryIdMissing { .. } => StatusCode::BAD_REQUEST,
             Error::QueryIdNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::LoadQueuedQuery { .. } => StatusCode::INTERNAL_SERVER_ERROR,
         };
         (status_code, format!("{self}")).into_response()
     }
@@ -42,16 +45,16 @@ pub async fn get_ui_query(
     State(state): State<Arc<AppState>>,
     RawQuery(query_id): RawQuery,
 ) -> Result<Html<String>, Error> {
-    state
-        .metrics
-        .http_counter
-        .add(1, &[KeyValue::new("resource", "get_ui_query")]);
+    let _timer = state.metrics.record_http_request("get_ui_query");
 
     let query_id = query_id.context(QueryIdMissingSnafu)?;
     let queued_query = state
         .persistence
         .load_queued_query(&query_id)
         .await
+        .context(LoadQueuedQuerySnafu {
+            query_id: &query_id,
+        })?
         .context(QueryIdNotFoundSnafu {
             query_id: &query_id,
         })?;
@@ -61,8 +64,10 @@ pub async fn get_ui_query(
         <h1>Query is queued in trino-lb</h1>
         <p>Your query with the ID {query_id:?} is currently queued and very important to us! Please hold the line.</p>
         <br>
-        <p>It is current queued for {queued_duration:?} in the trino cluster group {cluster_group}</p>",
+        <p>It is current queued for {queued_duration:?} in the trino cluster group {cluster_group}</p>
+        <p>Routing reason: {routing_reason}</p>",
         cluster_group = queued_query.cluster_group,
+        routing_reason = queued_query.routing_reason.as_deref().unwrap_or("unknown"),
         queued_duration = queued_query.creation_time.elapsed().unwrap_or_default(),
     )))
 }
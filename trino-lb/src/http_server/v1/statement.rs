@@ -1,35 +1,46 @@
 use std::{
     cmp::min,
     fmt::Debug,
+    future::Future,
     num::TryFromIntError,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, SystemTime, SystemTimeError},
 };
 
 use axum::{
     extract::{Path, State},
     response::{IntoResponse, Response},
-    Json,
+    Extension, Json,
 };
 use futures::TryFutureExt;
-use http::{HeaderMap, StatusCode, Uri};
-use opentelemetry::KeyValue;
-use snafu::{ResultExt, Snafu};
+use http::{HeaderMap, HeaderValue, StatusCode, Uri};
+use opentelemetry::{metrics::UpDownCounter, KeyValue};
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use subtle::ConstantTimeEq;
 use tokio::time::Instant;
-use tracing::{debug, info, info_span, instrument, warn, Instrument};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 use trino_lb_core::{
+    client_request_stats::sanitize_user,
+    clock_skew::elapsed_allowing_clock_skew,
+    config::QueryIdCollisionBehavior,
+    query_chargeback,
+    query_runtime::{blend_query_runtime, query_fingerprint},
     sanitization::Sanitize,
     trino_api::TrinoQueryApiResponse,
+    trino_cluster::ClusterState,
     trino_query::{QueuedQuery, TrinoQuery},
-    TrinoLbQueryId, TrinoQueryId,
+    TrinoClusterName, TrinoLbQueryId, TrinoQueryId,
 };
-use trino_lb_persistence::Persistence;
+use trino_lb_persistence::{Persistence, PersistenceImplementation, QueryCountDecrement};
 use url::Url;
 
 use crate::{
-    cluster_group_manager::{self, SendToTrinoResponse},
-    http_server::AppState,
+    cluster_group_manager::{self, BestCluster, SendToTrinoResponse},
+    http_server::{access_log::RoutedClusterGroup, admin::status::Replica, AppState},
     maintenance::leftover_queries::UPDATE_QUEUED_QUERY_LAST_ACCESSED_INTERVAL,
+    metrics::Metrics,
+    routing::RoutingDecision,
 };
 
 #[derive(Snafu, Debug)]
@@ -39,6 +50,11 @@ pub enum Error {
         source: trino_lb_core::trino_api::Error,
     },
 
+    #[snafu(display("Failed to modify infoUri trino send us to point to the external endpoint of the Trino cluster"))]
+    ModifyInfoUri {
+        source: trino_lb_core::trino_api::Error,
+    },
+
     #[snafu(display("Failed to convert queued query to trino query"))]
     ConvertQueuedQueryToTrinoQuery {
         source: trino_lb_core::trino_api::Error,
@@ -53,12 +69,44 @@ pub enum Error {
         query_id: TrinoLbQueryId,
     },
 
+    #[snafu(display("Queued query with id {query_id:?} not found"))]
+    QueuedQueryNotFound { query_id: TrinoLbQueryId },
+
     #[snafu(display("Failed to delete queued query with id {query_id:?} from persistence"))]
     DeleteQueuedQueryFromPersistence {
         source: trino_lb_persistence::Error,
         query_id: TrinoLbQueryId,
     },
 
+    #[snafu(display(
+        "Failed to load idempotent response for idempotency key {idempotency_key:?}"
+    ))]
+    LoadIdempotentResponse {
+        source: trino_lb_persistence::Error,
+        idempotency_key: String,
+    },
+
+    #[snafu(display(
+        "Failed to store idempotent response for idempotency key {idempotency_key:?}"
+    ))]
+    StoreIdempotentResponse {
+        source: trino_lb_persistence::Error,
+        idempotency_key: String,
+    },
+
+    #[snafu(display("Missing or invalid token for forcing the cluster group {cluster_group:?}"))]
+    InvalidClusterGroupOverrideToken { cluster_group: String },
+
+    #[snafu(display("Can not force the unknown cluster group {cluster_group:?}"))]
+    UnknownForcedClusterGroup { cluster_group: String },
+
+    #[snafu(display("The estimated queue wait of the cluster group {cluster_group} is {estimated_wait:?}, which exceeds the maximum of {max_estimated_wait:?}, please try again later"))]
+    EstimatedQueueWaitTooHigh {
+        cluster_group: String,
+        estimated_wait: Duration,
+        max_estimated_wait: Duration,
+    },
+
     #[snafu(display("Failed to store query in persistence"))]
     StoreQueryInPersistence {
         source: trino_lb_persistence::Error,
@@ -71,12 +119,21 @@ pub enum Error {
         query_id: TrinoQueryId,
     },
 
+    #[snafu(display("Query with id {query_id:?} not found"))]
+    QueryNotFound { query_id: TrinoQueryId },
+
     #[snafu(display("Failed to find best cluster for cluster group {cluster_group}"))]
     FindBestClusterForClusterGroup {
         source: cluster_group_manager::Error,
         cluster_group: String,
     },
 
+    #[snafu(display("Failed to determine if the cluster group {cluster_group} rejects queries"))]
+    DetermineIfClusterGroupRejectsQueries {
+        source: cluster_group_manager::Error,
+        cluster_group: String,
+    },
+
     #[snafu(display("Failed to send query to trino"))]
     SendQueryToTrino {
         source: cluster_group_manager::Error,
@@ -92,6 +149,16 @@ pub enum Error {
         source: cluster_group_manager::Error,
     },
 
+    #[snafu(display(
+        "The Trino cluster {trino_cluster:?} the query {query_id:?} was running on can not be reached, as it is {cluster_state:?} (e.g. because it was deactivated or stopped). Please submit the query again"
+    ))]
+    TrinoClusterOfQueryNotAvailable {
+        source: cluster_group_manager::Error,
+        query_id: TrinoQueryId,
+        trino_cluster: TrinoClusterName,
+        cluster_state: ClusterState,
+    },
+
     #[snafu(display(
         "Failed to decrement the query counter query trino cluster {trino_cluster:?}"
     ))]
@@ -126,12 +193,65 @@ pub enum Error {
         requested_path: String,
         trino_endpoint: Url,
     },
+
+    #[snafu(display(
+        "The requested path {requested_path:?} does not match the slug and token Trino issued for query {query_id:?}"
+    ))]
+    InvalidStatementPath {
+        query_id: TrinoQueryId,
+        requested_path: String,
+    },
+
+    #[snafu(display("Failed to update the nextUri path of query {query_id:?} in persistence"))]
+    UpdateQueryNextUriPath {
+        source: trino_lb_persistence::Error,
+        query_id: TrinoQueryId,
+    },
 }
 
+/// Sent as `Retry-After` header in case the queue is full or the estimated queue wait is too high. Queued queries are handed over to Trino as their clients
+/// poll them, which they do at least every [`MAX_POLL_DELAY`], so by then some room in the queue should have freed up.
+const QUEUE_FULL_RETRY_AFTER: Duration = Duration::from_secs(10);
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         warn!(error = ?self, "Error while processing request");
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{self:?}")).into_response()
+        let status_code = match &self {
+            // Same as Trino does for an invalid slug, so that we don't reveal the query exists
+            Error::InvalidStatementPath { .. }
+            | Error::QueuedQueryNotFound { .. }
+            | Error::QueryNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::InvalidClusterGroupOverrideToken { .. } => StatusCode::FORBIDDEN,
+            Error::UnknownForcedClusterGroup { .. } => StatusCode::BAD_REQUEST,
+            // Not a server error clients should retry, the query is lost together with the cluster
+            Error::TrinoClusterOfQueryNotAvailable { .. } => StatusCode::GONE,
+            // Clients should retry later, once some of the queued queries are handed over to Trino
+            Error::StoreQueuedQueryInPersistence { source } if source.is_queue_full() => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::EstimatedQueueWaitTooHigh { .. } => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = if status_code == StatusCode::NOT_FOUND {
+            // Trino answers requests for unknown queries with the same plain text body
+            "Query not found".to_owned()
+        } else if matches!(
+            self,
+            Error::TrinoClusterOfQueryNotAvailable { .. } | Error::EstimatedQueueWaitTooHigh { .. }
+        ) {
+            // Contains the guidance to submit the query (again)
+            self.to_string()
+        } else {
+            format!("{self:?}")
+        };
+        if status_code == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = [(
+                http::header::RETRY_AFTER,
+                QUEUE_FULL_RETRY_AFTER.as_secs().to_string(),
+            )];
+            return (status_code, retry_after, body).into_response();
+        }
+        (status_code, body).into_response()
     }
 }
 
@@ -142,25 +262,170 @@ impl IntoResponse for Error {
     fields(headers = ?headers.sanitize()),
 )]
 pub async fn post_statement(
-    headers: HeaderMap,
+    mut headers: HeaderMap,
     State(state): State<Arc<AppState>>,
     query: String,
-) -> Result<SendToTrinoResponse, Error> {
-    state
-        .metrics
-        .http_counter
-        .add(1, &[KeyValue::new("resource", "post_statement")]);
+) -> Result<Response, Error> {
+    let _timer = state.metrics.record_http_request("post_statement");
 
-    let cluster_group = state
-        .router
-        .get_target_cluster_group(&query, &headers)
-        .await;
+    // Needs to be validated before looking up the idempotency key, so that a retry can not skip the token check
+    let forced_cluster_group = forced_cluster_group(&state, &mut headers)?;
+
+    let idempotency_key = idempotency_key(&state, &headers);
+    if let Some(idempotency_key) = &idempotency_key {
+        let existing_response = state
+            .persistence
+            .load_idempotent_response(idempotency_key)
+            .await
+            .context(LoadIdempotentResponseSnafu { idempotency_key })?;
+
+        if let Some(trino_query_api_response) = existing_response {
+            info!(
+                idempotency_key,
+                query_id = trino_query_api_response.id,
+                "Query was already submitted with the same idempotency key, returning the existing query"
+            );
+            return Ok(SendToTrinoResponse::HandedOver {
+                trino_query_api_response,
+                headers: HeaderMap::new(),
+            }
+            .into_response());
+        }
+    }
+
+    let routing_decision = match forced_cluster_group {
+        Some(routing_decision) => routing_decision,
+        None => {
+            state
+                .router
+                .get_target_cluster_group(&query, &headers)
+                .await
+        }
+    };
 
     // While we technically construct an [`QueuedQuery`] object here, this does not mean the query will be queued!
     // We just use the same code flow for queued and (non-queued) fresh queries from the initial POST.
-    let queued_query = QueuedQuery::new_from(query, headers, cluster_group);
+    let routed_cluster_group = RoutedClusterGroup(routing_decision.cluster_group.clone());
+    let queued_query = QueuedQuery::new_from(
+        query,
+        headers,
+        routing_decision.cluster_group,
+        Some(routing_decision.reason),
+    );
+
+    let response = queue_or_hand_over_query(&state, queued_query, false, 0).await?;
+
+    // Both queries handed over to Trino and queries queued in trino-lb are answered with a `HandedOver` response, so
+    // that retries of queued queries get the queued query as well.
+    if let (
+        Some(idempotency_key),
+        Some(idempotency_config),
+        SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        },
+    ) = (
+        &idempotency_key,
+        &state.config.trino_lb.idempotency,
+        &response,
+    ) {
+        // Please note that there is a small window in which two concurrent requests with the same idempotency key
+        // can both submit the query, as we don't lock the idempotency key.
+        let result = state
+            .persistence
+            .store_idempotent_response(
+                idempotency_key,
+                trino_query_api_response,
+                idempotency_config.ttl,
+            )
+            .await
+            .context(StoreIdempotentResponseSnafu { idempotency_key });
+
+        // The query is already submitted, failing the request would cause the client to submit it a second time
+        if let Err(error) = result {
+            warn!(
+                ?error,
+                query_id = trino_query_api_response.id,
+                "Failed to store idempotent response, retries of the query will submit it again"
+            );
+        }
+    }
+
+    Ok((Extension(routed_cluster_group), response).into_response())
+}
+
+/// Returns the idempotency key the client has sent (if idempotency is configured at all). The key is scoped to the
+/// Trino user and the credentials in the `Authorization` header, so that a client can only get queries that were
+/// submitted with the same credentials. trino-lb does not authenticate the `x-trino-user` header itself, so clients
+/// that don't send credentials can get the queries of each other in case they use the same user and idempotency key.
+///
+/// Only a hash of the credentials is part of the key, as the key is stored and logged.
+fn idempotency_key(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    let idempotency_config = state.config.trino_lb.idempotency.as_ref()?;
+    let idempotency_key = headers
+        .get(&idempotency_config.header_name)?
+        .to_str()
+        .ok()?;
+    let user = headers
+        .get("x-trino-user")
+        .and_then(|user| user.to_str().ok())
+        .unwrap_or_default();
+    let credentials = Sha256::digest(
+        headers
+            .get(http::header::AUTHORIZATION)
+            .map(HeaderValue::as_bytes)
+            .unwrap_or_default(),
+    );
+
+    Some(format!("{user}/{credentials:x}/{idempotency_key}"))
+}
+
+/// Returns the cluster group the client forced using the override header, in case overriding is configured. Both
+/// override headers are removed, so that the token is never forwarded to Trino.
+fn forced_cluster_group(
+    state: &AppState,
+    headers: &mut HeaderMap,
+) -> Result<Option<RoutingDecision>, Error> {
+    let Some(override_config) = &state.config.trino_lb.cluster_group_override else {
+        return Ok(None);
+    };
+    let forced = headers.remove(&override_config.header_name);
+    let token = headers.remove(&override_config.token_header_name);
+    let Some(forced) = forced else {
+        return Ok(None);
+    };
+
+    let cluster_group = String::from_utf8_lossy(forced.as_bytes()).into_owned();
+    // Constant time comparison, so that the token can not be guessed by measuring response times
+    ensure!(
+        token.is_some_and(|token| token
+            .as_bytes()
+            .ct_eq(override_config.token.as_bytes())
+            .into()),
+        InvalidClusterGroupOverrideTokenSnafu { cluster_group }
+    );
+    ensure!(
+        state
+            .config
+            .trino_cluster_groups
+            .contains_key(&cluster_group),
+        UnknownForcedClusterGroupSnafu { cluster_group }
+    );
+
+    warn!(
+        cluster_group,
+        user = sanitize_user(
+            headers
+                .get("x-trino-user")
+                .and_then(|user| user.to_str().ok())
+        ),
+        "Cluster group of query was forced using the override header, skipping all routers"
+    );
 
-    queue_or_hand_over_query(&state, queued_query, false, 0).await
+    Ok(Some(RoutingDecision {
+        reason: format!("forced via {} header", override_config.header_name),
+        cluster_group,
+    }))
 }
 
 /// This function get's asked about the current state of a query that is queued in trino-lb.
@@ -173,10 +438,7 @@ pub async fn get_trino_lb_statement(
     State(state): State<Arc<AppState>>,
     Path((query_id, sequence_number)): Path<(TrinoLbQueryId, u64)>,
 ) -> Result<SendToTrinoResponse, Error> {
-    state
-        .metrics
-        .http_counter
-        .add(1, &[KeyValue::new("resource", "get_trino_lb_statement")]);
+    let _timer = state.metrics.record_http_request("get_trino_lb_statement");
 
     let queued_query = state
         .persistence
@@ -184,9 +446,197 @@ pub async fn get_trino_lb_statement(
         .await
         .context(LoadQueuedQueryFromPersistenceSnafu {
             query_id: &query_id,
+        })?
+        .context(QueuedQueryNotFoundSnafu {
+            query_id: &query_id,
         })?;
 
-    queue_or_hand_over_query(&state, queued_query, true, sequence_number).await
+    // In case the client disconnects while we are processing the request (most likely while we are delaying the
+    // response), hyper drops this future and the guard removes the queued query.
+    let disconnect_guard = ClientDisconnectGuard::new(Arc::clone(&state), queued_query.id.clone());
+    let response = queue_or_hand_over_query(&state, queued_query, true, sequence_number).await;
+    disconnect_guard.disarm();
+
+    response
+}
+
+/// Removes a queued query from the persistence in case the request polling for it is dropped before completion,
+/// which happens when the client closes the connection. Otherwise the queued query would linger around until the
+/// [`LeftoverQueryDetector`](crate::maintenance::leftover_queries::LeftoverQueryDetector) removes it, inflating the
+/// queue length in the meantime.
+///
+/// Only the id of the queued query is kept, as the queued query might be moved to a different cluster group (e.g. out
+/// of a parking cluster group) while the request is processed. The currently stored queued query is loaded right
+/// before removing it, so that it is removed from the queue of the cluster group it is actually queued in.
+struct ClientDisconnectGuard {
+    state: Arc<AppState>,
+    queued_query_id: Option<TrinoLbQueryId>,
+}
+
+impl ClientDisconnectGuard {
+    fn new(state: Arc<AppState>, queued_query_id: TrinoLbQueryId) -> Self {
+        Self {
+            state,
+            queued_query_id: Some(queued_query_id),
+        }
+    }
+
+    /// Needs to be called once the request was processed, so that the queued query is not removed.
+    fn disarm(mut self) {
+        self.queued_query_id = None;
+    }
+}
+
+impl Drop for ClientDisconnectGuard {
+    fn drop(&mut self) {
+        let Some(queued_query_id) = self.queued_query_id.take() else {
+            return;
+        };
+
+        info!(
+            queued_query_id,
+            "Client disconnected while polling for queued query, removing queued query"
+        );
+
+        let persistence = Arc::clone(&self.state.persistence);
+        tokio::spawn(async move {
+            let result = match persistence.load_queued_query(&queued_query_id).await {
+                Ok(Some(queued_query)) => persistence.remove_queued_query(&queued_query).await,
+                // Already removed, e.g. because it was handed over to Trino
+                Ok(None) => Ok(()),
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                warn!(
+                    ?error,
+                    queued_query_id, "Failed to remove queued query of disconnected client"
+                );
+            }
+        });
+    }
+}
+
+/// Tracks the number of requests currently proxied to Trino for the given cluster group. The counter is decremented
+/// once the guard is dropped, so that it is also decremented on early returns and errors.
+struct ProxyRequestInFlightGuard<'a> {
+    counter: UpDownCounter<i64>,
+    attributes: [KeyValue; 1],
+    replica: &'a Replica,
+    cluster_group: String,
+}
+
+impl<'a> ProxyRequestInFlightGuard<'a> {
+    fn new(counter: UpDownCounter<i64>, replica: &'a Replica, cluster_group: &str) -> Self {
+        let attributes = [KeyValue::new("cluster-group", cluster_group.to_owned())];
+        counter.add(1, &attributes);
+        replica.proxy_request_started(cluster_group);
+
+        Self {
+            counter,
+            attributes,
+            replica,
+            cluster_group: cluster_group.to_owned(),
+        }
+    }
+}
+
+impl Drop for ProxyRequestInFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.counter.add(-1, &self.attributes);
+        self.replica.proxy_request_finished(&self.cluster_group);
+    }
+}
+
+/// Decrements the query counter of the given cluster in case it is dropped before [`Self::disarm`] is called.
+/// This is needed, as the query counter is incremented before the query is send to Trino, so that the counter is not
+/// leaked in case the client disconnects before the query is send.
+///
+/// Once the request to Trino is sent, the query might be running on Trino even if the client disconnects in the
+/// meantime. Releasing the reservation in that case would let the query counter undercount the running queries, so
+/// the reservation needs to be disarmed before sending the query and only released again using [`Self::release`] in
+/// case sending the query failed for sure.
+struct ClusterQueryCounterReservation {
+    persistence: Arc<PersistenceImplementation>,
+    metrics: Arc<Metrics>,
+    trino_cluster: Option<TrinoClusterName>,
+}
+
+impl ClusterQueryCounterReservation {
+    fn new(
+        persistence: Arc<PersistenceImplementation>,
+        metrics: Arc<Metrics>,
+        trino_cluster: TrinoClusterName,
+    ) -> Self {
+        Self {
+            persistence,
+            metrics,
+            trino_cluster: Some(trino_cluster),
+        }
+    }
+
+    fn disarm(mut self) {
+        self.trino_cluster = None;
+    }
+
+    /// Decrements the query counter of a disarmed reservation, as the query was not sent to Trino.
+    async fn release(
+        persistence: &PersistenceImplementation,
+        metrics: &Metrics,
+        trino_cluster: &TrinoClusterName,
+    ) {
+        debug!(
+            trino_cluster,
+            "Query was not handed over to Trino cluster, decrementing query counter again"
+        );
+
+        if let Err(error) =
+            dec_cluster_query_count(persistence, metrics, trino_cluster, "release_reservation")
+                .await
+        {
+            warn!(
+                ?error,
+                trino_cluster, "Failed to decrement query counter of reserved Trino cluster"
+            );
+        }
+    }
+}
+
+impl Drop for ClusterQueryCounterReservation {
+    fn drop(&mut self) {
+        let Some(trino_cluster) = self.trino_cluster.take() else {
+            return;
+        };
+
+        let persistence = Arc::clone(&self.persistence);
+        let metrics = Arc::clone(&self.metrics);
+        tokio::spawn(async move {
+            Self::release(&persistence, &metrics, &trino_cluster).await;
+        });
+    }
+}
+
+/// Decrements the query counter of the given cluster and returns the query count after the decrement. Decrementing a
+/// counter that is already zero points to a bug (e.g. a query decremented twice or a lost increment), so it is logged
+/// together with the `call_site` and counted in the `cluster_counter_underflow_total` metric.
+async fn dec_cluster_query_count(
+    persistence: &PersistenceImplementation,
+    metrics: &Metrics,
+    trino_cluster: &TrinoClusterName,
+    call_site: &'static str,
+) -> Result<u64, trino_lb_persistence::Error> {
+    let decrement = persistence.dec_cluster_query_count(trino_cluster).await?;
+    if decrement == QueryCountDecrement::AlreadyZero {
+        error!(
+            trino_cluster,
+            call_site,
+            "Tried to decrement the query counter of the Trino cluster, but it was already zero. This should not happen"
+        );
+        metrics
+            .cluster_counter_underflow_counter
+            .add(1, &[KeyValue::new("cluster", trino_cluster.clone())]);
+    }
+
+    Ok(decrement.remaining())
 }
 
 /// This function get's asked about the current state of a query that is already sent to an
@@ -204,10 +654,9 @@ pub async fn get_trino_queued_statement(
     Path((query_id, _, _)): Path<(TrinoQueryId, String, u64)>,
     uri: Uri,
 ) -> Result<(HeaderMap, Json<TrinoQueryApiResponse>), Error> {
-    state.metrics.http_counter.add(
-        1,
-        &[KeyValue::new("resource", "get_trino_queued_statement")],
-    );
+    let _timer = state
+        .metrics
+        .record_http_request("get_trino_queued_statement");
 
     handle_query_running_on_trino(&state, headers, query_id, uri.path()).await
 }
@@ -227,14 +676,82 @@ pub async fn get_trino_executing_statement(
     Path((query_id, _, _)): Path<(TrinoQueryId, String, u64)>,
     uri: Uri,
 ) -> Result<(HeaderMap, Json<TrinoQueryApiResponse>), Error> {
-    state.metrics.http_counter.add(
-        1,
-        &[KeyValue::new("resource", "get_trino_executing_statement")],
-    );
+    let _timer = state
+        .metrics
+        .record_http_request("get_trino_executing_statement");
 
     handle_query_running_on_trino(&state, headers, query_id, uri.path()).await
 }
 
+/// Finds the best cluster of the cluster group and increments its query counter. Other requests might take the last
+/// slot of the cluster in the meantime, in which case we retry right away (up to `trinoLb.handOverRetries` times), as
+/// queuing the query would cost the client a poll round-trip. Returns [`None`] in case no cluster has capacity left.
+async fn reserve_cluster<'a>(
+    state: &'a AppState,
+    cluster_group: &str,
+) -> Result<Option<BestCluster<'a>>, Error> {
+    reserve_cluster_with(state, cluster_group, || {
+        state
+            .cluster_group_manager
+            .try_find_best_cluster_for_group(cluster_group)
+    })
+    .await
+}
+
+/// Same as [`reserve_cluster`], but the best cluster is found using `find_best_cluster`, so that tests can control
+/// how requests racing for the same cluster interleave.
+async fn reserve_cluster_with<'a, F, Fut>(
+    state: &'a AppState,
+    cluster_group: &str,
+    find_best_cluster: F,
+) -> Result<Option<BestCluster<'a>>, Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Option<BestCluster<'a>>, cluster_group_manager::Error>>,
+{
+    for attempt in 0..=state.config.trino_lb.hand_over_retries {
+        let Some(best) = find_best_cluster()
+            .await
+            .context(FindBestClusterForClusterGroupSnafu { cluster_group })?
+        else {
+            return Ok(None);
+        };
+
+        debug!(
+            cluster = best.cluster.name,
+            "Found cluster that has sufficient space"
+        );
+        let has_increased = state
+            .persistence
+            .inc_cluster_query_count(
+                state
+                    .cluster_group_manager
+                    .query_counter_of(&best.cluster.name),
+                best.max_running_queries,
+            )
+            .await
+            .context(DecClusterQueryCounterSnafu {
+                trino_cluster: &best.cluster.name,
+            })?;
+        if has_increased {
+            return Ok(Some(best));
+        }
+
+        debug!(
+            cluster = best.cluster.name,
+            attempt,
+            "The cluster had enough space when asked for the best cluster, but inc_cluster_query_count returned false, \
+            probably because the cluster has reached its maximum query count in the meantime"
+        );
+        state
+            .metrics
+            .hand_over_retries
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    Ok(None)
+}
+
 #[instrument(skip(state))]
 async fn queue_or_hand_over_query(
     state: &Arc<AppState>,
@@ -249,110 +766,51 @@ async fn queue_or_hand_over_query(
         creation_time,
         last_accessed,
         cluster_group,
+        routing_reason: _,
     } = &queued_query;
 
     let start_of_request = Instant::now();
 
-    let best_cluster_for_group = state
-        .cluster_group_manager
-        .try_find_best_cluster_for_group(cluster_group)
-        .await
-        .context(FindBestClusterForClusterGroupSnafu { cluster_group })?;
-
-    if let Some(cluster) = best_cluster_for_group {
-        debug!(
-            cluster = cluster.name,
-            "Found cluster that has sufficient space"
+    // The cluster group might have been removed from the config (e.g. during a restart) while the query was queued
+    if !state
+        .config
+        .trino_cluster_groups
+        .contains_key(cluster_group)
+    {
+        warn!(
+            cluster_group,
+            "Failing queued query, as its cluster group does not exist any more"
         );
-        let has_increased = state
-            .persistence
-            .inc_cluster_query_count(&cluster.name.to_string(), cluster.max_running_queries)
-            .await
-            .context(DecClusterQueryCounterSnafu {
-                trino_cluster: &cluster.name,
-            })?;
-
-        if has_increased {
-            let mut send_to_trino_response = state
-                .cluster_group_manager
-                .send_query_to_cluster(query.clone(), headers.clone(), cluster)
+        if queued_query_already_stored_in_persistence {
+            state
+                .persistence
+                .remove_queued_query(&queued_query)
                 .await
-                .context(SendQueryToTrinoSnafu)?;
-
-            match send_to_trino_response {
-                SendToTrinoResponse::HandedOver {
-                    ref mut trino_query_api_response,
-                    ..
-                } => {
-                    let queued_duration = creation_time
-                        .elapsed()
-                        .context(DetermineQueuedDurationSnafu)?;
-                    state.metrics.queued_time.record(
-                        queued_duration
-                            .as_millis()
-                            .try_into()
-                            .context(ConvertQueuedDurationToMillisSnafu { queued_duration })?,
-                        &[],
-                    );
+                .context(DeleteQueuedQueryFromPersistenceSnafu {
+                    query_id: queued_query_id,
+                })?;
+        }
 
-                    if trino_query_api_response.next_uri.is_some() {
-                        let query = TrinoQuery::new_from(
-                            cluster.name.clone(),
-                            trino_query_api_response.id.clone(),
-                            cluster.endpoint.clone(),
-                            *creation_time,
-                            SystemTime::now(),
-                        );
-                        let query_id = query.id.clone();
-
-                        state.persistence.store_query(query).await.context(
-                            StoreQueryInPersistenceSnafu {
-                                query_id: &query_id,
-                            },
-                        )?;
-
-                        trino_query_api_response
-                            .change_next_uri_to_trino_lb(&state.config.trino_lb.external_address)
-                            .context(ModifyNextUriSnafu)?;
-
-                        info!(
-                            query_id,
-                            trino_cluster_name = cluster.name,
-                            "Successfully handed query over to Trino cluster"
-                        );
-                    } else {
-                        warn!(
-                            trino_cluster_name = cluster.name,
-                            new_query_id = trino_query_api_response.id,
-                            "Trino got our query but send no nextUri. Maybe an Syntax error or something similar?"
-                        );
-
-                        // The queued query will be removed from the persistence below.
-                        // As the query is probably finished, lets decrement the query counter again.
-                        state
-                            .persistence
-                            .dec_cluster_query_count(&cluster.name)
-                            .await
-                            .context(DecClusterQueryCounterSnafu {
-                                trino_cluster: &cluster.name,
-                            })?;
-                    }
-                }
-                SendToTrinoResponse::Unauthorized { .. } => {
-                    // As the query was not actually started decrement the query counter again.
-                    state
-                        .persistence
-                        .dec_cluster_query_count(&cluster.name)
-                        .await
-                        .context(DecClusterQueryCounterSnafu {
-                            trino_cluster: &cluster.name,
-                        })?;
-
-                    // We don't need to store any information about this request in the persistence, as the client will
-                    // retry the POST /v1/statement shortly with the correct `Authorization` header set.
-                }
-            }
+        let trino_query_api_response =
+            TrinoQueryApiResponse::new_no_nodes_available_from_queued_query(
+                &queued_query,
+                &format!("The target cluster group {cluster_group} of the query no longer exists, please submit the query again"),
+                &state.config.trino_lb.external_address,
+                state.config.trino_lb.max_clock_skew,
+            )
+            .context(ConvertQueuedQueryToTrinoQuerySnafu)?;
+        return Ok(SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            headers: HeaderMap::new(),
+        });
+    }
 
+    if let Some(max_poll_sequence) = state.config.trino_lb.max_poll_sequence {
+        if current_sequence_number > max_poll_sequence {
+            warn!(
+                current_sequence_number,
+                max_poll_sequence, "Failing queued query, as it was polled too many times"
+            );
             if queued_query_already_stored_in_persistence {
                 state
                     .persistence
@@ -363,63 +821,317 @@ async fn queue_or_hand_over_query(
                     })?;
             }
 
-            return Ok(send_to_trino_response);
-        } else {
-            debug!(
-                    cluster = cluster.name,
-                    "The cluster had enough space when asked for the best cluster, but inc_cluster_query_count returned None, \
-                    probably because the cluster has reached its maximum query count in the meantime"
-                );
+            let trino_query_api_response = TrinoQueryApiResponse::new_abandoned_from_queued_query(
+                &queued_query,
+                &format!("Query abandoned after too many polls (more than {max_poll_sequence})"),
+                &state.config.trino_lb.external_address,
+                state.config.trino_lb.max_clock_skew,
+            )
+            .context(ConvertQueuedQueryToTrinoQuerySnafu)?;
+            return Ok(SendToTrinoResponse::HandedOver {
+                trino_query_api_response,
+                headers: HeaderMap::new(),
+            });
         }
     }
 
-    let trino_lb_query_api_response = TrinoQueryApiResponse::new_from_queued_query(
-        &queued_query,
-        current_sequence_number,
-        &state.config.trino_lb.external_address,
-    )
-    .context(ConvertQueuedQueryToTrinoQuerySnafu)?;
+    let reserved_cluster = if current_sequence_number < state.config.trino_lb.min_admission_sequence
+    {
+        debug!(
+            current_sequence_number,
+            "Keeping query queued, as it did not reach the minimum admission sequence yet"
+        );
+        None
+    } else {
+        reserve_cluster(state, cluster_group).await?
+    };
 
-    if !queued_query_already_stored_in_persistence {
-        state
-            .persistence
-            .store_queued_query(queued_query)
+    if let Some(best) = reserved_cluster {
+        let cluster = best.cluster;
+        let query_counter = state.cluster_group_manager.query_counter_of(&cluster.name);
+        let reservation = ClusterQueryCounterReservation::new(
+            Arc::clone(&state.persistence),
+            Arc::clone(&state.metrics),
+            query_counter.clone(),
+        );
+        // From here on the query might reach Trino, even if the client disconnects and this future is dropped
+        reservation.disarm();
+        let mut send_to_trino_response = match state
+            .cluster_group_manager
+            .send_query_to_cluster(query.clone(), headers.clone(), cluster)
             .await
-            .context(StoreQueuedQueryInPersistenceSnafu)?;
-    } else if last_accessed
-        .elapsed()
-        .context(DetermineLastAccessedDurationSnafu)?
-        >= UPDATE_QUEUED_QUERY_LAST_ACCESSED_INTERVAL
-    {
-        let queued_query = QueuedQuery {
-            last_accessed: SystemTime::now(),
-            ..queued_query
+        {
+            Ok(send_to_trino_response) => send_to_trino_response,
+            Err(error) => {
+                if error.is_query_not_sent() {
+                    ClusterQueryCounterReservation::release(
+                        &state.persistence,
+                        &state.metrics,
+                        query_counter,
+                    )
+                    .await;
+                } else {
+                    warn!(
+                        ?error,
+                        trino_cluster = cluster.name,
+                        "Unclear if the query reached Trino, keeping the query counter incremented until the \
+                        query count fetcher corrects it"
+                    );
+                }
+                return Err(error).context(SendQueryToTrinoSnafu);
+            }
         };
-        state
-            .persistence
-            .store_queued_query(queued_query)
-            .await
-            .context(StoreQueuedQueryInPersistenceSnafu)?;
-    }
 
-    // We slow down here, so that clients don't flood us with status requests. We skip this for the first request,
-    // so that e.g. trino-cli imminently shows the query is queued in trino-lb (at least in theory - in practice
-    // trino-cli behaves a bit strange).
-    if current_sequence_number > 1 {
-        let delay = delay_for_sequence_number(current_sequence_number);
-        tokio::time::sleep(delay.saturating_sub(start_of_request.elapsed()))
-            .instrument(info_span!("Delaying response to slow down clients", ?delay))
-            .await;
-    }
+        match send_to_trino_response {
+            SendToTrinoResponse::HandedOver {
+                ref mut trino_query_api_response,
+                ..
+            } => {
+                state
+                    .cluster_group_manager
+                    .change_info_uri_to_external_endpoint(&cluster.name, trino_query_api_response)
+                    .context(ModifyInfoUriSnafu)?;
+
+                let queued_duration = elapsed_allowing_clock_skew(
+                    *creation_time,
+                    state.config.trino_lb.max_clock_skew,
+                )
+                .context(DetermineQueuedDurationSnafu)?;
+                state.metrics.queued_time.record(
+                    queued_duration
+                        .as_millis()
+                        .try_into()
+                        .context(ConvertQueuedDurationToMillisSnafu { queued_duration })?,
+                    &[],
+                );
+                state
+                    .metrics
+                    .record_queue_wait(cluster_group, queued_duration);
+                let cluster_utilization_percent = best.utilization_percent();
+                state.metrics.handoff_cluster_utilization.record(
+                    cluster_utilization_percent,
+                    &[KeyValue::new("cluster-group", cluster_group.clone())],
+                );
 
-    Ok(SendToTrinoResponse::HandedOver {
-        trino_query_api_response: trino_lb_query_api_response,
-        headers: HeaderMap::new(),
-    })
-}
+                if trino_query_api_response.next_uri.is_some() {
+                    let next_uri_path = trino_query_api_response
+                        .next_uri_path()
+                        .context(ModifyNextUriSnafu)?;
+                    let query = TrinoQuery::new_from(
+                        cluster.name.clone(),
+                        trino_query_api_response.id.clone(),
+                        cluster.endpoint.clone(),
+                        *creation_time,
+                        SystemTime::now(),
+                        // Only needed in case we record the runtime of the query
+                        state
+                            .config
+                            .trino_lb
+                            .query_runtime_feedback
+                            .as_ref()
+                            .map(|_| query_fingerprint(query)),
+                        // Only needed in case we validate the slug and token clients send
+                        if state.config.trino_lb.validate_statement_uris {
+                            next_uri_path.clone()
+                        } else {
+                            None
+                        },
+                    );
+                    let query_id = query.id.clone();
+
+                    if let Err(error) = store_query(state, &query).await {
+                        if matches!(&error, Error::StoreQueryInPersistence { source, .. } if source.is_query_id_collision())
+                        {
+                            // The client can not reach the query, so it would run unnoticed
+                            abandon_handed_over_query(
+                                state,
+                                headers.clone(),
+                                &query,
+                                next_uri_path.as_deref(),
+                                query_counter,
+                            )
+                            .await;
+                        }
+                        return Err(error);
+                    }
 
-#[instrument(
-    skip(state),
+                    trino_query_api_response
+                        .change_next_uri_to_trino_lb(&state.config.trino_lb.external_address)
+                        .context(ModifyNextUriSnafu)?;
+
+                    info!(
+                        query_id,
+                        trino_cluster_name = cluster.name,
+                        cluster_query_count = best.query_count,
+                        max_running_queries = best.max_running_queries,
+                        cluster_utilization_percent,
+                        "Successfully handed query over to Trino cluster"
+                    );
+                    record_handed_over_query(state, headers, cluster_group);
+                } else {
+                    warn!(
+                        trino_cluster_name = cluster.name,
+                        new_query_id = trino_query_api_response.id,
+                        "Trino got our query but send no nextUri. Maybe an Syntax error or something similar?"
+                    );
+                    state
+                        .metrics
+                        .query_immediate_no_next_uri_counter
+                        .add(1, &[KeyValue::new("cluster", cluster.name.clone())]);
+
+                    // The queued query will be removed from the persistence below.
+                    // As the query is probably finished, lets decrement the query counter again.
+                    dec_cluster_query_count(
+                        &state.persistence,
+                        &state.metrics,
+                        query_counter,
+                        "query_without_next_uri",
+                    )
+                    .await
+                    .context(DecClusterQueryCounterSnafu {
+                        trino_cluster: &cluster.name,
+                    })?;
+                }
+            }
+            SendToTrinoResponse::Unauthorized { .. } => {
+                // As the query was not actually started decrement the query counter again.
+                dec_cluster_query_count(
+                    &state.persistence,
+                    &state.metrics,
+                    query_counter,
+                    "query_unauthorized",
+                )
+                .await
+                .context(DecClusterQueryCounterSnafu {
+                    trino_cluster: &cluster.name,
+                })?;
+
+                // We don't need to store any information about this request in the persistence, as the client will
+                // retry the POST /v1/statement shortly with the correct `Authorization` header set.
+            }
+        }
+
+        if queued_query_already_stored_in_persistence {
+            state
+                .persistence
+                .remove_queued_query(&queued_query)
+                .await
+                .context(DeleteQueuedQueryFromPersistenceSnafu {
+                    query_id: queued_query_id,
+                })?;
+        }
+
+        return Ok(send_to_trino_response);
+    }
+
+    if state
+        .cluster_group_manager
+        .rejects_queries(cluster_group)
+        .await
+        .context(DetermineIfClusterGroupRejectsQueriesSnafu { cluster_group })?
+    {
+        warn!(
+            cluster_group,
+            "Rejecting query, as no cluster of the cluster group is ready or can become ready"
+        );
+        if queued_query_already_stored_in_persistence {
+            state
+                .persistence
+                .remove_queued_query(&queued_query)
+                .await
+                .context(DeleteQueuedQueryFromPersistenceSnafu {
+                    query_id: queued_query_id,
+                })?;
+        }
+
+        let trino_query_api_response =
+            TrinoQueryApiResponse::new_no_nodes_available_from_queued_query(
+                &queued_query,
+                &format!("No Trino cluster of the cluster group {cluster_group} is ready or can become ready, please try again later"),
+                &state.config.trino_lb.external_address,
+                state.config.trino_lb.max_clock_skew,
+            )
+            .context(ConvertQueuedQueryToTrinoQuerySnafu)?;
+        return Ok(SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            headers: HeaderMap::new(),
+        });
+    }
+
+    if !queued_query_already_stored_in_persistence {
+        let max_estimated_wait = state
+            .config
+            .trino_cluster_groups
+            .get(cluster_group)
+            .and_then(|group_config| group_config.max_estimated_wait);
+        if let Some(max_estimated_wait) = max_estimated_wait {
+            if let Some(estimated_wait) = state
+                .metrics
+                .average_queue_wait(cluster_group)
+                .filter(|estimated_wait| *estimated_wait > max_estimated_wait)
+            {
+                // Same as a full queue, clients should retry later
+                return EstimatedQueueWaitTooHighSnafu {
+                    cluster_group,
+                    estimated_wait,
+                    max_estimated_wait,
+                }
+                .fail();
+            }
+        }
+    }
+
+    let trino_lb_query_api_response = TrinoQueryApiResponse::new_from_queued_query(
+        &queued_query,
+        current_sequence_number,
+        &state.config.trino_lb.queued_query_state,
+        &state.config.trino_lb.external_address,
+        state.config.trino_lb.max_clock_skew,
+    )
+    .context(ConvertQueuedQueryToTrinoQuerySnafu)?;
+    // Needs to be determined before the queued query is consumed below
+    let delay = poll_delay(state, cluster_group, current_sequence_number).await;
+
+    if !queued_query_already_stored_in_persistence {
+        let mut queued_query = queued_query;
+        if let Some(allowlist) = &state.config.trino_lb.queued_query_header_allowlist {
+            queued_query.retain_headers(allowlist);
+        }
+        state
+            .persistence
+            .store_queued_query(queued_query)
+            .await
+            .context(StoreQueuedQueryInPersistenceSnafu)?;
+    } else if elapsed_allowing_clock_skew(*last_accessed, state.config.trino_lb.max_clock_skew)
+        .context(DetermineLastAccessedDurationSnafu)?
+        >= UPDATE_QUEUED_QUERY_LAST_ACCESSED_INTERVAL
+    {
+        // Only the last access is updated, as the query might have been moved to a different cluster group since it was
+        // loaded (e.g. promoted out of a parking cluster group)
+        state
+            .persistence
+            .update_queued_query_last_accessed(&queued_query, SystemTime::now())
+            .await
+            .context(StoreQueuedQueryInPersistenceSnafu)?;
+    }
+
+    // We slow down here, so that clients don't flood us with status requests. We skip this for the first request,
+    // so that e.g. trino-cli imminently shows the query is queued in trino-lb (at least in theory - in practice
+    // trino-cli behaves a bit strange).
+    if current_sequence_number > 1 {
+        tokio::time::sleep(delay.saturating_sub(start_of_request.elapsed()))
+            .instrument(info_span!("Delaying response to slow down clients", ?delay))
+            .await;
+    }
+
+    Ok(SendToTrinoResponse::HandedOver {
+        trino_query_api_response: trino_lb_query_api_response,
+        headers: HeaderMap::new(),
+    })
+}
+
+#[instrument(
+    skip(state),
     fields(headers = ?headers.sanitize()),
 )]
 async fn handle_query_running_on_trino(
@@ -428,30 +1140,71 @@ async fn handle_query_running_on_trino(
     query_id: TrinoQueryId,
     requested_path: &str,
 ) -> Result<(HeaderMap, Json<TrinoQueryApiResponse>), Error> {
-    let query =
-        state
-            .persistence
-            .load_query(&query_id)
-            .await
-            .context(StoreQueryInPersistenceSnafu {
-                query_id: query_id.clone(),
-            })?;
+    let query = state
+        .persistence
+        .load_query(&query_id)
+        .await
+        .context(LoadQueryFromPersistenceSnafu {
+            query_id: query_id.clone(),
+        })?
+        .context(QueryNotFoundSnafu {
+            query_id: query_id.clone(),
+        })?;
+    ensure!(
+        query.is_valid_statement_path(requested_path),
+        InvalidStatementPathSnafu {
+            query_id,
+            requested_path
+        }
+    );
 
-    let (mut trino_query_api_response, trino_headers) = state
+    let cluster_group = state
         .cluster_group_manager
-        .ask_for_query_state(
-            query.trino_endpoint.join(requested_path).context(
-                JoinRequestPathToTrinoEndpointSnafu {
-                    requested_path,
-                    trino_endpoint: query.trino_endpoint.clone(),
-                },
-            )?,
-            headers,
-        )
-        .await
-        .context(AskTrinoForQueryStateSnafu)?;
+        .cluster_group_of_cluster(&query.trino_cluster)
+        .unwrap_or("unknown");
+    let _in_flight = ProxyRequestInFlightGuard::new(
+        state.metrics.proxy_requests_in_flight.clone(),
+        &state.replica,
+        cluster_group,
+    );
+
+    // Awaited in the request handler (and not spawned), so that the request to Trino is aborted as soon as the client
+    // goes away, e.g. because it cancelled the query. This frees the connection to Trino right away.
+    let ask_for_query_state = state.cluster_group_manager.ask_for_query_state(
+        query
+            .trino_endpoint
+            .join(requested_path)
+            .context(JoinRequestPathToTrinoEndpointSnafu {
+                requested_path,
+                trino_endpoint: query.trino_endpoint.clone(),
+            })?,
+        headers,
+    );
+    let (mut trino_query_api_response, trino_headers) = match ask_for_query_state.await {
+        Ok(response) => response,
+        Err(error) => return Err(ask_trino_for_query_state_error(state, &query, error).await),
+    };
+    state
+        .cluster_group_manager
+        .change_info_uri_to_external_endpoint(&query.trino_cluster, &mut trino_query_api_response)
+        .context(ModifyInfoUriSnafu)?;
 
     if trino_query_api_response.next_uri.is_some() {
+        if query.next_uri_path.is_some() {
+            if let Some(next_uri_path) = trino_query_api_response
+                .next_uri_path()
+                .context(ModifyNextUriSnafu)?
+            {
+                state
+                    .persistence
+                    .update_query_next_uri_path(&query_id, next_uri_path)
+                    .await
+                    .context(UpdateQueryNextUriPathSnafu {
+                        query_id: &query_id,
+                    })?;
+            }
+        }
+
         // Change the nextUri to actually point to trino-lb instead of Trino.
         trino_query_api_response
             .change_next_uri_to_trino_lb(&state.config.trino_lb.external_address)
@@ -459,28 +1212,250 @@ async fn handle_query_running_on_trino(
     } else {
         info!(%query_id, "Query completed (no next_uri send)");
 
-        tokio::try_join!(
+        if trino_query_api_response.error.is_none() {
+            record_query_runtime(state, &query).await;
+        }
+
+        let (_, remaining_queries) = tokio::try_join!(
             state.persistence.remove_query(&query_id).map_err(|err| {
                 Error::DeleteQueuedQueryFromPersistence {
                     source: err,
                     query_id: query_id.to_owned(),
                 }
             }),
-            state
-                .persistence
-                .dec_cluster_query_count(&query.trino_cluster)
-                .map_err(|err| {
-                    Error::DecClusterQueryCounter {
-                        source: err,
-                        trino_cluster: query.trino_cluster.to_owned(),
-                    }
-                }),
+            dec_cluster_query_count(
+                &state.persistence,
+                &state.metrics,
+                state
+                    .cluster_group_manager
+                    .query_counter_of(&query.trino_cluster),
+                "query_finished",
+            )
+            .map_err(|err| {
+                Error::DecClusterQueryCounter {
+                    source: err,
+                    trino_cluster: query.trino_cluster.to_owned(),
+                }
+            }),
         )?;
+        debug!(
+            trino_cluster = query.trino_cluster,
+            remaining_queries, "Decremented the query counter of the cluster"
+        );
     }
 
     Ok((trino_headers, Json(trino_query_api_response)))
 }
 
+/// The cluster of a query might have been deactivated or stopped between handing over the query and the next poll of
+/// the client. In this case a distinct error tells the client to submit the query again. The cluster state is only
+/// read once polling failed, so that polling queries on healthy clusters does not cause additional persistence reads.
+#[instrument(skip(state))]
+async fn ask_trino_for_query_state_error(
+    state: &AppState,
+    query: &TrinoQuery,
+    source: cluster_group_manager::Error,
+) -> Error {
+    match state
+        .persistence
+        .get_cluster_state(&query.trino_cluster)
+        .await
+    {
+        Ok(
+            cluster_state @ (ClusterState::Stopped
+            | ClusterState::Starting
+            | ClusterState::WarmingUp { .. }
+            | ClusterState::Terminating
+            | ClusterState::Deactivated),
+        ) => {
+            release_query_of_unavailable_cluster(state, query).await;
+
+            Error::TrinoClusterOfQueryNotAvailable {
+                source,
+                query_id: query.id.clone(),
+                trino_cluster: query.trino_cluster.clone(),
+                cluster_state,
+            }
+        }
+        Ok(ClusterState::Unknown | ClusterState::Ready | ClusterState::Draining { .. }) => {
+            Error::AskTrinoForQueryState { source }
+        }
+        Err(error) => {
+            warn!(
+                ?error,
+                "Failed to read the state of the Trino cluster the query is running on"
+            );
+            Error::AskTrinoForQueryState { source }
+        }
+    }
+}
+
+/// The client is told to submit the query again, so the query is removed and its slot in the query counter of the
+/// cluster is freed. Otherwise the counter would stay inflated forever, as e.g. the
+/// [`QueryCountFetcher`](crate::maintenance::query_count_fetcher::QueryCountFetcher) does not correct the counters of
+/// deactivated clusters. As the client gets an error anyway, failures are only logged.
+#[instrument(skip(state))]
+async fn release_query_of_unavailable_cluster(state: &AppState, query: &TrinoQuery) {
+    if let Err(error) = state.persistence.remove_query(&query.id).await {
+        warn!(
+            ?error,
+            "Failed to remove the query of the unavailable Trino cluster"
+        );
+    }
+
+    if let Err(error) = dec_cluster_query_count(
+        &state.persistence,
+        &state.metrics,
+        state
+            .cluster_group_manager
+            .query_counter_of(&query.trino_cluster),
+        "cluster_not_available",
+    )
+    .await
+    {
+        warn!(
+            ?error,
+            "Failed to decrement the query counter of the unavailable Trino cluster"
+        );
+    }
+}
+
+/// Blends the runtime of the completed query into the runtime observed so far for the same query fingerprint (see
+/// `trinoLb.queryRuntimeFeedback`). As this is only used to improve future routing decisions, failures are only logged.
+#[instrument(skip(state))]
+async fn record_query_runtime(state: &AppState, query: &TrinoQuery) {
+    let (Some(feedback_config), Some(query_fingerprint)) = (
+        &state.config.trino_lb.query_runtime_feedback,
+        &query.query_fingerprint,
+    ) else {
+        return;
+    };
+    let Ok(runtime) =
+        elapsed_allowing_clock_skew(query.delivered_time, state.config.trino_lb.max_clock_skew)
+    else {
+        warn!("Failed to determine the runtime of the query, not recording it");
+        return;
+    };
+
+    let result = async {
+        let previous = state
+            .persistence
+            .load_query_runtime(query_fingerprint)
+            .await?;
+        let blended = blend_query_runtime(previous, runtime, feedback_config.decay);
+        state
+            .persistence
+            .store_query_runtime(query_fingerprint, blended, feedback_config.ttl)
+            .await
+    }
+    .await;
+
+    if let Err(error) = result {
+        warn!(?error, "Failed to record the runtime of the query");
+    }
+}
+
+/// Stores the query handed over to a Trino cluster. In case a query with the same id is already stored for a different
+/// Trino cluster, `trinoLb.queryIdCollisionBehavior` decides whether it is overwritten.
+#[instrument(skip(state))]
+async fn store_query(state: &Arc<AppState>, query: &TrinoQuery) -> Result<(), Error> {
+    let query_id = query.id.clone();
+
+    match state.persistence.store_query(query.clone()).await {
+        Err(error)
+            if error.is_query_id_collision()
+                && state.config.trino_lb.query_id_collision_behavior
+                    == QueryIdCollisionBehavior::Overwrite =>
+        {
+            warn!(
+                query_id,
+                trino_cluster = query.trino_cluster,
+                "A query with the same id is already stored for a different Trino cluster, overwriting it"
+            );
+            state.persistence.remove_query(&query_id).await.context(
+                StoreQueryInPersistenceSnafu {
+                    query_id: &query_id,
+                },
+            )?;
+            state
+                .persistence
+                .store_query(query.clone())
+                .await
+                .context(StoreQueryInPersistenceSnafu { query_id })
+        }
+        result => result.context(StoreQueryInPersistenceSnafu { query_id }),
+    }
+}
+
+/// Cancels the query on Trino and releases its slot in the query counter, as the query was handed over to Trino but can
+/// not be polled by the client. Failures are only logged, as the request fails anyway.
+#[instrument(skip(state, headers))]
+async fn abandon_handed_over_query(
+    state: &Arc<AppState>,
+    headers: HeaderMap,
+    query: &TrinoQuery,
+    next_uri_path: Option<&str>,
+    query_counter: &TrinoClusterName,
+) {
+    if let Some(next_uri_path) = next_uri_path {
+        if let Err(error) = state
+            .cluster_group_manager
+            .cancel_query_on_trino(headers, query, next_uri_path)
+            .await
+        {
+            warn!(?error, "Failed to cancel the abandoned query on Trino");
+        }
+    }
+
+    if let Err(error) = dec_cluster_query_count(
+        &state.persistence,
+        &state.metrics,
+        query_counter,
+        "abandoned_query",
+    )
+    .await
+    {
+        warn!(
+            ?error,
+            "Failed to decrement the query counter of the abandoned query"
+        );
+    }
+}
+
+/// Accounts the handed over query to the user sending it, in case `trinoLb.queryChargeback` is configured. The query
+/// is recorded in the background, so that it doesn't slow down the hand-over. The estimated costs of the
+/// `ExplainCostsRouter` are not recorded, as they are only known for the queries it estimated and consist of multiple
+/// measurements that don't add up to a single cost.
+fn record_handed_over_query(state: &Arc<AppState>, headers: &HeaderMap, cluster_group: &str) {
+    let Some(config) = &state.config.trino_lb.query_chargeback else {
+        return;
+    };
+    let user = sanitize_user(
+        headers
+            .get("x-trino-user")
+            .and_then(|user| user.to_str().ok()),
+    );
+    let bucket = query_chargeback::bucket_of(SystemTime::now());
+    let (max_users, retention) = (config.max_users, config.retention);
+    let cluster_group = cluster_group.to_owned();
+    let persistence = Arc::clone(&state.persistence);
+
+    tokio::spawn(
+        async move {
+            if let Err(error) = persistence
+                .record_handed_over_query(bucket, &user, &cluster_group, max_users, retention)
+                .await
+            {
+                warn!(
+                    ?error,
+                    user, cluster_group, "Failed to record handed over query"
+                );
+            }
+        }
+        .in_current_span(),
+    );
+}
+
 /// This function get's asked to delete the queued query.
 /// IMPORTANT: It does not check that the user is authorized to delete the queued query. Instead we assume that the
 /// random part of the queryId trino-lb generates provides sufficient protection, as other clients can not extract
@@ -493,18 +1468,21 @@ pub async fn delete_trino_lb_statement(
     State(state): State<Arc<AppState>>,
     Path((query_id, _)): Path<(TrinoLbQueryId, u64)>,
 ) -> Result<(), Error> {
-    state
+    let _timer = state
         .metrics
-        .http_counter
-        .add(1, &[KeyValue::new("resource", "delete_trino_lb_statement")]);
+        .record_http_request("delete_trino_lb_statement");
 
-    let queued_query = state
+    let Some(queued_query) = state
         .persistence
         .load_queued_query(&query_id)
         .await
-        .context(LoadQueryFromPersistenceSnafu {
+        .context(LoadQueuedQueryFromPersistenceSnafu {
             query_id: &query_id,
-        })?;
+        })?
+    else {
+        // The queued query is already gone, so there is nothing to cancel
+        return Ok(());
+    };
     state
         .persistence
         .remove_queued_query(&queued_query)
@@ -527,10 +1505,9 @@ pub async fn delete_trino_queued_statement(
     Path((query_id, _, _)): Path<(TrinoQueryId, String, u64)>,
     uri: Uri,
 ) -> Result<(), Error> {
-    state.metrics.http_counter.add(
-        1,
-        &[KeyValue::new("resource", "delete_trino_queued_statement")],
-    );
+    let _timer = state
+        .metrics
+        .record_http_request("delete_trino_queued_statement");
 
     cancel_query_on_trino(headers, &state, query_id, uri.path()).await
 }
@@ -547,13 +1524,9 @@ pub async fn delete_trino_executing_statement(
     Path((query_id, _, _)): Path<(TrinoQueryId, String, u64)>,
     uri: Uri,
 ) -> Result<(), Error> {
-    state.metrics.http_counter.add(
-        1,
-        &[KeyValue::new(
-            "resource",
-            "delete_trino_executing_statement",
-        )],
-    );
+    let _timer = state
+        .metrics
+        .record_http_request("delete_trino_executing_statement");
 
     cancel_query_on_trino(headers, &state, query_id, uri.path()).await
 }
@@ -568,19 +1541,25 @@ async fn cancel_query_on_trino(
     query_id: TrinoQueryId,
     requested_path: &str,
 ) -> Result<(), Error> {
-    state
-        .metrics
-        .http_counter
-        .add(1, &[KeyValue::new("resource", "cancel_query_on_trino")]);
+    let _timer = state.metrics.record_http_request("cancel_query_on_trino");
 
-    let query =
-        state
-            .persistence
-            .load_query(&query_id)
-            .await
-            .context(StoreQueryInPersistenceSnafu {
-                query_id: query_id.clone(),
-            })?;
+    let query = state
+        .persistence
+        .load_query(&query_id)
+        .await
+        .context(LoadQueryFromPersistenceSnafu {
+            query_id: query_id.clone(),
+        })?
+        .context(QueryNotFoundSnafu {
+            query_id: query_id.clone(),
+        })?;
+    ensure!(
+        query.is_valid_statement_path(requested_path),
+        InvalidStatementPathSnafu {
+            query_id,
+            requested_path
+        }
+    );
 
     state
         .cluster_group_manager
@@ -616,11 +1595,144 @@ fn delay_for_sequence_number(sequence_number: u64) -> Duration {
     min(Duration::from_millis(millis), MAX_POLL_DELAY)
 }
 
+/// Polling frequently is pointless in case the cluster group is heavily backed up, so the delay also depends on the
+/// number of queued queries. We only ask for it in case it can actually make a difference.
+async fn poll_delay(state: &AppState, cluster_group: &str, sequence_number: u64) -> Duration {
+    let delay = delay_for_sequence_number(sequence_number);
+    if sequence_number <= 1 || delay >= MAX_POLL_DELAY {
+        return delay;
+    }
+
+    match state
+        .persistence
+        .get_queued_query_count(cluster_group)
+        .await
+    {
+        Ok(queued_queries) => {
+            delay_for_sequence_number_and_queue_depth(sequence_number, queued_queries)
+        }
+        Err(err) => {
+            warn!(
+                cluster_group,
+                ?err,
+                "Failed to get the queued query count, falling back to the delay based on the sequence number"
+            );
+            delay
+        }
+    }
+}
+
+/// Every doubling of the queued queries (starting at this number) increases the delay by one step of
+/// [`delay_for_sequence_number`].
+const QUEUE_DEPTH_PER_DELAY_STEP: u64 = 10;
+
+/// Same as [`delay_for_sequence_number`], but reaches the [`MAX_POLL_DELAY`] faster the more queries are queued in the
+/// cluster group, as the query can not expect to be handed over to Trino anytime soon.
+fn delay_for_sequence_number_and_queue_depth(
+    sequence_number: u64,
+    queued_queries: u64,
+) -> Duration {
+    if sequence_number == 0 {
+        return Duration::ZERO;
+    }
+
+    let additional_steps = (queued_queries / QUEUE_DEPTH_PER_DELAY_STEP)
+        .checked_ilog2()
+        .map_or(0, |steps| u64::from(steps) + 1);
+
+    delay_for_sequence_number(sequence_number.saturating_add(additional_steps))
+}
+
 #[cfg(test)]
 mod tests {
+    use axum::routing::{get, post};
     use rstest::rstest;
+    use tokio::{net::TcpListener, sync::oneshot};
+    use trino_lb_core::{
+        config::{Config, TrinoLbQueryChargebackConfig},
+        trino_cluster::ClusterState,
+    };
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
 
     use super::*;
+    use crate::{
+        cluster_group_manager::ClusterGroupManager, http_server::admin::events::StateEvents,
+        routing, scaling::Scaler, test_config::TestConfigBuilder,
+    };
+
+    #[rstest]
+    #[case::query_not_found(
+        Error::QueryNotFound { query_id: "20240101_000000_00000_aaaaa".to_owned() },
+        StatusCode::NOT_FOUND
+    )]
+    #[case::queued_query_not_found(
+        Error::QueuedQueryNotFound { query_id: "trino_lb_20240101_000000_aaaaaaaa".to_owned() },
+        StatusCode::NOT_FOUND
+    )]
+    #[case::invalid_statement_path(
+        Error::InvalidStatementPath {
+            query_id: "20240101_000000_00000_aaaaa".to_owned(),
+            requested_path: "/v1/statement/executing/20240101_000000_00000_aaaaa/wrong/1".to_owned(),
+        },
+        StatusCode::NOT_FOUND
+    )]
+    #[case::invalid_override_token(
+        Error::InvalidClusterGroupOverrideToken { cluster_group: "s".to_owned() },
+        StatusCode::FORBIDDEN
+    )]
+    #[case::unknown_forced_cluster_group(
+        Error::UnknownForcedClusterGroup { cluster_group: "xxl".to_owned() },
+        StatusCode::BAD_REQUEST
+    )]
+    #[case::queue_full(
+        Error::StoreQueuedQueryInPersistence {
+            source: trino_lb_persistence::Error::InMemoryError {
+                source: trino_lb_persistence::in_memory::Error::TooManyQueuedQueries {
+                    max_queued_queries: 1,
+                },
+            },
+        },
+        StatusCode::TOO_MANY_REQUESTS
+    )]
+    #[case::estimated_queue_wait_too_high(
+        Error::EstimatedQueueWaitTooHigh {
+            cluster_group: "s".to_owned(),
+            estimated_wait: Duration::from_secs(120),
+            max_estimated_wait: Duration::from_secs(60),
+        },
+        StatusCode::TOO_MANY_REQUESTS
+    )]
+    #[case::internal(
+        Error::LoadQueryFromPersistence {
+            source: trino_lb_persistence::Error::InMemoryError {
+                source: trino_lb_persistence::in_memory::Error::LastQueryCountFetcherUpdateOutOfRange {
+                    millis: 0,
+                },
+            },
+            query_id: "20240101_000000_00000_aaaaa".to_owned(),
+        },
+        StatusCode::INTERNAL_SERVER_ERROR
+    )]
+    #[tokio::test]
+    async fn test_error_status_codes(#[case] error: Error, #[case] expected: StatusCode) {
+        let response = error.into_response();
+        assert_eq!(response.status(), expected);
+        if expected == StatusCode::TOO_MANY_REQUESTS {
+            assert_eq!(response.headers()[http::header::RETRY_AFTER], "10");
+        } else {
+            assert!(!response.headers().contains_key(http::header::RETRY_AFTER));
+        }
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        if expected == StatusCode::NOT_FOUND {
+            // Does not reveal whether the query exists
+            assert_eq!(body, "Query not found");
+        } else {
+            assert_ne!(body, "Query not found");
+        }
+    }
 
     #[rstest]
     #[case(0, Duration::from_millis(0))]
@@ -643,4 +1755,1500 @@ mod tests {
     ) {
         assert_eq!(delay_for_sequence_number(sequence_number), expected_delay);
     }
+
+    #[rstest]
+    #[case(0, 1000, Duration::ZERO)]
+    // Short queues don't change anything
+    #[case(1, 0, Duration::from_millis(256))]
+    #[case(2, 9, Duration::from_millis(512))]
+    // Every doubling of the queue adds a step
+    #[case(1, 10, Duration::from_millis(512))]
+    #[case(1, 19, Duration::from_millis(512))]
+    #[case(1, 20, Duration::from_millis(1024))]
+    #[case(1, 40, Duration::from_millis(2048))]
+    #[case(2, 40, MAX_POLL_DELAY)]
+    #[case(1, 80, MAX_POLL_DELAY)]
+    #[case(1, u64::MAX, MAX_POLL_DELAY)]
+    #[case(u64::MAX, u64::MAX, MAX_POLL_DELAY)]
+    fn test_delay_for_sequence_number_and_queue_depth(
+        #[case] sequence_number: u64,
+        #[case] queued_queries: u64,
+        #[case] expected_delay: Duration,
+    ) {
+        assert_eq!(
+            delay_for_sequence_number_and_queue_depth(sequence_number, queued_queries),
+            expected_delay
+        );
+    }
+
+    /// Id of the queries submitted to the fake Trino coordinator.
+    const FAKE_TRINO_QUERY_ID: &str = "20240101_120000_00002_abcde";
+
+    /// Starts a fake Trino coordinator, which accepts new queries and answers polls of running queries. The query
+    /// finishes on the poll with token `2`.
+    async fn start_fake_trino() -> Url {
+        start_fake_trino_capturing_headers().await.0
+    }
+
+    /// Same as [`start_fake_trino`], but also returns the headers of the last submitted query.
+    async fn start_fake_trino_capturing_headers() -> (Url, Arc<std::sync::Mutex<Option<HeaderMap>>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let received_headers = Arc::new(std::sync::Mutex::new(None));
+        let received_headers_for_handler = Arc::clone(&received_headers);
+        let post_endpoint = endpoint.clone();
+        let get_endpoint = endpoint.clone();
+        let app = axum::Router::new()
+            .route(
+                "/v1/statement",
+                post(move |headers: HeaderMap| async move {
+                    *received_headers_for_handler.lock().unwrap() = Some(headers);
+                    Json(fake_trino_response(&post_endpoint, FAKE_TRINO_QUERY_ID, 0))
+                }),
+            )
+            .route(
+                "/v1/statement/executing/:query_id/:slug/:token",
+                get(
+                    move |Path((query_id, _, token)): Path<(String, String, u64)>| async move {
+                        Json(fake_trino_response(&get_endpoint, &query_id, token))
+                    },
+                ),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (endpoint, received_headers)
+    }
+
+    fn fake_trino_response(trino_endpoint: &Url, query_id: &str, token: u64) -> serde_json::Value {
+        let next_uri = (token < 2).then(|| {
+            trino_endpoint
+                .join(&format!(
+                    "/v1/statement/executing/{query_id}/y{}/{}",
+                    token + 1,
+                    token + 1
+                ))
+                .unwrap()
+        });
+        serde_json::json!({
+            "id": query_id,
+            "nextUri": next_uri,
+            "infoUri": trino_endpoint.join(&format!("/ui/query.html?{query_id}")).unwrap(),
+            "warnings": [],
+            "stats": {
+                "completedSplits": 0,
+                "cpuTimeMillis": 0,
+                "elapsedTimeMillis": 0,
+                "nodes": 1,
+                "peakMemoryBytes": 0,
+                "physicalInputBytes": 0,
+                "processedBytes": 0,
+                "processedRows": 0,
+                "queuedSplits": 0,
+                "queuedTimeMillis": 0,
+                "queued": false,
+                "runningSplits": 0,
+                "scheduled": true,
+                "spilledBytes": 0,
+                "state": if next_uri.is_some() { "RUNNING" } else { "FINISHED" },
+                "totalSplits": 0,
+                "wallTimeMillis": 0,
+            },
+        })
+    }
+
+    fn config(trino_endpoint: &Url, additional_trino_lb_config: &str) -> Config {
+        TestConfigBuilder::new()
+            .trino_lb(additional_trino_lb_config)
+            .cluster_group("s", 1, &[("trino-s-1", trino_endpoint.as_str())])
+            .build()
+    }
+
+    /// Builds the state the same way a freshly started trino-lb does, only the persistence is shared.
+    async fn app_state(
+        config: &Config,
+        persistence: Arc<PersistenceImplementation>,
+    ) -> Arc<AppState> {
+        let metrics = Arc::new(
+            Metrics::new(
+                prometheus::Registry::new(),
+                Arc::clone(&persistence),
+                config,
+            )
+            .unwrap(),
+        );
+        let cluster_group_manager =
+            ClusterGroupManager::new(Arc::clone(&persistence), config, false).unwrap();
+        let router = routing::ReloadableRouter::new(
+            routing::Router::new(config, Arc::clone(&persistence)).unwrap(),
+            "config.yaml".into(),
+            config.clone(),
+            Arc::clone(&persistence),
+        );
+        let scaler = Scaler::new(
+            config,
+            Arc::clone(&persistence),
+            cluster_group_manager.max_running_queries(),
+            Arc::clone(&metrics),
+        )
+        .await
+        .unwrap()
+        .start_loop();
+        let events = Arc::new(StateEvents::new(Arc::clone(&persistence), config));
+
+        Arc::new(AppState {
+            config: config.clone(),
+            persistence,
+            cluster_group_manager,
+            router,
+            scaler,
+            metrics,
+            replica: Replica::new(config).unwrap(),
+            events,
+            client_request_recorder: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_poll_running_query_after_restart() {
+        let trino_endpoint = start_fake_trino().await;
+        let config = config(&trino_endpoint, "");
+
+        // The persistence outlives the restart, as Redis or Postgres would
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        let query_id = "20240101_120000_00001_abcde".to_owned();
+        let requested_path = format!("/v1/statement/executing/{query_id}/y1/1");
+        persistence
+            .inc_cluster_query_count(&cluster, 1)
+            .await
+            .unwrap();
+        persistence
+            .store_query(TrinoQuery::new_from(
+                cluster.clone(),
+                query_id.clone(),
+                trino_endpoint.clone(),
+                SystemTime::now(),
+                SystemTime::now(),
+                None,
+                Some(requested_path.clone()),
+            ))
+            .await
+            .unwrap();
+
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        // The poll is proxied to the stored Trino endpoint and the nextUri points to trino-lb again
+        let (_, Json(response)) = get_trino_executing_statement(
+            HeaderMap::new(),
+            State(Arc::clone(&state)),
+            Path((query_id.clone(), "y1".to_owned(), 1)),
+            requested_path.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let next_uri_path = format!("/v1/statement/executing/{query_id}/y2/2");
+        assert_eq!(
+            response.next_uri.as_deref(),
+            Some(format!("https://trino-lb.example.com:8443{next_uri_path}").as_str())
+        );
+        let stored_query = persistence.load_query(&query_id).await.unwrap().unwrap();
+        assert_eq!(
+            stored_query.next_uri_path.as_deref(),
+            Some(next_uri_path.as_str())
+        );
+
+        // Once the query finished, it is removed and the query counter of the cluster is decremented
+        let (_, Json(response)) = get_trino_executing_statement(
+            HeaderMap::new(),
+            State(Arc::clone(&state)),
+            Path((query_id.clone(), "y2".to_owned(), 2)),
+            next_uri_path.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.next_uri, None);
+        assert!(persistence.load_query(&query_id).await.unwrap().is_none());
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            0
+        );
+    }
+
+    #[rstest]
+    #[case::handed_over("", false)]
+    #[case::queued("minAdmissionSequence: 100", true)]
+    #[tokio::test]
+    async fn test_retried_post_with_idempotency_key(
+        #[case] trino_lb_config: &str,
+        #[case] queued: bool,
+    ) {
+        let trino_endpoint = start_fake_trino().await;
+        let config = config(
+            &trino_endpoint,
+            &format!("idempotency: {{}}\n{trino_lb_config}"),
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-trino-user", "alice".parse().unwrap());
+        headers.insert("authorization", "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        headers.insert("x-trino-lb-idempotency-key", "nightly-1".parse().unwrap());
+        let submit = |headers: HeaderMap| {
+            let state = Arc::clone(&state);
+            async move {
+                let response = post_statement(headers, State(state), "SELECT 1".to_owned())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<TrinoQueryApiResponse>(&body)
+                    .unwrap()
+                    .id
+            }
+        };
+
+        // The client retries the submission, e.g. because the first response got lost
+        let first_query_id = submit(headers.clone()).await;
+        let retried_query_id = submit(headers.clone()).await;
+
+        // Both requests got the same query, which was only submitted once
+        assert_eq!(first_query_id, retried_query_id);
+        assert_eq!(
+            persistence.get_queued_query_count("s").await.unwrap(),
+            u64::from(queued)
+        );
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            u64::from(!queued)
+        );
+
+        // A client claiming to be the same user, but using different credentials, does not get the query
+        headers.insert("authorization", "Basic YWxpY2U6Z3Vlc3M=".parse().unwrap());
+        assert_ne!(submit(headers).await, first_query_id);
+    }
+
+    #[tokio::test]
+    async fn test_retried_post_with_idempotency_key_checks_override_token() {
+        let config = config(
+            &"http://127.0.0.1:1".parse().unwrap(),
+            "idempotency: {}\nminAdmissionSequence: 100\nclusterGroupOverride:\n  token: secret",
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, persistence).await;
+        let headers = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-trino-user", "alice".parse().unwrap());
+            headers.insert("x-trino-lb-idempotency-key", "nightly-1".parse().unwrap());
+            headers.insert("x-trino-lb-force-group", "s".parse().unwrap());
+            headers.insert("x-trino-lb-force-group-token", token.parse().unwrap());
+            headers
+        };
+
+        let response = post_statement(
+            headers("secret"),
+            State(Arc::clone(&state)),
+            "SELECT 1".to_owned(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The stored response of the idempotency key must not let the retry skip the token check
+        assert!(matches!(
+            post_statement(headers("wrong"), State(state), "SELECT 1".to_owned()).await,
+            Err(Error::InvalidClusterGroupOverrideToken { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dropping_poll_aborts_request_to_trino() {
+        /// Notifies the test once the fake Trino coordinator stops handling the request.
+        struct DropGuard(Option<oneshot::Sender<()>>);
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                let _ = self.0.take().unwrap().send(());
+            }
+        }
+
+        // Fake Trino coordinator, which never answers the poll
+        let (started_sender, started_receiver) = oneshot::channel();
+        let (dropped_sender, dropped_receiver) = oneshot::channel();
+        let senders = Arc::new(std::sync::Mutex::new(Some((
+            started_sender,
+            dropped_sender,
+        ))));
+        let app = axum::Router::new().route(
+            "/v1/statement/executing/:query_id/:slug/:token",
+            get(move || async move {
+                let (started_sender, dropped_sender) = senders.lock().unwrap().take().unwrap();
+                let _guard = DropGuard(Some(dropped_sender));
+                started_sender.send(()).unwrap();
+                std::future::pending::<()>().await;
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let trino_endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = config(&trino_endpoint, "");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let query_id = "20240101_120000_00001_abcde".to_owned();
+        let requested_path = format!("/v1/statement/executing/{query_id}/y1/1");
+        persistence
+            .store_query(TrinoQuery::new_from(
+                "trino-s-1".to_owned(),
+                query_id.clone(),
+                trino_endpoint.clone(),
+                SystemTime::now(),
+                SystemTime::now(),
+                None,
+                Some(requested_path.clone()),
+            ))
+            .await
+            .unwrap();
+        let state = app_state(&config, persistence).await;
+
+        let poll = tokio::spawn(get_trino_executing_statement(
+            HeaderMap::new(),
+            State(state),
+            Path((query_id, "y1".to_owned(), 1)),
+            requested_path.parse().unwrap(),
+        ));
+        started_receiver.await.unwrap();
+
+        // The client goes away, which causes axum to drop the handler future
+        poll.abort();
+        assert!(poll.await.unwrap_err().is_cancelled());
+
+        // The connection to Trino is closed, so Trino stops handling the request
+        tokio::time::timeout(Duration::from_secs(5), dropped_receiver)
+            .await
+            .expect("the request to Trino was not aborted")
+            .unwrap();
+    }
+
+    async fn store_queued_query(persistence: &PersistenceImplementation) -> QueuedQuery {
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        persistence
+            .store_queued_query(queued_query.clone())
+            .await
+            .unwrap();
+
+        queued_query
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_while_queued() {
+        let trino_endpoint = start_fake_trino().await;
+        // Keeps the query queued, so that the poll is delayed
+        let config = config(&trino_endpoint, "minAdmissionSequence: 100");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+        let queued_query = store_queued_query(&persistence).await;
+
+        let poll = tokio::spawn(get_trino_lb_statement(
+            State(state),
+            Path((queued_query.id.clone(), 5)),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // While the poll is delayed, the query is moved out of the cluster group of the copy the poll loaded (e.g. out
+        // of a parking cluster group)
+        persistence
+            .move_queued_query(&queued_query, "m")
+            .await
+            .unwrap();
+        poll.abort();
+        let Err(error) = poll.await else {
+            panic!("Expected the poll to be cancelled");
+        };
+        assert!(error.is_cancelled());
+
+        // The queued query is removed from the cluster group it was moved to
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while persistence
+                .load_queued_query(&queued_query.id)
+                .await
+                .unwrap()
+                .is_some()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the queued query was not removed");
+        assert_eq!(persistence.get_queued_query_count("m").await.unwrap(), 0);
+        assert_eq!(
+            persistence
+                .get_cluster_query_count(&"trino-s-1".to_owned())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_while_sending_query_to_trino() {
+        // Fake Trino coordinator, which never answers the submitted query
+        let (started_sender, started_receiver) = oneshot::channel();
+        let started_sender = Arc::new(std::sync::Mutex::new(Some(started_sender)));
+        let app = axum::Router::new().route(
+            "/v1/statement",
+            post(move || async move {
+                started_sender
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap()
+                    .send(())
+                    .unwrap();
+                std::future::pending::<()>().await;
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let trino_endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = config(&trino_endpoint, "");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+        let queued_query = store_queued_query(&persistence).await;
+
+        let poll = tokio::spawn(get_trino_lb_statement(
+            State(state),
+            Path((queued_query.id.clone(), 1)),
+        ));
+        started_receiver.await.unwrap();
+        poll.abort();
+        let Err(error) = poll.await else {
+            panic!("Expected the poll to be cancelled");
+        };
+        assert!(error.is_cancelled());
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while persistence
+                .load_queued_query(&queued_query.id)
+                .await
+                .unwrap()
+                .is_some()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the queued query was not removed");
+
+        // The query might be running on Trino, so its slot on the cluster is kept
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            persistence
+                .get_cluster_query_count(&"trino-s-1".to_owned())
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[rstest]
+    #[case::not_sent(false, 0)]
+    #[case::undecodable_response(true, 1)]
+    #[tokio::test]
+    async fn test_failed_send_to_trino(
+        #[case] trino_reachable: bool,
+        #[case] expected_query_count: u64,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let trino_endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        if trino_reachable {
+            let app = axum::Router::new().route("/v1/statement", post(|| async { "no json" }));
+            tokio::spawn(async move { axum::serve(listener, app).await });
+        } else {
+            drop(listener);
+        }
+
+        let config = config(&trino_endpoint, "");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+
+        let Err(error) = queue_or_hand_over_query(&state, queued_query, false, 0).await else {
+            panic!("Expected sending the query to fail");
+        };
+        assert!(matches!(error, Error::SendQueryToTrino { .. }));
+
+        // The query counter is only released in case the query did not reach Trino for sure
+        assert_eq!(
+            persistence
+                .get_cluster_query_count(&"trino-s-1".to_owned())
+                .await
+                .unwrap(),
+            expected_query_count
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forced_cluster_group() {
+        let override_config = config(
+            &"http://127.0.0.1:1".parse().unwrap(),
+            "clusterGroupOverride:\n  token: secret",
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&override_config, persistence).await;
+        let headers = |cluster_group: &str, token: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-trino-user", "alice".parse().unwrap());
+            headers.insert("x-trino-lb-force-group", cluster_group.parse().unwrap());
+            if let Some(token) = token {
+                headers.insert("x-trino-lb-force-group-token", token.parse().unwrap());
+            }
+            headers
+        };
+
+        // Queries without the header are routed as usual
+        assert_eq!(
+            forced_cluster_group(&state, &mut HeaderMap::new()).unwrap(),
+            None
+        );
+
+        let mut forced = headers("s", Some("secret"));
+        assert_eq!(
+            forced_cluster_group(&state, &mut forced).unwrap(),
+            Some(RoutingDecision {
+                cluster_group: "s".to_owned(),
+                reason: "forced via X-Trino-Lb-Force-Group header".to_owned(),
+            })
+        );
+        // The token must not be forwarded to Trino
+        assert_eq!(forced.len(), 1);
+
+        assert!(matches!(
+            forced_cluster_group(&state, &mut headers("s", None)),
+            Err(Error::InvalidClusterGroupOverrideToken { .. })
+        ));
+        assert!(matches!(
+            forced_cluster_group(&state, &mut headers("s", Some("wrong"))),
+            Err(Error::InvalidClusterGroupOverrideToken { .. })
+        ));
+        assert!(matches!(
+            forced_cluster_group(&state, &mut headers("xxl", Some("secret"))),
+            Err(Error::UnknownForcedClusterGroup { cluster_group }) if cluster_group == "xxl"
+        ));
+
+        // Without the config the header has no special meaning
+        let state = app_state(
+            &config(&"http://127.0.0.1:1".parse().unwrap(), ""),
+            Arc::new(InMemoryPersistence::default().into()),
+        )
+        .await;
+        let mut ignored = headers("s", Some("secret"));
+        assert_eq!(forced_cluster_group(&state, &mut ignored).unwrap(), None);
+        assert_eq!(ignored.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reserve_cluster_under_contention() {
+        const CAPACITY_PER_CLUSTER: u64 = 5;
+        const CONCURRENT_QUERIES: u64 = 50;
+
+        // Every query can only lose the race against the other queries, so this many retries guarantee that all free
+        // slots are taken
+        let mut config = config(
+            &"http://127.0.0.1:1".parse().unwrap(),
+            &format!("handOverRetries: {}", CONCURRENT_QUERIES - 1),
+        );
+        let group = config.trino_cluster_groups.get_mut("s").unwrap();
+        group.max_running_queries = CAPACITY_PER_CLUSTER;
+        let mut second_cluster = group.trino_clusters[0].clone();
+        second_cluster.name = "trino-s-2".to_owned();
+        second_cluster.endpoint = "http://127.0.0.1:2".parse().unwrap();
+        group.trino_clusters.push(second_cluster);
+        let clusters = ["trino-s-1".to_owned(), "trino-s-2".to_owned()];
+
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        for cluster in &clusters {
+            persistence
+                .set_cluster_state(cluster, ClusterState::Ready)
+                .await
+                .unwrap();
+        }
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let reserved = futures::future::join_all((0..CONCURRENT_QUERIES).map(|_| {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                reserve_cluster_with(&state, "s", || async {
+                    let best = state
+                        .cluster_group_manager
+                        .try_find_best_cluster_for_group("s")
+                        .await;
+                    // Let all other queries pick a cluster before reserving it, so that they race for the slots
+                    tokio::task::yield_now().await;
+                    best
+                })
+                .await
+                .unwrap()
+                .map(|best| best.cluster.name.clone())
+            })
+        }))
+        .await;
+        let reserved = reserved
+            .into_iter()
+            .map(|reserved| reserved.unwrap())
+            .collect::<Vec<_>>();
+
+        // All queries picked the same cluster at first. The queries losing the race only got the slots of the other
+        // cluster because they retried.
+        assert!(
+            state.metrics.hand_over_retries.load(Ordering::Relaxed)
+                >= CONCURRENT_QUERIES - CAPACITY_PER_CLUSTER
+        );
+        for cluster in &clusters {
+            assert_eq!(
+                reserved
+                    .iter()
+                    .filter(|reserved| reserved.as_ref() == Some(cluster))
+                    .count() as u64,
+                CAPACITY_PER_CLUSTER
+            );
+            assert_eq!(
+                persistence.get_cluster_query_count(cluster).await.unwrap(),
+                CAPACITY_PER_CLUSTER
+            );
+        }
+        assert_eq!(
+            reserved
+                .iter()
+                .filter(|reserved| reserved.is_none())
+                .count() as u64,
+            CONCURRENT_QUERIES - 2 * CAPACITY_PER_CLUSTER
+        );
+
+        // The clusters are full now
+        assert_eq!(
+            reserve_cluster(&state, "s")
+                .await
+                .unwrap()
+                .map(|best| &best.cluster.name),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_min_admission_sequence() {
+        let trino_endpoint = start_fake_trino().await;
+        let config = config(&trino_endpoint, "minAdmissionSequence: 2");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let queued_query_id = queued_query.id.clone();
+
+        // Even though the cluster has capacity left, the query is queued on submission and on the first poll. The
+        // first poll is not delayed, so that clients promptly see the query queued in trino-lb.
+        let start = Instant::now();
+        for (sequence_number, already_stored) in [(0, false), (1, true)] {
+            let queued_query = persistence
+                .load_queued_query(&queued_query_id)
+                .await
+                .unwrap()
+                .unwrap_or_else(|| queued_query.clone());
+            let SendToTrinoResponse::HandedOver {
+                trino_query_api_response,
+                ..
+            } = queue_or_hand_over_query(&state, queued_query, already_stored, sequence_number)
+                .await
+                .unwrap()
+            else {
+                panic!("Expected a queued query");
+            };
+            assert!(trino_query_api_response
+                .next_uri
+                .unwrap()
+                .contains("/v1/statement/queued_in_trino_lb/"));
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            0
+        );
+
+        // Once the minimum admission sequence is reached, the query is handed over
+        let queued_query = persistence
+            .load_queued_query(&queued_query_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query, true, 2)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected the query to be handed over");
+        };
+        assert_eq!(trino_query_api_response.id, FAKE_TRINO_QUERY_ID);
+        assert!(persistence
+            .load_queued_query(&queued_query_id)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll_does_not_undo_move_of_queued_query() {
+        let trino_endpoint = start_fake_trino().await;
+        // Keeps the query queued, so that it gets polled
+        let config = config(&trino_endpoint, "minAdmissionSequence: 100");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let last_accessed = SystemTime::now() - 2 * UPDATE_QUEUED_QUERY_LAST_ACCESSED_INTERVAL;
+        let queued_query = QueuedQuery {
+            last_accessed,
+            ..QueuedQuery::new_from(
+                "SELECT 1".to_owned(),
+                HeaderMap::new(),
+                "s".to_owned(),
+                None,
+            )
+        };
+        persistence
+            .store_queued_query(queued_query.clone())
+            .await
+            .unwrap();
+
+        // The query is moved while the poll works on the copy it loaded before
+        persistence
+            .move_queued_query(&queued_query, "m")
+            .await
+            .unwrap();
+        queue_or_hand_over_query(&state, queued_query.clone(), true, 1)
+            .await
+            .unwrap();
+
+        let stored = persistence
+            .load_queued_query(&queued_query.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.cluster_group, "m");
+        assert_eq!(stored.last_accessed, last_accessed);
+
+        // A removed query is not stored again
+        persistence.remove_queued_query(&stored).await.unwrap();
+        queue_or_hand_over_query(&state, queued_query.clone(), true, 1)
+            .await
+            .unwrap();
+        assert!(persistence
+            .load_queued_query(&queued_query.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_poll_sequence() {
+        let trino_endpoint = start_fake_trino().await;
+        // Keeps the query queued, so that it gets polled
+        let config = config(
+            &trino_endpoint,
+            "minAdmissionSequence: 100\nmaxPollSequence: 1",
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let queued_query_id = queued_query.id.clone();
+
+        // Polling up to the maximum poll sequence keeps the query queued
+        for (sequence_number, already_stored) in [(0, false), (1, true)] {
+            let queued_query = persistence
+                .load_queued_query(&queued_query_id)
+                .await
+                .unwrap()
+                .unwrap_or_else(|| queued_query.clone());
+            let SendToTrinoResponse::HandedOver {
+                trino_query_api_response,
+                ..
+            } = queue_or_hand_over_query(&state, queued_query, already_stored, sequence_number)
+                .await
+                .unwrap()
+            else {
+                panic!("Expected a queued query");
+            };
+            assert!(trino_query_api_response.next_uri.is_some());
+            assert!(trino_query_api_response.error.is_none());
+        }
+
+        // Exceeding it fails the query and removes it from the queue
+        let queued_query = persistence
+            .load_queued_query(&queued_query_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query, true, 2)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected a failed query");
+        };
+        assert_eq!(trino_query_api_response.next_uri, None);
+        assert_eq!(trino_query_api_response.stats.state, "FAILED");
+        assert_eq!(
+            trino_query_api_response.error.unwrap().error_name,
+            "ABANDONED_QUERY"
+        );
+        assert!(persistence
+            .load_queued_query(&queued_query_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rstest]
+    #[case::within_max_clock_skew("maxClockSkew: 5s", true)]
+    #[case::exceeding_max_clock_skew("maxClockSkew: 0s", false)]
+    #[tokio::test]
+    async fn test_hand_over_query_created_in_the_future(
+        #[case] trino_lb_config: &str,
+        #[case] succeeds: bool,
+    ) {
+        let trino_endpoint = start_fake_trino().await;
+        let config = config(&trino_endpoint, trino_lb_config);
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        // Queued by a trino-lb replica whose clock is ahead of ours
+        let queued_query = QueuedQuery {
+            creation_time: SystemTime::now() + Duration::from_secs(2),
+            ..QueuedQuery::new_from(
+                "SELECT 1".to_owned(),
+                HeaderMap::new(),
+                "s".to_owned(),
+                None,
+            )
+        };
+
+        let result = queue_or_hand_over_query(&state, queued_query, false, 0).await;
+        if succeeds {
+            let Ok(SendToTrinoResponse::HandedOver {
+                trino_query_api_response,
+                ..
+            }) = result
+            else {
+                panic!("Expected the query to be handed over");
+            };
+            assert_eq!(trino_query_api_response.id, FAKE_TRINO_QUERY_ID);
+        } else {
+            assert!(matches!(result, Err(Error::DetermineQueuedDuration { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fail_queued_query_of_removed_cluster_group() {
+        let trino_endpoint = start_fake_trino().await;
+        let config = config(&trino_endpoint, "");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        // Queued by a trino-lb that still had the cluster group configured
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "removed".to_owned(),
+            None,
+        );
+        let queued_query_id = queued_query.id.clone();
+        persistence.store_queued_query(queued_query).await.unwrap();
+
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = get_trino_lb_statement(State(state), Path((queued_query_id.clone(), 1)))
+            .await
+            .unwrap()
+        else {
+            panic!("Expected a failed query");
+        };
+        assert_eq!(trino_query_api_response.next_uri, None);
+        assert_eq!(trino_query_api_response.stats.state, "FAILED");
+        let error = trino_query_api_response.error.unwrap();
+        assert_eq!(error.error_name, "NO_NODES_AVAILABLE");
+        assert!(
+            error
+                .message
+                .contains("removed of the query no longer exists"),
+            "{}",
+            error.message
+        );
+        assert!(persistence
+            .load_queued_query(&queued_query_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reject_when_no_ready_cluster() {
+        let trino_endpoint = start_fake_trino().await;
+        let mut config = config(&trino_endpoint, "");
+        config
+            .trino_cluster_groups
+            .get_mut("s")
+            .unwrap()
+            .reject_when_no_ready_cluster = true;
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+        let queued_query = || {
+            QueuedQuery::new_from(
+                "SELECT 1".to_owned(),
+                HeaderMap::new(),
+                "s".to_owned(),
+                None,
+            )
+        };
+
+        // A full cluster will accept queries again, so the query is queued
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        persistence
+            .inc_cluster_query_count(&cluster, 1)
+            .await
+            .unwrap();
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query(), false, 0)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected a queued query");
+        };
+        assert_eq!(trino_query_api_response.stats.state, "QUEUED_IN_TRINO_LB");
+
+        // A deactivated cluster will not become ready without the help of an operator, so the query fails right away
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Deactivated)
+            .await
+            .unwrap();
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query(), false, 0)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected a failed query");
+        };
+        assert_eq!(trino_query_api_response.stats.state, "FAILED");
+        assert_eq!(trino_query_api_response.next_uri, None);
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_estimated_wait() {
+        let trino_endpoint = start_fake_trino().await;
+        let mut config = config(&trino_endpoint, "");
+        config
+            .trino_cluster_groups
+            .get_mut("s")
+            .unwrap()
+            .max_estimated_wait = Some(Duration::from_secs(60));
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+        let queued_query = || {
+            QueuedQuery::new_from(
+                "SELECT 1".to_owned(),
+                HeaderMap::new(),
+                "s".to_owned(),
+                None,
+            )
+        };
+
+        // Fill the ready cluster, so that queries are queued
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        persistence
+            .inc_cluster_query_count(&cluster, 1)
+            .await
+            .unwrap();
+
+        // Queries handed over recently waited less than the maximum, so the query is queued
+        state
+            .metrics
+            .record_queue_wait("s", Duration::from_secs(30));
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query(), false, 0)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected a queued query");
+        };
+        assert_eq!(trino_query_api_response.stats.state, "QUEUED_IN_TRINO_LB");
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 1);
+
+        // Now they waited longer than the maximum on average, so new queries are rejected
+        state
+            .metrics
+            .record_queue_wait("s", Duration::from_secs(120));
+        let Err(error) = queue_or_hand_over_query(&state, queued_query(), false, 0).await else {
+            panic!("Expected the query to be rejected");
+        };
+        assert!(matches!(
+            error,
+            Error::EstimatedQueueWaitTooHigh { ref cluster_group, .. } if cluster_group == "s"
+        ));
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "10");
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 1);
+
+        // Queries that are already queued keep waiting
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query(), true, 1)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected a queued query");
+        };
+        assert_eq!(trino_query_api_response.stats.state, "QUEUED_IN_TRINO_LB");
+    }
+
+    #[tokio::test]
+    async fn test_internal_and_external_endpoint() {
+        let internal_endpoint = start_fake_trino().await;
+        let mut config = config(&internal_endpoint, "");
+        let cluster_config = &mut config
+            .trino_cluster_groups
+            .get_mut("s")
+            .unwrap()
+            .trino_clusters[0];
+        // Not reachable from trino-lb
+        cluster_config.endpoint = "http://trino.invalid".parse().unwrap();
+        cluster_config.internal_endpoint = Some(internal_endpoint.clone());
+        cluster_config.external_endpoint = Some("https://trino.example.com".parse().unwrap());
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        // The query is sent to the internal endpoint, the link to the web UI points to the external endpoint
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query, false, 0)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected the query to be handed over");
+        };
+        assert_eq!(trino_query_api_response.id, FAKE_TRINO_QUERY_ID);
+        assert_eq!(
+            trino_query_api_response.info_uri,
+            format!("https://trino.example.com/ui/query.html?{FAKE_TRINO_QUERY_ID}")
+        );
+        let query = persistence
+            .load_query(&FAKE_TRINO_QUERY_ID.to_owned())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(query.trino_endpoint, internal_endpoint);
+
+        // Same for polls of the running query
+        let requested_path = format!("/v1/statement/executing/{FAKE_TRINO_QUERY_ID}/y1/1");
+        let (_, Json(response)) = get_trino_executing_statement(
+            HeaderMap::new(),
+            State(Arc::clone(&state)),
+            Path((FAKE_TRINO_QUERY_ID.to_owned(), "y1".to_owned(), 1)),
+            requested_path.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.info_uri,
+            format!("https://trino.example.com/ui/query.html?{FAKE_TRINO_QUERY_ID}")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_records_handed_over_query_for_chargeback() {
+        let trino_endpoint = start_fake_trino().await;
+        let mut config = config(&trino_endpoint, "");
+        config.trino_lb.query_chargeback = Some(TrinoLbQueryChargebackConfig {
+            retention: Duration::from_secs(24 * 60 * 60),
+            max_users: 10,
+        });
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-trino-user", "alice".parse().unwrap());
+        let queued_query =
+            QueuedQuery::new_from("SELECT 1".to_owned(), headers, "s".to_owned(), None);
+        assert!(matches!(
+            queue_or_hand_over_query(&state, queued_query, false, 0)
+                .await
+                .unwrap(),
+            SendToTrinoResponse::HandedOver { .. }
+        ));
+
+        // The query is recorded in the background
+        let bucket = query_chargeback::bucket_of(SystemTime::now());
+        let counts = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let counts = persistence
+                    .load_handed_over_query_counts(bucket - 1..=bucket)
+                    .await
+                    .unwrap();
+                if !counts.is_empty() {
+                    return counts;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the handed over query was not recorded");
+        assert_eq!(
+            counts,
+            std::collections::HashMap::from([(("alice".to_owned(), "s".to_owned()), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queued_query_header_allowlist() {
+        let (trino_endpoint, received_headers) = start_fake_trino_capturing_headers().await;
+        let config = config(
+            &trino_endpoint,
+            "minAdmissionSequence: 1\nqueuedQueryHeaderAllowlist: [X-My-Header]",
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        persistence
+            .set_cluster_state(&"trino-s-1".to_owned(), ClusterState::Ready)
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        headers.insert("x-trino-user", "alice".parse().unwrap());
+        headers.insert("x-trino-original-user", "bob".parse().unwrap());
+        headers.insert("x-trino-catalog", "hive".parse().unwrap());
+        headers.insert("x-trino-session", "query_max_run_time=1h".parse().unwrap());
+        headers.insert("x-my-header", "keep".parse().unwrap());
+        headers.insert("cookie", "big-cookie".parse().unwrap());
+        let queued_query =
+            QueuedQuery::new_from("SELECT 1".to_owned(), headers, "s".to_owned(), None);
+        let queued_query_id = queued_query.id.clone();
+
+        // The query is queued on submission, only the allowed headers are stored
+        queue_or_hand_over_query(&state, queued_query, false, 0)
+            .await
+            .unwrap();
+        let queued_query = persistence
+            .load_queued_query(&queued_query_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(queued_query.headers.get("cookie").is_none());
+
+        // Handing over the query still works and sends the essential headers to Trino
+        let SendToTrinoResponse::HandedOver {
+            trino_query_api_response,
+            ..
+        } = queue_or_hand_over_query(&state, queued_query, true, 1)
+            .await
+            .unwrap()
+        else {
+            panic!("Expected the query to be handed over");
+        };
+        assert_eq!(trino_query_api_response.id, FAKE_TRINO_QUERY_ID);
+
+        let received_headers = received_headers.lock().unwrap().take().unwrap();
+        for (name, value) in [
+            ("authorization", "Basic YWxpY2U6c2VjcmV0"),
+            ("x-trino-user", "alice"),
+            ("x-trino-original-user", "bob"),
+            ("x-trino-catalog", "hive"),
+            ("x-trino-session", "query_max_run_time=1h"),
+            ("x-my-header", "keep"),
+        ] {
+            assert_eq!(received_headers.get(name).unwrap(), value, "header {name}");
+        }
+        assert!(received_headers.get("cookie").is_none());
+    }
+
+    #[rstest]
+    #[case::deactivated(ClusterState::Deactivated, true)]
+    #[case::stopped(ClusterState::Stopped, true)]
+    #[case::terminating(ClusterState::Terminating, true)]
+    #[case::ready(ClusterState::Ready, false)]
+    #[tokio::test]
+    async fn test_poll_query_of_unavailable_cluster(
+        #[case] cluster_state: ClusterState,
+        #[case] expect_not_available: bool,
+    ) {
+        // The cluster went away after the query was handed over to it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let trino_endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+
+        let config = config(&trino_endpoint, "");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        let query_id = "20240101_120000_00001_abcde".to_owned();
+        let requested_path = format!("/v1/statement/executing/{query_id}/y1/1");
+        persistence
+            .store_query(TrinoQuery::new_from(
+                cluster.clone(),
+                query_id.clone(),
+                trino_endpoint,
+                SystemTime::now(),
+                SystemTime::now(),
+                None,
+                None,
+            ))
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+        persistence
+            .set_cluster_state(&cluster, cluster_state)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_query_count(&cluster, 1)
+            .await
+            .unwrap();
+
+        let result = get_trino_executing_statement(
+            HeaderMap::new(),
+            State(state),
+            Path((query_id.clone(), "y1".to_owned(), 1)),
+            requested_path.parse().unwrap(),
+        )
+        .await;
+
+        // The client needs to submit the query again, so its slot on the cluster is freed
+        assert_eq!(
+            persistence.load_query(&query_id).await.unwrap().is_none(),
+            expect_not_available
+        );
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            u64::from(!expect_not_available)
+        );
+
+        let Err(error) = result else {
+            panic!("Expected polling the query to fail");
+        };
+        if expect_not_available {
+            assert!(matches!(
+                error,
+                Error::TrinoClusterOfQueryNotAvailable { .. }
+            ));
+            assert_eq!(error.into_response().status(), StatusCode::GONE);
+        } else {
+            assert!(matches!(error, Error::AskTrinoForQueryState { .. }));
+        }
+    }
+
+    #[rstest]
+    #[case::error("", false)]
+    #[case::overwrite("queryIdCollisionBehavior: overwrite", true)]
+    #[tokio::test]
+    async fn test_query_id_collision(#[case] trino_lb_config: &str, #[case] overwrites: bool) {
+        let trino_endpoint: Url = "http://trino.example.com:8080".parse().unwrap();
+        let config = config(&trino_endpoint, trino_lb_config);
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        // Two Trino clusters generated the same query id
+        let query_id = "20240101_120000_00001_abcde".to_owned();
+        let query_of_cluster = |cluster: &str| {
+            TrinoQuery::new_from(
+                cluster.to_owned(),
+                query_id.clone(),
+                trino_endpoint.clone(),
+                SystemTime::now(),
+                SystemTime::now(),
+                None,
+                None,
+            )
+        };
+        store_query(&state, &query_of_cluster("trino-s-1"))
+            .await
+            .unwrap();
+
+        let result = store_query(&state, &query_of_cluster("trino-s-2")).await;
+        let stored_query = persistence.load_query(&query_id).await.unwrap().unwrap();
+        if overwrites {
+            result.unwrap();
+            assert_eq!(stored_query.trino_cluster, "trino-s-2");
+        } else {
+            let error = result.unwrap_err();
+            assert!(
+                matches!(&error, Error::StoreQueryInPersistence { source, .. } if source.is_query_id_collision())
+            );
+            assert_eq!(stored_query.trino_cluster, "trino-s-1");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hand_over_query_with_colliding_id() {
+        let trino_endpoint = start_fake_trino().await;
+        let config = config(&trino_endpoint, "");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cluster = "trino-s-1".to_owned();
+        persistence
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        // A different cluster already generated the id the fake Trino hands out
+        persistence
+            .store_query(TrinoQuery::new_from(
+                "trino-m-1".to_owned(),
+                FAKE_TRINO_QUERY_ID.to_owned(),
+                trino_endpoint.clone(),
+                SystemTime::now(),
+                SystemTime::now(),
+                None,
+                None,
+            ))
+            .await
+            .unwrap();
+        let state = app_state(&config, Arc::clone(&persistence)).await;
+
+        let queued_query = QueuedQuery::new_from(
+            "SELECT 1".to_owned(),
+            HeaderMap::new(),
+            "s".to_owned(),
+            None,
+        );
+        let Err(error) = queue_or_hand_over_query(&state, queued_query, false, 0).await else {
+            panic!("Expected the hand-over to fail");
+        };
+        assert!(
+            matches!(&error, Error::StoreQueryInPersistence { source, .. } if source.is_query_id_collision())
+        );
+
+        // The abandoned query does not occupy the cluster and the first query is kept
+        assert_eq!(
+            persistence.get_cluster_query_count(&cluster).await.unwrap(),
+            0
+        );
+        let stored_query = persistence
+            .load_query(&FAKE_TRINO_QUERY_ID.to_owned())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_query.trino_cluster, "trino-m-1");
+    }
 }
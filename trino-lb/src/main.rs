@@ -1,33 +1,46 @@
-use std::sync::Arc;
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc};
 
 use clap::Parser;
-use cluster_group_manager::ClusterGroupManager;
+use cluster_group_manager::{ClusterGroupManager, QueryCounters};
 use main_error::MainError;
 use maintenance::{
-    leftover_queries::LeftoverQueryDetector, query_count_fetcher,
-    query_count_fetcher::QueryCountFetcher,
+    leftover_queries::LeftoverQueryDetector, parked_queries, parked_queries::ParkedQueryPromoter,
+    query_chargeback::QueryChargebackCleaner, query_count_fetcher,
+    query_count_fetcher::QueryCountFetcher, removed_clusters,
 };
 use opentelemetry::global::shutdown_tracer_provider;
-use routing::Router;
+use routing::{ReloadableRouter, Router};
 use scaling::Scaler;
-use snafu::{ResultExt, Snafu};
+use snafu::{ensure, ResultExt, Snafu};
 use trino_lb_core::config::{self, Config, PersistenceConfig};
 use trino_lb_persistence::{
-    in_memory::InMemoryPersistence,
+    in_memory::{self, InMemoryPersistence},
+    instrumented::InstrumentedPersistence,
     postgres::{self, PostgresPersistence},
     redis::{self, RedisPersistence},
     PersistenceImplementation,
 };
 
-use crate::{args::Args, http_server::start_http_server};
+use crate::{
+    args::{Args, Command},
+    http_server::start_http_server,
+    metrics::Metrics,
+    migrate::MigrateArgs,
+};
 
 mod args;
 mod cluster_group_manager;
 mod http_server;
 mod maintenance;
+mod max_running_queries;
 mod metrics;
+mod migrate;
+mod persisted_state_cache;
 mod routing;
 mod scaling;
+#[cfg(test)]
+mod test_config;
+mod time_range;
 mod tracing;
 mod trino_client;
 
@@ -39,6 +52,9 @@ pub enum Error {
     #[snafu(display("Failed to set up tracing"))]
     SetUpTracing { source: tracing::Error },
 
+    #[snafu(display("Failed to set up metrics"))]
+    SetUpMetrics { source: metrics::Error },
+
     #[snafu(display("Failed to read configuration"))]
     ReadConfig { source: config::Error },
 
@@ -59,11 +75,28 @@ pub enum Error {
     #[snafu(display("Failed to create query count fetcher"))]
     CreateQueryCountFetcher { source: query_count_fetcher::Error },
 
+    #[snafu(display("Failed to create parked query promoter"))]
+    CreateParkedQueryPromoter { source: parked_queries::Error },
+
+    #[snafu(display("Failed to clean up clusters removed from the configuration"))]
+    CleanupRemovedClusters { source: removed_clusters::Error },
+
     #[snafu(display("Failed to create scaler"))]
     CreateScaler { source: scaling::Error },
 
     #[snafu(display("Failed to start HTTP server"))]
     StartHttpServer { source: http_server::Error },
+
+    #[snafu(display("Failed to migrate persistence"))]
+    MigratePersistence { source: migrate::Error },
+
+    #[snafu(display(
+        "The in-memory persistence configured in {config_file:?} can only be migrated in case it has a snapshotPath, as the state is lost otherwise"
+    ))]
+    MigrateInMemoryWithoutSnapshot { config_file: PathBuf },
+
+    #[snafu(display("Failed to write the snapshot of the in-memory persistence"))]
+    WriteInMemorySnapshot { source: in_memory::Error },
 }
 
 /// We can not use the `#[tokio::main]` macro, as we need at least 3 worker threads because of some magic happening
@@ -100,49 +133,25 @@ fn main() -> Result<(), MainError> {
 async fn start() -> Result<(), MainError> {
     let args = Args::parse();
 
-    let config = Config::read_from_file(&args.config_file)
+    if let Some(Command::Migrate(migrate_args)) = args.command {
+        return Ok(start_migration(migrate_args).await?);
+    }
+    let config_file = args
+        .config_file
+        .expect("clap makes sure the config file is passed in case no subcommand is used");
+
+    let config = Config::read_from_file(&config_file)
         .await
         .context(ReadConfigSnafu)?;
-    let cluster_groups = config.trino_cluster_groups.keys().cloned().collect();
+    let registry = tracing::init(config.trino_lb.tracing.as_ref()).context(SetUpTracingSnafu)?;
 
-    let persistence: Arc<PersistenceImplementation> =
-        Arc::new(match &config.trino_lb.persistence {
-            PersistenceConfig::InMemory {} => InMemoryPersistence::default().into(),
-            PersistenceConfig::Redis(redis_config) => {
-                if redis_config.cluster_mode {
-                    RedisPersistence::<
-                        ::redis::cluster_async::ClusterConnection<
-                            ::redis::aio::MultiplexedConnection,
-                        >,
-                    >::new(redis_config, cluster_groups)
-                    .await
-                    .context(CreateRedisPersistenceClientSnafu)?
-                    .into()
-                } else {
-                    RedisPersistence::<::redis::aio::ConnectionManager>::new(
-                        redis_config,
-                        cluster_groups,
-                    )
-                    .await
-                    .context(CreateRedisPersistenceClientSnafu)?
-                    .into()
-                }
-            }
-            PersistenceConfig::Postgres(postgres_config) => {
-                PostgresPersistence::new(postgres_config)
-                    .await
-                    .context(CreatePostgresPersistenceClientSnafu)?
-                    .into()
-            }
-        });
+    // The instrumentation needs to be added after the metrics are set up, as it would not record anything otherwise
+    let persistence = Arc::new(PersistenceImplementation::from(
+        InstrumentedPersistence::new(create_persistence(&config).await?),
+    ));
 
     let metrics = Arc::new(
-        tracing::init(
-            config.trino_lb.tracing.as_ref(),
-            Arc::clone(&persistence),
-            &config,
-        )
-        .context(SetUpTracingSnafu)?,
+        Metrics::new(registry, Arc::clone(&persistence), &config).context(SetUpMetricsSnafu)?,
     );
 
     let cluster_group_manager = ClusterGroupManager::new(
@@ -151,19 +160,41 @@ async fn start() -> Result<(), MainError> {
         config.trino_cluster_groups_ignore_cert,
     )
     .context(CreateClusterGroupManagerSnafu)?;
+    cluster_group_manager.start_max_running_queries_refresh_loop();
+    cluster_group_manager.start_routing_excluded_clusters_refresh_loop();
+
+    let router = Router::new(&config, Arc::clone(&persistence)).context(CreateRouterSnafu)?;
+    let router = ReloadableRouter::new(
+        router,
+        config_file,
+        config.clone(),
+        Arc::clone(&persistence),
+    );
+    router.start_disabled_routers_refresh_loop();
 
-    let router = Router::new(&config).context(CreateRouterSnafu)?;
+    if config.trino_lb.cleanup_removed_clusters {
+        removed_clusters::cleanup_removed_clusters(&persistence, &config)
+            .await
+            .context(CleanupRemovedClustersSnafu)?;
+    }
 
-    let scaler = Scaler::new(&config, Arc::clone(&persistence))
-        .await
-        .context(CreateScalerSnafu)?;
-    scaler.start_loop();
+    let scaler = Scaler::new(
+        &config,
+        Arc::clone(&persistence),
+        cluster_group_manager.max_running_queries(),
+        Arc::clone(&metrics),
+    )
+    .await
+    .context(CreateScalerSnafu)?;
+    let scaler = scaler.start_loop();
 
     let query_count_fetcher = QueryCountFetcher::new(
         Arc::clone(&persistence),
         &config.trino_cluster_groups,
         config.trino_cluster_groups_ignore_cert,
+        config.trino_lb.user_agent.clone(),
         &config.trino_lb.refresh_query_counter_interval,
+        config.trino_lb.refresh_query_counter_concurrency,
         Arc::clone(&metrics),
     )
     .context(CreateQueryCountFetcherSnafu)?;
@@ -171,17 +202,126 @@ async fn start() -> Result<(), MainError> {
 
     LeftoverQueryDetector::new(Arc::clone(&persistence)).start_loop();
 
+    if let Some(query_chargeback) = &config.trino_lb.query_chargeback {
+        QueryChargebackCleaner::new(Arc::clone(&persistence), query_chargeback).start_loop();
+    }
+
+    ParkedQueryPromoter::new(
+        Arc::clone(&persistence),
+        &config.trino_cluster_groups,
+        &QueryCounters::new(&config),
+        cluster_group_manager.max_running_queries(),
+    )
+    .context(CreateParkedQueryPromoterSnafu)?
+    .start_loop();
+
     start_http_server(
         config,
-        persistence,
+        Arc::clone(&persistence),
         cluster_group_manager,
         router,
+        scaler,
         Arc::clone(&metrics),
     )
     .await
     .context(StartHttpServerSnafu)?;
 
+    if let Some(in_memory) = persistence.as_in_memory() {
+        in_memory
+            .write_snapshot()
+            .await
+            .context(WriteInMemorySnapshotSnafu)?;
+    }
+
     shutdown_tracer_provider();
 
     Ok(())
 }
+
+async fn create_persistence(config: &Config) -> Result<PersistenceImplementation, Error> {
+    let cluster_groups = config.trino_cluster_groups.keys().cloned().collect();
+
+    Ok(match &config.trino_lb.persistence {
+        PersistenceConfig::InMemory(in_memory_config) => {
+            InMemoryPersistence::new(in_memory_config).into()
+        }
+        PersistenceConfig::Redis(redis_config) => {
+            if redis_config.cluster_mode {
+                RedisPersistence::<
+                    ::redis::cluster_async::ClusterConnection<::redis::aio::MultiplexedConnection>,
+                >::new(redis_config, cluster_groups)
+                .await
+                .context(CreateRedisPersistenceClientSnafu)?
+                .into()
+            } else {
+                RedisPersistence::<::redis::aio::ConnectionManager>::new(
+                    redis_config,
+                    cluster_groups,
+                )
+                .await
+                .context(CreateRedisPersistenceClientSnafu)?
+                .into()
+            }
+        }
+        PersistenceConfig::Postgres(postgres_config) => PostgresPersistence::new(postgres_config)
+            .await
+            .context(CreatePostgresPersistenceClientSnafu)?
+            .into(),
+    })
+}
+
+async fn start_migration(args: MigrateArgs) -> Result<(), Error> {
+    tracing::init_console_output().context(SetUpTracingSnafu)?;
+
+    let from = Config::read_from_file(&args.from)
+        .await
+        .context(ReadConfigSnafu)?;
+    let to = Config::read_from_file(&args.to)
+        .await
+        .context(ReadConfigSnafu)?;
+
+    // The in-memory state only lives as long as the migration runs, only the snapshot is read and written.
+    for (config, config_file) in [(&from, &args.from), (&to, &args.to)] {
+        if let PersistenceConfig::InMemory(in_memory_config) = &config.trino_lb.persistence {
+            ensure!(
+                in_memory_config.snapshot_path.is_some(),
+                MigrateInMemoryWithoutSnapshotSnafu { config_file }
+            );
+        }
+    }
+
+    // Migrate the cluster groups and clusters known to any of both configs, so that we don't loose any state.
+    let cluster_groups: BTreeSet<_> = from
+        .trino_cluster_groups
+        .keys()
+        .chain(to.trino_cluster_groups.keys())
+        .cloned()
+        .collect();
+    let clusters: BTreeSet<_> = from
+        .trino_cluster_groups
+        .values()
+        .chain(to.trino_cluster_groups.values())
+        .flat_map(|group| &group.trino_clusters)
+        .map(|cluster| cluster.name.clone())
+        .collect();
+
+    let destination = create_persistence(&to).await?;
+    migrate::migrate(
+        &create_persistence(&from).await?,
+        &destination,
+        &cluster_groups,
+        &clusters,
+        args.batch_size,
+    )
+    .await
+    .context(MigratePersistenceSnafu)?;
+
+    if let Some(in_memory) = destination.as_in_memory() {
+        in_memory
+            .write_snapshot()
+            .await
+            .context(WriteInMemorySnapshotSnafu)?;
+    }
+
+    Ok(())
+}
@@ -1,2 +1,5 @@
 pub mod leftover_queries;
+pub mod parked_queries;
+pub mod query_chargeback;
 pub mod query_count_fetcher;
+pub mod removed_clusters;
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures::future::try_join_all;
+use snafu::{ensure, OptionExt, Snafu};
+use tokio::time;
+use tracing::{debug, error, info, info_span, instrument, Instrument};
+use trino_lb_core::{config::TrinoClusterGroupConfig, TrinoClusterName};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+use crate::{cluster_group_manager::QueryCounters, max_running_queries::MaxRunningQueries};
+
+/// Number of parked queries that are loaded from the persistence in parallel.
+const LOAD_PARKED_QUERIES_BATCH_SIZE: usize = 100;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Configuration error: The parking trinoClusterGroup {cluster_group:?} must not contain any trinoClusters"))]
+    ClustersInParkingGroup { cluster_group: String },
+
+    #[snafu(display("Configuration error: The parking trinoClusterGroup {cluster_group:?} must not configure autoscaling"))]
+    AutoscalingInParkingGroup { cluster_group: String },
+
+    #[snafu(display("Configuration error: The parking trinoClusterGroup {cluster_group:?} is configured to promote queries to trinoClusterGroup {target_cluster_group:?} which does not exist"))]
+    TargetClusterGroupNotFound {
+        cluster_group: String,
+        target_cluster_group: String,
+    },
+
+    #[snafu(display("Configuration error: The parking trinoClusterGroup {cluster_group:?} is configured to promote queries to trinoClusterGroup {target_cluster_group:?} which is a parking cluster group itself"))]
+    TargetIsParkingGroup {
+        cluster_group: String,
+        target_cluster_group: String,
+    },
+}
+
+/// Promotes the queries of parking cluster groups to their target cluster groups, once the target cluster group has
+/// free capacity.
+pub struct ParkedQueryPromoter {
+    persistence: Arc<PersistenceImplementation>,
+    parking_groups: Vec<ParkingGroup>,
+}
+
+#[derive(Debug)]
+struct ParkingGroup {
+    name: String,
+    target_cluster_group: String,
+    target_clusters: Vec<TrinoClusterName>,
+    /// The query counters of the [`Self::target_clusters`] (in the same order), see [`QueryCounters`].
+    target_query_counters: Vec<TrinoClusterName>,
+    target_max_running_queries: Arc<MaxRunningQueries>,
+    promotion_interval: Duration,
+}
+
+impl ParkedQueryPromoter {
+    pub fn new(
+        persistence: Arc<PersistenceImplementation>,
+        config: &HashMap<String, TrinoClusterGroupConfig>,
+        query_counters: &QueryCounters,
+        max_running_queries: &HashMap<String, Arc<MaxRunningQueries>>,
+    ) -> Result<Self, Error> {
+        let mut parking_groups = Vec::new();
+        for (cluster_group, group_config) in config {
+            let Some(parking) = &group_config.parking else {
+                continue;
+            };
+
+            ensure!(
+                group_config.trino_clusters.is_empty(),
+                ClustersInParkingGroupSnafu { cluster_group }
+            );
+            ensure!(
+                group_config.autoscaling.is_none(),
+                AutoscalingInParkingGroupSnafu { cluster_group }
+            );
+            let target_cluster_group = &parking.target_cluster_group;
+            let target_config =
+                config
+                    .get(target_cluster_group)
+                    .context(TargetClusterGroupNotFoundSnafu {
+                        cluster_group,
+                        target_cluster_group,
+                    })?;
+            ensure!(
+                target_config.parking.is_none(),
+                TargetIsParkingGroupSnafu {
+                    cluster_group,
+                    target_cluster_group,
+                }
+            );
+
+            let target_clusters: Vec<_> = target_config
+                .trino_clusters
+                .iter()
+                .map(|c| c.name.clone())
+                .collect();
+            parking_groups.push(ParkingGroup {
+                name: cluster_group.clone(),
+                target_cluster_group: target_cluster_group.clone(),
+                target_query_counters: target_clusters
+                    .iter()
+                    .map(|c| query_counters.query_counter_of(c).clone())
+                    .collect(),
+                target_clusters,
+                target_max_running_queries: max_running_queries
+                    .get(target_cluster_group)
+                    .cloned()
+                    .context(TargetClusterGroupNotFoundSnafu {
+                        cluster_group,
+                        target_cluster_group,
+                    })?,
+                promotion_interval: parking.promotion_interval,
+            });
+        }
+
+        Ok(Self {
+            persistence,
+            parking_groups,
+        })
+    }
+
+    pub fn start_loop(self) {
+        for parking_group in self.parking_groups {
+            let persistence = Arc::clone(&self.persistence);
+
+            tokio::spawn(async move {
+                let mut interval = time::interval(parking_group.promotion_interval);
+                interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+                loop {
+                    // First tick does not sleep, so let's put it at the start of the loop.
+                    interval.tick().await;
+
+                    let result = promote_parked_queries(&persistence, &parking_group)
+                        .instrument(info_span!(
+                            "Promoting parked queries",
+                            cluster_group = parking_group.name
+                        ))
+                        .await;
+                    // Verbosity level defending on wether a parked query was promoted
+                    match result {
+                        Ok(0) => debug!(
+                            cluster_group = parking_group.name,
+                            "ParkedQueryPromoter: No parked queries promoted"
+                        ),
+                        Ok(promoted) => info!(
+                            promoted,
+                            cluster_group = parking_group.name,
+                            target_cluster_group = parking_group.target_cluster_group,
+                            "ParkedQueryPromoter: Successfully promoted parked queries"
+                        ),
+                        Err(error) => error!(
+                            ?error,
+                            cluster_group = parking_group.name,
+                            "ParkedQueryPromoter: Failed to promote parked queries"
+                        ),
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Moves the oldest parked queries into the target cluster group, but only as many as the target cluster group can
+/// start right away. Returns the number of promoted queries.
+#[instrument(skip(persistence))]
+async fn promote_parked_queries(
+    persistence: &PersistenceImplementation,
+    parking_group: &ParkingGroup,
+) -> Result<usize, trino_lb_persistence::Error> {
+    let free_capacity = free_capacity_of_target(persistence, parking_group).await?;
+    if free_capacity == 0 {
+        return Ok(0);
+    }
+
+    let parked_query_ids = persistence
+        .list_queued_query_ids(&parking_group.name)
+        .await?;
+    let mut parked_queries = Vec::with_capacity(parked_query_ids.len());
+    for batch in parked_query_ids.chunks(LOAD_PARKED_QUERIES_BATCH_SIZE) {
+        let loaded = try_join_all(batch.iter().map(|id| persistence.load_queued_query(id))).await?;
+        // Queries that were handed over or canceled in the meantime are not stored any more
+        parked_queries.extend(loaded.into_iter().flatten());
+    }
+    parked_queries.sort_by_key(|parked_query| parked_query.creation_time);
+
+    let mut promoted = 0;
+    for parked_query in parked_queries.iter().take(free_capacity) {
+        persistence
+            .move_queued_query(parked_query, &parking_group.target_cluster_group)
+            .await?;
+        promoted += 1;
+    }
+
+    Ok(promoted)
+}
+
+/// The number of queries the ready clusters of the target cluster group can start, minus the queries already queued
+/// for the target cluster group.
+async fn free_capacity_of_target(
+    persistence: &PersistenceImplementation,
+    parking_group: &ParkingGroup,
+) -> Result<usize, trino_lb_persistence::Error> {
+    let (cluster_states, cluster_query_counts) = tokio::try_join!(
+        try_join_all(
+            parking_group
+                .target_clusters
+                .iter()
+                .map(|c| persistence.get_cluster_state(c))
+        ),
+        try_join_all(
+            parking_group
+                .target_query_counters
+                .iter()
+                .map(|c| persistence.get_cluster_query_count(c))
+        ),
+    )?;
+    let queued = persistence
+        .get_queued_query_count(&parking_group.target_cluster_group)
+        .await?;
+
+    let target_max_running_queries = parking_group
+        .target_max_running_queries
+        .effective_at(&Utc::now());
+    let capacity: u64 = cluster_states
+        .iter()
+        .zip(cluster_query_counts)
+        .filter(|(state, _)| state.ready_to_accept_queries())
+        .map(|(_, count)| target_max_running_queries.saturating_sub(count))
+        .sum();
+
+    Ok(capacity
+        .saturating_sub(queued)
+        .try_into()
+        .unwrap_or(usize::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use trino_lb_core::{trino_cluster::ClusterState, trino_query::QueuedQuery};
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    fn promoter(
+        persistence: Arc<PersistenceImplementation>,
+        config: &HashMap<String, TrinoClusterGroupConfig>,
+    ) -> Result<ParkedQueryPromoter, Error> {
+        ParkedQueryPromoter::new(
+            persistence,
+            config,
+            &QueryCounters::default(),
+            &crate::max_running_queries::of_cluster_groups(config).unwrap(),
+        )
+    }
+
+    fn cluster_groups(target_cluster_group: &str) -> HashMap<String, TrinoClusterGroupConfig> {
+        TestConfigBuilder::new()
+            .cluster_group(
+                "s",
+                2,
+                &[
+                    ("trino-s-1", "https://trino-s-1:8443"),
+                    ("trino-s-2", "https://trino-s-2:8443"),
+                ],
+            )
+            .cluster_group_yaml(
+                "parking",
+                &format!(
+                    r#"
+maxRunningQueries: 0
+trinoClusters: []
+parking:
+  targetClusterGroup: {target_cluster_group}
+  promotionInterval: 10s
+"#
+                ),
+            )
+            .build()
+            .trino_cluster_groups
+    }
+
+    async fn park_query(persistence: &PersistenceImplementation, age: Duration) -> QueuedQuery {
+        let mut queued_query = QueuedQuery::new_from(
+            "select 42".to_owned(),
+            http::HeaderMap::new(),
+            "parking".to_owned(),
+            None,
+        );
+        queued_query.creation_time = SystemTime::now() - age;
+        persistence
+            .store_queued_query(queued_query.clone())
+            .await
+            .unwrap();
+
+        queued_query
+    }
+
+    async fn cluster_group_of(
+        persistence: &PersistenceImplementation,
+        query: &QueuedQuery,
+    ) -> String {
+        persistence
+            .load_queued_query(&query.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .cluster_group
+    }
+
+    #[tokio::test]
+    async fn test_promote_oldest_parked_queries() {
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let mut promoter = promoter(Arc::clone(&persistence), &cluster_groups("s")).unwrap();
+        let parking_group = promoter.parking_groups.pop().unwrap();
+
+        let newest = park_query(&persistence, Duration::from_secs(1)).await;
+        let oldest = park_query(&persistence, Duration::from_secs(3)).await;
+        let middle = park_query(&persistence, Duration::from_secs(2)).await;
+
+        // Clusters that are not ready don't offer any capacity
+        assert_eq!(
+            promote_parked_queries(&persistence, &parking_group)
+                .await
+                .unwrap(),
+            0
+        );
+
+        // trino-s-1 has room for one more query, trino-s-2 is full
+        for (cluster, count) in [("trino-s-1", 1), ("trino-s-2", 2)] {
+            persistence
+                .set_cluster_state(&cluster.to_owned(), ClusterState::Ready)
+                .await
+                .unwrap();
+            persistence
+                .set_cluster_query_count(&cluster.to_owned(), count)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            promote_parked_queries(&persistence, &parking_group)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(cluster_group_of(&persistence, &oldest).await, "s");
+        assert_eq!(cluster_group_of(&persistence, &middle).await, "parking");
+
+        // The promoted query has not been started yet, so it still occupies the free capacity
+        assert_eq!(
+            promote_parked_queries(&persistence, &parking_group)
+                .await
+                .unwrap(),
+            0
+        );
+
+        persistence
+            .set_cluster_query_count(&"trino-s-2".to_owned(), 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            promote_parked_queries(&persistence, &parking_group)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(cluster_group_of(&persistence, &middle).await, "s");
+        assert_eq!(cluster_group_of(&persistence, &newest).await, "s");
+        assert_eq!(
+            persistence.get_queued_query_count("parking").await.unwrap(),
+            0
+        );
+        assert_eq!(persistence.get_queued_query_count("s").await.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_invalid_parking_config() {
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+
+        assert!(matches!(
+            promoter(Arc::clone(&persistence), &cluster_groups("xl")),
+            Err(Error::TargetClusterGroupNotFound { target_cluster_group, .. }) if target_cluster_group == "xl"
+        ));
+        assert!(matches!(
+            promoter(Arc::clone(&persistence), &cluster_groups("parking")),
+            Err(Error::TargetIsParkingGroup { .. })
+        ));
+
+        let mut config = cluster_groups("s");
+        let clusters = config["s"].trino_clusters.clone();
+        config.get_mut("parking").unwrap().trino_clusters = clusters;
+        assert!(matches!(
+            promoter(persistence, &config),
+            Err(Error::ClustersInParkingGroup { cluster_group }) if cluster_group == "parking"
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::time;
+use tracing::{debug, error, info_span, Instrument};
+use trino_lb_core::{config::TrinoLbQueryChargebackConfig, query_chargeback};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+/// The buckets are days, so there is no need to check more often.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically deletes the handed over query counts that are older than the configured retention, so that this
+/// does not need to happen for every handed over query.
+pub struct QueryChargebackCleaner {
+    persistence: Arc<PersistenceImplementation>,
+    retention: Duration,
+}
+
+impl QueryChargebackCleaner {
+    pub fn new(
+        persistence: Arc<PersistenceImplementation>,
+        config: &TrinoLbQueryChargebackConfig,
+    ) -> Self {
+        Self {
+            persistence,
+            retention: config.retention,
+        }
+    }
+
+    pub fn start_loop(self) {
+        tokio::spawn(async move {
+            let mut interval = time::interval(CLEANUP_INTERVAL);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                // First tick does not sleep, so let's put it at the start of the loop.
+                interval.tick().await;
+
+                async {
+                    let oldest_bucket = oldest_bucket(SystemTime::now(), self.retention);
+                    match self
+                        .persistence
+                        .delete_handed_over_queries_before(oldest_bucket)
+                        .await
+                    {
+                        Ok(()) => debug!(
+                            oldest_bucket,
+                            "QueryChargebackCleaner: Successfully deleted expired handed over query counts"
+                        ),
+                        Err(error) => error!(
+                            ?error,
+                            "QueryChargebackCleaner: Failed to delete expired handed over query counts"
+                        ),
+                    }
+                }
+                .instrument(info_span!("Deleting expired handed over query counts"))
+                .await;
+            }
+        });
+    }
+}
+
+/// Returns the oldest bucket that is still within the retention.
+fn oldest_bucket(now: SystemTime, retention: Duration) -> u64 {
+    query_chargeback::bucket_of(now).saturating_sub(query_chargeback::bucket_count(retention))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use query_chargeback::QUERY_CHARGEBACK_BUCKET_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_oldest_bucket() {
+        let now = UNIX_EPOCH + 40 * QUERY_CHARGEBACK_BUCKET_SIZE + Duration::from_secs(60);
+        assert_eq!(oldest_bucket(now, QUERY_CHARGEBACK_BUCKET_SIZE), 39);
+        assert_eq!(oldest_bucket(now, 31 * QUERY_CHARGEBACK_BUCKET_SIZE), 9);
+        assert_eq!(oldest_bucket(UNIX_EPOCH, QUERY_CHARGEBACK_BUCKET_SIZE), 0);
+    }
+}
@@ -1,12 +1,13 @@
 use std::{
     collections::HashMap,
+    num::NonZeroUsize,
     sync::Arc,
     time::{Duration, SystemTime},
 };
 
 use futures::{future::join_all, TryFutureExt};
 use snafu::Snafu;
-use tokio::time;
+use tokio::{sync::Semaphore, time};
 use tracing::{error, info, info_span, instrument, Instrument};
 use trino_lb_core::{config::TrinoClusterConfig, trino_cluster::ClusterState, TrinoClusterName};
 use trino_lb_persistence::{Persistence, PersistenceImplementation};
@@ -23,7 +24,10 @@ pub struct QueryCountFetcher {
     persistence: Arc<PersistenceImplementation>,
     clusters: Vec<TrinoClusterConfig>,
     ignore_certs: bool,
+    user_agent: String,
     refresh_query_counter_interval: Duration,
+    /// How many clusters are asked for their query counts at the same time, [`None`] means all at once.
+    max_concurrency: Option<NonZeroUsize>,
     metrics: Arc<Metrics>,
 }
 
@@ -33,7 +37,9 @@ impl QueryCountFetcher {
         persistence: Arc<PersistenceImplementation>,
         config: &HashMap<String, TrinoClusterGroupConfig>,
         ignore_certs: bool,
+        user_agent: String,
         refresh_query_counter_interval: &Duration,
+        max_concurrency: Option<NonZeroUsize>,
         metrics: Arc<Metrics>,
     ) -> Result<Self, Error> {
         // Remove all the duplicated clusters that are part of multiple groups.
@@ -48,7 +54,9 @@ impl QueryCountFetcher {
             persistence,
             clusters,
             ignore_certs,
+            user_agent,
             refresh_query_counter_interval: *refresh_query_counter_interval,
+            max_concurrency,
             metrics,
         })
     }
@@ -132,42 +140,65 @@ impl QueryCountFetcher {
                 if let Ok(mut cluster_infos) = self.metrics.cluster_infos.write() {
                     cluster_infos.clear();
                 }
-                let result = join_all(
+                let processed_clusters = self.process_clusters(
                     self.clusters
                         .iter()
                         .zip(cluster_states)
                         .filter_map(|(cluster, state)| match state{
                             ClusterState::Unknown | ClusterState::Stopped | ClusterState::Starting | ClusterState::Terminating | ClusterState::Deactivated => None,
-                            ClusterState::Ready | ClusterState::Draining{ .. } => Some(cluster),
+                            ClusterState::WarmingUp { .. } | ClusterState::Ready | ClusterState::Draining{ .. } => Some(cluster),
                         })
-                        .map(|cluster| self.process_cluster(cluster)),
+                        .collect(),
                 )
                 .await;
 
                 info!(
                     "QueryCountFetcher: Updated query counters from {} remote clusters",
-                    result.len()
+                    processed_clusters
                 );
             }.instrument(info_span!("Fetching current query counters")).await;
         }
     }
 
+    /// Processes the given clusters, but at most [`Self::max_concurrency`] at the same time. Returns the number of
+    /// processed clusters.
+    async fn process_clusters(&self, clusters: Vec<&TrinoClusterConfig>) -> usize {
+        let permits = self
+            .max_concurrency
+            .map(NonZeroUsize::get)
+            .unwrap_or(clusters.len())
+            .clamp(1, Semaphore::MAX_PERMITS);
+        let semaphore = Semaphore::new(permits);
+
+        join_all(clusters.into_iter().map(|cluster| async {
+            // The semaphore is never closed, so acquiring a permit can not fail
+            let _permit = semaphore.acquire().await;
+            self.process_cluster(cluster).await;
+        }))
+        .await
+        .len()
+    }
+
     #[instrument(skip(self))]
     async fn process_cluster(&self, cluster: &TrinoClusterConfig) {
-        let cluster_info =
-            get_cluster_info(&cluster.endpoint, self.ignore_certs, &cluster.credentials).await;
+        let cluster_info = get_cluster_info(
+            cluster.internal_endpoint(),
+            self.ignore_certs,
+            &cluster.credentials,
+            &self.user_agent,
+        )
+        .await;
 
         match cluster_info {
             Ok(cluster_info) => {
-                let result = self
-                    .persistence
-                    .set_cluster_query_count(
+                let result = futures::try_join!(
+                    self.persistence
+                        .set_cluster_query_count(&cluster.name, cluster_info.query_count()),
+                    self.persistence.set_cluster_blocked_query_count(
                         &cluster.name,
-                        cluster_info.running_queries
-                            + cluster_info.blocked_queries
-                            + cluster_info.queued_queries,
-                    )
-                    .await;
+                        cluster_info.blocked_queries
+                    ),
+                );
 
                 if let Ok(mut cluster_infos) = self.metrics.cluster_infos.write() {
                     cluster_infos.insert(cluster.name.clone(), cluster_info);
@@ -189,3 +220,178 @@ impl QueryCountFetcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use axum::{
+        routing::{get, post},
+        Json,
+    };
+    use tokio::{net::TcpListener, sync::Barrier};
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+    use url::Url;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    /// Starts a fake Trino web UI, which reports the given number of running queries. Also returns the maximum number
+    /// of stats requests that were handled at the same time.
+    ///
+    /// The stats requests are only answered once `concurrent_requests` of them are waiting (or a timeout passed), so
+    /// that the maximum does not depend on how fast the requests are sent.
+    async fn start_fake_trino_ui(
+        running_queries: u64,
+        concurrent_requests: usize,
+    ) -> (Url, Arc<AtomicU64>) {
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let max_in_flight = Arc::new(AtomicU64::new(0));
+        let barrier = Arc::new(Barrier::new(concurrent_requests));
+        let app = axum::Router::new()
+            .route("/ui/login", post(|| async {}))
+            .route(
+                "/ui/api/stats",
+                get({
+                    let max_in_flight = Arc::clone(&max_in_flight);
+                    move || async move {
+                        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(current, Ordering::SeqCst);
+                        // In case fewer requests are sent concurrently, the test fails as the maximum is too low
+                        let _ = time::timeout(Duration::from_secs(5), barrier.wait()).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                        Json(serde_json::json!({
+                            "runningQueries": running_queries,
+                            "blockedQueries": 0,
+                            "queuedQueries": 0,
+                            "activeCoordinators": 1,
+                            "activeWorkers": 1,
+                            "runningDrivers": 0,
+                            "totalAvailableProcessors": 4,
+                            "reservedMemory": 0.0,
+                            "totalInputRows": 0,
+                            "totalInputBytes": 0,
+                            "totalCpuTimeSecs": 0,
+                        }))
+                    }
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (endpoint, max_in_flight)
+    }
+
+    fn clusters(clusters: &[(&str, &str)]) -> Vec<TrinoClusterConfig> {
+        TestConfigBuilder::new()
+            .cluster_group("s", 1, clusters)
+            .build()
+            .trino_cluster_groups
+            .remove("s")
+            .unwrap()
+            .trino_clusters
+    }
+
+    fn fetcher(
+        persistence: Arc<PersistenceImplementation>,
+        max_concurrency: Option<NonZeroUsize>,
+    ) -> QueryCountFetcher {
+        let config = TestConfigBuilder::new().build();
+        let metrics = Arc::new(
+            Metrics::new(
+                prometheus::Registry::new(),
+                Arc::clone(&persistence),
+                &config,
+            )
+            .unwrap(),
+        );
+
+        QueryCountFetcher::new(
+            persistence,
+            &config.trino_cluster_groups,
+            false,
+            "trino-lb".to_owned(),
+            &Duration::from_secs(5),
+            max_concurrency,
+            metrics,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fetches_query_count_from_internal_endpoint() {
+        let (internal_endpoint, _) = start_fake_trino_ui(7, 1).await;
+        let config = TestConfigBuilder::new()
+            .cluster_group_yaml(
+                "s",
+                &format!(
+                    r#"
+maxRunningQueries: 1
+trinoClusters:
+  - name: trino-s-1
+    # Not reachable from trino-lb
+    endpoint: http://trino.invalid
+    internalEndpoint: {internal_endpoint}
+    externalEndpoint: https://trino.example.com
+    credentials: {{username: admin, password: admin}}
+"#
+                ),
+            )
+            .build();
+        let cluster = &config.trino_cluster_groups["s"].trino_clusters[0];
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+
+        fetcher(Arc::clone(&persistence), None)
+            .process_cluster(cluster)
+            .await;
+        assert_eq!(
+            persistence
+                .get_cluster_query_count(&cluster.name)
+                .await
+                .unwrap(),
+            7
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(None, 6)]
+    #[case(NonZeroUsize::new(2), 2)]
+    #[case(NonZeroUsize::new(1), 1)]
+    #[tokio::test]
+    async fn test_process_clusters_concurrency(
+        #[case] max_concurrency: Option<NonZeroUsize>,
+        #[case] expected_max_in_flight: u64,
+    ) {
+        let (endpoint, max_in_flight) =
+            start_fake_trino_ui(3, expected_max_in_flight as usize).await;
+        let names = (1..=6).map(|i| format!("trino-s-{i}")).collect::<Vec<_>>();
+        let clusters = clusters(
+            &names
+                .iter()
+                .map(|name| (name.as_str(), endpoint.as_str()))
+                .collect::<Vec<_>>(),
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+
+        let processed = fetcher(Arc::clone(&persistence), max_concurrency)
+            .process_clusters(clusters.iter().collect())
+            .await;
+        assert_eq!(processed, 6);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), expected_max_in_flight);
+        for cluster in &clusters {
+            assert_eq!(
+                persistence
+                    .get_cluster_query_count(&cluster.name)
+                    .await
+                    .unwrap(),
+                3
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+
+use snafu::{ResultExt, Snafu};
+use tracing::{info, instrument};
+use trino_lb_core::{config::Config, TrinoClusterName};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to list the stored cluster states"))]
+    ListClusterStates { source: trino_lb_persistence::Error },
+
+    #[snafu(display("Failed to remove cluster {cluster:?} from the persistence"))]
+    RemoveCluster {
+        source: trino_lb_persistence::Error,
+        cluster: TrinoClusterName,
+    },
+}
+
+/// Removes the stored state and query count of all clusters that are not part of the given config any more, as nothing
+/// else would ever touch them again. Returns the removed clusters.
+///
+/// Removing a cluster is idempotent, so it does not matter if multiple trino-lb instances do this at the same time
+/// during startup.
+#[instrument(skip_all)]
+pub async fn cleanup_removed_clusters(
+    persistence: &PersistenceImplementation,
+    config: &Config,
+) -> Result<Vec<TrinoClusterName>, Error> {
+    let configured_clusters = config
+        .trino_cluster_groups
+        .values()
+        .flat_map(|group| &group.trino_clusters)
+        .map(|c| &c.name)
+        .collect::<HashSet<_>>();
+
+    let cluster_states = persistence
+        .list_cluster_states()
+        .await
+        .context(ListClusterStatesSnafu)?;
+
+    let mut removed = Vec::new();
+    for (cluster, state) in cluster_states {
+        if configured_clusters.contains(&cluster) {
+            continue;
+        }
+
+        persistence
+            .remove_cluster(&cluster)
+            .await
+            .context(RemoveClusterSnafu { cluster: &cluster })?;
+        info!(
+            cluster,
+            ?state,
+            "Removed state and query count of cluster that is not configured any more"
+        );
+        removed.push(cluster);
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use trino_lb_core::trino_cluster::ClusterState;
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    #[tokio::test]
+    async fn test_cleanup_removed_clusters() {
+        let config = TestConfigBuilder::new()
+            .cluster_group("s", 1, &[("trino-s-1", "https://trino-s-1:8443")])
+            .build();
+
+        let persistence: PersistenceImplementation = InMemoryPersistence::default().into();
+        for cluster in ["trino-s-1", "trino-removed"] {
+            let cluster = cluster.to_owned();
+            persistence
+                .set_cluster_state(&cluster, ClusterState::Ready)
+                .await
+                .unwrap();
+            persistence
+                .inc_cluster_query_count(&cluster, 10)
+                .await
+                .unwrap();
+        }
+
+        let removed = cleanup_removed_clusters(&persistence, &config)
+            .await
+            .unwrap();
+        assert_eq!(removed, ["trino-removed"]);
+
+        assert_eq!(
+            persistence.list_cluster_states().await.unwrap(),
+            [("trino-s-1".to_owned(), ClusterState::Ready)]
+        );
+        assert_eq!(
+            persistence
+                .get_cluster_query_count(&"trino-s-1".to_owned())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            persistence
+                .get_cluster_query_count(&"trino-removed".to_owned())
+                .await
+                .unwrap(),
+            0
+        );
+
+        // Nothing left to do
+        assert!(cleanup_removed_clusters(&persistence, &config)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
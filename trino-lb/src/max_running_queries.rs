@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use snafu::{ResultExt, Snafu};
+use trino_lb_core::config::TrinoClusterGroupConfig;
+
+use crate::time_range::{self, TimeRange};
+
+/// How often the cached [`MaxRunningQueries::current`] value is re-calculated.
+pub const MAX_RUNNING_QUERIES_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display(
+        "Failed to parse the maxRunningQueriesSchedule of the cluster group {cluster_group:?}"
+    ))]
+    ParseTimeRange {
+        source: time_range::Error,
+        cluster_group: String,
+    },
+}
+
+/// Parses the `maxRunningQueriesSchedule` of all cluster groups. This should only happen once, so that all components
+/// share the same [`MaxRunningQueries`] and only a single refresh loop is needed.
+pub fn of_cluster_groups(
+    config: &HashMap<String, TrinoClusterGroupConfig>,
+) -> Result<HashMap<String, Arc<MaxRunningQueries>>, Error> {
+    config
+        .iter()
+        .map(|(cluster_group, group_config)| {
+            MaxRunningQueries::new(group_config)
+                .map(|max_running_queries| (cluster_group.clone(), Arc::new(max_running_queries)))
+                .context(ParseTimeRangeSnafu { cluster_group })
+        })
+        .collect()
+}
+
+/// The `maxRunningQueries` of a cluster group, which can change over the day according to the
+/// `maxRunningQueriesSchedule`.
+///
+/// As it is needed for every query, the currently effective value is cached and only re-calculated by
+/// [`MaxRunningQueries::refresh`].
+#[derive(Debug)]
+pub struct MaxRunningQueries {
+    default: u64,
+    schedule: Vec<(TimeRange, u64)>,
+    current: AtomicU64,
+}
+
+impl MaxRunningQueries {
+    pub fn new(config: &TrinoClusterGroupConfig) -> Result<Self, time_range::Error> {
+        let schedule = config
+            .max_running_queries_schedule
+            .iter()
+            .map(|s| TimeRange::new(&s.time_utc, &s.weekdays).map(|time_range| (time_range, s.max)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let max_running_queries = Self {
+            default: config.max_running_queries,
+            schedule,
+            current: AtomicU64::new(config.max_running_queries),
+        };
+        max_running_queries.refresh(&Utc::now());
+
+        Ok(max_running_queries)
+    }
+
+    /// Returns the cached value, which was effective during the last [`MaxRunningQueries::refresh`].
+    pub fn current(&self) -> u64 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    pub fn effective_at(&self, date: &DateTime<Utc>) -> u64 {
+        self.schedule
+            .iter()
+            .rev()
+            .find(|(time_range, _)| time_range.date_is_in_range(date))
+            .map(|(_, max)| *max)
+            // In case no time period matches the plain maxRunningQueries applies
+            .unwrap_or(self.default)
+    }
+
+    pub fn refresh(&self, date: &DateTime<Utc>) {
+        self.current
+            .store(self.effective_at(date), Ordering::Relaxed);
+    }
+
+    /// Whether the value can change over time at all, so it needs to be refreshed periodically.
+    pub fn is_scheduled(&self) -> bool {
+        !self.schedule.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    fn cluster_group(yaml: &str) -> TrinoClusterGroupConfig {
+        TestConfigBuilder::new()
+            .cluster_group_yaml("s", yaml)
+            .build()
+            .trino_cluster_groups
+            .remove("s")
+            .unwrap()
+    }
+
+    fn max_running_queries(schedule: &[(&str, u64)]) -> MaxRunningQueries {
+        let schedule = schedule
+            .iter()
+            .map(|(time_utc, max)| {
+                format!("{{timeUtc: \"{time_utc}\", weekdays: Mon - Son, max: {max}}}")
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config = cluster_group(&format!(
+            "maxRunningQueries: 10\nmaxRunningQueriesSchedule: [{schedule}]\ntrinoClusters: []"
+        ));
+
+        MaxRunningQueries::new(&config).unwrap()
+    }
+
+    #[rstest]
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 0, 0, 0).unwrap(), 50)]
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 5, 59, 59).unwrap(), 50)]
+    // Boundary between the ETL window and the day
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 6, 0, 0).unwrap(), 10)]
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 12, 0, 0).unwrap(), 10)]
+    // Boundary between the day and the evening windows
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 21, 59, 59).unwrap(), 10)]
+    // Both evening windows match, the last one wins
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 22, 0, 0).unwrap(), 30)]
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 23, 0, 0).unwrap(), 30)]
+    #[case(Utc.with_ymd_and_hms(2024, 1, 8, 23, 59, 59).unwrap(), 30)]
+    fn test_effective_at(#[case] date: DateTime<Utc>, #[case] expected: u64) {
+        let max_running_queries = max_running_queries(&[
+            ("00:00:00 - 05:59:59", 50),
+            ("22:00:00 - 23:59:59", 20),
+            ("22:00:00 - 23:59:59", 30),
+        ]);
+
+        assert_eq!(max_running_queries.effective_at(&date), expected);
+
+        max_running_queries.refresh(&date);
+        assert_eq!(max_running_queries.current(), expected);
+    }
+
+    #[test]
+    fn test_no_schedule() {
+        let max_running_queries = max_running_queries(&[]);
+
+        assert!(!max_running_queries.is_scheduled());
+        assert_eq!(max_running_queries.current(), 10);
+    }
+
+    #[test]
+    fn test_invalid_schedule() {
+        let config = cluster_group(
+            r#"
+maxRunningQueries: 10
+maxRunningQueriesSchedule:
+  - timeUtc: "22:00 - 23:59"
+    weekdays: Mon - Son
+    max: 30
+trinoClusters: []
+"#,
+        );
+
+        assert!(MaxRunningQueries::new(&config).is_err());
+    }
+}
@@ -1,12 +1,16 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ops::Deref,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use futures::future::try_join_all;
 use opentelemetry::{
-    metrics::{Counter, Histogram, MetricsError},
+    metrics::{Counter, Histogram, MetricsError, UpDownCounter},
     KeyValue,
 };
 use prometheus::Registry;
@@ -25,6 +29,11 @@ use trino_lb_persistence::{Persistence, PersistenceImplementation};
 
 use crate::trino_client::ClusterInfo;
 
+/// Period the `avg_queue_wait` metric is calculated over.
+const QUEUE_WAIT_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Maximum number of queue durations per cluster group the `avg_queue_wait` metric is calculated from.
+const QUEUE_WAIT_WINDOW_MAX_ENTRIES: usize = 1000;
+
 #[derive(Snafu, Debug)]
 pub enum Error {
     #[snafu(display("Failed to register metrics callback"))]
@@ -34,11 +43,30 @@ pub enum Error {
 pub struct Metrics {
     pub registry: Registry,
     pub http_counter: Counter<u64>,
+    pub http_request_duration: Histogram<u64>,
     pub queued_time: Histogram<u64>,
+    pub handoff_cluster_utilization: Histogram<f64>,
+    pub query_immediate_no_next_uri_counter: Counter<u64>,
+    pub cluster_counter_underflow_counter: Counter<u64>,
+    pub proxy_requests_in_flight: UpDownCounter<i64>,
+    pub scaler_reconcile_duration: Histogram<f64>,
+    pub scaler_reconcile_counter: Counter<u64>,
+
+    /// How often a query was about to be handed over to a cluster, but the cluster filled up in the meantime.
+    /// Reported as the `hand_over_retries_total` counter.
+    pub hand_over_retries: Arc<AtomicU64>,
+
+    /// The number of clusters per state for every cluster group, as calculated by the last scaler reconciliation.
+    /// Uses a [`std::sync::RwLock`] for the same reasons as [`Self::cluster_infos`].
+    pub scaler_cluster_states: Arc<RwLock<HashMap<String, HashMap<&'static str, u64>>>>,
 
     /// We cant use [`tokio::sync::RwLock`] because of <https://github.com/open-telemetry/opentelemetry-rust/issues/1376>.
     /// As setting the HashMap values is not in a critical path should be fine (tm).
     pub cluster_infos: Arc<RwLock<HashMap<TrinoClusterName, ClusterInfo>>>,
+
+    /// The queue durations of the recently handed over queries for every cluster group. Uses a
+    /// [`std::sync::RwLock`] for the same reasons as [`Self::cluster_infos`].
+    queue_wait_windows: Arc<RwLock<HashMap<String, QueueWaitWindow>>>,
 }
 
 impl Metrics {
@@ -55,13 +83,70 @@ impl Metrics {
             .with_description("Total number of HTTP requests made.")
             .init();
 
+        let http_request_duration = meter
+            .u64_histogram("http_request_duration")
+            .with_unit("ms")
+            .with_description("The time it took to process HTTP requests")
+            .init();
+
         let queued_time = meter
             .u64_histogram("query_queued_duration")
             .with_unit("ms")
             .with_description("The time queries where queued in trino-lb")
             .init();
 
+        let handoff_cluster_utilization = meter
+            .f64_histogram("handoff_cluster_utilization")
+            .with_unit("%")
+            .with_description(
+                "How full the cluster queries were handed over to was, in percent of its maxRunningQueries",
+            )
+            .init();
+
+        let query_immediate_no_next_uri_counter = meter
+            .u64_counter("query_immediate_no_next_uri_total")
+            .with_description(
+                "Total number of queries Trino accepted but immediately returned no nextUri for, e.g. because of syntax errors",
+            )
+            .init();
+
+        let cluster_counter_underflow_counter = meter
+            .u64_counter("cluster_counter_underflow_total")
+            .with_description(
+                "Total number of attempts to decrement the query counter of a cluster that was already zero, which points to a bug",
+            )
+            .init();
+
+        let proxy_requests_in_flight = meter
+            .i64_up_down_counter("proxy_requests_in_flight")
+            .with_unit("requests")
+            .with_description(
+                "The number of client requests for queries running on Trino that are currently proxied to Trino",
+            )
+            .init();
+
+        let scaler_reconcile_duration = meter
+            .f64_histogram("scaler_reconcile_duration")
+            .with_unit("s")
+            .with_description("The time it took the scaler to reconcile a cluster group")
+            .init();
+
+        let scaler_reconcile_counter = meter
+            .u64_counter("scaler_reconcile_total")
+            .with_description("Total number of scaler reconciliations of a cluster group")
+            .init();
+
         let cluster_infos = Arc::new(RwLock::new(HashMap::<TrinoClusterName, ClusterInfo>::new()));
+        let scaler_cluster_states: Arc<RwLock<HashMap<String, HashMap<&str, u64>>>> =
+            Arc::default();
+
+        let scaler_cluster_states_metric = meter
+            .u64_observable_gauge("scaler_cluster_counts_per_state")
+            .with_unit("clusters")
+            .with_description(
+                "The number of clusters in each state for each cluster group, as calculated during the last scaler reconciliation",
+            )
+            .init();
 
         let cluster_counts_per_state_metric = meter
             .u64_observable_gauge("cluster_counts_per_state")
@@ -83,6 +168,57 @@ impl Metrics {
             .with_description("The number of queries queued across all trino-lb instances")
             .init();
 
+        let avg_queue_wait_metric = meter
+            .f64_observable_gauge("avg_queue_wait")
+            .with_unit("s")
+            .with_description(
+                "The average time the queries handed over by this trino-lb instance during the last 5 minutes were queued, per cluster group",
+            )
+            .init();
+
+        let build_info_metric = meter
+            .u64_observable_gauge("build_info")
+            .with_description(
+                "Always 1, labeled with the version, git commit and build timestamp of trino-lb",
+            )
+            .init();
+
+        let persistence_experimental_in_use_metric = meter
+            .u64_observable_gauge("persistence_experimental_in_use")
+            .with_description(
+                "1 in case an experimental persistence (currently Postgres) is in use, 0 otherwise",
+            )
+            .init();
+
+        meter
+            .register_callback(&[build_info_metric.as_any()], move |observer| {
+                observer.observe_u64(
+                    &build_info_metric,
+                    1,
+                    [
+                        KeyValue::new("version", env!("CARGO_PKG_VERSION")),
+                        KeyValue::new("git_commit", env!("TRINO_LB_GIT_COMMIT")),
+                        KeyValue::new("build_timestamp", env!("TRINO_LB_BUILD_TIMESTAMP")),
+                    ]
+                    .as_ref(),
+                );
+            })
+            .context(RegisterMetricsCallbackSnafu)?;
+
+        let persistence_experimental_in_use = u64::from(persistence.is_experimental());
+        meter
+            .register_callback(
+                &[persistence_experimental_in_use_metric.as_any()],
+                move |observer| {
+                    observer.observe_u64(
+                        &persistence_experimental_in_use_metric,
+                        persistence_experimental_in_use,
+                        &[],
+                    );
+                },
+            )
+            .context(RegisterMetricsCallbackSnafu)?;
+
         let cluster_infos_for_callback = Arc::clone(&cluster_infos);
         meter
             .register_callback(&[cluster_queries_metric.as_any()], move |observer| {
@@ -120,11 +256,100 @@ impl Metrics {
             })
             .context(RegisterMetricsCallbackSnafu)?;
 
+        let scaler_cluster_states_for_callback = Arc::clone(&scaler_cluster_states);
+        meter
+            .register_callback(&[scaler_cluster_states_metric.as_any()], move |observer| {
+                if let Ok(scaler_cluster_states) = scaler_cluster_states_for_callback.read() {
+                    for (cluster_group, counts) in scaler_cluster_states.deref() {
+                        for (state, count) in counts {
+                            observer.observe_u64(
+                                &scaler_cluster_states_metric,
+                                *count,
+                                [
+                                    KeyValue::new("cluster-group", cluster_group.clone()),
+                                    KeyValue::new("state", *state),
+                                ]
+                                .as_ref(),
+                            );
+                        }
+                    }
+                }
+            })
+            .context(RegisterMetricsCallbackSnafu)?;
+
+        let hand_over_retries_metric = meter
+            .u64_observable_counter("hand_over_retries_total")
+            .with_description(
+                "Total number of times the cluster a query was about to be handed over to filled up in the meantime, so that the cluster had to be picked again",
+            )
+            .init();
+        let hand_over_retries = Arc::new(AtomicU64::new(0));
+        let hand_over_retries_for_callback = Arc::clone(&hand_over_retries);
+        meter
+            .register_callback(&[hand_over_retries_metric.as_any()], move |observer| {
+                observer.observe_u64(
+                    &hand_over_retries_metric,
+                    hand_over_retries_for_callback.load(Ordering::Relaxed),
+                    &[],
+                );
+            })
+            .context(RegisterMetricsCallbackSnafu)?;
+
+        let queue_wait_windows: Arc<RwLock<HashMap<String, QueueWaitWindow>>> = Arc::default();
+        let queue_wait_windows_for_callback = Arc::clone(&queue_wait_windows);
+        meter
+            .register_callback(&[avg_queue_wait_metric.as_any()], move |observer| {
+                if let Ok(queue_wait_windows) = queue_wait_windows_for_callback.read() {
+                    let now = Instant::now();
+                    for (cluster_group, window) in queue_wait_windows.deref() {
+                        if let Some(average) = window.average(now) {
+                            observer.observe_f64(
+                                &avg_queue_wait_metric,
+                                average.as_secs_f64(),
+                                [KeyValue::new("cluster-group", cluster_group.clone())].as_ref(),
+                            );
+                        }
+                    }
+                }
+            })
+            .context(RegisterMetricsCallbackSnafu)?;
+
+        if persistence.as_in_memory().is_some() {
+            let in_memory_persistence_entries_metric = meter
+                .u64_observable_gauge("in_memory_persistence_entries")
+                .with_unit("entries")
+                .with_description(
+                    "The number of entries stored in the maps of the in-memory persistence",
+                )
+                .init();
+
+            let persistence_for_callback = Arc::clone(&persistence);
+            meter
+                .register_callback(
+                    &[in_memory_persistence_entries_metric.as_any()],
+                    move |observer| {
+                        if let Some(in_memory_persistence) = persistence_for_callback.as_in_memory()
+                        {
+                            for (map, entries) in in_memory_persistence.stored_entries() {
+                                observer.observe_u64(
+                                    &in_memory_persistence_entries_metric,
+                                    entries,
+                                    [KeyValue::new("map", map)].as_ref(),
+                                );
+                            }
+                        }
+                    },
+                )
+                .context(RegisterMetricsCallbackSnafu)?;
+        }
+
+        // The following metrics need to ask the persistence, so they are only re-calculated once per query counter
+        // refresh interval. Otherwise a tight scrape interval would multiply the load on the persistence.
+        let cache_ttl = config.trino_lb.refresh_query_counter_interval;
+
         // All of this mess can be removed once https://github.com/open-telemetry/opentelemetry-rust/issues/1376 is supported.
-        let (ping_sender, ping_receiver) = tokio::sync::mpsc::unbounded_channel::<()>();
-        let (metrics_sender, metrics_receiver) =
-            tokio::sync::mpsc::unbounded_channel::<HashMap<String, u64>>();
-        let metrics_receiver = RwLock::new(metrics_receiver);
+        let (handler_channel, ping_receiver, metrics_sender) =
+            MetricsHandlerChannel::<HashMap<String, u64>>::new("queued_query_counts");
 
         // This needs to go on a dedicated runtime, as otherwise systems with <= 2 cores will only have only one tokio
         // worker thread and would deadlock.
@@ -137,17 +362,13 @@ impl Metrics {
                 metrics_sender,
                 persistence_clone,
                 &trino_cluster_groups,
+                cache_ttl,
             ))
         });
 
         meter
             .register_callback(&[queued_queries_metric.as_any()], move |observer| {
-                ping_sender.send(()).unwrap();
-                let queued_queries = std::thread::scope(|s| {
-                    s.spawn(|| metrics_receiver.write().unwrap().blocking_recv().unwrap())
-                        .join()
-                        .unwrap()
-                });
+                let queued_queries = handler_channel.request();
 
                 for (cluster_group, queued) in queued_queries {
                     observer.observe_u64(
@@ -160,10 +381,10 @@ impl Metrics {
             .context(RegisterMetricsCallbackSnafu)?;
 
         // All of this mess can be removed once https://github.com/open-telemetry/opentelemetry-rust/issues/1376 is supported.
-        let (ping_sender, ping_receiver) = tokio::sync::mpsc::unbounded_channel::<()>();
-        let (metrics_sender, metrics_receiver) =
-            tokio::sync::mpsc::unbounded_channel::<HashMap<String, HashMap<ClusterState, u64>>>();
-        let metrics_receiver = RwLock::new(metrics_receiver);
+        let (handler_channel, ping_receiver, metrics_sender) =
+            MetricsHandlerChannel::<HashMap<String, HashMap<ClusterState, u64>>>::new(
+                "cluster_counts_per_state",
+            );
 
         // This needs to go on a dedicated runtime, as otherwise systems with <= 2 cores will only have only one tokio
         // worker thread and would deadlock.
@@ -176,6 +397,7 @@ impl Metrics {
                 metrics_sender,
                 persistence_clone,
                 &trino_cluster_groups,
+                cache_ttl,
             ))
         });
 
@@ -183,12 +405,7 @@ impl Metrics {
             .register_callback(
                 &[cluster_counts_per_state_metric.as_any()],
                 move |observer| {
-                    ping_sender.send(()).unwrap();
-                    let cluster_counts = std::thread::scope(|s| {
-                        s.spawn(|| metrics_receiver.write().unwrap().blocking_recv().unwrap())
-                            .join()
-                            .unwrap()
-                    });
+                    let cluster_counts = handler_channel.request();
 
                     for (cluster_group, counts) in cluster_counts {
                         for (state, count) in counts {
@@ -210,24 +427,233 @@ impl Metrics {
         Ok(Self {
             registry,
             http_counter,
+            http_request_duration,
             queued_time,
+            handoff_cluster_utilization,
+            query_immediate_no_next_uri_counter,
+            cluster_counter_underflow_counter,
+            proxy_requests_in_flight,
+            scaler_reconcile_duration,
+            scaler_reconcile_counter,
+            hand_over_retries,
             cluster_infos,
+            scaler_cluster_states,
+            queue_wait_windows,
         })
     }
 }
 
+impl Metrics {
+    /// Counts the HTTP request for the given resource and records its duration once the returned timer is dropped,
+    /// so it needs to be kept alive until the request is processed.
+    pub fn record_http_request(&self, resource: &'static str) -> HttpRequestTimer {
+        self.http_counter
+            .add(1, &[KeyValue::new("resource", resource)]);
+
+        HttpRequestTimer {
+            histogram: self.http_request_duration.clone(),
+            resource,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Records the time a query was queued in trino-lb before it was handed over to a Trino cluster of the given
+    /// cluster group.
+    pub fn record_queue_wait(&self, cluster_group: &str, queued_duration: Duration) {
+        let now = Instant::now();
+        if let Ok(mut queue_wait_windows) = self.queue_wait_windows.write() {
+            match queue_wait_windows.get_mut(cluster_group) {
+                Some(window) => window.record(now, queued_duration),
+                None => {
+                    let mut window = QueueWaitWindow::default();
+                    window.record(now, queued_duration);
+                    queue_wait_windows.insert(cluster_group.to_owned(), window);
+                }
+            }
+        }
+    }
+
+    /// Returns the average time the queries handed over during the last [`QUEUE_WAIT_WINDOW`] were queued in the
+    /// given cluster group, [`None`] in case no query was handed over.
+    pub fn average_queue_wait(&self, cluster_group: &str) -> Option<Duration> {
+        self.queue_wait_windows
+            .read()
+            .ok()?
+            .get(cluster_group)?
+            .average(Instant::now())
+    }
+}
+
+/// The queue durations of the queries handed over during the last [`QUEUE_WAIT_WINDOW`]. At most
+/// [`QUEUE_WAIT_WINDOW_MAX_ENTRIES`] durations are kept, so that the memory usage stays flat even if lots of queries
+/// are handed over.
+#[derive(Debug, Default)]
+struct QueueWaitWindow {
+    /// Ordered by the time the query was handed over, oldest first.
+    entries: VecDeque<(Instant, Duration)>,
+}
+
+impl QueueWaitWindow {
+    fn record(&mut self, now: Instant, queued_duration: Duration) {
+        if self.entries.len() >= QUEUE_WAIT_WINDOW_MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((now, queued_duration));
+
+        while self
+            .entries
+            .front()
+            .is_some_and(|(handed_over, _)| now.duration_since(*handed_over) > QUEUE_WAIT_WINDOW)
+        {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Returns [`None`] in case no query was handed over during the window.
+    fn average(&self, now: Instant) -> Option<Duration> {
+        let (count, total) = self
+            .entries
+            .iter()
+            .filter(|(handed_over, _)| now.duration_since(*handed_over) <= QUEUE_WAIT_WINDOW)
+            .fold(
+                (0u32, Duration::ZERO),
+                |(count, total), (_, queued_duration)| (count + 1, total + *queued_duration),
+            );
+
+        (count > 0).then(|| total / count)
+    }
+}
+
+pub struct HttpRequestTimer {
+    histogram: Histogram<u64>,
+    resource: &'static str,
+    start: Instant,
+}
+
+impl Drop for HttpRequestTimer {
+    fn drop(&mut self) {
+        self.histogram.record(
+            self.start.elapsed().as_millis() as u64,
+            &[KeyValue::new("resource", self.resource)],
+        );
+    }
+}
+
+/// The callback side of the channels to a metrics handler running on a dedicated runtime.
+struct MetricsHandlerChannel<T> {
+    handler: &'static str,
+    ping_sender: UnboundedSender<()>,
+    metrics_receiver: RwLock<UnboundedReceiver<T>>,
+    last_known: Mutex<Option<T>>,
+}
+
+impl<T: Clone + Default + Send> MetricsHandlerChannel<T> {
+    /// Returns the channel together with the ends that need to be passed to the handler.
+    fn new(handler: &'static str) -> (Self, UnboundedReceiver<()>, UnboundedSender<T>) {
+        let (ping_sender, ping_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (metrics_sender, metrics_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let channel = Self {
+            handler,
+            ping_sender,
+            metrics_receiver: RwLock::new(metrics_receiver),
+            last_known: Mutex::new(None),
+        };
+        (channel, ping_receiver, metrics_sender)
+    }
+
+    /// Asks the handler for the current metrics. In case the handler is gone (e.g. because its runtime thread died),
+    /// the last known metrics (or empty ones) are returned, so that the scrape keeps working.
+    fn request(&self) -> T {
+        let received = self
+            .ping_sender
+            .send(())
+            .ok()
+            .and_then(|()| {
+                // `blocking_recv` panics when called from within an async context, so we use a dedicated thread
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        self.metrics_receiver
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .blocking_recv()
+                    })
+                    .join()
+                })
+                .ok()
+            })
+            .flatten();
+
+        let mut last_known = self
+            .last_known
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match received {
+            Some(metrics) => {
+                *last_known = Some(metrics.clone());
+                metrics
+            }
+            None => {
+                error!(
+                    handler = self.handler,
+                    "The metrics handler is not reachable, reporting the last known metrics"
+                );
+                last_known.clone().unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// Remembers the last calculated value of a metric for the given time to live.
+struct MetricsCache<T> {
+    ttl: Duration,
+    cached: Option<(Instant, T)>,
+}
+
+impl<T: Clone> MetricsCache<T> {
+    fn new(ttl: Duration) -> Self {
+        Self { ttl, cached: None }
+    }
+
+    /// Returns the cached value, in case it has not expired yet.
+    fn get(&self) -> Option<T> {
+        self.cached
+            .as_ref()
+            .filter(|(calculated_at, _)| calculated_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn set(&mut self, value: T) {
+        self.cached = Some((Instant::now(), value));
+    }
+}
+
 // Copied from https://github.com/open-telemetry/opentelemetry-rust/issues/1376#issuecomment-1816813128
 async fn queued_query_counts_metrics_handler(
     mut ping_receiver: UnboundedReceiver<()>,
     metrics_sender: UnboundedSender<HashMap<String, u64>>,
     persistence: Arc<PersistenceImplementation>,
     trino_cluster_groups: &HashMap<String, TrinoClusterGroupConfig>,
+    cache_ttl: Duration,
 ) {
+    let mut cache = MetricsCache::new(cache_ttl);
     loop {
         let Some(()) = ping_receiver.recv().await else {
             break;
         };
 
+        if let Some(queued_query_counts) = cache.get() {
+            if let Err(e) = metrics_sender.send(queued_query_counts) {
+                error!(
+                    ?e,
+                    "queued_query_count_metrics_handler: Failed to send to metrics_sender"
+                );
+            }
+            continue;
+        }
+
         let counts = try_join_all(
             trino_cluster_groups
                 .keys()
@@ -253,7 +679,9 @@ async fn queued_query_counts_metrics_handler(
             }
         };
 
-        let queued_query_counts = trino_cluster_groups.keys().cloned().zip(counts).collect();
+        let queued_query_counts: HashMap<_, _> =
+            trino_cluster_groups.keys().cloned().zip(counts).collect();
+        cache.set(queued_query_counts.clone());
 
         if let Err(e) = metrics_sender.send(queued_query_counts) {
             error!(
@@ -269,12 +697,24 @@ async fn cluster_counts_per_state_metrics_handler(
     metrics_sender: UnboundedSender<HashMap<String, HashMap<ClusterState, u64>>>,
     persistence: Arc<PersistenceImplementation>,
     trino_cluster_groups: &HashMap<String, TrinoClusterGroupConfig>,
+    cache_ttl: Duration,
 ) {
-    loop {
+    let mut cache = MetricsCache::new(cache_ttl);
+    'outer: loop {
         let Some(()) = ping_receiver.recv().await else {
             break;
         };
 
+        if let Some(cluster_counts_per_state) = cache.get() {
+            if let Err(e) = metrics_sender.send(cluster_counts_per_state) {
+                error!(
+                    ?e,
+                    "cluster_counts_per_state_metrics_handler: Failed to send to metrics_sender"
+                );
+            }
+            continue;
+        }
+
         let mut cluster_counts_per_state = HashMap::new();
         // TODO: Improve parallelism
         for (cluster_group, clusters) in trino_cluster_groups {
@@ -300,7 +740,8 @@ async fn cluster_counts_per_state_metrics_handler(
                             "cluster_counts_per_state_metrics_handler: Failed to send to metrics_sender"
                         );
                     }
-                    continue;
+                    // Skip the remaining cluster groups, as we must only send a single answer per ping
+                    continue 'outer;
                 }
             };
 
@@ -311,6 +752,7 @@ async fn cluster_counts_per_state_metrics_handler(
 
             cluster_counts_per_state.insert(cluster_group.to_owned(), count_per_state);
         }
+        cache.set(cluster_counts_per_state.clone());
 
         if let Err(e) = metrics_sender.send(cluster_counts_per_state) {
             error!(
@@ -320,3 +762,98 @@ async fn cluster_counts_per_state_metrics_handler(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_cache() {
+        let mut cache = MetricsCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(), None::<u64>);
+
+        cache.set(42);
+        assert_eq!(cache.get(), Some(42));
+
+        cache.set(43);
+        assert_eq!(cache.get(), Some(43));
+    }
+
+    #[test]
+    fn test_metrics_handler_channel() {
+        let (channel, mut ping_receiver, metrics_sender) =
+            MetricsHandlerChannel::<HashMap<String, u64>>::new("test");
+
+        // The handler answers a single request and dies afterwards
+        let handler = std::thread::spawn(move || {
+            ping_receiver.blocking_recv().unwrap();
+            metrics_sender
+                .send(HashMap::from([("s".to_owned(), 42)]))
+                .unwrap();
+        });
+        let expected = HashMap::from([("s".to_owned(), 42)]);
+        assert_eq!(channel.request(), expected);
+        handler.join().unwrap();
+
+        // The channels are closed now, but we should keep reporting the last known metrics
+        assert_eq!(channel.request(), expected);
+        assert_eq!(channel.request(), expected);
+    }
+
+    #[test]
+    fn test_metrics_handler_channel_closed() {
+        let (channel, ping_receiver, metrics_sender) =
+            MetricsHandlerChannel::<HashMap<String, u64>>::new("test");
+        drop(ping_receiver);
+        drop(metrics_sender);
+
+        assert_eq!(channel.request(), HashMap::new());
+    }
+
+    #[test]
+    fn test_queue_wait_window() {
+        let start = Instant::now();
+        let mut window = QueueWaitWindow::default();
+        assert_eq!(window.average(start), None);
+
+        window.record(start, Duration::from_secs(10));
+        window.record(start + Duration::from_secs(60), Duration::from_secs(20));
+        assert_eq!(
+            window.average(start + Duration::from_secs(60)),
+            Some(Duration::from_secs(15))
+        );
+
+        // The first query leaves the window
+        let later = start + QUEUE_WAIT_WINDOW + Duration::from_secs(1);
+        assert_eq!(window.average(later), Some(Duration::from_secs(20)));
+
+        // Both queries left the window
+        let much_later = later + QUEUE_WAIT_WINDOW;
+        assert_eq!(window.average(much_later), None);
+        window.record(much_later, Duration::from_secs(30));
+        assert_eq!(window.entries.len(), 1);
+        assert_eq!(window.average(much_later), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_queue_wait_window_is_bounded() {
+        let now = Instant::now();
+        let mut window = QueueWaitWindow::default();
+        for _ in 0..QUEUE_WAIT_WINDOW_MAX_ENTRIES {
+            window.record(now, Duration::from_secs(1));
+        }
+        for _ in 0..QUEUE_WAIT_WINDOW_MAX_ENTRIES {
+            window.record(now, Duration::from_secs(3));
+        }
+
+        assert_eq!(window.entries.len(), QUEUE_WAIT_WINDOW_MAX_ENTRIES);
+        assert_eq!(window.average(now), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_metrics_cache_expires() {
+        let mut cache = MetricsCache::new(Duration::ZERO);
+        cache.set(42);
+        assert_eq!(cache.get(), None);
+    }
+}
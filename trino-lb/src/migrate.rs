@@ -0,0 +1,350 @@
+use std::{collections::BTreeSet, path::PathBuf};
+
+use clap::Parser;
+use futures::{stream, StreamExt, TryStreamExt};
+use snafu::{ResultExt, Snafu};
+use tracing::{debug, info, instrument};
+use trino_lb_core::{trino_cluster::ClusterState, TrinoClusterName, TrinoLbQueryId, TrinoQueryId};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to list the queued queries of cluster group {cluster_group:?}"))]
+    ListQueuedQueries {
+        source: trino_lb_persistence::Error,
+        cluster_group: String,
+    },
+
+    #[snafu(display("Failed to migrate queued query {queued_query_id:?}"))]
+    MigrateQueuedQuery {
+        source: trino_lb_persistence::Error,
+        queued_query_id: TrinoLbQueryId,
+    },
+
+    #[snafu(display("Failed to list the queries running on Trino"))]
+    ListQueries { source: trino_lb_persistence::Error },
+
+    #[snafu(display("Failed to migrate query {query_id:?}"))]
+    MigrateQuery {
+        source: trino_lb_persistence::Error,
+        query_id: TrinoQueryId,
+    },
+
+    #[snafu(display("Failed to migrate the query count of cluster {cluster:?}"))]
+    MigrateClusterQueryCount {
+        source: trino_lb_persistence::Error,
+        cluster: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to migrate the state of cluster {cluster:?}"))]
+    MigrateClusterState {
+        source: trino_lb_persistence::Error,
+        cluster: TrinoClusterName,
+    },
+
+    #[snafu(display("Failed to migrate the disabled routers"))]
+    MigrateDisabledRouters { source: trino_lb_persistence::Error },
+
+    #[snafu(display("Failed to migrate the clusters excluded from routing"))]
+    MigrateRoutingExcludedClusters { source: trino_lb_persistence::Error },
+
+    #[snafu(display("Failed to migrate the manually managed clusters"))]
+    MigrateManuallyManagedClusters { source: trino_lb_persistence::Error },
+
+    #[snafu(display("Failed to migrate the last query count fetcher update"))]
+    MigrateLastQueryCountFetcherUpdate { source: trino_lb_persistence::Error },
+}
+
+/// Copies the state of trino-lb from one persistence to another, e.g. when switching from Redis to Postgres.
+/// Please stop all trino-lb instances before migrating, as the state must not change during the migration.
+#[derive(Parser, Debug)]
+pub struct MigrateArgs {
+    /// Config file containing the persistence to read the state from.
+    #[arg(long)]
+    pub from: PathBuf,
+
+    /// Config file containing the persistence to write the state to.
+    #[arg(long)]
+    pub to: PathBuf,
+
+    /// Number of queued queries and queries running on Trino that are migrated concurrently.
+    #[arg(long, default_value_t = 100)]
+    pub batch_size: usize,
+}
+
+/// Migrates all queued queries of the given cluster groups, the queries running on Trino, the query counts and states of
+/// the given clusters as well as the disabled routers, clusters excluded from routing and manually managed clusters from
+/// `source` to `destination`.
+#[instrument(skip(source, destination))]
+pub async fn migrate(
+    source: &PersistenceImplementation,
+    destination: &PersistenceImplementation,
+    cluster_groups: &BTreeSet<String>,
+    clusters: &BTreeSet<TrinoClusterName>,
+    batch_size: usize,
+) -> Result<(), Error> {
+    for cluster_group in cluster_groups {
+        let queued_query_ids = source
+            .list_queued_query_ids(cluster_group)
+            .await
+            .context(ListQueuedQueriesSnafu { cluster_group })?;
+        let migrated = queued_query_ids.len();
+
+        // Only the ids are held in memory, the queued queries themselves are streamed over in batches.
+        stream::iter(queued_query_ids)
+            .map(|queued_query_id| async move {
+                let Some(queued_query) = source.load_queued_query(&queued_query_id).await.context(
+                    MigrateQueuedQuerySnafu {
+                        queued_query_id: &queued_query_id,
+                    },
+                )?
+                else {
+                    debug!(queued_query_id, "Queued query was removed in the meantime");
+                    return Ok(());
+                };
+                destination
+                    .store_queued_query(queued_query)
+                    .await
+                    .context(MigrateQueuedQuerySnafu { queued_query_id })
+            })
+            .buffer_unordered(batch_size.max(1))
+            .try_collect::<()>()
+            .await?;
+
+        info!(cluster_group, migrated, "Migrated queued queries");
+    }
+
+    let query_ids = source.list_query_ids().await.context(ListQueriesSnafu)?;
+    let migrated = query_ids.len();
+    stream::iter(query_ids)
+        .map(|query_id| async move {
+            let Some(query) = source
+                .load_query(&query_id)
+                .await
+                .context(MigrateQuerySnafu {
+                    query_id: &query_id,
+                })?
+            else {
+                debug!(query_id, "Query was removed in the meantime");
+                return Ok(());
+            };
+            destination
+                .store_query(query)
+                .await
+                .context(MigrateQuerySnafu { query_id })
+        })
+        .buffer_unordered(batch_size.max(1))
+        .try_collect::<()>()
+        .await?;
+    info!(migrated, "Migrated queries running on Trino");
+
+    for cluster in clusters {
+        let query_count = source
+            .get_cluster_query_count(cluster)
+            .await
+            .context(MigrateClusterQueryCountSnafu { cluster })?;
+        destination
+            .set_cluster_query_count(cluster, query_count)
+            .await
+            .context(MigrateClusterQueryCountSnafu { cluster })?;
+        let blocked_query_count = source
+            .get_cluster_blocked_query_count(cluster)
+            .await
+            .context(MigrateClusterQueryCountSnafu { cluster })?;
+        destination
+            .set_cluster_blocked_query_count(cluster, blocked_query_count)
+            .await
+            .context(MigrateClusterQueryCountSnafu { cluster })?;
+
+        let state = source
+            .get_cluster_state(cluster)
+            .await
+            .context(MigrateClusterStateSnafu { cluster })?;
+        // Unknown is the default in case no state was stored, so there is nothing to migrate.
+        if state != ClusterState::Unknown {
+            destination
+                .set_cluster_state(cluster, state.clone())
+                .await
+                .context(MigrateClusterStateSnafu { cluster })?;
+        }
+
+        info!(
+            cluster,
+            query_count,
+            blocked_query_count,
+            ?state,
+            "Migrated cluster"
+        );
+    }
+
+    let disabled_routers = source
+        .list_disabled_routers()
+        .await
+        .context(MigrateDisabledRoutersSnafu)?;
+    for router_index in &disabled_routers {
+        destination
+            .set_router_disabled(*router_index, true)
+            .await
+            .context(MigrateDisabledRoutersSnafu)?;
+    }
+    info!(?disabled_routers, "Migrated disabled routers");
+
+    let routing_excluded_clusters = source
+        .list_routing_excluded_clusters()
+        .await
+        .context(MigrateRoutingExcludedClustersSnafu)?;
+    for cluster in &routing_excluded_clusters {
+        destination
+            .set_cluster_routing_excluded(cluster, true)
+            .await
+            .context(MigrateRoutingExcludedClustersSnafu)?;
+    }
+    info!(
+        ?routing_excluded_clusters,
+        "Migrated clusters excluded from routing"
+    );
+
+    let manually_managed_clusters = source
+        .list_manually_managed_clusters()
+        .await
+        .context(MigrateManuallyManagedClustersSnafu)?;
+    for cluster in &manually_managed_clusters {
+        destination
+            .set_cluster_manually_managed(cluster, true)
+            .await
+            .context(MigrateManuallyManagedClustersSnafu)?;
+    }
+    info!(
+        ?manually_managed_clusters,
+        "Migrated manually managed clusters"
+    );
+
+    let last_update = source
+        .get_last_query_count_fetcher_update()
+        .await
+        .context(MigrateLastQueryCountFetcherUpdateSnafu)?;
+    destination
+        .set_last_query_count_fetcher_update(last_update)
+        .await
+        .context(MigrateLastQueryCountFetcherUpdateSnafu)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use trino_lb_core::trino_query::{QueuedQuery, TrinoQuery};
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let source: PersistenceImplementation = InMemoryPersistence::default().into();
+        let destination: PersistenceImplementation = InMemoryPersistence::default().into();
+
+        let mut queued_query_ids = Vec::new();
+        for i in 0..5 {
+            let cluster_group = if i % 2 == 0 { "s" } else { "m" };
+            let queued_query = QueuedQuery::new_from(
+                format!("select {i}"),
+                http::HeaderMap::new(),
+                cluster_group.to_owned(),
+                None,
+            );
+            queued_query_ids.push(queued_query.id.clone());
+            source.store_queued_query(queued_query).await.unwrap();
+        }
+        let cluster = "trino-s-1".to_owned();
+        let query = TrinoQuery::new_from(
+            cluster.clone(),
+            "20240101_120000_00001_abcde".to_owned(),
+            "https://trino-s-1:8443".parse().unwrap(),
+            SystemTime::now(),
+            SystemTime::now(),
+            None,
+            Some("/v1/statement/executing/x/y/1".to_owned()),
+        );
+        source.store_query(query.clone()).await.unwrap();
+        source.set_cluster_query_count(&cluster, 7).await.unwrap();
+        source
+            .set_cluster_blocked_query_count(&cluster, 2)
+            .await
+            .unwrap();
+        source
+            .set_cluster_state(&cluster, ClusterState::Ready)
+            .await
+            .unwrap();
+        source.set_router_disabled(1, true).await.unwrap();
+        source
+            .set_cluster_routing_excluded(&cluster, true)
+            .await
+            .unwrap();
+        source
+            .set_cluster_manually_managed(&cluster, true)
+            .await
+            .unwrap();
+        let last_update = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        source
+            .set_last_query_count_fetcher_update(last_update)
+            .await
+            .unwrap();
+
+        migrate(
+            &source,
+            &destination,
+            &["s".to_owned(), "m".to_owned()].into(),
+            &[cluster.clone()].into(),
+            2,
+        )
+        .await
+        .unwrap();
+
+        for queued_query_id in &queued_query_ids {
+            let queued_query = destination
+                .load_queued_query(queued_query_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&queued_query.id, queued_query_id);
+        }
+        assert_eq!(destination.get_queued_query_count("s").await.unwrap(), 3);
+        assert_eq!(destination.get_queued_query_count("m").await.unwrap(), 2);
+        let migrated_query = destination.load_query(&query.id).await.unwrap().unwrap();
+        assert_eq!(migrated_query.trino_cluster, cluster);
+        assert_eq!(migrated_query.next_uri_path, query.next_uri_path);
+        assert_eq!(
+            destination.get_cluster_query_count(&cluster).await.unwrap(),
+            7
+        );
+        assert_eq!(
+            destination
+                .get_cluster_blocked_query_count(&cluster)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            destination.get_cluster_state(&cluster).await.unwrap(),
+            ClusterState::Ready
+        );
+        assert_eq!(destination.list_disabled_routers().await.unwrap(), [1]);
+        assert_eq!(
+            destination.list_routing_excluded_clusters().await.unwrap(),
+            [cluster.clone()]
+        );
+        assert_eq!(
+            destination.list_manually_managed_clusters().await.unwrap(),
+            [cluster.clone()]
+        );
+        assert_eq!(
+            destination
+                .get_last_query_count_fetcher_update()
+                .await
+                .unwrap(),
+            last_update
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::{sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use futures::future::BoxFuture;
+use tokio::time;
+use tracing::{debug, warn};
+use trino_lb_persistence::PersistenceImplementation;
+
+/// How often the [`PersistedStateCache`] is refreshed, which is how long it takes at most until changes made on other
+/// trino-lb replicas are picked up.
+pub const PERSISTED_STATE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+type Loader<T> = for<'a> fn(
+    &'a PersistenceImplementation,
+) -> BoxFuture<'a, Result<T, trino_lb_persistence::Error>>;
+
+/// Caches state that rarely changes (e.g. via the admin API), but is needed for every query, such as the disabled
+/// routers. This way routing does not need to read the persistence for every query.
+///
+/// The state is refreshed periodically by [`PersistedStateCache::start_refresh_loop`], so that changes made on other
+/// trino-lb replicas are picked up. The replica making a change should call [`PersistedStateCache::refresh`], so that
+/// the change applies to it right away.
+pub struct PersistedStateCache<T> {
+    /// Used in log messages.
+    name: &'static str,
+    persistence: Arc<PersistenceImplementation>,
+    load: Loader<T>,
+    value: ArcSwap<T>,
+}
+
+impl<T: Default + Send + Sync + 'static> PersistedStateCache<T> {
+    /// The cache starts out with the default value until it is refreshed for the first time.
+    pub fn new(
+        name: &'static str,
+        persistence: Arc<PersistenceImplementation>,
+        load: Loader<T>,
+    ) -> Self {
+        Self {
+            name,
+            persistence,
+            load,
+            value: ArcSwap::from_pointee(T::default()),
+        }
+    }
+
+    /// Returns the value as of the last successful refresh.
+    pub fn get(&self) -> Arc<T> {
+        self.value.load_full()
+    }
+
+    /// Reads the current value from the persistence. In case that fails, the previous value is kept.
+    pub async fn refresh(&self) -> Result<(), trino_lb_persistence::Error> {
+        let value = (self.load)(&self.persistence).await?;
+        self.value.store(Arc::new(value));
+
+        Ok(())
+    }
+
+    pub fn start_refresh_loop(self: &Arc<Self>) {
+        let me = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = time::interval(PERSISTED_STATE_REFRESH_INTERVAL);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                // First tick does not sleep, so let's put it at the start of the loop.
+                interval.tick().await;
+
+                match me.refresh().await {
+                    Ok(()) => debug!(name = me.name, "Refreshed cached state"),
+                    Err(error) => warn!(
+                        ?error,
+                        name = me.name,
+                        "Failed to refresh cached state, keeping the previous state"
+                    ),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trino_lb_persistence::{in_memory::InMemoryPersistence, Persistence};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let cache = PersistedStateCache::new("disabled routers", Arc::clone(&persistence), |p| {
+            Box::pin(p.list_disabled_routers())
+        });
+
+        persistence.set_router_disabled(1, true).await.unwrap();
+        assert!(cache.get().is_empty());
+
+        cache.refresh().await.unwrap();
+        assert_eq!(*cache.get(), [1]);
+    }
+}
@@ -1,17 +1,15 @@
 use std::collections::HashSet;
 
-use http::HeaderValue;
 use snafu::Snafu;
 use tracing::{instrument, warn};
 use trino_lb_core::{
+    client_tags::ClientTags,
     config::{ClientTagsRouterConfig, TagMatchingStrategy},
     sanitization::Sanitize,
 };
 
 use crate::routing::RouterImplementationTrait;
 
-const TRINO_CLIENT_TAGS_HEADER: &str = "x-trino-client-tags";
-
 #[derive(Snafu, Debug)]
 pub enum Error {
     #[snafu(display(
@@ -50,29 +48,21 @@ impl RouterImplementationTrait for ClientTagsRouter {
         fields(headers = ?headers.sanitize()),
     )]
     async fn route(&self, query: &str, headers: &http::HeaderMap) -> Option<String> {
-        if let Some(Ok(client_tags)) = headers
-            .get(TRINO_CLIENT_TAGS_HEADER)
-            .map(HeaderValue::to_str)
-        {
-            let client_tags = client_tags
-                .split(',')
-                .map(String::from)
-                .collect::<HashSet<_>>();
-            match &self.config.tag_matching_strategy {
-                TagMatchingStrategy::OneOf(one_of) => {
-                    if !one_of.is_disjoint(&client_tags) {
-                        return Some(self.config.trino_cluster_group.clone());
-                    }
-                }
-                TagMatchingStrategy::AllOf(all_of) => {
-                    if all_of.is_subset(&client_tags) {
-                        return Some(self.config.trino_cluster_group.clone());
-                    }
-                }
-            }
+        let client_tags = ClientTags::from_headers(headers);
+        if client_tags.is_empty() {
+            return None;
         }
 
-        None
+        let matches = match &self.config.tag_matching_strategy {
+            TagMatchingStrategy::OneOf(one_of) => {
+                one_of.iter().any(|tag| client_tags.contains(tag))
+            }
+            TagMatchingStrategy::AllOf(all_of) => {
+                all_of.iter().all(|tag| client_tags.contains(tag))
+            }
+        };
+
+        matches.then(|| self.config.trino_cluster_group.clone())
     }
 }
 
@@ -92,6 +82,8 @@ mod tests {
     #[case(Some("foo,bar,bak,system=airflow"), Some("my-target"))]
     #[case(Some("bak,foo,bar,system=airflow"), Some("my-target"))]
     #[case(Some("foo,bar,bak,something-else"), Some("my-target"))]
+    #[case(Some(" something-else , bar "), Some("my-target"))]
+    #[case(Some("system=,=airflow,system"), None)]
     #[tokio::test]
     async fn test_routing_with_one_of(
         #[case] x_trino_client_tags: Option<&str>,
@@ -129,6 +121,8 @@ mod tests {
     #[case(Some("foo,bar,bak,system=airflow"), Some("my-target"))]
     #[case(Some("bak,foo,bar,system=airflow"), Some("my-target"))]
     #[case(Some("foo,bar,bak,system=airflow,something-else"), Some("my-target"))]
+    #[case(Some("foo,,bar, bak ,system=airflow,foo"), Some("my-target"))]
+    #[case(Some("foo,bar,bak,system=airflow=x"), None)]
     #[tokio::test]
     async fn test_routing_with_all_of(
         #[case] x_trino_client_tags: Option<&str>,
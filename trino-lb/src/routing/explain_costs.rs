@@ -1,8 +1,12 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use snafu::{ResultExt, Snafu};
-use tracing::{instrument, warn};
-use trino_lb_core::sanitization::Sanitize;
+use tokio::sync::{Semaphore, SemaphorePermit, TryAcquireError};
+use tracing::{debug, instrument, warn};
+use trino_lb_core::{
+    query_runtime::query_fingerprint, sanitization::Sanitize, trino_query_plan::QueryPlanEstimation,
+};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
 
 use crate::{
     config::{ExplainCostTargetConfig, ExplainCostsRouterConfig},
@@ -24,13 +28,20 @@ pub enum Error {
 pub struct ExplainCostsRouter {
     config: ExplainCostsRouterConfig,
     trino_client: TrinoClient,
+
+    /// Limits the number of concurrent `explain` queries, only set in case `maxConcurrentExplains` is configured.
+    explain_permits: Option<Semaphore>,
+
+    /// Only set in case the (experimental) query runtime feedback is enabled.
+    query_runtimes: Option<Arc<PersistenceImplementation>>,
 }
 
 impl ExplainCostsRouter {
-    #[instrument(name = "ExplainCostsRouter::new")]
+    #[instrument(name = "ExplainCostsRouter::new", skip(query_runtimes))]
     pub fn new(
         config: &ExplainCostsRouterConfig,
         valid_target_groups: HashSet<String>,
+        query_runtimes: Option<Arc<PersistenceImplementation>>,
     ) -> Result<Self, Error> {
         for ExplainCostTargetConfig {
             trino_cluster_group,
@@ -51,8 +62,38 @@ impl ExplainCostsRouter {
         Ok(Self {
             config: config.clone(),
             trino_client,
+            explain_permits: config.max_concurrent_explains.map(Semaphore::new),
+            query_runtimes,
         })
     }
+
+    /// We don't wait for a permit, as routing should not take longer than the explain query itself. Returns `None` in
+    /// case the number of concurrent explain queries is not limited.
+    fn try_acquire_explain_permit(&self) -> Result<Option<SemaphorePermit<'_>>, TryAcquireError> {
+        self.explain_permits
+            .as_ref()
+            .map(Semaphore::try_acquire)
+            .transpose()
+    }
+
+    /// Returns the runtime observed for previous runs of the given query (if any).
+    async fn observed_runtime(&self, query: &str) -> Option<Duration> {
+        let query_runtimes = self.query_runtimes.as_ref()?;
+
+        match query_runtimes
+            .load_query_runtime(&query_fingerprint(query))
+            .await
+        {
+            Ok(observed_runtime) => observed_runtime,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "Failed to load observed query runtime, only using the query estimation"
+                );
+                None
+            }
+        }
+    }
 }
 
 impl RouterImplementationTrait for ExplainCostsRouter {
@@ -62,21 +103,38 @@ impl RouterImplementationTrait for ExplainCostsRouter {
         fields(headers = ?headers.sanitize()),
     )]
     async fn route(&self, query: &str, headers: &http::HeaderMap) -> Option<String> {
-        let query_estimation = match self.trino_client.query_estimation(query, headers).await {
+        let Ok(_explain_permit) = self.try_acquire_explain_permit() else {
+            warn!(
+                max_concurrent_explains = self.config.max_concurrent_explains,
+                "Too many concurrent query estimations, skipped routing"
+            );
+            return None;
+        };
+
+        let query_estimation = match self
+            .trino_client
+            .query_estimation(query, headers, self.config.explain_timeout)
+            .await
+        {
             Ok(query_estimation) => query_estimation,
+            Err(error @ trino_client::Error::ExplainQueryTimeout { .. }) => {
+                warn!(query, ?error, "Query estimation timed out, skipped routing");
+                return None;
+            }
             Err(error) => {
                 warn!(query, ?error, "Query estimation failed, skipped routing");
                 return None;
             }
         };
 
-        for ExplainCostTargetConfig {
-            cluster_max_query_plan_estimation,
-            trino_cluster_group,
-        } in &self.config.targets
-        {
-            if query_estimation.smaller_in_all_measurements(cluster_max_query_plan_estimation) {
-                return Some(trino_cluster_group.clone());
+        let observed_runtime = self.observed_runtime(query).await;
+        if let Some(observed_runtime) = observed_runtime {
+            debug!(?observed_runtime, "Found observed runtime for query");
+        }
+
+        for target in &self.config.targets {
+            if target_accepts_query(target, &query_estimation, observed_runtime) {
+                return Some(target.trino_cluster_group.clone());
             }
         }
 
@@ -88,3 +146,152 @@ impl RouterImplementationTrait for ExplainCostsRouter {
         None
     }
 }
+
+/// Observed runtimes are preferred over the query estimation, as the estimations are often quite off. In case the
+/// runtime was not observed yet or the target does not specify a `maxObservedRuntime`, we fall back to the estimation.
+fn target_accepts_query(
+    target: &ExplainCostTargetConfig,
+    query_estimation: &QueryPlanEstimation,
+    observed_runtime: Option<Duration>,
+) -> bool {
+    match (observed_runtime, target.max_observed_runtime) {
+        (Some(observed_runtime), Some(max_observed_runtime)) => {
+            observed_runtime <= max_observed_runtime
+        }
+        _ => {
+            query_estimation.smaller_in_all_measurements(&target.cluster_max_query_plan_estimation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use tokio::{net::TcpListener, time::Instant};
+
+    use super::*;
+    use crate::config::TrinoClientConfig;
+
+    fn estimation(cost: f32) -> QueryPlanEstimation {
+        QueryPlanEstimation {
+            output_row_count: cost,
+            output_size_in_bytes: cost,
+            cpu_cost: cost,
+            memory_cost: cost,
+            network_cost: cost,
+        }
+    }
+
+    #[rstest]
+    // No observed runtime, only the estimation counts
+    #[case(10.0, None, Some(60), true)]
+    #[case(1000.0, None, Some(60), false)]
+    // The observed runtime wins over the estimation
+    #[case(1000.0, Some(30), Some(60), true)]
+    #[case(10.0, Some(120), Some(60), false)]
+    #[case(10.0, Some(60), Some(60), true)]
+    // Targets without maxObservedRuntime only look at the estimation
+    #[case(10.0, Some(120), None, true)]
+    #[case(1000.0, Some(30), None, false)]
+    fn test_target_accepts_query(
+        #[case] estimated_cost: f32,
+        #[case] observed_runtime_secs: Option<u64>,
+        #[case] max_observed_runtime_secs: Option<u64>,
+        #[case] expected: bool,
+    ) {
+        let target = ExplainCostTargetConfig {
+            cluster_max_query_plan_estimation: estimation(100.0),
+            trino_cluster_group: "s".to_owned(),
+            max_observed_runtime: max_observed_runtime_secs.map(Duration::from_secs),
+        };
+
+        assert_eq!(
+            target_accepts_query(
+                &target,
+                &estimation(estimated_cost),
+                observed_runtime_secs.map(Duration::from_secs)
+            ),
+            expected
+        );
+    }
+
+    fn slow_trino_config(slow_trino: &TcpListener) -> ExplainCostsRouterConfig {
+        ExplainCostsRouterConfig {
+            trino_cluster_to_run_explain_query: TrinoClientConfig {
+                endpoint: format!("http://{}", slow_trino.local_addr().unwrap())
+                    .parse()
+                    .unwrap(),
+                ignore_cert: false,
+                username: "admin".to_owned(),
+                password: "admin".to_owned(),
+            },
+            targets: vec![ExplainCostTargetConfig {
+                cluster_max_query_plan_estimation: estimation(100.0),
+                trino_cluster_group: "s".to_owned(),
+                max_observed_runtime: None,
+            }],
+            explain_timeout: Duration::from_millis(200),
+            max_concurrent_explains: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_explain_timeout() {
+        // A Trino coordinator that accepts connections, but never answers
+        let slow_trino = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = slow_trino_config(&slow_trino);
+        let router = ExplainCostsRouter::new(&config, HashSet::from(["s".to_owned()]), None)
+            .expect("Failed to create ExplainCostsRouter");
+
+        let start = Instant::now();
+        let target_group = router
+            .route("select * from t", &http::HeaderMap::new())
+            .await;
+
+        assert_eq!(target_group, None);
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "The router did not abstain in time"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_explains() {
+        let slow_trino = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ExplainCostsRouterConfig {
+            max_concurrent_explains: Some(2),
+            ..slow_trino_config(&slow_trino)
+        };
+        let router = ExplainCostsRouter::new(&config, HashSet::from(["s".to_owned()]), None)
+            .expect("Failed to create ExplainCostsRouter");
+
+        let first = router.try_acquire_explain_permit().unwrap();
+        let second = router.try_acquire_explain_permit().unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert!(router.try_acquire_explain_permit().is_err());
+
+        // With all permits in use the router abstains instead of waiting for the slow Trino
+        let start = Instant::now();
+        let target_group = router
+            .route("select * from t", &http::HeaderMap::new())
+            .await;
+        assert_eq!(target_group, None);
+        assert!(start.elapsed() < config.explain_timeout);
+
+        drop(first);
+        assert!(router.try_acquire_explain_permit().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_concurrent_explains() {
+        let slow_trino = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let router = ExplainCostsRouter::new(
+            &slow_trino_config(&slow_trino),
+            HashSet::from(["s".to_owned()]),
+            None,
+        )
+        .expect("Failed to create ExplainCostsRouter");
+
+        assert!(router.try_acquire_explain_permit().unwrap().is_none());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use futures::future::try_join_all;
+use snafu::Snafu;
+use tracing::{debug, instrument, warn};
+use trino_lb_core::{
+    config::{LoadAwareRouterConfig, TrinoClusterGroupConfig},
+    sanitization::Sanitize,
+    TrinoClusterName,
+};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
+
+use crate::{cluster_group_manager::QueryCounters, routing::RouterImplementationTrait};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display(
+        "Configuration error: The LoadAwareRouter needs at least one trinoClusterGroup"
+    ))]
+    NoTargetClusterGroups {},
+}
+
+pub struct LoadAwareRouter {
+    /// The candidate cluster groups (in the configured order) together with the query counters of the clusters they
+    /// consist of (see [`QueryCounters`]). Clusters sharing a query counter are only listed once.
+    candidates: Vec<(String, Vec<TrinoClusterName>)>,
+    persistence: Arc<PersistenceImplementation>,
+}
+
+impl LoadAwareRouter {
+    /// The caller needs to make sure that all configured target cluster groups exist, which [`super::Router::new`] does.
+    #[instrument(
+        name = "LoadAwareRouter::new",
+        skip(cluster_groups, query_counters, persistence)
+    )]
+    pub fn new(
+        config: &LoadAwareRouterConfig,
+        cluster_groups: &HashMap<String, TrinoClusterGroupConfig>,
+        query_counters: &QueryCounters,
+        persistence: Arc<PersistenceImplementation>,
+    ) -> Result<Self, Error> {
+        if config.trino_cluster_groups.is_empty() {
+            NoTargetClusterGroupsSnafu.fail()?;
+        }
+
+        let candidates = config
+            .trino_cluster_groups
+            .iter()
+            .map(|cluster_group| {
+                let clusters = cluster_groups
+                    .get(cluster_group)
+                    .expect("the target cluster groups are checked to exist by Router::new")
+                    .trino_clusters
+                    .iter()
+                    .map(|cluster| query_counters.query_counter_of(&cluster.name).clone())
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect();
+
+                (cluster_group.clone(), clusters)
+            })
+            .collect();
+
+        Ok(Self {
+            candidates,
+            persistence,
+        })
+    }
+
+    /// The load of a cluster group is the number of queries queued in trino-lb plus the number of queries running on
+    /// all clusters of the group.
+    #[instrument(skip(self))]
+    async fn cluster_group_load(
+        &self,
+        cluster_group: &str,
+        clusters: &[TrinoClusterName],
+    ) -> Result<u64, trino_lb_persistence::Error> {
+        let (queued, running) = tokio::try_join!(
+            self.persistence.get_queued_query_count(cluster_group),
+            try_join_all(
+                clusters
+                    .iter()
+                    .map(|cluster| self.persistence.get_cluster_query_count(cluster))
+            ),
+        )?;
+
+        Ok(queued + running.iter().sum::<u64>())
+    }
+}
+
+impl RouterImplementationTrait for LoadAwareRouter {
+    #[instrument(
+        name = "LoadAwareRouter::route"
+        skip(self),
+        fields(headers = ?headers.sanitize()),
+    )]
+    async fn route(&self, query: &str, headers: &http::HeaderMap) -> Option<String> {
+        let loads = match try_join_all(
+            self.candidates
+                .iter()
+                .map(|(cluster_group, clusters)| self.cluster_group_load(cluster_group, clusters)),
+        )
+        .await
+        {
+            Ok(loads) => loads,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "Failed to determine the load of the cluster groups, skipped routing"
+                );
+                return None;
+            }
+        };
+        debug!(?loads, "Determined load of the candidate cluster groups");
+
+        // `min_by_key` returns the first element in case multiple have the same load, which keeps the configured order
+        // as tie breaker.
+        self.candidates
+            .iter()
+            .zip(loads)
+            .min_by_key(|(_, load)| *load)
+            .map(|((cluster_group, _), _)| cluster_group.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trino_lb_core::trino_query::QueuedQuery;
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    fn cluster_groups() -> HashMap<String, TrinoClusterGroupConfig> {
+        TestConfigBuilder::new()
+            .cluster_group(
+                "s",
+                10,
+                &[
+                    ("trino-s-1", "https://trino-s-1:8443"),
+                    ("trino-s-2", "https://trino-s-2:8443"),
+                ],
+            )
+            .cluster_group(
+                "m",
+                10,
+                &[
+                    ("trino-m-1", "https://trino-m-1:8443"),
+                    ("trino-m-2", "https://trino-m-2:8443"),
+                ],
+            )
+            .build()
+            .trino_cluster_groups
+    }
+
+    fn router(persistence: &Arc<PersistenceImplementation>) -> LoadAwareRouter {
+        let config = LoadAwareRouterConfig {
+            trino_cluster_groups: vec!["s".to_owned(), "m".to_owned()],
+        };
+
+        LoadAwareRouter::new(
+            &config,
+            &cluster_groups(),
+            &QueryCounters::default(),
+            Arc::clone(persistence),
+        )
+        .unwrap()
+    }
+
+    async fn route(router: &LoadAwareRouter) -> Option<String> {
+        router.route("select 42", &http::HeaderMap::new()).await
+    }
+
+    #[tokio::test]
+    async fn test_routes_to_least_loaded_group() {
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let router = router(&persistence);
+
+        // Both groups are idle, so the first one wins
+        assert_eq!(route(&router).await.as_deref(), Some("s"));
+
+        persistence
+            .set_cluster_query_count(&"trino-s-1".to_owned(), 3)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_query_count(&"trino-m-2".to_owned(), 2)
+            .await
+            .unwrap();
+        assert_eq!(route(&router).await.as_deref(), Some("m"));
+
+        // Queued queries count towards the load as well
+        for _ in 0..2 {
+            persistence
+                .store_queued_query(QueuedQuery::new_from(
+                    "select 42".to_owned(),
+                    http::HeaderMap::new(),
+                    "m".to_owned(),
+                    None,
+                ))
+                .await
+                .unwrap();
+        }
+        assert_eq!(route(&router).await.as_deref(), Some("s"));
+    }
+
+    #[tokio::test]
+    async fn test_shared_query_counters() {
+        // trino-s-2 and trino-m-1 are the same coordinator, whose queries are counted in the query counter of trino-m-1
+        let config = TestConfigBuilder::new()
+            .trino_lb("shareQueryCountersOfSameEndpoint: true")
+            .cluster_group(
+                "s",
+                10,
+                &[
+                    ("trino-s-1", "https://trino-s-1:8443"),
+                    ("trino-s-2", "https://trino-shared:8443"),
+                ],
+            )
+            .cluster_group(
+                "m",
+                10,
+                &[
+                    ("trino-m-1", "https://trino-shared:8443"),
+                    ("trino-m-2", "https://trino-m-2:8443"),
+                ],
+            )
+            .build();
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let router = LoadAwareRouter::new(
+            &LoadAwareRouterConfig {
+                trino_cluster_groups: vec!["s".to_owned(), "m".to_owned()],
+            },
+            &config.trino_cluster_groups,
+            &QueryCounters::new(&config),
+            Arc::clone(&persistence),
+        )
+        .unwrap();
+
+        persistence
+            .set_cluster_query_count(&"trino-s-1".to_owned(), 1)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_query_count(&"trino-m-1".to_owned(), 3)
+            .await
+            .unwrap();
+
+        // The shared coordinator counts towards the load of both groups
+        assert_eq!(route(&router).await.as_deref(), Some("m"));
+    }
+}
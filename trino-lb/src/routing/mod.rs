@@ -1,18 +1,30 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use arc_swap::ArcSwap;
 use enum_dispatch::enum_dispatch;
 use snafu::{ResultExt, Snafu};
-use tracing::instrument;
-use trino_lb_core::sanitization::Sanitize;
+use tracing::{debug, info, instrument};
+use trino_lb_core::{config, sanitization::Sanitize};
+use trino_lb_persistence::{Persistence, PersistenceImplementation};
 
-use crate::config::{Config, RoutingConfig};
+use crate::{
+    cluster_group_manager::QueryCounters,
+    config::{Config, RoutingConfig},
+    persisted_state_cache::PersistedStateCache,
+};
 
 mod client_tags;
 mod explain_costs;
+mod load_aware;
 mod python_script;
+mod statement_type;
 mod trino_routing_group_header;
 
 pub use client_tags::ClientTagsRouter;
 pub use explain_costs::ExplainCostsRouter;
+pub use load_aware::LoadAwareRouter;
 pub use python_script::PythonScriptRouter;
+pub use statement_type::StatementTypeRouter;
 pub use trino_routing_group_header::TrinoRoutingGroupHeaderRouter;
 
 #[derive(Snafu, Debug)]
@@ -26,6 +38,9 @@ pub enum Error {
     #[snafu(display("Failed to create client tags router"))]
     CreateClientTagsRouter { source: client_tags::Error },
 
+    #[snafu(display("Failed to create load aware router"))]
+    CreateLoadAwareRouter { source: load_aware::Error },
+
     #[snafu(display("Configuration error: The router {router:?} is configured to route to trinoClusterGroup {trino_cluster_group:?} which does not exist"))]
     ConfigErrorClusterGroupDoesNotExist {
         router: String,
@@ -34,16 +49,50 @@ pub enum Error {
 
     #[snafu(display("Configuration error: The routingFallback is configured to route to trinoClusterGroup {routing_fallback:?} which does not exist"))]
     ConfigErrorRoutingFallbackDoesNotExist { routing_fallback: String },
+
+    #[snafu(display("Configuration error: The routingFallbackBySource is configured to route queries with source {client_source:?} to trinoClusterGroup {trino_cluster_group:?} which does not exist"))]
+    ConfigErrorRoutingFallbackBySourceDoesNotExist {
+        client_source: String,
+        trino_cluster_group: String,
+    },
+}
+
+#[derive(Snafu, Debug)]
+pub enum ReloadError {
+    #[snafu(display("Failed to read configuration file at {config_file:?}"))]
+    ReadConfig {
+        source: config::Error,
+        config_file: PathBuf,
+    },
+
+    #[snafu(display(
+        "Failed to create router from the new configuration, keeping the current router"
+    ))]
+    CreateRouter { source: Error },
 }
 
 pub struct Router {
     routers: Vec<RoutingImplementation>,
     routing_fallback: String,
+    routing_fallback_by_source: HashMap<String, String>,
+}
+
+/// The cluster group a query should run on, together with the reason why it was chosen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoutingDecision {
+    pub cluster_group: String,
+
+    /// Human-readable reason, e.g. `ClientTagsRouter at routers[1]`, `routingFallbackBySource[airflow]` or
+    /// `routingFallback`.
+    pub reason: String,
 }
 
 impl Router {
-    #[instrument]
-    pub fn new(config: &Config) -> Result<Self, Error> {
+    #[instrument(skip(persistence))]
+    pub fn new(
+        config: &Config,
+        persistence: Arc<PersistenceImplementation>,
+    ) -> Result<Self, Error> {
         let mut routers = Vec::with_capacity(config.routers.len());
         let cluster_groups = &config.trino_cluster_groups.keys().collect::<Vec<_>>();
 
@@ -53,9 +102,17 @@ impl Router {
                     let targets = router_config.targets.iter().map(|t| &t.trino_cluster_group);
                     check_every_target_group_exists(targets, cluster_groups, "ExplainCostsRouter")?;
 
+                    // Observed query runtimes are only recorded if the feedback loop is enabled
+                    let query_runtimes = config
+                        .trino_lb
+                        .query_runtime_feedback
+                        .as_ref()
+                        .map(|_| Arc::clone(&persistence));
+
                     ExplainCostsRouter::new(
                         router_config,
                         config.trino_cluster_groups.keys().cloned().collect(),
+                        query_runtimes,
                     )
                     .context(CreateExplainCostsRouterSnafu)?
                     .into()
@@ -79,6 +136,37 @@ impl Router {
                 )
                 .context(CreateClientTagsRouterSnafu)?
                 .into(),
+                RoutingConfig::LoadAware(router_config) => {
+                    check_every_target_group_exists(
+                        router_config.trino_cluster_groups.iter(),
+                        cluster_groups,
+                        "LoadAwareRouter",
+                    )?;
+
+                    LoadAwareRouter::new(
+                        router_config,
+                        &config.trino_cluster_groups,
+                        &QueryCounters::new(config),
+                        Arc::clone(&persistence),
+                    )
+                    .context(CreateLoadAwareRouterSnafu)?
+                    .into()
+                }
+                RoutingConfig::StatementType(router_config) => {
+                    let targets = [
+                        &router_config.ddl,
+                        &router_config.dml,
+                        &router_config.query,
+                        &router_config.admin,
+                    ];
+                    check_every_target_group_exists(
+                        targets.into_iter().flatten(),
+                        cluster_groups,
+                        "StatementTypeRouter",
+                    )?;
+
+                    StatementTypeRouter::new(router_config).into()
+                }
             };
             routers.push(router);
         }
@@ -90,28 +178,165 @@ impl Router {
             .fail()?;
         }
 
+        for (client_source, trino_cluster_group) in &config.routing_fallback_by_source {
+            if !cluster_groups.contains(&trino_cluster_group) {
+                ConfigErrorRoutingFallbackBySourceDoesNotExistSnafu {
+                    client_source,
+                    trino_cluster_group,
+                }
+                .fail()?;
+            }
+        }
+
         Ok(Self {
             routers,
             routing_fallback: config.routing_fallback.clone(),
+            routing_fallback_by_source: config.routing_fallback_by_source.clone(),
         })
     }
 
+    /// Names of the configured routers, in the order they are asked.
+    pub fn router_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.routers.iter().map(RoutingImplementation::name)
+    }
+
     #[instrument(
         skip(self),
         fields(headers = ?headers.sanitize()),
     )]
+    /// The routers at the indices of the `disabled_routers` are skipped.
     pub async fn get_target_cluster_group(
         &self,
         query: &String,
         headers: &http::HeaderMap,
-    ) -> String {
-        for router in &self.routers {
+        disabled_routers: &[usize],
+    ) -> RoutingDecision {
+        for (index, router) in self.routers.iter().enumerate() {
+            if disabled_routers.contains(&index) {
+                debug!(index, router = router.name(), "Skipping disabled router");
+                continue;
+            }
             if let Some(target_cluster_group) = router.route(query, headers).await {
-                return target_cluster_group;
+                return RoutingDecision {
+                    cluster_group: target_cluster_group,
+                    reason: format!("{} at routers[{index}]", router.name()),
+                };
             }
         }
 
-        self.routing_fallback.clone()
+        let client_source = headers
+            .get("x-trino-source")
+            .and_then(|source| source.to_str().ok());
+        if let Some((client_source, cluster_group)) = client_source
+            .and_then(|client_source| self.routing_fallback_by_source.get_key_value(client_source))
+        {
+            return RoutingDecision {
+                cluster_group: cluster_group.clone(),
+                reason: format!("routingFallbackBySource[{client_source}]"),
+            };
+        }
+
+        RoutingDecision {
+            cluster_group: self.routing_fallback.clone(),
+            reason: "routingFallback".to_owned(),
+        }
+    }
+}
+
+/// Holds the current [`Router`], which can be swapped atomically to change the routing rules without restarting
+/// trino-lb. Only the `routers`, `routingFallback` and `routingFallbackBySource` are re-read, all other settings (such as the cluster groups) are
+/// kept as they were during startup.
+pub struct ReloadableRouter {
+    router: ArcSwap<Router>,
+    config_file: PathBuf,
+    config: Config,
+    persistence: Arc<PersistenceImplementation>,
+
+    /// Indices of the routers that are disabled using the admin API. It is kept across reloads.
+    disabled_routers: Arc<PersistedStateCache<Vec<usize>>>,
+}
+
+impl ReloadableRouter {
+    pub fn new(
+        router: Router,
+        config_file: PathBuf,
+        config: Config,
+        persistence: Arc<PersistenceImplementation>,
+    ) -> Self {
+        let disabled_routers = Arc::new(PersistedStateCache::new(
+            "disabled routers",
+            Arc::clone(&persistence),
+            |persistence| Box::pin(persistence.list_disabled_routers()),
+        ));
+
+        Self {
+            router: ArcSwap::from_pointee(router),
+            config_file,
+            config,
+            persistence,
+            disabled_routers,
+        }
+    }
+
+    /// Returns the current router. Requests keep using the router they got, even if it is swapped in the meantime.
+    pub fn load(&self) -> Arc<Router> {
+        self.router.load_full()
+    }
+
+    /// Routes the query using the current router, skipping the routers that were disabled as of the last refresh of
+    /// the disabled routers.
+    pub async fn get_target_cluster_group(
+        &self,
+        query: &String,
+        headers: &http::HeaderMap,
+    ) -> RoutingDecision {
+        self.load()
+            .get_target_cluster_group(query, headers, &self.disabled_routers.get())
+            .await
+    }
+
+    /// Re-reads the disabled routers, so that a change made via the admin API applies right away.
+    pub async fn refresh_disabled_routers(&self) -> Result<(), trino_lb_persistence::Error> {
+        self.disabled_routers.refresh().await
+    }
+
+    /// Periodically re-reads the disabled routers, so that changes made on other trino-lb replicas are picked up.
+    pub fn start_disabled_routers_refresh_loop(&self) {
+        self.disabled_routers.start_refresh_loop();
+    }
+
+    /// Re-reads the configuration file and swaps the router. In case the new routing configuration is invalid, the
+    /// current router is kept.
+    #[instrument(skip(self), fields(config_file = ?self.config_file))]
+    pub async fn reload(&self) -> Result<(), ReloadError> {
+        let new_config =
+            Config::read_from_file(&self.config_file)
+                .await
+                .context(ReadConfigSnafu {
+                    config_file: &self.config_file,
+                })?;
+
+        self.reload_from(new_config)
+    }
+
+    fn reload_from(&self, new_config: Config) -> Result<(), ReloadError> {
+        let config = Config {
+            routers: new_config.routers,
+            routing_fallback: new_config.routing_fallback,
+            routing_fallback_by_source: new_config.routing_fallback_by_source,
+            ..self.config.clone()
+        };
+        let router =
+            Router::new(&config, Arc::clone(&self.persistence)).context(CreateRouterSnafu)?;
+
+        self.router.store(Arc::new(router));
+        info!(
+            routers = config.routers.len(),
+            routing_fallback = config.routing_fallback,
+            "Reloaded routers"
+        );
+
+        Ok(())
     }
 }
 
@@ -129,6 +354,22 @@ pub enum RoutingImplementation {
     TrinoRoutingGroupHeader(TrinoRoutingGroupHeaderRouter),
     PythonScript(PythonScriptRouter),
     ClientTagHeaders(ClientTagsRouter),
+    LoadAware(LoadAwareRouter),
+    StatementType(StatementTypeRouter),
+}
+
+impl RoutingImplementation {
+    /// Name of the router as used in the documentation.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RoutingImplementation::ExplainCosts(_) => "ExplainCostsRouter",
+            RoutingImplementation::TrinoRoutingGroupHeader(_) => "TrinoRoutingGroupHeaderRouter",
+            RoutingImplementation::PythonScript(_) => "PythonScriptRouter",
+            RoutingImplementation::ClientTagHeaders(_) => "ClientTagsRouter",
+            RoutingImplementation::LoadAware(_) => "LoadAwareRouter",
+            RoutingImplementation::StatementType(_) => "StatementTypeRouter",
+        }
+    }
 }
 
 #[instrument(skip(targets))]
@@ -149,3 +390,255 @@ fn check_every_target_group_exists<'a>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use trino_lb_persistence::in_memory::InMemoryPersistence;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    fn config(routing: &str) -> Config {
+        TestConfigBuilder::new()
+            .external_address("http://127.0.0.1:8080")
+            .cluster_group("s", 1, &[])
+            .cluster_group("m", 1, &[])
+            .routing(routing)
+            .build()
+    }
+
+    fn reloadable_router() -> ReloadableRouter {
+        let config = config("routers: []\nroutingFallback: s");
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let router = Router::new(&config, Arc::clone(&persistence)).unwrap();
+
+        ReloadableRouter::new(router, PathBuf::from("unused.yaml"), config, persistence)
+    }
+
+    async fn route(router: &ReloadableRouter, client_tags: &str) -> String {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-trino-client-tags", client_tags.parse().unwrap());
+        router
+            .get_target_cluster_group(&"select 42".to_owned(), &headers)
+            .await
+            .cluster_group
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let router = reloadable_router();
+        assert_eq!(route(&router, "etl").await, "s");
+
+        router
+            .reload_from(config(
+                r#"
+routers:
+  - clientTags:
+      oneOf: ["etl"]
+      trinoClusterGroup: m
+routingFallback: s
+"#,
+            ))
+            .unwrap();
+        assert_eq!(route(&router, "etl").await, "m");
+        assert_eq!(route(&router, "adhoc").await, "s");
+    }
+
+    #[tokio::test]
+    async fn test_routing_reason() {
+        let config = config(
+            r#"
+routers:
+  - trinoRoutingGroupHeader: {}
+  - clientTags:
+      oneOf: ["etl"]
+      trinoClusterGroup: m
+routingFallback: s
+"#,
+        );
+        let persistence = Arc::new(InMemoryPersistence::default().into());
+        let router = Router::new(&config, persistence).unwrap();
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-trino-client-tags", "etl".parse().unwrap());
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &headers, &[])
+                .await,
+            RoutingDecision {
+                cluster_group: "m".to_owned(),
+                reason: "ClientTagsRouter at routers[1]".to_owned(),
+            }
+        );
+
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &http::HeaderMap::new(), &[])
+                .await,
+            RoutingDecision {
+                cluster_group: "s".to_owned(),
+                reason: "routingFallback".to_owned(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_router_is_skipped() {
+        let config = config(
+            r#"
+routers:
+  - clientTags:
+      oneOf: ["etl"]
+      trinoClusterGroup: m
+  - clientTags:
+      oneOf: ["etl"]
+      trinoClusterGroup: s
+routingFallback: s
+"#,
+        );
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(InMemoryPersistence::default().into());
+        let router = ReloadableRouter::new(
+            Router::new(&config, Arc::clone(&persistence)).unwrap(),
+            PathBuf::from("unused.yaml"),
+            config,
+            Arc::clone(&persistence),
+        );
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-trino-client-tags", "etl".parse().unwrap());
+
+        persistence.set_router_disabled(0, true).await.unwrap();
+        router.refresh_disabled_routers().await.unwrap();
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &headers)
+                .await,
+            RoutingDecision {
+                cluster_group: "s".to_owned(),
+                reason: "ClientTagsRouter at routers[1]".to_owned(),
+            }
+        );
+
+        // The router stays disabled after a reload
+        router.reload_from(router.config.clone()).unwrap();
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &headers)
+                .await
+                .cluster_group,
+            "s"
+        );
+
+        persistence.set_router_disabled(0, false).await.unwrap();
+        router.refresh_disabled_routers().await.unwrap();
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &headers)
+                .await
+                .reason,
+            "ClientTagsRouter at routers[0]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routing_fallback_by_source() {
+        let config = config(
+            r#"
+routers: []
+routingFallback: s
+routingFallbackBySource:
+  airflow: m
+"#,
+        );
+        let persistence = Arc::new(InMemoryPersistence::default().into());
+        let router = Router::new(&config, persistence).unwrap();
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-trino-source", "airflow".parse().unwrap());
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &headers, &[])
+                .await,
+            RoutingDecision {
+                cluster_group: "m".to_owned(),
+                reason: "routingFallbackBySource[airflow]".to_owned(),
+            }
+        );
+
+        headers.insert("x-trino-source", "trino-cli".parse().unwrap());
+        assert_eq!(
+            router
+                .get_target_cluster_group(&"select 42".to_owned(), &headers, &[])
+                .await,
+            RoutingDecision {
+                cluster_group: "s".to_owned(),
+                reason: "routingFallback".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_routing_fallback_by_source_with_invalid_target_group_is_rejected() {
+        let config = config(
+            r#"
+routers: []
+routingFallback: s
+routingFallbackBySource:
+  airflow: xl
+"#,
+        );
+        let persistence = Arc::new(InMemoryPersistence::default().into());
+
+        assert!(matches!(
+            Router::new(&config, persistence),
+            Err(Error::ConfigErrorRoutingFallbackBySourceDoesNotExist { .. })
+        ));
+    }
+
+    #[test]
+    fn test_load_aware_router_with_invalid_target_group_is_rejected() {
+        let config = config(
+            r#"
+routers:
+  - loadAware:
+      trinoClusterGroups: ["s", "xl"]
+routingFallback: s
+"#,
+        );
+        let persistence = Arc::new(InMemoryPersistence::default().into());
+
+        assert!(matches!(
+            Router::new(&config, persistence),
+            Err(Error::ConfigErrorClusterGroupDoesNotExist { router, trino_cluster_group })
+                if router == "LoadAwareRouter" && trino_cluster_group == "xl"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reload_with_invalid_target_group_is_rejected() {
+        let router = reloadable_router();
+
+        let result = router.reload_from(config(
+            r#"
+routers:
+  - clientTags:
+      oneOf: ["etl"]
+      trinoClusterGroup: xl
+routingFallback: s
+"#,
+        ));
+        assert!(matches!(result, Err(ReloadError::CreateRouter { .. })));
+
+        let result = router.reload_from(config("routers: []\nroutingFallback: xl"));
+        assert!(matches!(
+            result,
+            Err(ReloadError::CreateRouter {
+                source: Error::ConfigErrorRoutingFallbackDoesNotExist { .. }
+            })
+        ));
+
+        // The old router is still in place
+        assert_eq!(route(&router, "etl").await, "s");
+    }
+}
@@ -6,7 +6,11 @@ use pyo3::{
 };
 use snafu::{ResultExt, Snafu};
 use tracing::{error, instrument, warn};
-use trino_lb_core::{config::PythonScriptRouterConfig, sanitization::Sanitize};
+use trino_lb_core::{
+    client_tags::{ClientTags, TRINO_CLIENT_TAGS_HEADER},
+    config::PythonScriptRouterConfig,
+    sanitization::Sanitize,
+};
 
 use crate::routing::RouterImplementationTrait;
 
@@ -94,6 +98,8 @@ impl RouterImplementationTrait for PythonScriptRouter {
     }
 }
 
+/// The client tags are passed in a normalized form (see [`ClientTags`]), so that scripts can simply split them on `,`
+/// and `=`.
 #[instrument(fields(headers = ?headers.sanitize()))]
 fn header_map_to_hashmap(headers: &http::HeaderMap) -> HashMap<String, String> {
     let mut result = HashMap::new();
@@ -104,6 +110,15 @@ fn header_map_to_hashmap(headers: &http::HeaderMap) -> HashMap<String, String> {
         }
     }
 
+    result.remove(TRINO_CLIENT_TAGS_HEADER);
+    let client_tags = ClientTags::from_headers(headers);
+    if !client_tags.is_empty() {
+        result.insert(
+            TRINO_CLIENT_TAGS_HEADER.to_owned(),
+            client_tags.to_header_value(),
+        );
+    }
+
     result
 }
 
@@ -260,6 +275,27 @@ def get_client_tags(headers: dict[str, str]) -> dict[str, str]:
         );
     }
 
+    #[rstest]
+    #[case(None, None)]
+    #[case(Some(""), None)]
+    #[case(Some("label=foo"), Some("label=foo"))]
+    #[case(Some(" label=foo ,, bak,label=foo "), Some("label=foo,bak"))]
+    fn test_header_map_to_hashmap_normalizes_client_tags(
+        #[case] x_trino_client_tags: Option<&str>,
+        #[case] expected: Option<&str>,
+    ) {
+        let headers = header_map_to_hashmap(&get_headers(Some("airflow"), x_trino_client_tags));
+
+        assert_eq!(
+            headers.get("x-trino-source").map(String::as_str),
+            Some("airflow")
+        );
+        assert_eq!(
+            headers.get("x-trino-client-tags").map(String::as_str),
+            expected
+        );
+    }
+
     #[rstest]
     #[case("show catalogs", None)]
     #[case("ALTER TABLE foo EXECUTE OPTIMIZE", Some("l"))]
@@ -0,0 +1,173 @@
+use tracing::{debug, instrument};
+use trino_lb_core::{config::StatementTypeRouterConfig, sanitization::Sanitize};
+
+use crate::routing::RouterImplementationTrait;
+
+/// Category of a SQL statement, determined by its leading keyword.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StatementType {
+    Ddl,
+    Dml,
+    Query,
+    Admin,
+}
+
+impl StatementType {
+    /// Classifies the statement by its first keyword, skipping leading whitespace and comments. This is no SQL parser,
+    /// so statements with an unknown leading keyword are not classified.
+    fn classify(query: &str) -> Option<Self> {
+        let statement = skip_whitespace_and_comments(query)?;
+
+        // Parenthesized queries, such as `(SELECT 1) UNION (SELECT 2)`
+        if statement.starts_with('(') {
+            return Some(Self::Query);
+        }
+
+        let keyword_len = statement
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(statement.len());
+        let keyword = statement[..keyword_len].to_ascii_uppercase();
+
+        match keyword.as_str() {
+            "CREATE" | "ALTER" | "DROP" | "COMMENT" | "REFRESH" => Some(Self::Ddl),
+            "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "TRUNCATE" => Some(Self::Dml),
+            "SELECT" | "WITH" | "VALUES" | "TABLE" | "SHOW" | "DESCRIBE" | "EXPLAIN" => {
+                Some(Self::Query)
+            }
+            "GRANT" | "REVOKE" | "DENY" | "SET" | "RESET" | "USE" | "CALL" | "ANALYZE"
+            | "PREPARE" | "EXECUTE" | "DEALLOCATE" | "START" | "COMMIT" | "ROLLBACK" => {
+                Some(Self::Admin)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Returns the query starting at its first token. Returns [`None`] in case there is no token or a block comment is not
+/// terminated.
+fn skip_whitespace_and_comments(mut query: &str) -> Option<&str> {
+    loop {
+        query = query.trim_start();
+
+        if let Some(comment) = query.strip_prefix("--") {
+            query = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = query.strip_prefix("/*") {
+            query = comment.split_once("*/")?.1;
+        } else if query.is_empty() {
+            return None;
+        } else {
+            return Some(query);
+        }
+    }
+}
+
+pub struct StatementTypeRouter {
+    config: StatementTypeRouterConfig,
+}
+
+impl StatementTypeRouter {
+    #[instrument(name = "StatementTypeRouter::new")]
+    pub fn new(config: &StatementTypeRouterConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl RouterImplementationTrait for StatementTypeRouter {
+    #[instrument(
+        name = "StatementTypeRouter::route"
+        skip(self),
+        fields(headers = ?headers.sanitize()),
+    )]
+    async fn route(&self, query: &str, headers: &http::HeaderMap) -> Option<String> {
+        let statement_type = StatementType::classify(query);
+        debug!(?statement_type, "Classified query statement");
+
+        match statement_type? {
+            StatementType::Ddl => self.config.ddl.clone(),
+            StatementType::Dml => self.config.dml.clone(),
+            StatementType::Query => self.config.query.clone(),
+            StatementType::Admin => self.config.admin.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("select 42", Some(StatementType::Query))]
+    #[case("  \n\tSeLeCt 42", Some(StatementType::Query))]
+    #[case("with a as (select 1) select * from a", Some(StatementType::Query))]
+    #[case("(select 1) union (select 2)", Some(StatementType::Query))]
+    #[case("show catalogs", Some(StatementType::Query))]
+    #[case("EXPLAIN SELECT 1", Some(StatementType::Query))]
+    #[case("ALTER TABLE foo EXECUTE OPTIMIZE", Some(StatementType::Ddl))]
+    #[case("alter table foo execute optimize", Some(StatementType::Ddl))]
+    #[case(
+        "ALTER TABLE foo EXECUTE OPTIMIZE(file_size_threshold => '10MB')
+    ",
+        Some(StatementType::Ddl)
+    )]
+    #[case(
+        "ALTER TABLE foo EXECUTE OPTIMIZE WHERE partition_key = 1",
+        Some(StatementType::Ddl)
+    )]
+    #[case("CREATE TABLE foo AS SELECT 1", Some(StatementType::Ddl))]
+    #[case("drop table foo", Some(StatementType::Ddl))]
+    #[case("INSERT INTO foo VALUES (1)", Some(StatementType::Dml))]
+    #[case(
+        "merge into foo using bar on true when matched then delete",
+        Some(StatementType::Dml)
+    )]
+    #[case("GRANT SELECT ON foo TO alice", Some(StatementType::Admin))]
+    #[case(
+        "call system.sync_partition_metadata('a', 'b', 'FULL')",
+        Some(StatementType::Admin)
+    )]
+    #[case("-- select\ninsert into foo values (1)", Some(StatementType::Dml))]
+    #[case("/* insert */ select 1", Some(StatementType::Query))]
+    #[case(
+        "-- Nightly compaction\n/* owner: data-platform\n */\n  alter table foo execute optimize",
+        Some(StatementType::Ddl)
+    )]
+    #[case("select/* comment */1", Some(StatementType::Query))]
+    #[case("/* unterminated select 1", None)]
+    #[case("-- only a comment", None)]
+    #[case("", None)]
+    #[case("   ", None)]
+    #[case("selectx 1", None)]
+    #[case("foo bar", None)]
+    fn test_classify(#[case] query: &str, #[case] expected: Option<StatementType>) {
+        assert_eq!(StatementType::classify(query), expected);
+    }
+
+    #[rstest]
+    #[case("show catalogs", Some("interactive"))]
+    #[case("ALTER TABLE foo EXECUTE OPTIMIZE", Some("maintenance"))]
+    #[case("insert into foo select * from bar", Some("etl"))]
+    #[case("grant select on foo to alice", None)]
+    #[case("foo bar", None)]
+    #[tokio::test]
+    async fn test_routing(#[case] query: &str, #[case] expected: Option<&str>) {
+        let config = serde_yaml::from_str(
+            r#"
+            ddl: maintenance
+            dml: etl
+            query: interactive
+        "#,
+        )
+        .unwrap();
+        let router = StatementTypeRouter::new(&config);
+
+        assert_eq!(
+            router.route(query, &HeaderMap::new()).await.as_deref(),
+            expected
+        );
+    }
+}
@@ -1,22 +1,17 @@
-use std::{sync::OnceLock, time::Duration};
+use std::time::Duration;
 
-use chrono::{DateTime, Timelike, Utc};
-use regex::Regex;
-use snafu::{OptionExt, Snafu};
+use chrono::{DateTime, Utc};
+use snafu::{ResultExt, Snafu};
 use trino_lb_core::config::{MinClustersConfig, TrinoClusterGroupAutoscalingConfig};
 
-static TIME_RANGE_REGEX: OnceLock<Regex> = OnceLock::new();
+use crate::time_range::{self, TimeRange};
+
 const MIN_DRAIN_IDLE_DURATION_BEFORE_SHUTDOWN: Duration = Duration::from_secs(10);
 
 #[derive(Snafu, Debug)]
 pub enum Error {
-    #[snafu(display(
-        "Time range {time_range:?} can not be parsed. It needs to have the format \"09:00:00 - 11:59:59\""
-    ))]
-    InvalidTimeRange { time_range: String },
-
-    #[snafu(display("Any weekdays other tha \"Mon - Son\" are not supported yet"))]
-    WeekdaysNotSupportedYet {},
+    #[snafu(display("Failed to parse the time range of minClusters"))]
+    ParseMinClustersTimeRange { source: time_range::Error },
 
     #[snafu(display(
         "Please configure a drainIdleDurationBeforeShutdown of at least {min_duration:?}"
@@ -30,6 +25,10 @@ pub struct TrinoClusterGroupAutoscaling {
     pub downscale_running_queries_percentage_threshold: u64,
     pub drain_idle_duration_before_shutdown: Duration,
     pub min_clusters: Vec<MinClusters>,
+    pub allow_scale_to_zero: bool,
+    pub ready_grace_period: Duration,
+    pub upscale_blocked_queries_threshold: Option<u64>,
+    pub blocked_queries_weight_percentage: u64,
 }
 
 impl TryFrom<TrinoClusterGroupAutoscalingConfig> for TrinoClusterGroupAutoscaling {
@@ -52,18 +51,26 @@ impl TryFrom<TrinoClusterGroupAutoscalingConfig> for TrinoClusterGroupAutoscalin
                 .into_iter()
                 .map(|m| m.try_into())
                 .collect::<Result<Vec<_>, Error>>()?,
+            allow_scale_to_zero: config.allow_scale_to_zero,
+            ready_grace_period: config.ready_grace_period,
+            upscale_blocked_queries_threshold: config.upscale_blocked_queries_threshold,
+            blocked_queries_weight_percentage: config.blocked_queries_weight_percentage,
         })
     }
 }
 
+impl TrinoClusterGroupAutoscaling {
+    /// Blocked queries are already part of the cluster query counts, so we only need to look at them separately in
+    /// case they should trigger an upscale or are weighted differently than running queries.
+    pub fn considers_blocked_queries(&self) -> bool {
+        self.upscale_blocked_queries_threshold.is_some()
+            || self.blocked_queries_weight_percentage != 100
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MinClusters {
-    time_start_hour: u32,
-    time_start_minute: u32,
-    time_start_second: u32,
-    time_end_hour: u32,
-    time_end_minute: u32,
-    time_end_second: u32,
+    time_range: TimeRange,
     pub min: u64,
 }
 
@@ -71,32 +78,9 @@ impl TryFrom<MinClustersConfig> for MinClusters {
     type Error = Error;
 
     fn try_from(config: MinClustersConfig) -> Result<Self, Error> {
-        let time_range_regex = TIME_RANGE_REGEX.get_or_init(|| {
-            Regex::new(
-                r"^([0-9][0-9]):([0-9][0-9]):([0-9][0-9]) - ([0-9][0-9]):([0-9][0-9]):([0-9][0-9])$",
-            )
-            .unwrap()
-        });
-
-        let time_captures =
-            time_range_regex
-                .captures(&config.time_utc)
-                .context(InvalidTimeRangeSnafu {
-                    time_range: &config.time_utc,
-                })?;
-
-        if config.weekdays != "Mon - Son" {
-            WeekdaysNotSupportedYetSnafu.fail()?;
-        }
-
         Ok(MinClusters {
-            // Safety: The array access and digit parsing can not fail as of the regex content
-            time_start_hour: time_captures[1].parse().unwrap(),
-            time_start_minute: time_captures[2].parse().unwrap(),
-            time_start_second: time_captures[3].parse().unwrap(),
-            time_end_hour: time_captures[4].parse().unwrap(),
-            time_end_minute: time_captures[5].parse().unwrap(),
-            time_end_second: time_captures[6].parse().unwrap(),
+            time_range: TimeRange::new(&config.time_utc, &config.weekdays)
+                .context(ParseMinClustersTimeRangeSnafu)?,
             min: config.min,
         })
     }
@@ -104,16 +88,7 @@ impl TryFrom<MinClustersConfig> for MinClusters {
 
 impl MinClusters {
     pub fn date_is_in_range(&self, date: &DateTime<Utc>) -> bool {
-        let hour = date.hour();
-        let minute = date.minute();
-        let second = date.second();
-
-        let date = hour * 60 * 60 + minute * 60 + second;
-        date >= self.time_start_hour * 60 * 60
-            + self.time_start_minute * 60
-            + self.time_start_second
-            && date
-                <= self.time_end_hour * 60 * 60 + self.time_end_minute * 60 + self.time_end_second
+        self.time_range.date_is_in_range(date)
     }
 }
 
@@ -151,6 +126,13 @@ mod tests {
     #[case("08:00:00 - 09:00:00", Utc.with_ymd_and_hms(2023, 12, 8, 12, 0, 0).unwrap(), false)]
     #[case("08:00:00 - 09:00:00", Utc.with_ymd_and_hms(2023, 12, 8, 23, 0, 0).unwrap(), false)]
     #[case("08:00:00 - 09:00:00", Utc.with_ymd_and_hms(2023, 12, 8, 23, 59, 59).unwrap(), false)]
+    // Crosses midnight
+    #[case("22:00:00 - 05:59:59", Utc.with_ymd_and_hms(2023, 12, 8, 0, 0, 0).unwrap(), true)]
+    #[case("22:00:00 - 05:59:59", Utc.with_ymd_and_hms(2023, 12, 8, 5, 59, 59).unwrap(), true)]
+    #[case("22:00:00 - 05:59:59", Utc.with_ymd_and_hms(2023, 12, 8, 6, 0, 0).unwrap(), false)]
+    #[case("22:00:00 - 05:59:59", Utc.with_ymd_and_hms(2023, 12, 8, 21, 59, 59).unwrap(), false)]
+    #[case("22:00:00 - 05:59:59", Utc.with_ymd_and_hms(2023, 12, 8, 22, 0, 0).unwrap(), true)]
+    #[case("22:00:00 - 05:59:59", Utc.with_ymd_and_hms(2023, 12, 8, 23, 59, 59).unwrap(), true)]
     fn test_date_is_in_range(
         #[case] time_utc: String,
         #[case] date: DateTime<Utc>,
@@ -7,27 +7,38 @@ use std::{
 use chrono::{DateTime, Utc};
 use enum_dispatch::enum_dispatch;
 use futures::future::try_join_all;
+use opentelemetry::KeyValue;
 use snafu::{OptionExt, ResultExt, Snafu};
 use stackable::StackableScaler;
 use tokio::{
     join,
+    sync::{mpsc, oneshot},
     task::{JoinError, JoinSet},
-    time,
+    time::{self, Instant},
 };
 use tracing::{debug, error, info, instrument, Instrument, Span};
 use trino_lb_core::{
+    clock_skew::elapsed_allowing_clock_skew,
     config::{Config, ScalerConfig},
     trino_cluster::ClusterState,
     TrinoClusterName,
 };
 use trino_lb_persistence::{Persistence, PersistenceImplementation};
 
-use crate::cluster_group_manager::TrinoCluster;
+use crate::{
+    cluster_group_manager::{QueryCounters, TrinoCluster},
+    max_running_queries::MaxRunningQueries,
+    metrics::Metrics,
+};
 
-use self::config::TrinoClusterGroupAutoscaling;
+use self::{
+    config::TrinoClusterGroupAutoscaling,
+    webhook::{ClusterStateChange, ClusterStateWebhook},
+};
 
 pub mod config;
 pub mod stackable;
+pub mod webhook;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -41,6 +52,9 @@ pub enum Error {
     #[snafu(display("Failed to create Stackable autoscaler"))]
     CreateStackableAutoscaler { source: stackable::Error },
 
+    #[snafu(display("Failed to create cluster state webhook"))]
+    CreateClusterStateWebhook { source: webhook::Error },
+
     #[snafu(display("Failed to get the counter of running queries on the cluster {cluster:?}"))]
     GetClusterQueryCounter {
         source: trino_lb_persistence::Error,
@@ -77,6 +91,9 @@ pub enum Error {
         cluster: TrinoClusterName,
     },
 
+    #[snafu(display("Failed to list the manually managed clusters from persistence"))]
+    ListManuallyManagedClusters { source: trino_lb_persistence::Error },
+
     #[snafu(display(
         "Failed to read current cluster state for cluster group {cluster_group:?} from persistence"
     ))]
@@ -91,6 +108,14 @@ pub enum Error {
         cluster: TrinoClusterName,
     },
 
+    #[snafu(display(
+        "Failed to determine how long the cluster {cluster:?} is ready (currently warming up). Maybe the clocks are out of sync"
+    ))]
+    DetermineDurationSinceReady {
+        source: SystemTimeError,
+        cluster: TrinoClusterName,
+    },
+
     #[snafu(display(
         "Failed to determine how long the cluster {cluster:?} has no queries running (currently draining). Maybe the clocks are out of sync"
     ))]
@@ -110,6 +135,35 @@ pub enum Error {
 
     #[snafu(display("The variable \"scaler\" is None. This should never happen, as we only run the reconciliation when a scaler is configured!"))]
     ScalerVariableIsNone {},
+
+    #[snafu(display(
+        "The scaler loop is not running, so the reconciliation could not be triggered"
+    ))]
+    ScalerLoopNotRunning {},
+}
+
+/// Requests an immediate reconciliation. The result of the reconciliation will be send to the contained sender.
+type ReconcileRequest = oneshot::Sender<Result<(), Error>>;
+
+/// Allows triggering a reconciliation of the [`Scaler`] out of band (e.g. via the admin API), as the [`Scaler`] itself
+/// is owned by the task started in [`Scaler::start_loop`].
+#[derive(Clone)]
+pub struct ScalerHandle {
+    reconcile_requests: mpsc::Sender<ReconcileRequest>,
+}
+
+impl ScalerHandle {
+    /// Triggers a reconciliation and waits for it to complete.
+    pub async fn reconcile(&self) -> Result<(), Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.reconcile_requests
+            .send(sender)
+            .await
+            .ok()
+            .context(ScalerLoopNotRunningSnafu)?;
+
+        receiver.await.ok().context(ScalerLoopNotRunningSnafu)?
+    }
 }
 
 /// The scaler periodically
@@ -123,16 +177,27 @@ pub struct Scaler {
     persistence: Arc<PersistenceImplementation>,
     /// Stores a list of all Trino clusters per cluster group.
     groups: HashMap<String, Vec<TrinoCluster>>,
+    max_running_queries: HashMap<String, Arc<MaxRunningQueries>>,
     /// Stores the scaling config per cluster group. This HashMap only contains entries for the cluster groups that
     /// actually need scaling, non-scaled cluster groups are missing from the HashMap.
     scaling_config: HashMap<String, TrinoClusterGroupAutoscaling>,
+    /// Notified about every change of a cluster state, [`None`] in case no webhook is configured.
+    cluster_state_webhook: Option<Arc<ClusterStateWebhook>>,
+    /// The points in time stored in the cluster states might have been recorded by other trino-lb replicas.
+    max_clock_skew: Duration,
+    /// Which query counter the clusters use, as the clusters might share them.
+    query_counters: QueryCounters,
+    metrics: Arc<Metrics>,
 }
 
 impl Scaler {
-    #[instrument(skip(persistence))]
+    #[instrument(skip(persistence, metrics))]
+    /// The `max_running_queries` are only read, they need to be refreshed by the [`ClusterGroupManager`](crate::cluster_group_manager::ClusterGroupManager).
     pub async fn new(
         config: &Config,
         persistence: Arc<PersistenceImplementation>,
+        max_running_queries: &HashMap<String, Arc<MaxRunningQueries>>,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, Error> {
         let mut scaling_config = HashMap::new();
 
@@ -154,12 +219,15 @@ impl Scaler {
                 }
 
                 Some(match scaler {
-                    ScalerConfig::Stackable(scaler_config) => {
-                        StackableScaler::new(scaler_config, &config.trino_cluster_groups)
-                            .await
-                            .context(CreateStackableAutoscalerSnafu)?
-                            .into()
-                    }
+                    ScalerConfig::Stackable(scaler_config) => StackableScaler::new(
+                        scaler_config,
+                        &config.trino_cluster_groups,
+                        config.trino_cluster_groups_ignore_cert,
+                        &config.trino_lb.user_agent,
+                    )
+                    .await
+                    .context(CreateStackableAutoscalerSnafu)?
+                    .into(),
                 })
             }
         };
@@ -180,22 +248,41 @@ impl Scaler {
 
                 group.push(TrinoCluster {
                     name: cluster_name,
-                    max_running_queries: group_config.max_running_queries,
-                    endpoint: cluster_config.endpoint.clone(),
+                    endpoint: cluster_config.internal_endpoint().clone(),
+                    external_endpoint: cluster_config.external_endpoint().clone(),
+                    overflow: cluster_config.overflow,
+                    weight: cluster_config.weight,
                 })
             }
             groups.insert(group_name.clone(), group);
         }
 
+        let cluster_state_webhook = config
+            .trino_lb
+            .cluster_state_webhook
+            .as_ref()
+            .map(|webhook| ClusterStateWebhook::new(webhook, &config.trino_lb.user_agent))
+            .transpose()
+            .context(CreateClusterStateWebhookSnafu)?
+            .map(Arc::new);
+
         Ok(Scaler {
             scaler,
             persistence,
             groups,
+            max_running_queries: max_running_queries.clone(),
             scaling_config,
+            cluster_state_webhook,
+            max_clock_skew: config.trino_lb.max_clock_skew,
+            query_counters: QueryCounters::new(config),
+            metrics,
         })
     }
 
-    pub fn start_loop(self) {
+    pub fn start_loop(self) -> ScalerHandle {
+        let (reconcile_requests, mut reconcile_request_receiver) =
+            mpsc::channel::<ReconcileRequest>(16);
+
         if self.scaler.is_some() {
             // As there is a scaler configured, let's start it normally.
             let mut interval = time::interval(Duration::from_secs(10));
@@ -205,12 +292,24 @@ impl Scaler {
             tokio::spawn(async move {
                 loop {
                     // First tick does not sleep, so let's put it at the start of the loop.
-                    interval.tick().await;
+                    let reconcile_request = tokio::select! {
+                        _ = interval.tick() => None,
+                        Some(reconcile_request) = reconcile_request_receiver.recv() => {
+                            info!("Scaler: Got request to reconcile immediately");
+                            Some(reconcile_request)
+                        },
+                    };
 
-                    match me.clone().reconcile().await {
+                    let result = me.clone().reconcile().await;
+                    match &result {
                         Ok(()) => info!("Scaler: reconciled"),
                         Err(error) => error!(?error, "Scaler: reconciled failed"),
                     }
+
+                    if let Some(reconcile_request) = reconcile_request {
+                        // The requester might have gone away in the meantime, which is fine
+                        let _ = reconcile_request.send(result);
+                    }
                 }
             });
         } else {
@@ -223,13 +322,25 @@ impl Scaler {
             tokio::spawn(async move {
                 loop {
                     // First tick does not sleep, so let's put it at the start of the loop.
-                    interval.tick().await;
-                    if let Err(error) = self.set_all_clusters_to_ready().await {
+                    let reconcile_request = tokio::select! {
+                        _ = interval.tick() => None,
+                        Some(reconcile_request) = reconcile_request_receiver.recv() => Some(reconcile_request),
+                    };
+
+                    let result = self.set_all_clusters_to_ready().await;
+                    if let Err(error) = &result {
                         error!(?error, "Scaler: Failed to set all clusters to ready");
                     }
+
+                    if let Some(reconcile_request) = reconcile_request {
+                        // The requester might have gone away in the meantime, which is fine
+                        let _ = reconcile_request.send(result);
+                    }
                 }
             });
         }
+
+        ScalerHandle { reconcile_requests }
     }
 
     #[instrument(name = "Scaler::reconcile", skip(self))]
@@ -239,8 +350,24 @@ impl Scaler {
         for (cluster_group, clusters) in self.groups.clone() {
             let me = Arc::clone(&self);
             join_set.spawn(
-                me.reconcile_cluster_group(cluster_group, clusters)
-                    .instrument(Span::current()),
+                async move {
+                    let start = Instant::now();
+                    let result = Arc::clone(&me)
+                        .reconcile_cluster_group(cluster_group.clone(), clusters)
+                        .await;
+
+                    let attributes = [
+                        KeyValue::new("cluster-group", cluster_group),
+                        KeyValue::new("outcome", if result.is_ok() { "success" } else { "error" }),
+                    ];
+                    me.metrics
+                        .scaler_reconcile_duration
+                        .record(start.elapsed().as_secs_f64(), &attributes);
+                    me.metrics.scaler_reconcile_counter.add(1, &attributes);
+
+                    result
+                }
+                .instrument(Span::current()),
             );
         }
 
@@ -259,20 +386,24 @@ impl Scaler {
         clusters: Vec<TrinoCluster>,
     ) -> Result<(), Error> {
         let now = Utc::now();
+        // Manually managed clusters are left out entirely, so other clusters are started to satisfy the minimum number
+        // of clusters instead.
+        let manually_managed_clusters = self.manually_managed_clusters().await?;
+        let clusters = clusters
+            .into_iter()
+            .filter(|cluster| !manually_managed_clusters.contains(&cluster.name))
+            .collect::<Vec<_>>();
         let scaling_config = match self.scaling_config.get(&cluster_group) {
             Some(scaling_config) => scaling_config,
             None => {
                 // As there is no scaling configured for this cluster group, we periodically need to set all clusters
                 // ready. We need to do this repeatedly, as the state would be stuck in Unknown until trino-lb get's
                 // restarted once the persistence gets wiped.
-                for cluster in clusters {
-                    self.persistence
-                        .set_cluster_state(&cluster.name, ClusterState::Ready)
-                        .await
-                        .context(SetCurrentClusterStateInPersistenceSnafu {
-                            cluster: &cluster.name,
-                        })?;
+                let mut states = Vec::with_capacity(clusters.len());
+                for cluster in &clusters {
+                    states.push(self.set_cluster_to_ready(&cluster.name).await?);
                 }
+                self.record_cluster_states(&cluster_group, &states);
                 return Ok(());
             }
         };
@@ -286,10 +417,12 @@ impl Scaler {
             );
         }
 
+        let mut stored_states = HashMap::new();
         let mut target_states = HashMap::new();
         while let Some(res) = join_set.join_next().await {
-            let (cluster_name, current_state) =
+            let (cluster_name, stored_state, current_state) =
                 res.context(JoinGetCurrentClusterStateTaskSnafu)??;
+            stored_states.insert(cluster_name.clone(), stored_state);
             target_states.insert(cluster_name, current_state);
         }
         info!(current_states = ?target_states, "Current cluster states");
@@ -302,9 +435,46 @@ impl Scaler {
             .context(GetQueuedQueryCounterForGroupSnafu {
                 cluster_group: &cluster_group,
             })?;
-        if queued >= scaling_config.upscale_queued_queries_threshold {
+        let blocked = if scaling_config.considers_blocked_queries() {
+            // Only clusters that are running can have blocked queries, all other counts might be outdated.
+            let blocked_query_counters = try_join_all(
+                clusters
+                    .iter()
+                    .filter(|c| {
+                        matches!(
+                            target_states.get(&c.name),
+                            Some(ClusterState::Ready | ClusterState::Draining { .. })
+                        )
+                    })
+                    .map(|c| async {
+                        self.persistence
+                            .get_cluster_blocked_query_count(&c.name)
+                            .await
+                    }),
+            )
+            .await
+            .context(GetQueryCounterForGroupSnafu {
+                cluster_group: &cluster_group,
+            })?;
+            blocked_query_counters.iter().sum()
+        } else {
+            0
+        };
+        let min_clusters = self.get_current_min_cluster_count(scaling_config, &cluster_group, &now);
+        debug!(cluster_group, min_clusters, "Current min clusters");
+
+        let upscale_because_of_blocked_queries = scaling_config
+            .upscale_blocked_queries_threshold
+            .is_some_and(|threshold| blocked >= threshold);
+        debug!(queued, blocked, "Current queued and blocked queries");
+
+        if queued >= scaling_config.upscale_queued_queries_threshold
+            || upscale_because_of_blocked_queries
+        {
             // Check if there is already a cluster starting, nothing to do in that case
-            let already_starting = target_states.values().any(|s| *s == ClusterState::Starting);
+            let already_starting = target_states
+                .values()
+                .any(|s| matches!(s, ClusterState::Starting | ClusterState::WarmingUp { .. }));
 
             if !already_starting {
                 // Walk list top to bottom and start the first cluster that can be started
@@ -319,34 +489,38 @@ impl Scaler {
             }
         } else if queued == 0 {
             // Determine excess clusters, this only makes sense when we don't upscale
-            let cluster_query_counters = try_join_all(
-                clusters
-                    .iter()
-                    .map(|g| async { self.persistence.get_cluster_query_count(&g.name).await }),
-            )
+            let cluster_query_counters = try_join_all(clusters.iter().map(|g| {
+                self.persistence
+                    .get_cluster_query_count(self.query_counters.query_counter_of(&g.name))
+            }))
             .await
             .context(GetQueryCounterForGroupSnafu {
                 cluster_group: &cluster_group,
             })?;
-            let max_running_queries: u64 = clusters
+            // The scaler only runs every few seconds, so there is no need to use the cached value
+            let max_running_queries_per_cluster = self
+                .max_running_queries
+                .get(&cluster_group)
+                .map(|m| m.effective_at(&now))
+                .unwrap_or_default();
+            let ready_clusters = clusters
                 .iter()
-                .map(|c| (c, target_states.get(&c.name).unwrap()))
-                .filter(|(_, s)| s.ready_to_accept_queries())
-                .map(|(c, _)| c.max_running_queries)
-                .sum();
+                .filter(|c| {
+                    target_states
+                        .get(&c.name)
+                        .unwrap()
+                        .ready_to_accept_queries()
+                })
+                .count() as u64;
+            let max_running_queries = ready_clusters * max_running_queries_per_cluster;
             let current_running_queries: u64 = cluster_query_counters.iter().sum();
 
-            let utilization_percent = match (max_running_queries, current_running_queries) {
-                // No cluster is ready to accept queries and no queries running
-                (0, 0) => 0,
-                // No cluster is ready to accept queries but there are some queries still running
-                // This means the clusters are even more utilized than they normally should have (although they e.g. can
-                // have some queries running during draining)
-                // So we set the utilization to 100%
-                (0, _) => 100,
-                // We can calculate the percentage normally, it's safe to divide by max_running_queries
-                (_, _) => 100 * current_running_queries / max_running_queries,
-            };
+            let utilization_percent = utilization_percent(
+                current_running_queries,
+                blocked,
+                scaling_config.blocked_queries_weight_percentage,
+                max_running_queries,
+            );
 
             debug!(
                 current_running_queries,
@@ -365,34 +539,46 @@ impl Scaler {
                     });
 
                 if !already_shutting_down {
-                    // Walk list bottom to top and find the first cluster that is currently active
-                    let shut_down_candidates = clusters
+                    // Walk list bottom to top and find the first cluster that is currently active. Warming up
+                    // clusters did not get any queries yet, so they are preferred. Warming up clusters needed for the
+                    // minimum amount of clusters are skipped, as they would be started right again below.
+                    let mut shut_down_candidates = clusters
                         .iter()
+                        .enumerate()
                         .rev()
-                        .map(|c| (target_states.get(&c.name).unwrap(), c))
-                        .filter(|(state, _)| **state == ClusterState::Ready)
+                        .filter(|(index, c)| match target_states.get(&c.name).unwrap() {
+                            ClusterState::Ready => true,
+                            ClusterState::WarmingUp { .. } => *index >= min_clusters as usize,
+                            _ => false,
+                        })
+                        .map(|(_, c)| c)
                         .collect::<Vec<_>>();
+                    shut_down_candidates.sort_by_key(|c| {
+                        !matches!(
+                            target_states.get(&c.name).unwrap(),
+                            ClusterState::WarmingUp { .. }
+                        )
+                    });
 
-                    // We don't want to shut down the last remaining cluster obviously
-                    // The only exception is the case no queries were running at all, in that case we shut down
-                    // the unneeded cluster.
-                    if shut_down_candidates.len() > 1 || current_running_queries == 0 {
-                        if let Some((_, to_shut_down)) = shut_down_candidates.first() {
-                            target_states.insert(
-                                to_shut_down.name.to_owned(),
-                                ClusterState::Draining {
-                                    last_time_seen_with_queries: SystemTime::now(),
-                                },
-                            );
-                        }
+                    if let Some(to_shut_down) = select_cluster_to_shut_down(
+                        &shut_down_candidates,
+                        current_running_queries,
+                        scaling_config.allow_scale_to_zero,
+                    ) {
+                        let target_state = match target_states.get(&to_shut_down.name).unwrap() {
+                            // There are no queries to drain
+                            ClusterState::WarmingUp { .. } => ClusterState::Terminating,
+                            _ => ClusterState::Draining {
+                                last_time_seen_with_queries: SystemTime::now(),
+                            },
+                        };
+                        target_states.insert(to_shut_down.name.to_owned(), target_state);
                     }
                 }
             }
         }
 
         // Spin up the minimum amount of required clusters
-        let min_clusters = self.get_current_min_cluster_count(scaling_config, &cluster_group, &now);
-        debug!(cluster_group, min_clusters, "Current min clusters");
         for cluster in clusters.iter().take(min_clusters as usize) {
             let current_state = target_states.get(&cluster.name).unwrap();
             let target_state = current_state.start();
@@ -400,16 +586,23 @@ impl Scaler {
         }
 
         debug!(?target_states, "Target cluster states");
+        self.record_cluster_states(&cluster_group, target_states.values());
 
         let mut join_set = JoinSet::new();
 
         for cluster in clusters {
             // FIXME: unwrap
             let me = Arc::clone(&self);
+            let previous_state = stored_states.remove(&cluster.name).unwrap();
             let target_state = target_states.get(&cluster.name).unwrap();
             join_set.spawn(
-                me.apply_cluster_target_state(cluster, target_state.clone())
-                    .instrument(Span::current()),
+                me.apply_cluster_target_state(
+                    cluster,
+                    cluster_group.clone(),
+                    previous_state,
+                    target_state.clone(),
+                )
+                .instrument(Span::current()),
             );
         }
 
@@ -421,12 +614,13 @@ impl Scaler {
         Ok(())
     }
 
+    /// Returns the state stored in the persistence as well as the current state of the cluster.
     #[instrument(name = "Scaler::get_current_state", skip(self))]
     async fn get_current_cluster_state(
         self: Arc<Self>,
         cluster_name: TrinoClusterName,
         scaling_config: TrinoClusterGroupAutoscaling,
-    ) -> Result<(TrinoClusterName, ClusterState), Error> {
+    ) -> Result<(TrinoClusterName, ClusterState, ClusterState), Error> {
         let scaler = self.scaler.as_ref().context(ScalerVariableIsNoneSnafu)?;
 
         let (stored_state, activated, ready) = join!(
@@ -442,7 +636,7 @@ impl Scaler {
             ready?,
         );
 
-        let current_state = match stored_state {
+        let current_state = match stored_state.clone() {
             ClusterState::Unknown => {
                 // State not known in persistence, so let's determine current state
                 match (activated, ready) {
@@ -459,10 +653,29 @@ impl Scaler {
             }
             ClusterState::Stopped => ClusterState::Stopped,
             ClusterState::Starting => {
-                if ready {
+                if !ready {
+                    ClusterState::Starting
+                } else if scaling_config.ready_grace_period.is_zero() {
                     ClusterState::Ready
                 } else {
+                    ClusterState::WarmingUp {
+                        ready_since: SystemTime::now(),
+                    }
+                }
+            }
+            ClusterState::WarmingUp { ready_since } => {
+                if !ready {
+                    // The cluster is not ready anymore, so we need to wait for it to become ready again
                     ClusterState::Starting
+                } else if elapsed_allowing_clock_skew(ready_since, self.max_clock_skew).context(
+                    DetermineDurationSinceReadySnafu {
+                        cluster: &cluster_name,
+                    },
+                )? >= scaling_config.ready_grace_period
+                {
+                    ClusterState::Ready
+                } else {
+                    ClusterState::WarmingUp { ready_since }
                 }
             }
             ClusterState::Ready => {
@@ -483,17 +696,21 @@ impl Scaler {
                 } else {
                     let current_query_counter = self
                         .persistence
-                        .get_cluster_query_count(&cluster_name)
+                        .get_cluster_query_count(
+                            self.query_counters.query_counter_of(&cluster_name),
+                        )
                         .await
                         .context(GetClusterQueryCounterSnafu {
                             cluster: &cluster_name,
                         })?;
 
-                    let duration_with_no_queries = last_time_seen_with_queries.elapsed().context(
-                        DetermineDurationWithoutQueriesSnafu {
-                            cluster: &cluster_name,
-                        },
-                    )?;
+                    let duration_with_no_queries = elapsed_allowing_clock_skew(
+                        last_time_seen_with_queries,
+                        self.max_clock_skew,
+                    )
+                    .context(DetermineDurationWithoutQueriesSnafu {
+                        cluster: &cluster_name,
+                    })?;
 
                     if current_query_counter == 0 {
                         if duration_with_no_queries
@@ -523,13 +740,15 @@ impl Scaler {
             ClusterState::Deactivated => ClusterState::Deactivated,
         };
 
-        Ok((cluster_name, current_state))
+        Ok((cluster_name, stored_state, current_state))
     }
 
     #[instrument(name = "Scaler::apply_target_states", skip(self))]
     async fn apply_cluster_target_state(
         self: Arc<Self>,
         cluster: TrinoCluster,
+        cluster_group: String,
+        previous_state: ClusterState,
         target_state: ClusterState,
     ) -> Result<(), Error> {
         let scaler = self.scaler.as_ref().context(ScalerVariableIsNoneSnafu)?;
@@ -541,7 +760,10 @@ impl Scaler {
             ClusterState::Stopped | ClusterState::Terminating => {
                 scaler.deactivate(&cluster.name).await?;
             }
-            ClusterState::Starting | ClusterState::Ready | ClusterState::Draining { .. } => {
+            ClusterState::Starting
+            | ClusterState::WarmingUp { .. }
+            | ClusterState::Ready
+            | ClusterState::Draining { .. } => {
                 scaler.activate(&cluster.name).await?;
             }
             ClusterState::Deactivated => {
@@ -556,6 +778,21 @@ impl Scaler {
                 cluster: &cluster.name,
             })?;
 
+        if let Some(webhook) = &self.cluster_state_webhook {
+            if let Some(state_change) = ClusterStateChange::new(
+                &cluster.name,
+                &cluster_group,
+                &previous_state,
+                &target_state,
+            ) {
+                // Don't block the reconciliation on a slow webhook
+                let webhook = Arc::clone(webhook);
+                tokio::spawn(
+                    async move { webhook.notify(&state_change).await }.instrument(Span::current()),
+                );
+            }
+        }
+
         Ok(())
     }
 
@@ -563,18 +800,75 @@ impl Scaler {
     /// the assumptions is that they will also fail.
     #[instrument(name = "Scaler::set_all_clusters_to_ready", skip(self))]
     async fn set_all_clusters_to_ready(&self) -> Result<(), Error> {
-        for cluster in self.groups.values().flatten() {
-            self.persistence
-                .set_cluster_state(&cluster.name, ClusterState::Ready)
-                .await
-                .context(SetCurrentClusterStateInPersistenceSnafu {
-                    cluster: &cluster.name,
-                })?;
+        let manually_managed_clusters = self.manually_managed_clusters().await?;
+        for cluster in self
+            .groups
+            .values()
+            .flatten()
+            .filter(|cluster| !manually_managed_clusters.contains(&cluster.name))
+        {
+            self.set_cluster_to_ready(&cluster.name).await?;
         }
 
         Ok(())
     }
 
+    /// Sets the cluster to ready, unless it was deactivated, as only the (possible human) operator activates it again.
+    /// Returns the state the cluster is in afterwards.
+    async fn set_cluster_to_ready(
+        &self,
+        cluster: &TrinoClusterName,
+    ) -> Result<ClusterState, Error> {
+        let current_state = self
+            .persistence
+            .get_cluster_state(cluster)
+            .await
+            .context(ReadCurrentClusterStateFromPersistenceSnafu { cluster })?;
+        if current_state == ClusterState::Deactivated {
+            return Ok(current_state);
+        }
+
+        self.persistence
+            .set_cluster_state(cluster, ClusterState::Ready)
+            .await
+            .context(SetCurrentClusterStateInPersistenceSnafu { cluster })?;
+
+        Ok(ClusterState::Ready)
+    }
+
+    /// The scaler does not touch manually managed clusters at all, e.g. because an operator stopped them for debugging.
+    async fn manually_managed_clusters(&self) -> Result<HashSet<TrinoClusterName>, Error> {
+        let manually_managed_clusters = self
+            .persistence
+            .list_manually_managed_clusters()
+            .await
+            .context(ListManuallyManagedClustersSnafu)?;
+        if !manually_managed_clusters.is_empty() {
+            debug!(
+                ?manually_managed_clusters,
+                "Skipping manually managed clusters"
+            );
+        }
+
+        Ok(manually_managed_clusters.into_iter().collect())
+    }
+
+    /// Exposes the number of clusters per state of the given cluster group as metric.
+    fn record_cluster_states<'a>(
+        &self,
+        cluster_group: &str,
+        states: impl IntoIterator<Item = &'a ClusterState>,
+    ) {
+        let mut counts = HashMap::new();
+        for state in states {
+            *counts.entry(state.into()).or_default() += 1;
+        }
+
+        if let Ok(mut scaler_cluster_states) = self.metrics.scaler_cluster_states.write() {
+            scaler_cluster_states.insert(cluster_group.to_owned(), counts);
+        }
+    }
+
     #[instrument(skip(self))]
     fn get_current_min_cluster_count(
         &self,
@@ -595,6 +889,50 @@ impl Scaler {
     }
 }
 
+/// Calculates how much the cluster group is utilized in percent.
+///
+/// The `current_running_queries` contain the `blocked_queries`, which are weighted by `blocked_queries_weight_percentage`
+/// compared to the other queries.
+fn utilization_percent(
+    current_running_queries: u64,
+    blocked_queries: u64,
+    blocked_queries_weight_percentage: u64,
+    max_running_queries: u64,
+) -> u64 {
+    // The blocked queries are read separately from the query counter, so they might not add up exactly.
+    let blocked_queries = blocked_queries.min(current_running_queries);
+    let weighted_queries_percent = 100 * (current_running_queries - blocked_queries)
+        + blocked_queries * blocked_queries_weight_percentage;
+
+    match (max_running_queries, weighted_queries_percent) {
+        // No cluster is ready to accept queries and no queries running
+        (0, 0) => 0,
+        // No cluster is ready to accept queries but there are some queries still running
+        // This means the clusters are even more utilized than they normally should have (although they e.g. can
+        // have some queries running during draining)
+        // So we set the utilization to 100%
+        (0, _) => 100,
+        // We can calculate the percentage normally, it's safe to divide by max_running_queries
+        (_, _) => weighted_queries_percent / max_running_queries,
+    }
+}
+
+/// Picks the first of the given shut down candidates (which are ordered by shut down preference).
+///
+/// We don't want to shut down the last remaining cluster obviously. The exceptions are the case no queries were running
+/// at all, in that case we shut down the unneeded cluster, or the cluster group explicitly allows scaling to zero.
+fn select_cluster_to_shut_down<'a>(
+    shut_down_candidates: &[&'a TrinoCluster],
+    current_running_queries: u64,
+    allow_scale_to_zero: bool,
+) -> Option<&'a TrinoCluster> {
+    if shut_down_candidates.len() > 1 || current_running_queries == 0 || allow_scale_to_zero {
+        shut_down_candidates.first().copied()
+    } else {
+        None
+    }
+}
+
 #[enum_dispatch(ScalerImplementation)]
 pub trait ScalerTrait {
     async fn activate(&self, cluster: &TrinoClusterName) -> Result<(), Error>;
@@ -609,4 +947,458 @@ pub trait ScalerTrait {
 #[enum_dispatch]
 pub enum ScalerImplementation {
     Stackable(StackableScaler),
+    #[cfg(test)]
+    Fake(tests::FakeScaler),
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use trino_lb_core::config::TrinoLbClusterStateWebhookConfig;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    fn cluster(name: &str) -> TrinoCluster {
+        TrinoCluster {
+            name: name.to_owned(),
+            endpoint: "https://trino.example.com".parse().unwrap(),
+            external_endpoint: "https://trino.example.com".parse().unwrap(),
+            overflow: false,
+            weight: 0,
+        }
+    }
+
+    #[rstest]
+    #[case(&["trino-s-2", "trino-s-1"], 5, false, Some("trino-s-2"))]
+    #[case(&["trino-s-2", "trino-s-1"], 5, true, Some("trino-s-2"))]
+    #[case(&["trino-s-1"], 0, false, Some("trino-s-1"))]
+    #[case(&["trino-s-1"], 1, false, None)]
+    #[case(&["trino-s-1"], 1, true, Some("trino-s-1"))]
+    #[case(&[], 0, false, None)]
+    #[case(&[], 0, true, None)]
+    fn test_select_cluster_to_shut_down(
+        #[case] candidates: &[&str],
+        #[case] current_running_queries: u64,
+        #[case] allow_scale_to_zero: bool,
+        #[case] expected: Option<&str>,
+    ) {
+        let candidates = candidates.iter().map(|c| cluster(c)).collect::<Vec<_>>();
+        let candidates = candidates.iter().collect::<Vec<_>>();
+
+        let to_shut_down =
+            select_cluster_to_shut_down(&candidates, current_running_queries, allow_scale_to_zero);
+        assert_eq!(to_shut_down.map(|c| c.name.as_str()), expected);
+    }
+
+    #[rstest]
+    #[case(0, 0, 100, 0, 0)]
+    #[case(5, 0, 100, 0, 100)]
+    #[case(5, 0, 100, 20, 25)]
+    #[case(5, 5, 100, 20, 25)]
+    #[case(5, 5, 0, 20, 0)]
+    #[case(10, 4, 50, 20, 40)]
+    #[case(10, 4, 300, 20, 90)]
+    // More blocked queries than queries in total, e.g. because the counters were read at different times
+    #[case(2, 4, 300, 20, 30)]
+    fn test_utilization_percent(
+        #[case] current_running_queries: u64,
+        #[case] blocked_queries: u64,
+        #[case] blocked_queries_weight_percentage: u64,
+        #[case] max_running_queries: u64,
+        #[case] expected: u64,
+    ) {
+        assert_eq!(
+            utilization_percent(
+                current_running_queries,
+                blocked_queries,
+                blocked_queries_weight_percentage,
+                max_running_queries
+            ),
+            expected
+        );
+    }
+
+    /// Activates clusters instantly, so that they are ready right away.
+    #[derive(Clone, Default)]
+    pub struct FakeScaler {
+        activated: Arc<std::sync::Mutex<HashSet<TrinoClusterName>>>,
+    }
+
+    impl ScalerTrait for FakeScaler {
+        async fn activate(&self, cluster: &TrinoClusterName) -> Result<(), Error> {
+            self.activated.lock().unwrap().insert(cluster.clone());
+            Ok(())
+        }
+
+        async fn deactivate(&self, cluster: &TrinoClusterName) -> Result<(), Error> {
+            self.activated.lock().unwrap().remove(cluster);
+            Ok(())
+        }
+
+        async fn is_activated(&self, cluster: &TrinoClusterName) -> Result<bool, Error> {
+            Ok(self.activated.lock().unwrap().contains(cluster))
+        }
+
+        async fn is_ready(&self, cluster: &TrinoClusterName) -> Result<bool, Error> {
+            self.is_activated(cluster).await
+        }
+    }
+
+    /// Creates a [`Scaler`] for the cluster group "s" without autoscaling and the cluster group "a", which is
+    /// autoscaled by the returned [`FakeScaler`] and needs at least one cluster.
+    async fn scaler() -> (Arc<PersistenceImplementation>, Arc<Scaler>, FakeScaler) {
+        scaler_with(None, Duration::ZERO).await
+    }
+
+    async fn scaler_with(
+        cluster_state_webhook: Option<ClusterStateWebhook>,
+        ready_grace_period: Duration,
+    ) -> (Arc<PersistenceImplementation>, Arc<Scaler>, FakeScaler) {
+        let config = TestConfigBuilder::new()
+            .cluster_group(
+                "s",
+                1,
+                &[
+                    ("trino-s-1", "https://trino-s-1:8443"),
+                    ("trino-s-2", "https://trino-s-2:8443"),
+                ],
+            )
+            .cluster_group_yaml(
+                "a",
+                r#"
+maxRunningQueries: 1
+autoscaling:
+  upscaleQueuedQueriesThreshold: 1
+  downscaleRunningQueriesPercentageThreshold: 70
+  drainIdleDurationBeforeShutdown: 60s
+  minClusters:
+    - timeUtc: 00:00:00 - 23:59:59
+      weekdays: Mon - Son
+      min: 1
+trinoClusters:
+  - name: trino-a-1
+    endpoint: https://trino-a-1:8443
+    credentials: {username: admin, password: admin}
+  - name: trino-a-2
+    endpoint: https://trino-a-2:8443
+    credentials: {username: admin, password: admin}
+"#,
+            )
+            .build();
+        let persistence: Arc<PersistenceImplementation> =
+            Arc::new(trino_lb_persistence::in_memory::InMemoryPersistence::default().into());
+        let metrics = Arc::new(
+            Metrics::new(
+                prometheus::Registry::new(),
+                Arc::clone(&persistence),
+                &config,
+            )
+            .unwrap(),
+        );
+        // No clusterAutoscaler is configured, so that we can plug in the fake one
+        let max_running_queries =
+            crate::max_running_queries::of_cluster_groups(&config.trino_cluster_groups).unwrap();
+        let mut scaler = Scaler::new(
+            &config,
+            Arc::clone(&persistence),
+            &max_running_queries,
+            metrics,
+        )
+        .await
+        .unwrap();
+        let fake_scaler = FakeScaler::default();
+        scaler.scaler = Some(fake_scaler.clone().into());
+        let mut scaling_config: TrinoClusterGroupAutoscaling = config.trino_cluster_groups["a"]
+            .autoscaling
+            .clone()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        scaling_config.ready_grace_period = ready_grace_period;
+        scaler.scaling_config.insert("a".to_owned(), scaling_config);
+        scaler.cluster_state_webhook = cluster_state_webhook.map(Arc::new);
+
+        (persistence, Arc::new(scaler), fake_scaler)
+    }
+
+    #[tokio::test]
+    async fn test_skip_manually_managed_clusters() {
+        let (persistence, scaler, fake_scaler) = scaler().await;
+
+        // An operator stopped the clusters for debugging
+        let manually_managed = "trino-s-2".to_owned();
+        let manually_managed_autoscaled = "trino-a-1".to_owned();
+        for cluster in [&manually_managed, &manually_managed_autoscaled] {
+            persistence
+                .set_cluster_state(cluster, ClusterState::Stopped)
+                .await
+                .unwrap();
+            persistence
+                .set_cluster_manually_managed(cluster, true)
+                .await
+                .unwrap();
+        }
+
+        // The next cluster is started to satisfy the minimum number of clusters instead
+        Arc::clone(&scaler)
+            .reconcile_cluster_group("a".to_owned(), scaler.groups["a"].clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence
+                .get_cluster_state(&manually_managed_autoscaled)
+                .await
+                .unwrap(),
+            ClusterState::Stopped
+        );
+        assert_eq!(
+            persistence
+                .get_cluster_state(&"trino-a-2".to_owned())
+                .await
+                .unwrap(),
+            ClusterState::Starting
+        );
+        assert_eq!(
+            *fake_scaler.activated.lock().unwrap(),
+            HashSet::from(["trino-a-2".to_owned()])
+        );
+
+        scaler.set_all_clusters_to_ready().await.unwrap();
+        Arc::clone(&scaler)
+            .reconcile_cluster_group("s".to_owned(), scaler.groups["s"].clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence
+                .get_cluster_state(&"trino-s-1".to_owned())
+                .await
+                .unwrap(),
+            ClusterState::Ready
+        );
+        assert_eq!(
+            persistence
+                .get_cluster_state(&manually_managed)
+                .await
+                .unwrap(),
+            ClusterState::Stopped
+        );
+
+        // Once handed back, the scaler takes care of the cluster again
+        persistence
+            .set_cluster_manually_managed(&manually_managed, false)
+            .await
+            .unwrap();
+        scaler.set_all_clusters_to_ready().await.unwrap();
+        assert_eq!(
+            persistence
+                .get_cluster_state(&manually_managed)
+                .await
+                .unwrap(),
+            ClusterState::Ready
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_deactivated_clusters() {
+        let (persistence, scaler, _) = scaler().await;
+
+        // Only the operator activates the cluster again
+        let deactivated = "trino-s-2".to_owned();
+        persistence
+            .set_cluster_state(&deactivated, ClusterState::Deactivated)
+            .await
+            .unwrap();
+
+        scaler.set_all_clusters_to_ready().await.unwrap();
+        Arc::clone(&scaler)
+            .reconcile_cluster_group("s".to_owned(), scaler.groups["s"].clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence
+                .get_cluster_state(&"trino-s-1".to_owned())
+                .await
+                .unwrap(),
+            ClusterState::Ready
+        );
+        assert_eq!(
+            persistence.get_cluster_state(&deactivated).await.unwrap(),
+            ClusterState::Deactivated
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_target_states() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(
+                move |axum::Json(payload): axum::Json<serde_json::Value>| async move {
+                    sender.send(payload).unwrap();
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let webhook = ClusterStateWebhook::new(
+            &TrinoLbClusterStateWebhookConfig {
+                url: url.parse().unwrap(),
+                timeout: Duration::from_secs(5),
+            },
+            "trino-lb/test",
+        )
+        .unwrap();
+        let (persistence, scaler, fake_scaler) = scaler_with(Some(webhook), Duration::ZERO).await;
+
+        for cluster in ["trino-a-1", "trino-a-2"] {
+            persistence
+                .set_cluster_state(&cluster.to_owned(), ClusterState::Stopped)
+                .await
+                .unwrap();
+        }
+
+        // The first cluster is started to satisfy the minimum number of clusters
+        Arc::clone(&scaler)
+            .reconcile_cluster_group("a".to_owned(), scaler.groups["a"].clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence
+                .get_cluster_state(&"trino-a-1".to_owned())
+                .await
+                .unwrap(),
+            ClusterState::Starting
+        );
+        assert_eq!(
+            persistence
+                .get_cluster_state(&"trino-a-2".to_owned())
+                .await
+                .unwrap(),
+            ClusterState::Stopped
+        );
+        assert_eq!(
+            *fake_scaler.activated.lock().unwrap(),
+            HashSet::from(["trino-a-1".to_owned()])
+        );
+
+        // Only the cluster that changed its state is sent to the webhook, with the state stored before
+        let payload = receiver.recv().await.unwrap();
+        assert_eq!(payload["cluster"], "trino-a-1");
+        assert_eq!(payload["clusterGroup"], "a");
+        assert_eq!(payload["oldState"], "Stopped");
+        assert_eq!(payload["newState"], "Starting");
+
+        // The fake cluster is ready right away, so the next reconciliation moves it on
+        Arc::clone(&scaler)
+            .reconcile_cluster_group("a".to_owned(), scaler.groups["a"].clone())
+            .await
+            .unwrap();
+        let payload = receiver.recv().await.unwrap();
+        assert_eq!(payload["cluster"], "trino-a-1");
+        assert_eq!(payload["oldState"], "Starting");
+        assert_eq!(payload["newState"], "Ready");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_started_cluster() {
+        let ready_grace_period = Duration::from_secs(60 * 60);
+        let (persistence, scaler, _) = scaler_with(None, ready_grace_period).await;
+        let cluster = "trino-a-1".to_owned();
+        for cluster in [&cluster, &"trino-a-2".to_owned()] {
+            persistence
+                .set_cluster_state(cluster, ClusterState::Stopped)
+                .await
+                .unwrap();
+        }
+        let reconcile = || {
+            Arc::clone(&scaler).reconcile_cluster_group("a".to_owned(), scaler.groups["a"].clone())
+        };
+
+        // The first cluster is started to satisfy the minimum number of clusters
+        reconcile().await.unwrap();
+        assert_eq!(
+            persistence.get_cluster_state(&cluster).await.unwrap(),
+            ClusterState::Starting
+        );
+
+        // The fake cluster is ready right away, but does not get queries during the grace period
+        reconcile().await.unwrap();
+        let ClusterState::WarmingUp { ready_since } =
+            persistence.get_cluster_state(&cluster).await.unwrap()
+        else {
+            panic!("Expected the cluster to warm up");
+        };
+
+        // It is not shut down, even though the cluster group is not utilized at all
+        reconcile().await.unwrap();
+        assert_eq!(
+            persistence.get_cluster_state(&cluster).await.unwrap(),
+            ClusterState::WarmingUp { ready_since }
+        );
+
+        // Once the grace period is over, the cluster is ready
+        persistence
+            .set_cluster_state(
+                &cluster,
+                ClusterState::WarmingUp {
+                    ready_since: SystemTime::now() - ready_grace_period,
+                },
+            )
+            .await
+            .unwrap();
+        reconcile().await.unwrap();
+        assert_eq!(
+            persistence.get_cluster_state(&cluster).await.unwrap(),
+            ClusterState::Ready
+        );
+    }
+
+    #[tokio::test]
+    async fn test_terminate_warming_up_cluster_on_scale_down() {
+        let (persistence, scaler, fake_scaler) =
+            scaler_with(None, Duration::from_secs(60 * 60)).await;
+        let ready = "trino-a-1".to_owned();
+        let warming_up = "trino-a-2".to_owned();
+        persistence
+            .set_cluster_state(&ready, ClusterState::Ready)
+            .await
+            .unwrap();
+        persistence
+            .set_cluster_state(
+                &warming_up,
+                ClusterState::WarmingUp {
+                    ready_since: SystemTime::now(),
+                },
+            )
+            .await
+            .unwrap();
+        fake_scaler
+            .activated
+            .lock()
+            .unwrap()
+            .extend([ready.clone(), warming_up.clone()]);
+
+        // No queries are queued or running anymore, so the cluster that did not get any queries yet is terminated
+        // right away instead of draining the ready cluster
+        Arc::clone(&scaler)
+            .reconcile_cluster_group("a".to_owned(), scaler.groups["a"].clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence.get_cluster_state(&ready).await.unwrap(),
+            ClusterState::Ready
+        );
+        assert_eq!(
+            persistence.get_cluster_state(&warming_up).await.unwrap(),
+            ClusterState::Terminating
+        );
+        assert_eq!(
+            *fake_scaler.activated.lock().unwrap(),
+            HashSet::from([ready])
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, sync::Mutex, time::Duration};
 
 use kube::{
     api::{Patch, PatchParams},
@@ -7,16 +7,28 @@ use kube::{
 };
 use serde_json::Value;
 use snafu::{OptionExt, ResultExt, Snafu};
-use tracing::{debug_span, instrument, Instrument};
+use tokio::time::Instant;
+use tracing::{debug_span, info, instrument, warn, Instrument};
 use trino_lb_core::{
-    config::{StackableScalerConfig, TrinoClusterGroupConfig},
+    config::{
+        StackableGracefulShutdownConfig, StackableScalerConfig, TrinoClusterConfig,
+        TrinoClusterGroupConfig,
+    },
     TrinoClusterName,
 };
 
+use crate::trino_client::{get_cluster_info, request_graceful_shutdown};
+
 use super::ScalerTrait;
 
 const K8S_FIELD_MANAGER: &str = "trino-lb";
 
+/// How often Kubernetes API calls are attempted in case they fail with a transient error.
+const K8S_API_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a Kubernetes API call, which doubles for every further retry.
+const K8S_API_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
 #[derive(Snafu, Debug)]
 pub enum Error {
     #[snafu(display("Failed to create Kubernetes client"))]
@@ -125,6 +137,20 @@ pub enum Error {
 
 pub struct StackableScaler {
     clusters: HashMap<String, StackableTrinoCluster>,
+
+    graceful_shutdown: Option<StackableGracefulShutdown>,
+}
+
+struct StackableGracefulShutdown {
+    config: StackableGracefulShutdownConfig,
+    trino_clusters: HashMap<TrinoClusterName, TrinoClusterConfig>,
+    ignore_certs: bool,
+    user_agent: String,
+
+    /// The point in time we asked the Trino clusters to shut down gracefully.
+    /// As the scaler runs on a single trino-lb instance at a time, it's fine to keep this in memory. In the worst case
+    /// the graceful shutdown is requested a second time by a different trino-lb instance.
+    requested: Mutex<HashMap<TrinoClusterName, Instant>>,
 }
 
 struct StackableTrinoCluster {
@@ -137,11 +163,54 @@ struct StackableTrinoCluster {
     api: Api<DynamicObject>,
 }
 
+impl StackableGracefulShutdown {
+    /// Returns if the graceful shutdown that was `requested` at the given point in time is finished, in which case the
+    /// request is forgotten.
+    async fn finished_since(
+        &self,
+        cluster_name: &TrinoClusterName,
+        trino_cluster: &TrinoClusterConfig,
+        requested: Instant,
+    ) -> bool {
+        let finished = if requested.elapsed() >= self.config.timeout {
+            warn!(
+                cluster = cluster_name,
+                timeout = ?self.config.timeout,
+                "StackableScaler: Graceful shutdown did not finish in time, stopping the cluster"
+            );
+            true
+        } else {
+            match get_cluster_info(
+                trino_cluster.internal_endpoint(),
+                self.ignore_certs,
+                &trino_cluster.credentials,
+                &self.user_agent,
+            )
+            .await
+            {
+                Ok(cluster_info) => cluster_info.query_count() == 0,
+                // Trino terminates once all queries are finished
+                Err(_) => true,
+            }
+        };
+
+        if finished {
+            if let Ok(mut requested) = self.requested.lock() {
+                requested.remove(cluster_name);
+            }
+        }
+
+        finished
+    }
+}
+
 impl StackableScaler {
     #[instrument(name = "StackableScaler::new")]
     pub async fn new(
         config: &StackableScalerConfig,
         trino_cluster_groups: &HashMap<String, TrinoClusterGroupConfig>,
+        ignore_certs: bool,
+        user_agent: &str,
     ) -> Result<Self, Error> {
         let client = Client::try_default().await.context(CreateClientSnafu)?;
         let discovery = Discovery::new(client.clone())
@@ -208,7 +277,82 @@ impl StackableScaler {
             );
         }
 
-        Ok(StackableScaler { clusters })
+        let graceful_shutdown =
+            config
+                .graceful_shutdown
+                .as_ref()
+                .map(|graceful_shutdown| StackableGracefulShutdown {
+                    config: graceful_shutdown.clone(),
+                    trino_clusters: trino_cluster_groups
+                        .values()
+                        .flat_map(|g| &g.trino_clusters)
+                        .map(|c| (c.name.clone(), c.clone()))
+                        .collect(),
+                    ignore_certs,
+                    user_agent: user_agent.to_owned(),
+                    requested: Mutex::default(),
+                });
+
+        Ok(StackableScaler {
+            clusters,
+            graceful_shutdown,
+        })
+    }
+
+    /// Returns if the cluster can be stopped. In case the graceful shutdown was not requested yet, this asks Trino to
+    /// shut down gracefully. Afterwards the cluster can be stopped once Trino has no queries left, is not reachable any
+    /// more or the graceful shutdown timeout is reached.
+    #[instrument(skip(self, graceful_shutdown))]
+    async fn graceful_shutdown_finished(
+        &self,
+        graceful_shutdown: &StackableGracefulShutdown,
+        cluster_name: &TrinoClusterName,
+    ) -> Result<bool, super::Error> {
+        let Some(trino_cluster) = graceful_shutdown.trino_clusters.get(cluster_name) else {
+            return Ok(true);
+        };
+
+        let requested = graceful_shutdown
+            .requested
+            .lock()
+            .ok()
+            .and_then(|requested| requested.get(cluster_name).copied());
+
+        let Some(requested) = requested else {
+            // The scaler also deactivates clusters that are already stopped, there is nothing to shut down.
+            if !self.is_activated(cluster_name).await? {
+                return Ok(true);
+            }
+
+            if let Err(err) = request_graceful_shutdown(
+                trino_cluster.internal_endpoint(),
+                graceful_shutdown.ignore_certs,
+                &trino_cluster.credentials,
+                &graceful_shutdown.user_agent,
+            )
+            .await
+            {
+                warn!(
+                    cluster = cluster_name,
+                    ?err,
+                    "StackableScaler: Failed to request graceful shutdown, stopping the cluster right away"
+                );
+                return Ok(true);
+            }
+
+            info!(
+                cluster = cluster_name,
+                "StackableScaler: Requested graceful shutdown"
+            );
+            if let Ok(mut requested) = graceful_shutdown.requested.lock() {
+                requested.insert(cluster_name.to_owned(), Instant::now());
+            }
+            return Ok(false);
+        };
+
+        Ok(graceful_shutdown
+            .finished_since(cluster_name, trino_cluster, requested)
+            .await)
     }
 
     #[instrument(skip(self))]
@@ -233,15 +377,15 @@ impl StackableScaler {
             .force();
         let patch = Patch::Apply(&patch);
 
-        cluster
-            .api
-            .patch(&cluster.name, &params, &patch)
-            .instrument(debug_span!("Patching Trino cluster"))
-            .await
-            .context(PatchTrinoClusterSnafu {
-                cluster: &cluster.name,
-                namespace: &cluster.namespace,
-            })?;
+        retry_transient_errors(K8S_API_RETRY_BACKOFF, || {
+            cluster.api.patch(&cluster.name, &params, &patch)
+        })
+        .instrument(debug_span!("Patching Trino cluster"))
+        .await
+        .context(PatchTrinoClusterSnafu {
+            cluster: &cluster.name,
+            namespace: &cluster.namespace,
+        })?;
 
         Ok(())
     }
@@ -250,11 +394,27 @@ impl StackableScaler {
 impl ScalerTrait for StackableScaler {
     #[instrument(name = "StackableScaler::activate", skip(self))]
     async fn activate(&self, cluster: &TrinoClusterName) -> Result<(), super::Error> {
+        if let Some(graceful_shutdown) = &self.graceful_shutdown {
+            if let Ok(mut requested) = graceful_shutdown.requested.lock() {
+                requested.remove(cluster);
+            }
+        }
+
         Ok(self.set_activation(cluster, true).await?)
     }
 
     #[instrument(name = "StackableScaler::deactivate", skip(self))]
     async fn deactivate(&self, cluster: &TrinoClusterName) -> Result<(), super::Error> {
+        if let Some(graceful_shutdown) = &self.graceful_shutdown {
+            if !self
+                .graceful_shutdown_finished(graceful_shutdown, cluster)
+                .await?
+            {
+                // Trino is still finishing the running queries, we check again during the next reconciliation.
+                return Ok(());
+            }
+        }
+
         Ok(self.set_activation(cluster, false).await?)
     }
 
@@ -265,15 +425,15 @@ impl ScalerTrait for StackableScaler {
             .get(cluster)
             .context(ClusterNotFoundSnafu { cluster })?;
 
-        let status = cluster
-            .api
-            .get_status(&cluster.name)
-            .instrument(debug_span!("Get Trino cluster status"))
-            .await
-            .context(GetTrinoClusterStatusSnafu {
-                cluster: &cluster.name,
-                namespace: &cluster.namespace,
-            })?;
+        let status = retry_transient_errors(K8S_API_RETRY_BACKOFF, || {
+            cluster.api.get_status(&cluster.name)
+        })
+        .instrument(debug_span!("Get Trino cluster status"))
+        .await
+        .context(GetTrinoClusterStatusSnafu {
+            cluster: &cluster.name,
+            namespace: &cluster.namespace,
+        })?;
 
         // I would prefer switching to using https://docs.rs/k8s-openapi/latest/k8s_openapi/apimachinery/pkg/apis/meta/v1/struct.Condition.html
         // for parsing. Sadly the Stackable Condition is not compatible with the k8s-openapi Condition struct, so I
@@ -333,15 +493,14 @@ impl ScalerTrait for StackableScaler {
             .get(cluster)
             .context(ClusterNotFoundSnafu { cluster })?;
 
-        let stackable_cluster = cluster
-            .api
-            .get(&cluster.name)
-            .instrument(debug_span!("Getting Trino cluster"))
-            .await
-            .context(ReadTrinoClusterSnafu {
-                cluster: &cluster.name,
-                namespace: &cluster.namespace,
-            })?;
+        let stackable_cluster =
+            retry_transient_errors(K8S_API_RETRY_BACKOFF, || cluster.api.get(&cluster.name))
+                .instrument(debug_span!("Getting Trino cluster"))
+                .await
+                .context(ReadTrinoClusterSnafu {
+                    cluster: &cluster.name,
+                    namespace: &cluster.namespace,
+                })?;
 
         Ok(!stackable_cluster
             .data
@@ -355,3 +514,215 @@ impl ScalerTrait for StackableScaler {
             })?)
     }
 }
+
+/// Retries the given Kubernetes API call with an exponential backoff in case it fails with a transient error, e.g.
+/// because the API server is briefly unavailable during a control plane upgrade. Other errors (such as `404 Not Found`
+/// or `409 Conflict`) are returned right away.
+async fn retry_transient_errors<T, F, Fut>(backoff: Duration, mut call: F) -> Result<T, kube::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, kube::Error>>,
+{
+    let mut backoff = backoff;
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Err(err) if attempt < K8S_API_ATTEMPTS && is_transient_error(&err) => {
+                warn!(
+                    ?err,
+                    attempt,
+                    ?backoff,
+                    "StackableScaler: Kubernetes API call failed with a transient error, retrying"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient_error(err: &kube::Error) -> bool {
+    match err {
+        kube::Error::Api(response) => response.code >= 500 || response.code == 429,
+        // Connection problems and timeouts
+        kube::Error::HyperError(_) | kube::Error::Service(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use axum::{
+        routing::{get, post},
+        Json,
+    };
+    use kube::core::ErrorResponse;
+    use rstest::rstest;
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    use super::*;
+    use crate::test_config::TestConfigBuilder;
+
+    /// Starts a fake Trino web UI, which reports the given number of running queries.
+    async fn start_fake_trino_ui(running_queries: u64) -> Url {
+        let app = axum::Router::new()
+            .route("/ui/login", post(|| async {}))
+            .route(
+                "/ui/api/stats",
+                get(move || async move {
+                    Json(serde_json::json!({
+                        "runningQueries": running_queries,
+                        "blockedQueries": 0,
+                        "queuedQueries": 0,
+                        "activeCoordinators": 1,
+                        "activeWorkers": 1,
+                        "runningDrivers": 0,
+                        "totalAvailableProcessors": 4,
+                        "reservedMemory": 0.0,
+                        "totalInputRows": 0,
+                        "totalInputBytes": 0,
+                        "totalCpuTimeSecs": 0,
+                    }))
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        endpoint
+    }
+
+    /// Returns the graceful shutdown of a single cluster, which was requested just now.
+    fn graceful_shutdown(endpoint: Url, timeout: Duration) -> StackableGracefulShutdown {
+        let cluster = TestConfigBuilder::new()
+            .cluster_group("s", 1, &[("trino-s-1", endpoint.as_str())])
+            .build()
+            .trino_cluster_groups
+            .remove("s")
+            .unwrap()
+            .trino_clusters
+            .remove(0);
+
+        StackableGracefulShutdown {
+            config: StackableGracefulShutdownConfig { timeout },
+            trino_clusters: [(cluster.name.clone(), cluster)].into(),
+            ignore_certs: false,
+            user_agent: "trino-lb".to_owned(),
+            requested: Mutex::new([("trino-s-1".to_owned(), Instant::now())].into()),
+        }
+    }
+
+    async fn finished(graceful_shutdown: &StackableGracefulShutdown) -> bool {
+        let cluster_name = "trino-s-1".to_owned();
+        let trino_cluster = &graceful_shutdown.trino_clusters[&cluster_name];
+        let requested = graceful_shutdown.requested.lock().unwrap()[&cluster_name];
+
+        graceful_shutdown
+            .finished_since(&cluster_name, trino_cluster, requested)
+            .await
+    }
+
+    fn still_requested(graceful_shutdown: &StackableGracefulShutdown) -> bool {
+        graceful_shutdown
+            .requested
+            .lock()
+            .unwrap()
+            .contains_key("trino-s-1")
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_still_running() {
+        let endpoint = start_fake_trino_ui(2).await;
+        let graceful_shutdown = graceful_shutdown(endpoint, Duration::from_secs(60 * 60));
+
+        assert!(!finished(&graceful_shutdown).await);
+        assert!(still_requested(&graceful_shutdown));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_finished() {
+        let endpoint = start_fake_trino_ui(0).await;
+        let graceful_shutdown = graceful_shutdown(endpoint, Duration::from_secs(60 * 60));
+
+        assert!(finished(&graceful_shutdown).await);
+        assert!(!still_requested(&graceful_shutdown));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_timeout() {
+        // Trino still has running queries, but we don't wait for them any longer
+        let endpoint = start_fake_trino_ui(2).await;
+        let graceful_shutdown = graceful_shutdown(endpoint, Duration::ZERO);
+
+        assert!(finished(&graceful_shutdown).await);
+        assert!(!still_requested(&graceful_shutdown));
+    }
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".to_owned(),
+            message: "test".to_owned(),
+            reason: "test".to_owned(),
+            code,
+        })
+    }
+
+    #[rstest]
+    #[case(500, true)]
+    #[case(503, true)]
+    #[case(429, true)]
+    #[case(404, false)]
+    #[case(409, false)]
+    #[case(403, false)]
+    fn test_is_transient_error(#[case] code: u16, #[case] expected: bool) {
+        assert_eq!(is_transient_error(&api_error(code)), expected);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors_succeeds_after_transient_error() {
+        let calls = AtomicU32::new(0);
+        let result = retry_transient_errors(Duration::ZERO, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(api_error(503)),
+                _ => Ok(42),
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors_gives_up() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_transient_errors(Duration::ZERO, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(api_error(503))
+        })
+        .await;
+
+        assert!(matches!(result, Err(kube::Error::Api(response)) if response.code == 503));
+        assert_eq!(calls.load(Ordering::SeqCst), K8S_API_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors_does_not_retry_permanent_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_transient_errors(Duration::ZERO, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(api_error(404))
+        })
+        .await;
+
+        assert!(matches!(result, Err(kube::Error::Api(response)) if response.code == 404));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,181 @@
+use chrono::Utc;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use tracing::{debug, instrument, warn};
+use trino_lb_core::{
+    config::TrinoLbClusterStateWebhookConfig, trino_cluster::ClusterState, TrinoClusterName,
+};
+use url::Url;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to create HTTP client"))]
+    CreateHttpClient { source: reqwest::Error },
+}
+
+/// Notifies an external system (e.g. Slack or PagerDuty) about the state changes of Trino clusters.
+pub struct ClusterStateWebhook {
+    url: Url,
+    http_client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterStateChange {
+    pub cluster: TrinoClusterName,
+    pub cluster_group: String,
+    pub old_state: &'static str,
+    pub new_state: &'static str,
+    /// RFC 3339 timestamp of the state change.
+    pub timestamp: String,
+}
+
+impl ClusterStateChange {
+    /// Returns [`None`] in case the state did not change. Only the kind of state is compared, so that e.g. a draining
+    /// cluster does not trigger a notification every time it is seen with queries.
+    pub fn new(
+        cluster: &TrinoClusterName,
+        cluster_group: &str,
+        old_state: &ClusterState,
+        new_state: &ClusterState,
+    ) -> Option<Self> {
+        let old_state: &'static str = old_state.into();
+        let new_state: &'static str = new_state.into();
+        if old_state == new_state {
+            return None;
+        }
+
+        Some(Self {
+            cluster: cluster.clone(),
+            cluster_group: cluster_group.to_owned(),
+            old_state,
+            new_state,
+            timestamp: Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+impl ClusterStateWebhook {
+    pub fn new(config: &TrinoLbClusterStateWebhookConfig, user_agent: &str) -> Result<Self, Error> {
+        let http_client = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(config.timeout)
+            .build()
+            .context(CreateHttpClientSnafu)?;
+
+        Ok(Self {
+            url: config.url.clone(),
+            http_client,
+        })
+    }
+
+    /// Failures are only logged, as the webhook must not interfere with the scaling.
+    #[instrument(skip(self))]
+    pub async fn notify(&self, state_change: &ClusterStateChange) {
+        let result = self
+            .http_client
+            .post(self.url.clone())
+            .json(state_change)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => debug!("Sent cluster state change to webhook"),
+            Err(error) => warn!(?error, "Failed to send cluster state change to webhook"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration, time::SystemTime};
+
+    use axum::{routing::post, Json};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    #[test]
+    fn test_identical_states_are_debounced() {
+        let cluster = "trino-s-1".to_owned();
+        let draining = |secs| ClusterState::Draining {
+            last_time_seen_with_queries: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        };
+
+        assert!(ClusterStateChange::new(&cluster, "s", &draining(1), &draining(2)).is_none());
+        assert!(
+            ClusterStateChange::new(&cluster, "s", &ClusterState::Ready, &ClusterState::Ready)
+                .is_none()
+        );
+
+        let state_change =
+            ClusterStateChange::new(&cluster, "s", &ClusterState::Ready, &draining(1)).unwrap();
+        assert_eq!(state_change.old_state, "Ready");
+        assert_eq!(state_change.new_state, "Draining");
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let sender = Arc::new(sender);
+        let app = axum::Router::new().route(
+            "/hook",
+            post(move |Json(payload): Json<serde_json::Value>| async move {
+                sender.send(payload).unwrap();
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook = ClusterStateWebhook::new(
+            &TrinoLbClusterStateWebhookConfig {
+                url: url.parse().unwrap(),
+                timeout: Duration::from_secs(5),
+            },
+            "trino-lb/test",
+        )
+        .unwrap();
+        let state_change = ClusterStateChange::new(
+            &"trino-s-1".to_owned(),
+            "s",
+            &ClusterState::Terminating,
+            &ClusterState::Stopped,
+        )
+        .unwrap();
+        webhook.notify(&state_change).await;
+
+        let payload = receiver.recv().await.unwrap();
+        assert_eq!(payload["cluster"], "trino-s-1");
+        assert_eq!(payload["clusterGroup"], "s");
+        assert_eq!(payload["oldState"], "Terminating");
+        assert_eq!(payload["newState"], "Stopped");
+        assert_eq!(payload["timestamp"], state_change.timestamp);
+    }
+
+    #[tokio::test]
+    async fn test_notify_failure_is_not_fatal() {
+        // Nobody is listening on the port anymore
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+
+        let webhook = ClusterStateWebhook::new(
+            &TrinoLbClusterStateWebhookConfig {
+                url: url.parse().unwrap(),
+                timeout: Duration::from_secs(5),
+            },
+            "trino-lb/test",
+        )
+        .unwrap();
+        let state_change = ClusterStateChange::new(
+            &"trino-s-1".to_owned(),
+            "s",
+            &ClusterState::Stopped,
+            &ClusterState::Starting,
+        )
+        .unwrap();
+        webhook.notify(&state_change).await;
+    }
+}
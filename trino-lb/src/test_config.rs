@@ -0,0 +1,117 @@
+use trino_lb_core::config::Config;
+
+/// Builds the [`Config`] used by tests, so that they only need to specify the parts they actually care about. It uses
+/// the in-memory persistence, and falls back to the cluster group "s" without any routers.
+///
+/// Everything that is not covered by a dedicated function is passed as YAML, the same way it would be written in the
+/// config file. The YAML snippets are indented automatically, so they should start at column zero.
+pub struct TestConfigBuilder {
+    external_address: String,
+    trino_lb: Vec<String>,
+    cluster_groups: Vec<String>,
+    routing: String,
+}
+
+impl Default for TestConfigBuilder {
+    fn default() -> Self {
+        Self {
+            external_address: "https://trino-lb.example.com:8443".to_owned(),
+            trino_lb: Vec::new(),
+            cluster_groups: Vec::new(),
+            routing: "routers: []\nroutingFallback: s".to_owned(),
+        }
+    }
+}
+
+impl TestConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn external_address(mut self, external_address: &str) -> Self {
+        external_address.clone_into(&mut self.external_address);
+        self
+    }
+
+    /// Adds settings below `trinoLb`, e.g. `minAdmissionSequence: 2`.
+    pub fn trino_lb(mut self, yaml: &str) -> Self {
+        self.trino_lb.push(indent(yaml, 2));
+        self
+    }
+
+    /// Adds a cluster group with the given `(name, endpoint)` clusters, which all use the credentials `admin:admin`.
+    pub fn cluster_group(
+        self,
+        name: &str,
+        max_running_queries: u64,
+        clusters: &[(&str, &str)],
+    ) -> Self {
+        let clusters = clusters
+            .iter()
+            .map(|(name, endpoint)| {
+                format!(
+                    "{{name: {name}, endpoint: {endpoint}, credentials: {{username: admin, password: admin}}}}"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        self.cluster_group_yaml(
+            name,
+            &format!("maxRunningQueries: {max_running_queries}\ntrinoClusters: [{clusters}]"),
+        )
+    }
+
+    /// Adds a cluster group, which is fully specified by the given YAML, e.g. because it uses autoscaling.
+    pub fn cluster_group_yaml(mut self, name: &str, yaml: &str) -> Self {
+        self.cluster_groups
+            .push(format!("  {name}:\n{}", indent(yaml, 4)));
+        self
+    }
+
+    /// Replaces the routing, which defaults to `routers: []` and `routingFallback: s`.
+    pub fn routing(mut self, yaml: &str) -> Self {
+        yaml.clone_into(&mut self.routing);
+        self
+    }
+
+    pub fn build(self) -> Config {
+        let Self {
+            external_address,
+            trino_lb,
+            cluster_groups,
+            routing,
+        } = self;
+        let trino_lb = trino_lb.join("\n");
+        let cluster_groups = if cluster_groups.is_empty() {
+            "trinoClusterGroups: {}".to_owned()
+        } else {
+            format!("trinoClusterGroups:\n{}", cluster_groups.join("\n"))
+        };
+
+        let config = format!(
+            r#"
+trinoLb:
+  externalAddress: {external_address}
+  persistence:
+    inMemory: {{}}
+{trino_lb}
+{cluster_groups}
+{routing}
+"#
+        );
+        serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(
+            &config,
+        ))
+        .unwrap_or_else(|error| panic!("Invalid test config {config}: {error}"))
+    }
+}
+
+fn indent(yaml: &str, spaces: usize) -> String {
+    let indentation = " ".repeat(spaces);
+    yaml.trim_matches('\n')
+        .lines()
+        .map(|line| format!("{indentation}{line}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
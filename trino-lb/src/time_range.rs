@@ -0,0 +1,75 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Timelike, Utc};
+use regex::Regex;
+use snafu::{OptionExt, Snafu};
+
+static TIME_RANGE_REGEX: OnceLock<Regex> = OnceLock::new();
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display(
+        "Time range {time_range:?} can not be parsed. It needs to have the format \"09:00:00 - 11:59:59\""
+    ))]
+    InvalidTimeRange { time_range: String },
+
+    #[snafu(display("Any weekdays other tha \"Mon - Son\" are not supported yet"))]
+    WeekdaysNotSupportedYet {},
+}
+
+/// A daily time range (in UTC), as configured e.g. for `minClusters`.
+#[derive(Clone, Debug)]
+pub struct TimeRange {
+    time_start_hour: u32,
+    time_start_minute: u32,
+    time_start_second: u32,
+    time_end_hour: u32,
+    time_end_minute: u32,
+    time_end_second: u32,
+}
+
+impl TimeRange {
+    pub fn new(time_utc: &str, weekdays: &str) -> Result<Self, Error> {
+        let time_range_regex = TIME_RANGE_REGEX.get_or_init(|| {
+            Regex::new(
+                r"^([0-9][0-9]):([0-9][0-9]):([0-9][0-9]) - ([0-9][0-9]):([0-9][0-9]):([0-9][0-9])$",
+            )
+            .unwrap()
+        });
+
+        let time_captures = time_range_regex
+            .captures(time_utc)
+            .context(InvalidTimeRangeSnafu {
+                time_range: time_utc,
+            })?;
+
+        if weekdays != "Mon - Son" {
+            WeekdaysNotSupportedYetSnafu.fail()?;
+        }
+
+        Ok(TimeRange {
+            // Safety: The array access and digit parsing can not fail as of the regex content
+            time_start_hour: time_captures[1].parse().unwrap(),
+            time_start_minute: time_captures[2].parse().unwrap(),
+            time_start_second: time_captures[3].parse().unwrap(),
+            time_end_hour: time_captures[4].parse().unwrap(),
+            time_end_minute: time_captures[5].parse().unwrap(),
+            time_end_second: time_captures[6].parse().unwrap(),
+        })
+    }
+
+    /// Both start and end are inclusive. In case the end is before the start (e.g. `22:00:00 - 05:59:59`), the time
+    /// range crosses midnight.
+    pub fn date_is_in_range(&self, date: &DateTime<Utc>) -> bool {
+        let date = date.num_seconds_from_midnight();
+        let start =
+            self.time_start_hour * 60 * 60 + self.time_start_minute * 60 + self.time_start_second;
+        let end = self.time_end_hour * 60 * 60 + self.time_end_minute * 60 + self.time_end_second;
+
+        if start <= end {
+            start <= date && date <= end
+        } else {
+            start <= date || date <= end
+        }
+    }
+}
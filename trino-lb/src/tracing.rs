@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::time::Duration;
 
 use opentelemetry::{
     global,
@@ -20,10 +20,7 @@ use opentelemetry_sdk::{
 use snafu::{ResultExt, Snafu};
 use tracing::{level_filters::LevelFilter, subscriber::SetGlobalDefaultError};
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer};
-use trino_lb_core::config::{Config, TrinoLbTracingConfig};
-use trino_lb_persistence::PersistenceImplementation;
-
-use crate::metrics::{self, Metrics};
+use trino_lb_core::config::TrinoLbTracingConfig;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -38,21 +35,11 @@ pub enum Error {
 
     #[snafu(display("Failed to set global tracing subscriber"))]
     SetGlobalTracingSubscriber { source: SetGlobalDefaultError },
-
-    #[snafu(display("Failed to set up metrics"))]
-    SetUpMetrics { source: metrics::Error },
 }
 
-pub fn init(
-    tracing_config: Option<&TrinoLbTracingConfig>,
-    persistence: Arc<PersistenceImplementation>,
-    config: &Config,
-) -> Result<Metrics, Error> {
-    let env_filter_layer = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy();
-    let console_output_layer = tracing_subscriber::fmt::layer().with_filter(env_filter_layer);
-    let mut layers = vec![console_output_layer.boxed()];
+/// Returns the Prometheus registry the metrics are exported to.
+pub fn init(tracing_config: Option<&TrinoLbTracingConfig>) -> Result<prometheus::Registry, Error> {
+    let mut layers = vec![console_output_layer().boxed()];
 
     if let Some(tracing_config) = tracing_config {
         if tracing_config.enabled {
@@ -86,9 +73,26 @@ pub fn init(
     opentelemetry::global::set_meter_provider(meter_provider);
     opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
 
-    let metrics = Metrics::new(registry, persistence, config).context(SetUpMetricsSnafu)?;
+    Ok(registry)
+}
 
-    Ok(metrics)
+/// Only sets up the console output, which is used by commands that don't need traces or metrics (e.g. `migrate`).
+pub fn init_console_output() -> Result<(), Error> {
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(console_output_layer()),
+    )
+    .context(SetGlobalTracingSubscriberSnafu)
+}
+
+fn console_output_layer<S>() -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    let env_filter_layer = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+
+    tracing_subscriber::fmt::layer().with_filter(env_filter_layer)
 }
 
 fn otel_tracer(tracing_config: &TrinoLbTracingConfig) -> Result<trace::Tracer, Error> {
@@ -162,6 +166,34 @@ fn setup_custom_metrics(i: &Instrument) -> Option<Stream> {
                     record_min_max: true,
                 }),
         )
+    } else if i.name == "handoff_cluster_utilization" {
+        Some(
+            Stream::new()
+                .name(i.name.clone())
+                .description(i.description.clone())
+                .unit(i.unit.clone())
+                .aggregation(Aggregation::ExplicitBucketHistogram {
+                    boundaries: vec![
+                        0.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0, 100.0,
+                    ],
+                    record_min_max: true,
+                }),
+        )
+    } else if i.name == "persistence_operation_duration" {
+        Some(
+            Stream::new()
+                .name(i.name.clone())
+                .description(i.description.clone())
+                .unit(i.unit.clone())
+                .aggregation(Aggregation::ExplicitBucketHistogram {
+                    // Most operations take less than a millisecond, so the default boundaries are too coarse
+                    boundaries: vec![
+                        0.0, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0,
+                        1000.0, 2500.0, 5000.0, 10000.0,
+                    ],
+                    record_min_max: true,
+                }),
+        )
     } else {
         None
     }
@@ -52,16 +52,25 @@ pub struct ClusterInfo {
     pub total_cpu_time_secs: u64,
 }
 
+impl ClusterInfo {
+    /// The number of queries that count towards the query counter of the cluster.
+    pub fn query_count(&self) -> u64 {
+        self.running_queries + self.blocked_queries + self.queued_queries
+    }
+}
+
 #[instrument]
 pub async fn get_cluster_info(
     endpoint: &Url,
     ignore_certs: bool,
     credentials: &TrinoClusterCredentialsConfig,
+    user_agent: &str,
 ) -> Result<ClusterInfo, Error> {
     // We create a new client here every time just to be sure we don't accidentally leak the cookie store to a different
     // connection.
     let client = reqwest::Client::builder()
         .cookie_store(true)
+        .user_agent(user_agent)
         .danger_accept_invalid_certs(ignore_certs)
         .build()
         .context(ConstructHttpClientSnafu)?;
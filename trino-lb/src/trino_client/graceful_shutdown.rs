@@ -0,0 +1,58 @@
+use reqwest::header;
+use snafu::{ResultExt, Snafu};
+use tracing::instrument;
+use trino_lb_core::config::TrinoClusterCredentialsConfig;
+use url::Url;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to construct http client"))]
+    ConstructHttpClient { source: reqwest::Error },
+
+    #[snafu(display("Failed to join state path onto trino endpoint {trino_endpoint}"))]
+    JoinStatePathToTrinoEndpoint {
+        source: url::ParseError,
+        trino_endpoint: Url,
+    },
+
+    #[snafu(display("Failed to request graceful shutdown using endpoint {state_endpoint}"))]
+    RequestGracefulShutdown {
+        source: reqwest::Error,
+        state_endpoint: Url,
+    },
+}
+
+/// Asks the Trino coordinator to shut down gracefully. Trino stops accepting new queries and terminates once all
+/// running queries finished.
+#[instrument(skip(credentials))]
+pub async fn request_graceful_shutdown(
+    endpoint: &Url,
+    ignore_certs: bool,
+    credentials: &TrinoClusterCredentialsConfig,
+    user_agent: &str,
+) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .danger_accept_invalid_certs(ignore_certs)
+        .build()
+        .context(ConstructHttpClientSnafu)?;
+
+    let state_endpoint =
+        endpoint
+            .join("v1/info/state")
+            .context(JoinStatePathToTrinoEndpointSnafu {
+                trino_endpoint: endpoint.clone(),
+            })?;
+    client
+        .put(state_endpoint.clone())
+        .basic_auth(&credentials.username, Some(&credentials.password))
+        .header("X-Trino-User", &credentials.username)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body("\"SHUTTING_DOWN\"")
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context(RequestGracefulShutdownSnafu { state_endpoint })?;
+
+    Ok(())
+}
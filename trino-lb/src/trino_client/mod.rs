@@ -1,45 +1,61 @@
+use std::{fmt, future::Future, time::Duration};
+
+use crate::config::TrinoClientConfig;
+pub use cluster_info::{get_cluster_info, ClusterInfo, Error as ClusterInfoError};
+pub use graceful_shutdown::request_graceful_shutdown;
 use http::HeaderMap;
-use prusto::{auth::Auth, Client, ClientBuilder, DataSet};
+use serde::{
+    de::{IgnoredAny, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
 use snafu::{OptionExt, ResultExt, Snafu};
-use tracing::instrument;
+use tokio::time;
+use tracing::{debug, instrument, warn};
 use trino_lb_core::{
     sanitization::Sanitize,
-    trino_query_plan::{ExplainQueryResult, QueryPlan, QueryPlanEstimation},
+    trino_api::TrinoQueryApiResponse,
+    trino_query_plan::{QueryPlan, QueryPlanEstimation},
 };
-use url::Url;
-
-use crate::config::TrinoClientConfig;
-pub use cluster_info::{get_cluster_info, ClusterInfo};
 use workarounds::query_estimation_workarounds;
 
 mod cluster_info;
+mod graceful_shutdown;
 mod workarounds;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
-    #[snafu(display("Failed to extract Trino host from URL {url}"))]
-    ExtractTrinoHost { url: Url },
-
-    #[snafu(display("Failed to extract Trino port from URL {url}"))]
-    ExtractTrinoPort { url: Url },
-
-    #[snafu(display("Failed to create Trino client"))]
-    CreateTrinoClient { source: prusto::error::Error },
-
     #[snafu(display("Failed to create HTTP client"))]
     CreateHttpClient { source: reqwest::Error },
 
     #[snafu(display("Failed to execute explain query {explain_query:?}"))]
     ExecuteExplainQuery {
-        source: prusto::error::Error,
+        source: reqwest::Error,
+        explain_query: String,
+    },
+
+    #[snafu(display("The explain query {explain_query:?} failed: {message}"))]
+    ExplainQueryFailed {
         explain_query: String,
+        message: String,
     },
 
-    #[snafu(display("Failed to extract query plan from query_plan {query_plan:?}"))]
-    ExtractQueryPlan {
-        query_plan: DataSet<ExplainQueryResult>,
+    #[snafu(display("The explain query {explain_query:?} did not finish within {timeout:?}"))]
+    ExplainQueryTimeout {
+        explain_query: String,
+        timeout: Duration,
+    },
+
+    #[snafu(display("Failed to parse the data of the explain query {explain_query:?}"))]
+    ParseExplainQueryData {
+        source: serde_json::Error,
+        explain_query: String,
     },
 
+    #[snafu(display(
+        "The explain query {explain_query:?} finished without returning a query plan"
+    ))]
+    ExtractQueryPlan { explain_query: String },
+
     #[snafu(display("Failed to parse query plan {query_plan:?}"))]
     ParseQueryPlan {
         source: serde_json::Error,
@@ -58,18 +74,19 @@ pub enum Error {
 
 pub struct TrinoClient {
     config: TrinoClientConfig,
-    client: Client,
+    http_client: reqwest::Client,
 }
 
 impl TrinoClient {
     pub fn new(config: &TrinoClientConfig) -> Result<Self, Error> {
-        let client = trino_client_builder_from_config(config)?
+        let http_client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(config.ignore_cert)
             .build()
-            .context(CreateTrinoClientSnafu)?;
+            .context(CreateHttpClientSnafu)?;
 
         Ok(Self {
             config: config.clone(),
-            client,
+            http_client,
         })
     }
 }
@@ -86,6 +103,7 @@ impl TrinoClient {
         &self,
         query: &str,
         client_headers: &HeaderMap,
+        timeout: Duration,
     ) -> Result<QueryPlanEstimation, Error> {
         let explain_query = format!("explain (format json) {query}");
         if let Some(query_estimation_workarounds) = query_estimation_workarounds(query) {
@@ -93,72 +111,429 @@ impl TrinoClient {
             return Ok(query_estimation_workarounds);
         }
 
-        let trino_catalog = client_headers
-            .get("x-trino-catalog")
-            .and_then(|header| header.to_str().ok());
-        let trino_schema = client_headers
-            .get("x-trino-schema")
-            .and_then(|header| header.to_str().ok());
-
-        let query_plan: DataSet<ExplainQueryResult> =
-            if trino_catalog.is_none() && trino_schema.is_none() {
-                self.client
-                    .get_all(explain_query.clone())
-                    .await
-                    .context(ExecuteExplainQuerySnafu { explain_query })?
-            } else {
-                // In this case we sadly need to build a custom client that is set up to use the specific
-                // catalog and schema :/
-
-                let mut client_builder = trino_client_builder_from_config(&self.config)?;
-                if let Some(trino_catalog) = trino_catalog {
-                    client_builder = client_builder.catalog(trino_catalog);
+        let mut headers = HeaderMap::new();
+        for header in ["x-trino-catalog", "x-trino-schema"] {
+            if let Some(value) = client_headers.get(header) {
+                headers.insert(header, value.clone());
+            }
+        }
+
+        // Only dropping the future would leave the explain query running on Trino until it notices that nobody polls
+        // it any more, so we cancel it using the last nextUri we got. In case Trino did not even answer the submission,
+        // there is nothing we can cancel.
+        let mut next_uri = None;
+        let query_plan = match time::timeout(
+            timeout,
+            self.fetch_query_plan(&explain_query, headers, &mut next_uri),
+        )
+        .await
+        {
+            Ok(query_plan) => query_plan?,
+            Err(_) => {
+                if let Some(next_uri) = next_uri {
+                    // Trino is probably overloaded, so we don't wait for the cancellation to not delay the query
+                    // even further.
+                    tokio::spawn(self.cancel_query(next_uri));
                 }
-                if let Some(trino_schema) = trino_schema {
-                    client_builder = client_builder.schema(trino_schema);
+                return ExplainQueryTimeoutSnafu {
+                    explain_query,
+                    timeout,
                 }
-                let client = client_builder.build().context(CreateTrinoClientSnafu)?;
-
-                client
-                    .get_all(explain_query.clone())
-                    .await
-                    .context(ExecuteExplainQuerySnafu { explain_query })?
-            };
-
-        let query_plan = &query_plan
-            .as_slice()
-            .first()
-            .context(ExtractQueryPlanSnafu {
-                query_plan: query_plan.clone(),
-            })?
-            .query_plan;
+                .fail();
+            }
+        };
         let query_plan: QueryPlan =
-            serde_json::from_str(query_plan).context(ParseQueryPlanSnafu { query_plan })?;
+            serde_json::from_str(&query_plan).context(ParseQueryPlanSnafu { query_plan })?;
 
         Ok(query_plan.total_estimates())
     }
+
+    /// Runs the explain query and returns the plan contained in the first row, without fetching (and buffering) the
+    /// rest of the result. Very large plans can be spread across multiple pages, so the query is cancelled once we got
+    /// the first row.
+    ///
+    /// `current_next_uri` is kept up to date with the latest nextUri, so that the caller can cancel the query in case
+    /// it gives up on it.
+    async fn fetch_query_plan(
+        &self,
+        explain_query: &str,
+        headers: HeaderMap,
+        current_next_uri: &mut Option<String>,
+    ) -> Result<String, Error> {
+        let mut response: TrinoQueryApiResponse = self
+            .http_client
+            .post(
+                self.config
+                    .endpoint
+                    .join("v1/statement")
+                    .context(ConstructTrinoApiPathSnafu)?,
+            )
+            .basic_auth(&self.config.username, Some(&self.config.password))
+            .header("x-trino-user", &self.config.username)
+            .headers(headers)
+            .body(explain_query.to_owned())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context(ExecuteExplainQuerySnafu { explain_query })?
+            .json()
+            .await
+            .context(ExecuteExplainQuerySnafu { explain_query })?;
+
+        loop {
+            current_next_uri.clone_from(&response.next_uri);
+
+            if let Some(error) = response.error {
+                return ExplainQueryFailedSnafu {
+                    explain_query,
+                    message: error.message,
+                }
+                .fail();
+            }
+
+            if let Some(data) = &response.data {
+                let FirstRow(row) = serde_json::from_str(data.get())
+                    .context(ParseExplainQueryDataSnafu { explain_query })?;
+                if let Some((query_plan,)) = row {
+                    if let Some(next_uri) = &response.next_uri {
+                        self.cancel_query(next_uri.clone()).await;
+                    }
+                    return Ok(query_plan);
+                }
+            }
+
+            let next_uri = response
+                .next_uri
+                .context(ExtractQueryPlanSnafu { explain_query })?;
+            response = self
+                .http_client
+                .get(next_uri)
+                .basic_auth(&self.config.username, Some(&self.config.password))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .context(ExecuteExplainQuerySnafu { explain_query })?
+                .json()
+                .await
+                .context(ExecuteExplainQuerySnafu { explain_query })?;
+        }
+    }
+
+    /// Failing to cancel the query is not a problem, Trino abandons it once we stop polling.
+    ///
+    /// The returned future does not borrow the client, so it can also be spawned in case the caller does not want to
+    /// wait for the cancellation.
+    fn cancel_query(&self, next_uri: String) -> impl Future<Output = ()> + Send + 'static {
+        let request = self
+            .http_client
+            .delete(&next_uri)
+            .basic_auth(&self.config.username, Some(&self.config.password))
+            .send();
+
+        async move {
+            match request.await.and_then(reqwest::Response::error_for_status) {
+                Ok(_) => debug!(next_uri, "Cancelled explain query"),
+                Err(error) => warn!(?error, next_uri, "Failed to cancel explain query"),
+            }
+        }
+    }
 }
 
-#[instrument]
-fn trino_client_builder_from_config(config: &TrinoClientConfig) -> Result<ClientBuilder, Error> {
-    Ok(ClientBuilder::new(
-        &config.username,
-        config.endpoint.host().context(ExtractTrinoHostSnafu {
-            url: config.endpoint.clone(),
-        })?,
-    )
-    .port(
-        config
-            .endpoint
-            .port_or_known_default()
-            .context(ExtractTrinoPortSnafu {
-                url: config.endpoint.clone(),
-            })?,
-    )
-    .secure(config.endpoint.scheme() == "https")
-    .no_verify(config.ignore_cert)
-    .auth(Auth::Basic(
-        config.username.to_owned(),
-        Some(config.password.to_owned()),
-    )))
+/// The first row of the `data` Trino returned, all other rows are skipped without being parsed.
+struct FirstRow<T>(Option<T>);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for FirstRow<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FirstRowVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for FirstRowVisitor<T> {
+            type Value = FirstRow<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of rows")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let first = seq.next_element()?;
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+                Ok(FirstRow(first))
+            }
+        }
+
+        deserializer.deserialize_seq(FirstRowVisitor(std::marker::PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        extract::State,
+        routing::{get, post},
+        Json,
+    };
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeTrinoCalls {
+        fetched_second_page: AtomicBool,
+        cancelled: AtomicBool,
+    }
+
+    fn fake_trino_response(
+        trino_endpoint: &Url,
+        next_uri: Option<&str>,
+        data: Option<serde_json::Value>,
+        error: Option<&str>,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "id": "20240101_120000_00001_abcde",
+            "nextUri": next_uri.map(|next_uri| trino_endpoint.join(next_uri).unwrap()),
+            "infoUri": trino_endpoint.join("/ui/query.html?20240101_120000_00001_abcde").unwrap(),
+            "data": data,
+            "error": error.map(|message| serde_json::json!({
+                "message": message,
+                "errorCode": 1,
+                "errorName": "SYNTAX_ERROR",
+                "errorType": "USER_ERROR",
+                "failureInfo": {
+                    "type": "io.trino.sql.parser.ParsingException",
+                    "message": message,
+                    "suppressed": [],
+                    "stack": [],
+                },
+            })),
+            "warnings": [],
+            "stats": {
+                "completedSplits": 0,
+                "cpuTimeMillis": 0,
+                "elapsedTimeMillis": 0,
+                "nodes": 1,
+                "peakMemoryBytes": 0,
+                "physicalInputBytes": 0,
+                "processedBytes": 0,
+                "processedRows": 0,
+                "queuedSplits": 0,
+                "queuedTimeMillis": 0,
+                "queued": false,
+                "runningSplits": 0,
+                "scheduled": true,
+                "spilledBytes": 0,
+                "state": "RUNNING",
+                "totalSplits": 0,
+                "wallTimeMillis": 0,
+            },
+        })
+    }
+
+    /// A plan with many fragments, which each estimate a single output row.
+    fn large_query_plan(fragments: usize) -> String {
+        let estimates = serde_json::json!([{
+            "outputRowCount": 1.0,
+            "outputSizeInBytes": 8.0,
+            "cpuCost": 8.0,
+            "memoryCost": 0.0,
+            "networkCost": "NaN",
+        }]);
+        let plan: serde_json::Map<_, _> = (0..fragments)
+            .map(|fragment| {
+                (
+                    fragment.to_string(),
+                    serde_json::json!({
+                        "id": fragment.to_string(),
+                        "name": "Output",
+                        "descriptor": { "columnNames": "[_col0]" },
+                        "outputs": [{ "symbol": "_col0", "type": "bigint" }],
+                        "details": ["_col0 := expr"],
+                        "estimates": estimates,
+                        "children": [],
+                    }),
+                )
+            })
+            .collect();
+        serde_json::to_string(&plan).unwrap()
+    }
+
+    /// Starts a fake Trino coordinator. The explain query is queued on submission, the first page contains the plan and
+    /// the second page should never be fetched.
+    async fn start_fake_trino(
+        query_plan: String,
+        error: Option<&'static str>,
+    ) -> (Url, Arc<FakeTrinoCalls>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let calls = Arc::new(FakeTrinoCalls::default());
+
+        let post_endpoint = endpoint.clone();
+        let first_page_endpoint = endpoint.clone();
+        let second_page_endpoint = endpoint.clone();
+        let app = axum::Router::new()
+            .route(
+                "/v1/statement",
+                post(move || async move {
+                    Json(fake_trino_response(
+                        &post_endpoint,
+                        Some("/v1/statement/queued/q/y/1"),
+                        None,
+                        None,
+                    ))
+                }),
+            )
+            .route(
+                "/v1/statement/queued/q/y/1",
+                get(move || async move {
+                    match error {
+                        Some(error) => Json(fake_trino_response(
+                            &first_page_endpoint,
+                            None,
+                            None,
+                            Some(error),
+                        )),
+                        None => Json(fake_trino_response(
+                            &first_page_endpoint,
+                            Some("/v1/statement/executing/q/y/2"),
+                            Some(serde_json::json!([[query_plan], ["second row"]])),
+                            None,
+                        )),
+                    }
+                }),
+            )
+            .route(
+                "/v1/statement/executing/q/y/2",
+                get(|State(calls): State<Arc<FakeTrinoCalls>>| async move {
+                    calls.fetched_second_page.store(true, Ordering::SeqCst);
+                    Json(fake_trino_response(
+                        &second_page_endpoint,
+                        None,
+                        Some(serde_json::json!([["third row"]])),
+                        None,
+                    ))
+                })
+                .delete(|State(calls): State<Arc<FakeTrinoCalls>>| async move {
+                    calls.cancelled.store(true, Ordering::SeqCst);
+                }),
+            )
+            .with_state(Arc::clone(&calls));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (endpoint, calls)
+    }
+
+    fn trino_client(endpoint: Url) -> TrinoClient {
+        TrinoClient::new(&TrinoClientConfig {
+            endpoint,
+            ignore_cert: false,
+            username: "admin".to_owned(),
+            password: "admin".to_owned(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query_estimation_only_fetches_first_row() {
+        let (endpoint, calls) = start_fake_trino(large_query_plan(10_000), None).await;
+
+        let estimation = trino_client(endpoint)
+            .query_estimation(
+                "SELECT * FROM big_view",
+                &HeaderMap::new(),
+                Duration::from_secs(10),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(estimation.output_row_count, 10_000.0);
+        assert_eq!(estimation.network_cost, 0.0);
+        assert!(!calls.fetched_second_page.load(Ordering::SeqCst));
+        assert!(calls.cancelled.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_query_estimation_failed_explain_query() {
+        let (endpoint, _) =
+            start_fake_trino(String::new(), Some("line 1:1: mismatched input")).await;
+
+        let error = trino_client(endpoint)
+            .query_estimation("SELEKT 1", &HeaderMap::new(), Duration::from_secs(10))
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(&error, Error::ExplainQueryFailed { message, .. } if message == "line 1:1: mismatched input"),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_estimation_timeout_cancels_query() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let calls = Arc::new(FakeTrinoCalls::default());
+
+        // The explain query is queued on submission and stays queued forever
+        let post_endpoint = endpoint.clone();
+        let app = axum::Router::new()
+            .route(
+                "/v1/statement",
+                post(move || async move {
+                    Json(fake_trino_response(
+                        &post_endpoint,
+                        Some("/v1/statement/queued/q/y/1"),
+                        None,
+                        None,
+                    ))
+                }),
+            )
+            .route(
+                "/v1/statement/queued/q/y/1",
+                get(std::future::pending::<()>).delete(
+                    |State(calls): State<Arc<FakeTrinoCalls>>| async move {
+                        calls.cancelled.store(true, Ordering::SeqCst);
+                    },
+                ),
+            )
+            .with_state(Arc::clone(&calls));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let error = trino_client(endpoint)
+            .query_estimation("SELECT 1", &HeaderMap::new(), Duration::from_millis(200))
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(error, Error::ExplainQueryTimeout { .. }),
+            "{error:?}"
+        );
+
+        // The cancellation happens in the background
+        time::timeout(Duration::from_secs(5), async {
+            while !calls.cancelled.load(Ordering::SeqCst) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The explain query was not cancelled");
+    }
+
+    #[test]
+    fn test_first_row() {
+        let FirstRow(row) =
+            serde_json::from_str::<FirstRow<(String,)>>(r#"[["a"], [1, 2], {"ignored": true}]"#)
+                .unwrap();
+        assert_eq!(row, Some(("a".to_owned(),)));
+
+        let FirstRow(row) = serde_json::from_str::<FirstRow<(String,)>>("[]").unwrap();
+        assert_eq!(row, None);
+    }
 }